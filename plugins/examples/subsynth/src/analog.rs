@@ -0,0 +1,266 @@
+use nih_plug::prelude::*;
+use rand::Rng;
+use rand_pcg::Pcg32;
+
+use crate::envelope::EnvelopeSettings;
+
+#[derive(Params)]
+pub struct AnalogParams {
+    /// How far the voices' pitches wander, in cents.
+    #[id = "analog_drift"]
+    pub drift: FloatParam,
+    /// How often the pitch drift picks a new direction.
+    #[id = "analog_drift_rate"]
+    pub drift_rate: FloatParam,
+    /// How far apart the voices' filter cutoffs are, in semitones.
+    #[id = "analog_cutoff"]
+    pub cutoff_spread: FloatParam,
+    /// How much the voices' envelope times differ from each other.
+    #[id = "analog_env"]
+    pub envelope_spread: FloatParam,
+}
+
+impl Default for AnalogParams {
+    fn default() -> Self {
+        Self {
+            drift: FloatParam::new(
+                "Analog Drift",
+                0.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 50.0,
+                },
+            )
+            .with_step_size(0.1)
+            .with_unit(" cents"),
+            drift_rate: FloatParam::new(
+                "Analog Drift Rate",
+                0.5,
+                FloatRange::Skewed {
+                    min: 0.05,
+                    max: 5.0,
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_unit(" Hz")
+            .with_value_to_string(formatters::v2s_f32_rounded(2)),
+            cutoff_spread: FloatParam::new(
+                "Analog Cutoff Spread",
+                0.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 12.0,
+                },
+            )
+            .with_step_size(0.01)
+            .with_unit(" st"),
+            envelope_spread: FloatParam::new(
+                "Analog Envelope Spread",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 0.5 },
+            )
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+        }
+    }
+}
+
+impl AnalogParams {
+    pub fn settings(&self, sample_rate: f32) -> AnalogSettings {
+        AnalogSettings {
+            drift_semitones: self.drift.value() / 100.0,
+            drift_phase_delta: self.drift_rate.value() / sample_rate,
+            cutoff_spread_octaves: self.cutoff_spread.value() / 12.0,
+            envelope_spread: self.envelope_spread.value(),
+        }
+    }
+}
+
+/// The analog section's parameters, computed once per buffer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AnalogSettings {
+    pub drift_semitones: f32,
+    /// How far the drift moves towards its next target every sample.
+    pub drift_phase_delta: f32,
+    pub cutoff_spread_octaves: f32,
+    pub envelope_spread: f32,
+}
+
+/// The component tolerances of a single voice slot, like the voice cards in a polysynth. A card's
+/// offsets are drawn once when the plugin is reset, so every voice slot keeps its own character.
+/// The parameters only scale how much of that character is heard.
+#[derive(Debug, Clone)]
+pub struct VoiceCard {
+    /// The cutoff offset in `[-1, 1]`, scaled by the cutoff spread.
+    cutoff_offset: f32,
+    /// The attack, decay and release time offsets in `[-1, 1]`, scaled by the envelope spread.
+    envelope_offsets: [f32; 3],
+    drift: Drift,
+}
+
+impl VoiceCard {
+    pub fn new(prng: &mut Pcg32) -> Self {
+        Self {
+            cutoff_offset: prng.gen_range(-1.0..=1.0),
+            envelope_offsets: std::array::from_fn(|_| prng.gen_range(-1.0..=1.0)),
+            drift: Drift::new(prng.gen()),
+        }
+    }
+
+    /// Advance the pitch drift by `num_samples` samples. The drift is slow enough to only be
+    /// updated once per block.
+    pub fn advance(&mut self, settings: &AnalogSettings, num_samples: usize) {
+        self.drift
+            .advance(settings.drift_phase_delta * num_samples as f32);
+    }
+
+    /// The pitch drift in semitones.
+    pub fn drift_semitones(&self, settings: &AnalogSettings) -> f32 {
+        self.drift.value() * settings.drift_semitones
+    }
+
+    /// The cutoff offset in octaves.
+    pub fn cutoff_octaves(&self, settings: &AnalogSettings) -> f32 {
+        self.cutoff_offset * settings.cutoff_spread_octaves
+    }
+
+    /// Lengthen or shorten `envelope`'s attack, decay and release times according to this card's
+    /// offsets.
+    pub fn vary_envelope(
+        &self,
+        envelope: EnvelopeSettings,
+        settings: &AnalogSettings,
+    ) -> EnvelopeSettings {
+        let [attack, decay, release] = self
+            .envelope_offsets
+            .map(|offset| 1.0 + offset * settings.envelope_spread);
+        EnvelopeSettings {
+            attack_ms: envelope.attack_ms * attack,
+            decay_ms: envelope.decay_ms * decay,
+            release_ms: envelope.release_ms * release,
+            ..envelope
+        }
+    }
+}
+
+/// A slow random walk in `[-1, 1]` that glides between random targets.
+#[derive(Debug, Clone)]
+struct Drift {
+    prng: Pcg32,
+    previous: f32,
+    target: f32,
+    /// How far along the glide from `previous` to `target` the drift is, in `[0, 1)`.
+    phase: f32,
+}
+
+impl Drift {
+    fn new(seed: u64) -> Self {
+        let mut prng = Pcg32::new(seed, 1442695040888963407);
+        let previous = prng.gen_range(-1.0..=1.0);
+        let target = prng.gen_range(-1.0..=1.0);
+
+        Self {
+            prng,
+            previous,
+            target,
+            phase: 0.0,
+        }
+    }
+
+    fn advance(&mut self, phase_delta: f32) {
+        self.phase += phase_delta;
+        while self.phase >= 1.0 {
+            self.phase -= 1.0;
+            self.previous = self.target;
+            self.target = self.prng.gen_range(-1.0..=1.0);
+        }
+    }
+
+    fn value(&self) -> f32 {
+        // A cosine shaped glide doesn't have any corners when the drift changes direction
+        let t = 0.5 - 0.5 * (self.phase * std::f32::consts::PI).cos();
+        self.previous + (self.target - self.previous) * t
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> AnalogSettings {
+        AnalogSettings {
+            drift_semitones: 0.1,
+            drift_phase_delta: 1.0 / 44100.0,
+            cutoff_spread_octaves: 0.5,
+            envelope_spread: 0.2,
+        }
+    }
+
+    #[test]
+    fn test_voice_cards_are_fixed_and_differ() {
+        let mut prng = Pcg32::new(420, 1337);
+        let cards: Vec<VoiceCard> = (0..8).map(|_| VoiceCard::new(&mut prng)).collect();
+        let mut prng = Pcg32::new(420, 1337);
+        let same_cards: Vec<VoiceCard> = (0..8).map(|_| VoiceCard::new(&mut prng)).collect();
+
+        let settings = settings();
+        for (card, same_card) in cards.iter().zip(&same_cards) {
+            assert_eq!(
+                card.cutoff_octaves(&settings),
+                same_card.cutoff_octaves(&settings)
+            );
+            assert_eq!(card.envelope_offsets, same_card.envelope_offsets);
+            assert_eq!(
+                card.drift_semitones(&settings),
+                same_card.drift_semitones(&settings)
+            );
+        }
+        assert!(cards
+            .windows(2)
+            .all(|pair| pair[0].cutoff_offset != pair[1].cutoff_offset));
+    }
+
+    #[test]
+    fn test_drift_is_slow_and_bounded() {
+        let settings = settings();
+        let mut card = VoiceCard::new(&mut Pcg32::new(1, 2));
+        let mut previous = card.drift_semitones(&settings);
+        let mut min: f32 = previous;
+        let mut max: f32 = previous;
+        // Ten seconds in 64 sample blocks
+        for _ in 0..(441000 / 64) {
+            card.advance(&settings, 64);
+            let drift = card.drift_semitones(&settings);
+            assert!(drift.abs() <= settings.drift_semitones);
+            // At one target per second, a block can only move a small part of the full range
+            assert!((drift - previous).abs() < settings.drift_semitones * 0.02);
+            previous = drift;
+            min = min.min(drift);
+            max = max.max(drift);
+        }
+        assert!(max - min > settings.drift_semitones * 0.5);
+    }
+
+    #[test]
+    fn test_zero_spread_leaves_envelopes_alone() {
+        let card = VoiceCard::new(&mut Pcg32::new(1, 2));
+        let envelope = EnvelopeSettings {
+            attack_ms: 10.0,
+            decay_ms: 200.0,
+            sustain: 0.5,
+            release_ms: 500.0,
+            ..EnvelopeSettings::default()
+        };
+        let mut settings = settings();
+        settings.envelope_spread = 0.0;
+        assert_eq!(card.vary_envelope(envelope, &settings), envelope);
+
+        settings.envelope_spread = 0.2;
+        let varied = card.vary_envelope(envelope, &settings);
+        assert_ne!(varied, envelope);
+        assert!((8.0..=12.0).contains(&varied.attack_ms));
+        assert!((400.0..=600.0).contains(&varied.release_ms));
+        assert_eq!(varied.sustain, envelope.sustain);
+    }
+}
@@ -0,0 +1,674 @@
+use enum_iterator::Sequence;
+use nih_plug::prelude::*;
+use rand::Rng;
+use rand_pcg::Pcg32;
+
+use crate::lfo::SyncRate;
+use crate::pedals::NoteSet;
+use crate::voice_mode::{HeldNote, HeldNotes, MAX_HELD_NOTES};
+
+/// How far every second step is delayed at 100% swing, as a fraction of a step.
+const MAX_SWING_DELAY: f64 = 0.5;
+/// How far the host's playback position may drift from the arpeggiator's own position, in steps,
+/// before the arpeggiator treats it as a jump in the transport.
+const TRANSPORT_JUMP_TOLERANCE: f64 = 0.01;
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Enum, Sequence)]
+pub enum ArpMode {
+    Up,
+    Down,
+    #[name = "Up/Down"]
+    UpDown,
+    Random,
+    /// Play the notes in the order they were played in.
+    #[name = "As Played"]
+    AsPlayed,
+    /// Play all notes at once on every step.
+    Chord,
+}
+
+/// What happens to the arpeggiated notes when their keys are released.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Enum, Sequence)]
+pub enum ArpLatch {
+    /// Notes are removed from the pattern when their keys are released.
+    Off,
+    /// The pattern keeps playing after all keys have been released. The next note that's played
+    /// after that starts a new pattern.
+    Latch,
+    /// Every note that's played is added to the pattern until hold is switched off again.
+    Hold,
+}
+
+/// The arpeggiator's parameters. The arpeggiator sits between the incoming notes and the voices.
+#[derive(Params)]
+pub struct ArpParams {
+    #[id = "arp_enable"]
+    pub enabled: BoolParam,
+    #[id = "arp_mode"]
+    pub mode: EnumParam<ArpMode>,
+    #[id = "arp_octaves"]
+    pub octaves: IntParam,
+    /// The step rate in Hertz, used when the arpeggiator is not synced to the host's tempo or when
+    /// the host doesn't provide a tempo.
+    #[id = "arp_rate"]
+    pub rate: FloatParam,
+    #[id = "arp_sync"]
+    pub sync: BoolParam,
+    #[id = "arp_sync_rate"]
+    pub sync_rate: EnumParam<SyncRate>,
+    /// How long every note is held, as a fraction of its step.
+    #[id = "arp_gate"]
+    pub gate: FloatParam,
+    /// Delays every second step. At 100% the step is delayed by half a step.
+    #[id = "arp_swing"]
+    pub swing: FloatParam,
+    #[id = "arp_latch"]
+    pub latch: EnumParam<ArpLatch>,
+    /// Also send the arpeggiated notes to the plugin's MIDI output.
+    #[id = "arp_midi_out"]
+    pub midi_output: BoolParam,
+}
+
+impl Default for ArpParams {
+    fn default() -> Self {
+        Self {
+            enabled: BoolParam::new("Arpeggiator", false),
+            mode: EnumParam::new("Arp Mode", ArpMode::Up),
+            octaves: IntParam::new("Arp Octaves", 1, IntRange::Linear { min: 1, max: 4 }),
+            rate: FloatParam::new(
+                "Arp Rate",
+                8.0,
+                FloatRange::Skewed {
+                    min: 0.5,
+                    max: 40.0,
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_unit(" Hz")
+            .with_value_to_string(formatters::v2s_f32_rounded(2)),
+            sync: BoolParam::new("Arp Sync", true),
+            sync_rate: EnumParam::new("Arp Sync Rate", SyncRate::Sixteenth),
+            gate: FloatParam::new(
+                "Arp Gate",
+                0.5,
+                FloatRange::Linear {
+                    min: 0.05,
+                    max: 1.0,
+                },
+            )
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            swing: FloatParam::new("Arp Swing", 0.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_unit(" %")
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_string_to_value(formatters::s2v_f32_percentage()),
+            latch: EnumParam::new("Arp Latch", ArpLatch::Off),
+            midi_output: BoolParam::new("Arp MIDI Output", false),
+        }
+    }
+}
+
+impl ArpParams {
+    /// The arpeggiator's settings for the current buffer. `tempo` is the host's tempo in beats per
+    /// minute and `pos_beats` is the host's playback position in quarter notes while it's playing.
+    /// A synced arpeggiator follows the playback position if there is one, and it runs freely at
+    /// the host's tempo otherwise.
+    pub fn settings(
+        &self,
+        sample_rate: f32,
+        tempo: Option<f64>,
+        pos_beats: Option<f64>,
+    ) -> ArpSettings {
+        let (steps_per_sample, transport_position) = match (self.sync.value(), tempo) {
+            (true, Some(tempo)) => {
+                let step_beats = self.sync_rate.value().beats();
+                (
+                    tempo / 60.0 / step_beats / sample_rate as f64,
+                    pos_beats.map(|pos_beats| pos_beats / step_beats),
+                )
+            }
+            _ => (self.rate.value() as f64 / sample_rate as f64, None),
+        };
+
+        ArpSettings {
+            mode: self.mode.value(),
+            octaves: self.octaves.value() as usize,
+            steps_per_sample,
+            transport_position,
+            gate: self.gate.value() as f64,
+            swing_delay: self.swing.value() as f64 * MAX_SWING_DELAY,
+            latch: self.latch.value(),
+            midi_output: self.midi_output.value(),
+        }
+    }
+}
+
+/// The arpeggiator's parameters, computed once per buffer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ArpSettings {
+    pub mode: ArpMode,
+    pub octaves: usize,
+    pub steps_per_sample: f64,
+    /// The host's playback position in steps at the start of the buffer, if the arpeggiator
+    /// follows it.
+    pub transport_position: Option<f64>,
+    pub gate: f64,
+    /// How far every second step is delayed, as a fraction of a step.
+    pub swing_delay: f64,
+    pub latch: ArpLatch,
+    /// Whether the notes are also sent to the plugin's MIDI output.
+    pub midi_output: bool,
+}
+
+impl ArpSettings {
+    /// The position where step `step` starts. Odd steps are delayed by the swing.
+    fn step_position(&self, step: i64) -> f64 {
+        if step.rem_euclid(2) == 1 {
+            step as f64 + self.swing_delay
+        } else {
+            step as f64
+        }
+    }
+}
+
+/// Something the arpeggiator wants to happen at the current position.
+// Boxing the notes would allocate on the audio thread
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArpEvent {
+    /// Release the previous step's notes.
+    NoteOff(NoteSet),
+    /// Start playing a step's notes.
+    NoteOn(HeldNotes),
+}
+
+/// The arpeggiator's state. Positions are measured in steps, and step `n` starts at position `n`
+/// unless it's delayed by the swing.
+#[derive(Debug, Clone)]
+pub struct Arpeggiator {
+    /// The keys that are physically held down and that were played into the arpeggiator.
+    keys_down: NoteSet,
+    /// The notes in the pattern, in the order they were played in. With latch or hold enabled this
+    /// also contains notes whose keys have been released.
+    notes: HeldNotes,
+    /// The notes from the last step that are still playing.
+    playing: NoteSet,
+    /// The previous latch mode, so notes can be removed from the pattern when latch is disabled.
+    latch: ArpLatch,
+    position: f64,
+    /// The index of the next step.
+    next_step: i64,
+    /// The position where the playing notes are released.
+    gate_end: f64,
+    /// The number of steps played since the pattern started, used to pick the step's notes.
+    pattern_step: usize,
+    /// Whether the position follows the host's transport. New patterns then start on the next
+    /// step, instead of immediately.
+    synced: bool,
+    prng: Pcg32,
+}
+
+impl Default for Arpeggiator {
+    fn default() -> Self {
+        Self {
+            keys_down: NoteSet::default(),
+            notes: HeldNotes::default(),
+            playing: NoteSet::default(),
+            latch: ArpLatch::Off,
+            position: 0.0,
+            next_step: 0,
+            gate_end: 0.0,
+            pattern_step: 0,
+            synced: false,
+            prng: Pcg32::new(2468, 1357),
+        }
+    }
+}
+
+impl Arpeggiator {
+    /// Register a key being pressed while the arpeggiator is enabled.
+    pub fn key_down(&mut self, held_note: HeldNote) {
+        if self.latch == ArpLatch::Latch && self.keys_down.is_empty() {
+            self.notes.clear();
+        }
+
+        if self.notes.is_empty() {
+            self.pattern_step = 0;
+            if !self.synced {
+                self.position = 0.0;
+                self.next_step = 0;
+                self.gate_end = 0.0;
+            }
+        }
+
+        self.keys_down.insert(held_note.channel, held_note.note);
+        self.notes.push(held_note);
+    }
+
+    /// Register a key being released. Returns `false` if the key wasn't played into the
+    /// arpeggiator, in which case the note should be released as usual.
+    pub fn key_up(&mut self, channel: u8, note: u8) -> bool {
+        if !self.keys_down.contains(channel, note) {
+            return false;
+        }
+
+        self.keys_down.remove(channel, note);
+        if self.latch == ArpLatch::Off {
+            self.notes.remove(channel, note);
+        }
+
+        true
+    }
+
+    /// Whether `note` on `channel` is one of the notes that are currently playing.
+    pub fn is_playing(&self, channel: u8, note: u8) -> bool {
+        self.playing.contains(channel, note)
+    }
+
+    /// Stop the pattern. The notes that are still playing are released with the next event.
+    pub fn stop(&mut self) {
+        self.keys_down = NoteSet::default();
+        self.notes.clear();
+        self.gate_end = self.gate_end.min(self.position);
+    }
+
+    /// Apply the settings for the next buffer. This needs to be called at the start of every
+    /// buffer.
+    pub fn update(&mut self, settings: &ArpSettings) {
+        // Notes that were only held by the latch are dropped when it's switched off
+        if settings.latch == ArpLatch::Off && self.latch != ArpLatch::Off {
+            let mut released_notes = NoteSet::default();
+            for held_note in self.notes.iter() {
+                if !self.keys_down.contains(held_note.channel, held_note.note) {
+                    released_notes.insert(held_note.channel, held_note.note);
+                }
+            }
+            for (channel, note) in released_notes.iter() {
+                self.notes.remove(channel, note);
+            }
+        }
+        self.latch = settings.latch;
+
+        match settings.transport_position {
+            Some(position) => {
+                if !self.synced || (position - self.position).abs() > TRANSPORT_JUMP_TOLERANCE {
+                    self.position = position;
+                    self.next_step = position.ceil() as i64;
+                    self.gate_end = self.gate_end.min(position);
+                }
+                self.synced = true;
+            }
+            None => self.synced = false,
+        }
+    }
+
+    /// The number of samples until the next event, if there is one.
+    pub fn samples_until_next_event(&self, settings: &ArpSettings) -> Option<f64> {
+        let gate_end = Some(self.gate_end).filter(|_| !self.playing.is_empty());
+        let next_step =
+            Some(settings.step_position(self.next_step)).filter(|_| !self.notes.is_empty());
+        let next_event = match (gate_end, next_step) {
+            (Some(gate_end), Some(next_step)) => Some(gate_end.min(next_step)),
+            (gate_end, next_step) => gate_end.or(next_step),
+        };
+
+        next_event.map(|position| ((position - self.position) / settings.steps_per_sample).max(0.0))
+    }
+
+    /// The next event that's due at the current position. This should be called until it returns
+    /// `None`.
+    pub fn next_event(&mut self, settings: &ArpSettings) -> Option<ArpEvent> {
+        if self.notes.is_empty() {
+            // Keep the next step ahead of the position so a new pattern starts on the next step
+            self.next_step = self.next_step.max(self.position.ceil() as i64);
+        }
+
+        let step_start = settings.step_position(self.next_step);
+        let step_due = !self.notes.is_empty() && step_start <= self.position;
+        if !self.playing.is_empty() && (self.gate_end <= self.position || step_due) {
+            let released_notes = self.playing;
+            self.playing = NoteSet::default();
+            return Some(ArpEvent::NoteOff(released_notes));
+        }
+        if !step_due {
+            return None;
+        }
+
+        let step_end = settings.step_position(self.next_step + 1);
+        self.gate_end = step_start + (step_end - step_start) * settings.gate;
+        self.next_step += 1;
+
+        let step_notes = self.step_notes(settings.mode, settings.octaves);
+        self.pattern_step += 1;
+        for held_note in step_notes.iter() {
+            self.playing.insert(held_note.channel, held_note.note);
+        }
+
+        Some(ArpEvent::NoteOn(step_notes))
+    }
+
+    /// Move the position forward by `num_samples` samples.
+    pub fn advance(&mut self, num_samples: usize, settings: &ArpSettings) {
+        self.position += num_samples as f64 * settings.steps_per_sample;
+    }
+
+    /// The notes for the current pattern step.
+    fn step_notes(&mut self, mode: ArpMode, octaves: usize) -> HeldNotes {
+        let mut notes = [None; MAX_HELD_NOTES];
+        for (slot, held_note) in notes.iter_mut().zip(self.notes.iter()) {
+            *slot = Some(held_note);
+        }
+        let notes = &mut notes[..self.notes.len()];
+        if mode != ArpMode::AsPlayed {
+            notes.sort_by_key(|held_note| held_note.map(|held_note| held_note.note));
+        }
+
+        let transpose = |held_note: Option<HeldNote>, octave: usize| {
+            let mut held_note = held_note.unwrap();
+            let note = held_note.note as usize + octave * 12;
+            if note > 127 {
+                return None;
+            }
+
+            held_note.note = note as u8;
+            Some(held_note)
+        };
+
+        let mut step_notes = HeldNotes::default();
+        let pattern_len = notes.len() * octaves;
+        let idx = match mode {
+            ArpMode::Up | ArpMode::AsPlayed => self.pattern_step % pattern_len,
+            ArpMode::Down => pattern_len - 1 - self.pattern_step % pattern_len,
+            ArpMode::UpDown if pattern_len == 1 => 0,
+            ArpMode::UpDown => {
+                // The highest and lowest notes are only played once per cycle
+                let idx = self.pattern_step % (pattern_len * 2 - 2);
+                if idx < pattern_len {
+                    idx
+                } else {
+                    pattern_len * 2 - 2 - idx
+                }
+            }
+            ArpMode::Random => self.prng.gen_range(0..pattern_len),
+            ArpMode::Chord => {
+                let octave = self.pattern_step % octaves;
+                for held_note in notes.iter() {
+                    if let Some(held_note) = transpose(*held_note, octave) {
+                        step_notes.push(held_note);
+                    }
+                }
+
+                return step_notes;
+            }
+        };
+
+        if let Some(held_note) = transpose(notes[idx % notes.len()], idx / notes.len()) {
+            step_notes.push(held_note);
+        }
+
+        step_notes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(mode: ArpMode, octaves: usize) -> ArpSettings {
+        ArpSettings {
+            mode,
+            octaves,
+            // Every step is four samples long
+            steps_per_sample: 0.25,
+            transport_position: None,
+            gate: 0.5,
+            swing_delay: 0.0,
+            latch: ArpLatch::Off,
+            midi_output: false,
+        }
+    }
+
+    fn held_note(note: u8) -> HeldNote {
+        HeldNote {
+            voice_id: None,
+            channel: 0,
+            note,
+            velocity: 0.8,
+            sequenced: false,
+        }
+    }
+
+    /// Run the arpeggiator for `num_samples` samples, returning the sample offsets and notes of
+    /// every note on event. Notes that are played together are ordered from low to high.
+    fn note_ons(
+        arp: &mut Arpeggiator,
+        settings: &ArpSettings,
+        num_samples: usize,
+    ) -> Vec<(usize, Vec<u8>)> {
+        let mut note_ons = Vec::new();
+        arp.update(settings);
+        for sample_idx in 0..num_samples {
+            while let Some(event) = arp.next_event(settings) {
+                if let ArpEvent::NoteOn(notes) = event {
+                    note_ons.push((sample_idx, notes.iter().map(|note| note.note).collect()));
+                }
+            }
+            arp.advance(1, settings);
+        }
+
+        note_ons
+    }
+
+    fn pattern(mode: ArpMode, octaves: usize, notes: &[u8], num_steps: usize) -> Vec<Vec<u8>> {
+        let mut arp = Arpeggiator::default();
+        for note in notes {
+            arp.key_down(held_note(*note));
+        }
+
+        note_ons(&mut arp, &settings(mode, octaves), num_steps * 4)
+            .into_iter()
+            .map(|(_, notes)| notes)
+            .collect()
+    }
+
+    #[test]
+    fn test_modes() {
+        assert_eq!(
+            pattern(ArpMode::Up, 1, &[64, 60, 67], 4),
+            [vec![60], vec![64], vec![67], vec![60]]
+        );
+        assert_eq!(
+            pattern(ArpMode::Down, 1, &[64, 60, 67], 4),
+            [vec![67], vec![64], vec![60], vec![67]]
+        );
+        assert_eq!(
+            pattern(ArpMode::UpDown, 1, &[64, 60, 67], 6),
+            [vec![60], vec![64], vec![67], vec![64], vec![60], vec![64]]
+        );
+        assert_eq!(
+            pattern(ArpMode::AsPlayed, 1, &[64, 60, 67], 4),
+            [vec![64], vec![60], vec![67], vec![64]]
+        );
+        assert_eq!(
+            pattern(ArpMode::Chord, 1, &[64, 60, 67], 2),
+            [vec![60, 64, 67], vec![60, 64, 67]]
+        );
+
+        let random_pattern = pattern(ArpMode::Random, 1, &[64, 60, 67], 16);
+        assert_eq!(random_pattern.len(), 16);
+        assert!(random_pattern
+            .iter()
+            .all(|notes| notes.len() == 1 && [60, 64, 67].contains(&notes[0])));
+    }
+
+    #[test]
+    fn test_octaves() {
+        assert_eq!(
+            pattern(ArpMode::Up, 2, &[60, 64], 5),
+            [vec![60], vec![64], vec![72], vec![76], vec![60]]
+        );
+        assert_eq!(
+            pattern(ArpMode::Down, 2, &[60, 64], 4),
+            [vec![76], vec![72], vec![64], vec![60]]
+        );
+        assert_eq!(
+            pattern(ArpMode::Chord, 2, &[60, 64], 3),
+            [vec![60, 64], vec![72, 76], vec![60, 64]]
+        );
+
+        // Notes that would end up above the MIDI range are skipped
+        assert_eq!(pattern(ArpMode::Up, 2, &[120], 2), [vec![120], vec![]]);
+    }
+
+    #[test]
+    fn test_gate_and_swing() {
+        let mut arp = Arpeggiator::default();
+        arp.key_down(held_note(60));
+        let settings = ArpSettings {
+            gate: 0.25,
+            swing_delay: 0.5,
+            ..settings(ArpMode::Up, 1)
+        };
+
+        let mut events = Vec::new();
+        arp.update(&settings);
+        for sample_idx in 0..16 {
+            while let Some(event) = arp.next_event(&settings) {
+                events.push((sample_idx, matches!(event, ArpEvent::NoteOn(_))));
+            }
+            arp.advance(1, &settings);
+        }
+
+        // The odd steps are delayed by half a step, so the steps are six and two samples long
+        assert_eq!(
+            events,
+            [
+                (0, true),
+                (2, false),
+                (6, true),
+                (7, false),
+                (8, true),
+                (10, false),
+                (14, true),
+                (15, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_samples_until_next_event() {
+        let mut arp = Arpeggiator::default();
+        let settings = settings(ArpMode::Up, 1);
+        arp.update(&settings);
+        assert_eq!(arp.samples_until_next_event(&settings), None);
+
+        arp.key_down(held_note(60));
+        assert_eq!(arp.samples_until_next_event(&settings), Some(0.0));
+        assert!(matches!(
+            arp.next_event(&settings),
+            Some(ArpEvent::NoteOn(_))
+        ));
+        assert_eq!(arp.next_event(&settings), None);
+        assert_eq!(arp.samples_until_next_event(&settings), Some(2.0));
+
+        // The last note still plays until the end of its gate after its key is released
+        arp.key_up(0, 60);
+        arp.advance(2, &settings);
+        assert!(matches!(
+            arp.next_event(&settings),
+            Some(ArpEvent::NoteOff(_))
+        ));
+        assert_eq!(arp.samples_until_next_event(&settings), None);
+    }
+
+    #[test]
+    fn test_latch() {
+        let mut arp = Arpeggiator::default();
+        let unlatched_settings = settings(ArpMode::Up, 1);
+        let settings = ArpSettings {
+            latch: ArpLatch::Latch,
+            ..unlatched_settings
+        };
+        arp.update(&settings);
+
+        arp.key_down(held_note(60));
+        arp.key_down(held_note(64));
+        assert!(arp.key_up(0, 60));
+        assert!(arp.key_up(0, 64));
+        let notes: Vec<_> = note_ons(&mut arp, &settings, 8)
+            .into_iter()
+            .map(|(_, notes)| notes)
+            .collect();
+        assert_eq!(notes, [vec![60], vec![64]]);
+
+        // A new note after all keys have been released starts a new pattern
+        arp.key_down(held_note(67));
+        assert!(arp.key_up(0, 67));
+        let notes: Vec<_> = note_ons(&mut arp, &settings, 8)
+            .into_iter()
+            .map(|(_, notes)| notes)
+            .collect();
+        assert_eq!(notes, [vec![67], vec![67]]);
+
+        // Switching latch off drops the notes whose keys are no longer held
+        arp.key_down(held_note(72));
+        arp.update(&unlatched_settings);
+        assert!(arp.key_up(0, 72));
+        assert_eq!(note_ons(&mut arp, &unlatched_settings, 8), []);
+    }
+
+    #[test]
+    fn test_hold() {
+        let mut arp = Arpeggiator::default();
+        let settings = ArpSettings {
+            latch: ArpLatch::Hold,
+            ..settings(ArpMode::Up, 1)
+        };
+        arp.update(&settings);
+
+        arp.key_down(held_note(60));
+        arp.key_up(0, 60);
+        arp.key_down(held_note(64));
+        arp.key_up(0, 64);
+        let notes: Vec<_> = note_ons(&mut arp, &settings, 12)
+            .into_iter()
+            .map(|(_, notes)| notes)
+            .collect();
+        assert_eq!(notes, [vec![60], vec![64], vec![60]]);
+    }
+
+    #[test]
+    fn test_transport_sync() {
+        let mut arp = Arpeggiator::default();
+        let settings = ArpSettings {
+            transport_position: Some(2.5),
+            ..settings(ArpMode::Up, 1)
+        };
+        arp.update(&settings);
+
+        // A new pattern waits for the next step on the grid
+        arp.key_down(held_note(60));
+        assert_eq!(
+            note_ons(&mut arp, &settings, 8),
+            [(2, vec![60]), (6, vec![60])]
+        );
+
+        // Jumps in the transport restart the pattern at the new position
+        let settings = ArpSettings {
+            transport_position: Some(10.0),
+            ..settings
+        };
+        assert_eq!(note_ons(&mut arp, &settings, 4), [(0, vec![60])]);
+    }
+
+    #[test]
+    fn test_keys_that_were_not_arpeggiated() {
+        let mut arp = Arpeggiator::default();
+        assert!(!arp.key_up(0, 60));
+
+        arp.key_down(held_note(60));
+        arp.stop();
+        assert!(!arp.key_up(0, 60));
+    }
+}
@@ -0,0 +1,199 @@
+use nih_plug::prelude::*;
+use std::f32::consts::TAU;
+
+use crate::effects::{bypass_param, mix_param, slot_param, DelayLine, EffectKind};
+
+/// The most chorus voices per channel. With more than one voice the chorus turns into an
+/// ensemble effect.
+pub const MAX_CHORUS_VOICES: usize = 3;
+/// The delay the chorus voices are modulated around, in milliseconds.
+const BASE_DELAY_MS: f32 = 8.0;
+const MAX_DEPTH_MS: f32 = 6.0;
+
+#[derive(Params)]
+pub struct ChorusParams {
+    #[id = "chorus_bypass"]
+    pub bypass: BoolParam,
+    #[id = "chorus_mix"]
+    pub mix: FloatParam,
+    #[id = "chorus_slot"]
+    pub slot: IntParam,
+    #[id = "chorus_rate"]
+    pub rate: FloatParam,
+    /// How far the delay times are modulated, in milliseconds.
+    #[id = "chorus_depth"]
+    pub depth_ms: FloatParam,
+    #[id = "chorus_voices"]
+    pub voices: IntParam,
+}
+
+impl Default for ChorusParams {
+    fn default() -> Self {
+        Self {
+            bypass: bypass_param("Chorus"),
+            mix: mix_param("Chorus", 0.5),
+            slot: slot_param("Chorus", EffectKind::Chorus),
+            rate: FloatParam::new(
+                "Chorus Rate",
+                0.8,
+                FloatRange::Skewed {
+                    min: 0.05,
+                    max: 5.0,
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_unit(" Hz")
+            .with_value_to_string(formatters::v2s_f32_rounded(2)),
+            depth_ms: FloatParam::new(
+                "Chorus Depth",
+                3.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: MAX_DEPTH_MS,
+                },
+            )
+            .with_unit(" ms")
+            .with_step_size(0.1),
+            voices: IntParam::new(
+                "Chorus Voices",
+                1,
+                IntRange::Linear {
+                    min: 1,
+                    max: MAX_CHORUS_VOICES as i32,
+                },
+            ),
+        }
+    }
+}
+
+impl ChorusParams {
+    /// The chorus' settings for the current buffer, or `None` if it's bypassed.
+    pub fn settings(&self, sample_rate: f32) -> Option<ChorusSettings> {
+        if self.bypass.value() {
+            return None;
+        }
+
+        let samples_per_ms = sample_rate / 1000.0;
+        Some(ChorusSettings {
+            phase_delta: self.rate.value() / sample_rate,
+            base_delay: BASE_DELAY_MS * samples_per_ms,
+            depth: self.depth_ms.value() * samples_per_ms,
+            voices: self.voices.value() as usize,
+        })
+    }
+}
+
+/// The chorus' parameters, computed once per buffer. The delays are in samples.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChorusSettings {
+    pub phase_delta: f32,
+    pub base_delay: f32,
+    pub depth: f32,
+    pub voices: usize,
+}
+
+impl ChorusSettings {
+    pub fn tail_seconds(&self) -> f32 {
+        (BASE_DELAY_MS + MAX_DEPTH_MS) / 1000.0
+    }
+}
+
+/// A stereo chorus. Every voice reads from the delay line at a position modulated by its own sine
+/// LFO, with the voices spread evenly over the LFO's cycle. The right channel's LFOs run a quarter
+/// cycle behind the left channel's.
+#[derive(Debug, Clone)]
+pub struct Chorus {
+    delay_lines: [DelayLine; 2],
+    phase: f32,
+}
+
+impl Chorus {
+    pub fn new(sample_rate: f32) -> Self {
+        let max_delay = ((BASE_DELAY_MS + MAX_DEPTH_MS) / 1000.0 * sample_rate).ceil() as usize;
+
+        Self {
+            delay_lines: [DelayLine::new(max_delay), DelayLine::new(max_delay)],
+            phase: 0.0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for delay_line in &mut self.delay_lines {
+            delay_line.clear();
+        }
+        self.phase = 0.0;
+    }
+
+    /// Process a stereo sample, returning the wet signal.
+    pub fn process(&mut self, (left, right): (f32, f32), settings: &ChorusSettings) -> (f32, f32) {
+        self.delay_lines[0].write(left);
+        self.delay_lines[1].write(right);
+
+        let mut wet = [0.0; 2];
+        for (channel_idx, (wet, delay_line)) in wet.iter_mut().zip(&self.delay_lines).enumerate() {
+            for voice_idx in 0..settings.voices {
+                let phase = self.phase
+                    + voice_idx as f32 / settings.voices as f32
+                    + channel_idx as f32 * 0.25;
+                let modulation = 0.5 + 0.5 * (phase * TAU).sin();
+                *wet += delay_line.read(settings.base_delay + settings.depth * modulation);
+            }
+            *wet /= settings.voices as f32;
+        }
+
+        self.phase = (self.phase + settings.phase_delta).fract();
+
+        (wet[0], wet[1])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chorus_delays_within_range() {
+        let sample_rate = 1000.0;
+        let settings = ChorusSettings {
+            phase_delta: 1.0 / 100.0,
+            base_delay: BASE_DELAY_MS,
+            depth: MAX_DEPTH_MS,
+            voices: MAX_CHORUS_VOICES,
+        };
+        let mut chorus = Chorus::new(sample_rate);
+
+        // An impulse should only come out of the chorus between the shortest and longest delay
+        let mut output = Vec::new();
+        for sample_idx in 0..200 {
+            let input = if sample_idx == 0 { 1.0 } else { 0.0 };
+            output.push(chorus.process((input, input), &settings));
+        }
+
+        for (sample_idx, (left, right)) in output.iter().enumerate() {
+            if sample_idx + 1 < BASE_DELAY_MS as usize
+                || sample_idx > (BASE_DELAY_MS + MAX_DEPTH_MS) as usize + 1
+            {
+                assert_eq!((*left, *right), (0.0, 0.0), "{sample_idx}");
+            }
+        }
+        assert!(output.iter().any(|(left, _)| *left != 0.0));
+    }
+
+    #[test]
+    fn test_chorus_passes_dc_at_unity_gain() {
+        let settings = ChorusSettings {
+            phase_delta: 0.01,
+            base_delay: 10.0,
+            depth: 4.0,
+            voices: 2,
+        };
+        let mut chorus = Chorus::new(1000.0);
+        let mut output = (0.0, 0.0);
+        for _ in 0..100 {
+            output = chorus.process((0.5, -0.25), &settings);
+        }
+
+        assert!((output.0 - 0.5).abs() < 1e-6);
+        assert!((output.1 + 0.25).abs() < 1e-6);
+    }
+}
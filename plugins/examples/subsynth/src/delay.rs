@@ -0,0 +1,284 @@
+use nih_plug::prelude::*;
+use std::f32::consts::TAU;
+
+use crate::effects::{bypass_param, mix_param, slot_param, DelayLine, EffectKind};
+use crate::lfo::SyncRate;
+
+/// The longest delay time. Synced delay times are clamped to this at slow tempos.
+const MAX_DELAY_SECONDS: f32 = 4.0;
+/// The level the echoes need to decay to before the delay's tail ends, -60 dB.
+const TAIL_THRESHOLD: f32 = 0.001;
+/// The longest tail reported to the host. Very high feedback values would otherwise result in
+/// tails of several minutes.
+const MAX_TAIL_SECONDS: f32 = 30.0;
+/// How quickly the delay time follows changes, in seconds. Jumping straight to a new delay time
+/// would cause clicks.
+const DELAY_TIME_SMOOTHING_SECONDS: f32 = 0.05;
+
+#[derive(Params)]
+pub struct DelayParams {
+    #[id = "delay_bypass"]
+    pub bypass: BoolParam,
+    #[id = "delay_mix"]
+    pub mix: FloatParam,
+    #[id = "delay_slot"]
+    pub slot: IntParam,
+    /// The delay time in milliseconds, used when the delay is not synced to the host's tempo or
+    /// when the host doesn't provide a tempo.
+    #[id = "delay_time"]
+    pub time_ms: FloatParam,
+    #[id = "delay_sync"]
+    pub sync: BoolParam,
+    #[id = "delay_sync_rate"]
+    pub sync_rate: EnumParam<SyncRate>,
+    #[id = "delay_feedback"]
+    pub feedback: FloatParam,
+    /// Bounce the echoes between the left and the right channel.
+    #[id = "delay_ping_pong"]
+    pub ping_pong: BoolParam,
+    /// The cutoff of the lowpass filter in the feedback path. Every repeat gets darker.
+    #[id = "delay_damping"]
+    pub damping_hz: FloatParam,
+}
+
+impl Default for DelayParams {
+    fn default() -> Self {
+        Self {
+            bypass: bypass_param("Delay"),
+            mix: mix_param("Delay", 0.3),
+            slot: slot_param("Delay", EffectKind::Delay),
+            time_ms: FloatParam::new(
+                "Delay Time",
+                375.0,
+                FloatRange::Skewed {
+                    min: 1.0,
+                    max: MAX_DELAY_SECONDS * 1000.0,
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_unit(" ms")
+            .with_step_size(0.1),
+            sync: BoolParam::new("Delay Sync", true),
+            sync_rate: EnumParam::new("Delay Sync Rate", SyncRate::EighthDotted),
+            feedback: FloatParam::new(
+                "Delay Feedback",
+                0.4,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 0.95,
+                },
+            )
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            ping_pong: BoolParam::new("Delay Ping-Pong", true),
+            damping_hz: FloatParam::new(
+                "Delay Damping",
+                8000.0,
+                FloatRange::Skewed {
+                    min: 500.0,
+                    max: 20000.0,
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_unit(" Hz")
+            .with_value_to_string(formatters::v2s_f32_rounded(0)),
+        }
+    }
+}
+
+impl DelayParams {
+    /// The delay's settings for the current buffer, or `None` if it's bypassed. `tempo` is the
+    /// host's tempo in beats per minute.
+    pub fn settings(&self, sample_rate: f32, tempo: Option<f64>) -> Option<DelaySettings> {
+        if self.bypass.value() {
+            return None;
+        }
+
+        let delay_seconds = match (self.sync.value(), tempo) {
+            (true, Some(tempo)) => (self.sync_rate.value().beats() * 60.0 / tempo) as f32,
+            _ => self.time_ms.value() / 1000.0,
+        };
+
+        Some(DelaySettings {
+            delay_samples: delay_seconds.min(MAX_DELAY_SECONDS) * sample_rate,
+            feedback: self.feedback.value(),
+            ping_pong: self.ping_pong.value(),
+            damping: (-TAU * self.damping_hz.value().min(sample_rate * 0.45) / sample_rate).exp(),
+        })
+    }
+}
+
+/// The delay's parameters, computed once per buffer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DelaySettings {
+    pub delay_samples: f32,
+    pub feedback: f32,
+    pub ping_pong: bool,
+    /// The feedback lowpass filter's pole.
+    pub damping: f32,
+}
+
+impl DelaySettings {
+    /// How long it takes for the echoes to decay to -60 dB.
+    pub fn tail_seconds(&self, sample_rate: f32) -> f32 {
+        let repeats = if self.feedback > 0.0 {
+            (TAIL_THRESHOLD.ln() / self.feedback.ln()).ceil()
+        } else {
+            1.0
+        };
+
+        (repeats * self.delay_samples / sample_rate).min(MAX_TAIL_SECONDS)
+    }
+}
+
+/// A stereo delay with damped feedback. In ping-pong mode the input is summed to mono and fed
+/// into the left channel, and every echo feeds into the opposite channel.
+#[derive(Debug, Clone)]
+pub struct PingPongDelay {
+    delay_lines: [DelayLine; 2],
+    damping_filters: [f32; 2],
+    /// The current delay time in samples, following the delay time setting.
+    delay_samples: Option<f32>,
+    smoothing_coefficient: f32,
+}
+
+impl PingPongDelay {
+    pub fn new(sample_rate: f32) -> Self {
+        let max_delay = (MAX_DELAY_SECONDS * sample_rate).ceil() as usize + 1;
+
+        Self {
+            delay_lines: [DelayLine::new(max_delay), DelayLine::new(max_delay)],
+            damping_filters: [0.0; 2],
+            delay_samples: None,
+            smoothing_coefficient: (-1.0 / (DELAY_TIME_SMOOTHING_SECONDS * sample_rate)).exp(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for delay_line in &mut self.delay_lines {
+            delay_line.clear();
+        }
+        self.damping_filters = [0.0; 2];
+        self.delay_samples = None;
+    }
+
+    /// Process a stereo sample, returning the wet signal.
+    pub fn process(&mut self, (left, right): (f32, f32), settings: &DelaySettings) -> (f32, f32) {
+        let delay_samples = match self.delay_samples {
+            Some(delay_samples) => {
+                settings.delay_samples
+                    + (delay_samples - settings.delay_samples) * self.smoothing_coefficient
+            }
+            None => settings.delay_samples,
+        };
+        self.delay_samples = Some(delay_samples);
+
+        // The write happens after the read, so a delay of one sample is the previous sample
+        let wet = [
+            self.delay_lines[0].read(delay_samples),
+            self.delay_lines[1].read(delay_samples),
+        ];
+        let mut feedback = [0.0; 2];
+        for ((feedback, filter), wet) in feedback.iter_mut().zip(&mut self.damping_filters).zip(wet)
+        {
+            *filter = wet + (*filter - wet) * settings.damping;
+            *feedback = *filter * settings.feedback;
+        }
+
+        if settings.ping_pong {
+            self.delay_lines[0].write((left + right) * 0.5 + feedback[1]);
+            self.delay_lines[1].write(feedback[0]);
+        } else {
+            self.delay_lines[0].write(left + feedback[0]);
+            self.delay_lines[1].write(right + feedback[1]);
+        }
+
+        (wet[0], wet[1])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn impulse_response(settings: &DelaySettings, len: usize) -> Vec<(f32, f32)> {
+        let mut delay = PingPongDelay::new(1000.0);
+        (0..len)
+            .map(|sample_idx| {
+                let input = if sample_idx == 0 { 1.0 } else { 0.0 };
+                delay.process((input, input), settings)
+            })
+            .collect()
+    }
+
+    /// The indices of the samples that are louder than silence.
+    fn echoes(channel: impl Iterator<Item = f32>) -> Vec<usize> {
+        channel
+            .enumerate()
+            .filter(|(_, sample)| sample.abs() > 1e-6)
+            .map(|(sample_idx, _)| sample_idx)
+            .collect()
+    }
+
+    #[test]
+    fn test_ping_pong() {
+        let settings = DelaySettings {
+            delay_samples: 10.0,
+            feedback: 0.5,
+            ping_pong: true,
+            damping: 0.0,
+        };
+        let output = impulse_response(&settings, 45);
+
+        // The echoes alternate between the left and the right channel
+        assert_eq!(echoes(output.iter().map(|(left, _)| *left)), [10, 30]);
+        assert_eq!(echoes(output.iter().map(|(_, right)| *right)), [20, 40]);
+        assert_eq!(output[10].0, 1.0);
+        assert_eq!(output[20].1, 0.5);
+        assert_eq!(output[30].0, 0.25);
+    }
+
+    #[test]
+    fn test_stereo_feedback() {
+        let settings = DelaySettings {
+            delay_samples: 10.0,
+            feedback: 0.5,
+            ping_pong: false,
+            damping: 0.0,
+        };
+        let output = impulse_response(&settings, 35);
+
+        assert_eq!(echoes(output.iter().map(|(left, _)| *left)), [10, 20, 30]);
+        assert_eq!(output[20], (0.5, 0.5));
+        assert_eq!(output[30], (0.25, 0.25));
+    }
+
+    #[test]
+    fn test_tail() {
+        let settings = DelaySettings {
+            delay_samples: 1000.0,
+            feedback: 0.5,
+            ping_pong: false,
+            damping: 0.0,
+        };
+        // 0.5^10 is just below -60 dB
+        assert_eq!(settings.tail_seconds(1000.0), 10.0);
+        assert_eq!(
+            DelaySettings {
+                feedback: 0.0,
+                ..settings
+            }
+            .tail_seconds(1000.0),
+            1.0
+        );
+        assert_eq!(
+            DelaySettings {
+                feedback: 0.95,
+                ..settings
+            }
+            .tail_seconds(1000.0),
+            MAX_TAIL_SECONDS
+        );
+    }
+}
@@ -0,0 +1,149 @@
+use nih_plug::prelude::*;
+use std::f32::consts::TAU;
+
+use crate::drive::DriveCurve;
+use crate::effects::{bypass_param, mix_param, slot_param, EffectKind};
+
+#[derive(Params)]
+pub struct DistortionParams {
+    #[id = "dist_bypass"]
+    pub bypass: BoolParam,
+    #[id = "dist_mix"]
+    pub mix: FloatParam,
+    #[id = "dist_slot"]
+    pub slot: IntParam,
+    /// The gain going into the waveshaper, in decibels.
+    #[id = "dist_drive"]
+    pub drive: FloatParam,
+    #[id = "dist_curve"]
+    pub curve: EnumParam<DriveCurve>,
+    /// The cutoff of the lowpass filter after the waveshaper, which tames the added harmonics.
+    #[id = "dist_tone"]
+    pub tone_hz: FloatParam,
+    #[id = "dist_output"]
+    pub output_gain: FloatParam,
+}
+
+impl Default for DistortionParams {
+    fn default() -> Self {
+        Self {
+            bypass: bypass_param("Distortion"),
+            mix: mix_param("Distortion", 1.0),
+            slot: slot_param("Distortion", EffectKind::Distortion),
+            drive: FloatParam::new(
+                "Distortion Drive",
+                12.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 36.0,
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_step_size(0.1)
+            .with_unit(" dB"),
+            curve: EnumParam::new("Distortion Curve", DriveCurve::Soft),
+            tone_hz: FloatParam::new(
+                "Distortion Tone",
+                12000.0,
+                FloatRange::Skewed {
+                    min: 500.0,
+                    max: 20000.0,
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_unit(" Hz")
+            .with_value_to_string(formatters::v2s_f32_rounded(0)),
+            output_gain: FloatParam::new(
+                "Distortion Output",
+                -6.0,
+                FloatRange::Linear {
+                    min: -24.0,
+                    max: 6.0,
+                },
+            )
+            .with_step_size(0.1)
+            .with_unit(" dB"),
+        }
+    }
+}
+
+impl DistortionParams {
+    /// The distortion's settings for the current buffer, or `None` if it's bypassed.
+    pub fn settings(&self, sample_rate: f32) -> Option<DistortionSettings> {
+        if self.bypass.value() {
+            return None;
+        }
+
+        Some(DistortionSettings {
+            drive_gain: util::db_to_gain(self.drive.value()),
+            curve: self.curve.value(),
+            tone: (-TAU * self.tone_hz.value().min(sample_rate * 0.45) / sample_rate).exp(),
+            output_gain: util::db_to_gain(self.output_gain.value()),
+        })
+    }
+}
+
+/// The distortion's parameters, computed once per buffer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DistortionSettings {
+    pub drive_gain: f32,
+    pub curve: DriveCurve,
+    /// The tone lowpass filter's pole.
+    pub tone: f32,
+    pub output_gain: f32,
+}
+
+/// A waveshaping distortion using the same curves as the filter's drive stage, followed by a
+/// lowpass filter.
+#[derive(Debug, Clone, Default)]
+pub struct Distortion {
+    tone_filters: [f32; 2],
+}
+
+impl Distortion {
+    pub fn reset(&mut self) {
+        self.tone_filters = [0.0; 2];
+    }
+
+    /// Process a stereo sample, returning the wet signal.
+    pub fn process(
+        &mut self,
+        (left, right): (f32, f32),
+        settings: &DistortionSettings,
+    ) -> (f32, f32) {
+        let mut wet = [left, right];
+        for (sample, filter) in wet.iter_mut().zip(&mut self.tone_filters) {
+            let shaped = settings.curve.process(*sample, settings.drive_gain);
+            *filter = shaped + (*filter - shaped) * settings.tone;
+            *sample = *filter * settings.output_gain;
+        }
+
+        (wet[0], wet[1])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distortion_is_bounded() {
+        let settings = DistortionSettings {
+            drive_gain: util::db_to_gain(36.0),
+            curve: DriveCurve::Hard,
+            tone: 0.5,
+            output_gain: 1.0,
+        };
+        let mut distortion = Distortion::default();
+        for sample_idx in 0..1000 {
+            let input = (sample_idx as f32 * 0.05).sin() * 4.0;
+            let (left, right) = distortion.process((input, -input), &settings);
+            assert!(left.abs() <= 1.0 + 1e-6 && right.abs() <= 1.0 + 1e-6);
+        }
+
+        distortion.reset();
+        for _ in 0..100 {
+            assert_eq!(distortion.process((0.0, 0.0), &settings), (0.0, 0.0));
+        }
+    }
+}
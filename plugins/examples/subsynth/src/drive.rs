@@ -0,0 +1,93 @@
+use enum_iterator::Sequence;
+use nih_plug::params::enums::Enum;
+
+/// The bias used by the tube curve. This makes the curve asymmetric so it adds even harmonics.
+const TUBE_BIAS: f32 = 0.3;
+
+/// The saturation curve used by the drive stage in front of the filter.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Enum, Sequence)]
+pub enum DriveCurve {
+    /// Bypass the drive stage entirely.
+    Off,
+    Soft,
+    Hard,
+    /// An asymmetric soft clipper.
+    Tube,
+    /// A sine wavefolder. Signals louder than full scale fold back on themselves.
+    Fold,
+}
+
+impl DriveCurve {
+    /// Apply `gain` to `input` and then saturate it with this curve. Unless the drive stage is
+    /// bypassed, the output stays within `[-1, 1]`.
+    pub fn process(self, input: f32, gain: f32) -> f32 {
+        let driven = input * gain;
+        match self {
+            DriveCurve::Off => input,
+            DriveCurve::Soft => driven.tanh(),
+            DriveCurve::Hard => driven.clamp(-1.0, 1.0),
+            DriveCurve::Tube => {
+                // The bias is removed again afterwards so silence stays silent, and the result is
+                // scaled so the negative half reaches exactly -1
+                let bias = TUBE_BIAS.tanh();
+                ((driven + TUBE_BIAS).tanh() - bias) / (1.0 + bias)
+            }
+            DriveCurve::Fold => (driven * std::f32::consts::FRAC_PI_2).sin(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURVES: [DriveCurve; 4] = [
+        DriveCurve::Soft,
+        DriveCurve::Hard,
+        DriveCurve::Tube,
+        DriveCurve::Fold,
+    ];
+
+    #[test]
+    fn test_off_is_transparent() {
+        for input in [-3.0, -0.5, 0.0, 0.25, 2.0] {
+            assert_eq!(DriveCurve::Off.process(input, 10.0), input);
+        }
+    }
+
+    #[test]
+    fn test_curves_are_bounded_and_silent_at_zero() {
+        for curve in CURVES {
+            assert_eq!(curve.process(0.0, 8.0), 0.0, "{curve:?}");
+            for i in -1000..=1000 {
+                let output = curve.process(i as f32 / 100.0, 16.0);
+                assert!(output.abs() <= 1.0 + 1.0e-6, "{curve:?}: {output}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_curve_symmetry() {
+        for curve in [DriveCurve::Soft, DriveCurve::Hard, DriveCurve::Fold] {
+            for input in [0.1, 0.5, 0.9, 1.5] {
+                assert_eq!(curve.process(-input, 2.0), -curve.process(input, 2.0), "{curve:?}");
+            }
+        }
+
+        // The tube curve should clip the positive half earlier than the negative half
+        assert!(DriveCurve::Tube.process(1.0, 4.0) < -DriveCurve::Tube.process(-1.0, 4.0));
+    }
+
+    #[test]
+    fn test_more_drive_saturates_more() {
+        for curve in [DriveCurve::Soft, DriveCurve::Hard, DriveCurve::Tube] {
+            // The ratio between the output and the driven input shrinks as the gain goes up
+            let mut previous_ratio = f32::INFINITY;
+            for gain in [1.0, 2.0, 4.0, 8.0] {
+                let ratio = curve.process(0.5, gain) / (0.5 * gain);
+                assert!(ratio <= previous_ratio, "{curve:?} at {gain}");
+                previous_ratio = ratio;
+            }
+        }
+    }
+}
@@ -1,5 +1,7 @@
 //use atomic_float::AtomicF32;
-use nih_plug::prelude::{Editor, GuiContext};
+use nih_plug::prelude::{
+    util, AsyncExecutor, BoolParam, Editor, FloatParam, GuiContext, IntParam, Param, ParamSetter,
+};
 use nih_plug_iced::*;
 use nih_plug_iced::widgets as nih_widgets;
 use std::sync::Arc;
@@ -9,80 +11,491 @@ use nih_plug_iced::widget::Space;
 //use nih_plug_iced::Font;
 use nih_plug_iced::Length;
 //use nih_plug_iced::widget::*;
-use crate::SubSynthParams;
+use crate::lfo::{LfoParams, NUM_VOICE_LFOS};
+use crate::mod_matrix::{ModSlotParams, NUM_MOD_SLOTS};
+use crate::mpe::MpeParams;
+use crate::voice_mode::VoiceModeParams;
+use crate::analog::AnalogParams;
+use crate::scaling::{ScalingParams, NUM_VELOCITY_POINTS};
+use crate::arp::ArpParams;
+use crate::distortion::DistortionParams;
+use crate::chorus::ChorusParams;
+use crate::delay::DelayParams;
+use crate::reverb::ReverbParams;
+use crate::sequencer::{Step, SequencerParams, MAX_GATE_STEPS, MAX_STEPS, MIN_STEPS};
+use crate::tuning::{Tuning, TuningFiles};
+use crate::presets::{self, Preset, PresetBank, PresetCategory, PresetFilter};
+use crate::wavetable::WavetableSource;
+use crate::{SubSynth, SubSynthParams, Task};
 
 // Remove impl TextStyle block
 
 pub(crate) fn default_state() -> Arc<IcedState> {
-    IcedState::from_size(720, 300)
+    IcedState::from_size(900, 720)
 }
 
 pub(crate) fn create(
     params: Arc<SubSynthParams>,
     editor_state: Arc<IcedState>,
+    async_executor: AsyncExecutor<SubSynth>,
 ) -> Option<Box<dyn Editor>> {
-    create_iced_editor::<SubSynthEditor>(editor_state, params)
+    create_iced_editor::<SubSynthEditor>(editor_state, (params, async_executor))
 }
 
 struct SubSynthEditor {
     params: Arc<SubSynthParams>,
+    /// Used to build imported wavetables on a background thread.
+    async_executor: AsyncExecutor<SubSynth>,
     context: Arc<dyn GuiContext>,
 
     gain_slider_state: nih_widgets::param_slider::State,
+    pan_slider_state: nih_widgets::param_slider::State,
     waveform_slider_state: nih_widgets::param_slider::State,
+    pulse_width_slider_state: nih_widgets::param_slider::State,
+    pwm_source_slider_state: nih_widgets::param_slider::State,
+    pwm_depth_slider_state: nih_widgets::param_slider::State,
+    pwm_rate_slider_state: nih_widgets::param_slider::State,
+    pwm_env_attack_ms_slider_state: nih_widgets::param_slider::State,
+    pwm_env_decay_ms_slider_state: nih_widgets::param_slider::State,
     amp_attack_ms_slider_state: nih_widgets::param_slider::State,
     amp_release_ms_slider_state: nih_widgets::param_slider::State,
     amp_decay_ms_slider_state: nih_widgets::param_slider::State,
     amp_sustain_level_slider_state: nih_widgets::param_slider::State,
     filter_cut_attack_ms_slider_state: nih_widgets::param_slider::State,
     filter_cut_decay_ms_slider_state: nih_widgets::param_slider::State,
-    filter_cut_sustain_ms_slider_state: nih_widgets::param_slider::State,
+    filter_cut_sustain_level_slider_state: nih_widgets::param_slider::State,
     filter_cut_release_ms_slider_state: nih_widgets::param_slider::State,
     filter_res_attack_ms_slider_state: nih_widgets::param_slider::State,
     filter_res_decay_ms_slider_state: nih_widgets::param_slider::State,
-    filter_res_sustain_ms_slider_state: nih_widgets::param_slider::State,
+    filter_res_sustain_level_slider_state: nih_widgets::param_slider::State,
     filter_res_release_ms_slider_state: nih_widgets::param_slider::State,
+    amp_attack_curve_slider_state: nih_widgets::param_slider::State,
+    amp_decay_curve_slider_state: nih_widgets::param_slider::State,
+    amp_release_curve_slider_state: nih_widgets::param_slider::State,
+    amp_tension_slider_state: nih_widgets::param_slider::State,
+    filter_cut_attack_curve_slider_state: nih_widgets::param_slider::State,
+    filter_cut_decay_curve_slider_state: nih_widgets::param_slider::State,
+    filter_cut_release_curve_slider_state: nih_widgets::param_slider::State,
+    filter_cut_tension_slider_state: nih_widgets::param_slider::State,
+    filter_res_attack_curve_slider_state: nih_widgets::param_slider::State,
+    filter_res_decay_curve_slider_state: nih_widgets::param_slider::State,
+    filter_res_release_curve_slider_state: nih_widgets::param_slider::State,
+    filter_res_tension_slider_state: nih_widgets::param_slider::State,
     filter_type_slider_state: nih_widgets::param_slider::State,
+    filter_slope_slider_state: nih_widgets::param_slider::State,
+    filter_env_amount_slider_state: nih_widgets::param_slider::State,
+    filter_keytrack_slider_state: nih_widgets::param_slider::State,
+    filter_velocity_slider_state: nih_widgets::param_slider::State,
+    filter_drive_slider_state: nih_widgets::param_slider::State,
+    filter_drive_curve_slider_state: nih_widgets::param_slider::State,
     filter_cut_slider_state: nih_widgets::param_slider::State,
     filter_res_slider_state: nih_widgets::param_slider::State,
+    osc2_waveform_slider_state: nih_widgets::param_slider::State,
+    osc2_coarse_slider_state: nih_widgets::param_slider::State,
+    osc2_fine_slider_state: nih_widgets::param_slider::State,
+    osc2_level_slider_state: nih_widgets::param_slider::State,
+    sub_waveform_slider_state: nih_widgets::param_slider::State,
+    sub_octave_slider_state: nih_widgets::param_slider::State,
+    sub_level_slider_state: nih_widgets::param_slider::State,
+    osc1_level_slider_state: nih_widgets::param_slider::State,
+    noise_level_slider_state: nih_widgets::param_slider::State,
+    noise_color_slider_state: nih_widgets::param_slider::State,
+    osc2_sync_slider_state: nih_widgets::param_slider::State,
+    fm_amount_slider_state: nih_widgets::param_slider::State,
+    ring_level_slider_state: nih_widgets::param_slider::State,
+    unison_voices_slider_state: nih_widgets::param_slider::State,
+    unison_detune_slider_state: nih_widgets::param_slider::State,
+    unison_spread_slider_state: nih_widgets::param_slider::State,
+    unison_blend_slider_state: nih_widgets::param_slider::State,
+    lfo_slider_states: [LfoSliderStates; NUM_VOICE_LFOS],
+    global_lfo_slider_states: LfoSliderStates,
+    mod_slot_slider_states: [ModSlotSliderStates; NUM_MOD_SLOTS],
+    mpe_slider_states: MpeSliderStates,
+    voice_mode_slider_states: VoiceModeSliderStates,
+    analog_slider_states: AnalogSliderStates,
+    scaling_slider_states: ScalingSliderStates,
+    arp_slider_states: ArpSliderStates,
+    effects_slider_states: EffectsSliderStates,
+    sequencer_slider_states: SequencerSliderStates,
+    tuning_states: TuningStates,
+    /// The paths typed into the tuning section's text inputs.
+    scl_path: String,
+    kbm_path: String,
+    /// The loaded scale's description, or the reason the tuning failed to load.
+    tuning_status: String,
+    wavetable_states: WavetableStates,
+    /// The path typed into the wavetable section's text input.
+    wavetable_path: String,
+    /// The reason the last wavetable import failed, if it did.
+    wavetable_error: Option<String>,
+    preset_browser: PresetBrowser,
+
+    scrollable_state: scrollable::State,
+}
+
+/// The slider states for one LFO's parameters. The per-voice LFOs and the global LFO all share
+/// the same layout.
+#[derive(Default)]
+struct LfoSliderStates {
+    shape: nih_widgets::param_slider::State,
+    rate: nih_widgets::param_slider::State,
+    sync: nih_widgets::param_slider::State,
+    sync_rate: nih_widgets::param_slider::State,
+    retrigger: nih_widgets::param_slider::State,
+    fade_in: nih_widgets::param_slider::State,
+    pitch_depth: nih_widgets::param_slider::State,
+    cutoff_depth: nih_widgets::param_slider::State,
+    resonance_depth: nih_widgets::param_slider::State,
+    pulse_width_depth: nih_widgets::param_slider::State,
+    pan_depth: nih_widgets::param_slider::State,
+    amp_depth: nih_widgets::param_slider::State,
+}
+
+#[derive(Default)]
+struct ModSlotSliderStates {
+    source: nih_widgets::param_slider::State,
+    via: nih_widgets::param_slider::State,
+    destination: nih_widgets::param_slider::State,
+    amount: nih_widgets::param_slider::State,
+}
+
+#[derive(Default)]
+struct MpeSliderStates {
+    mode: nih_widgets::param_slider::State,
+    bend_range: nih_widgets::param_slider::State,
+    member_bend_range: nih_widgets::param_slider::State,
+    glide: nih_widgets::param_slider::State,
+    pressure_destination: nih_widgets::param_slider::State,
+    pressure_amount: nih_widgets::param_slider::State,
+    brightness_destination: nih_widgets::param_slider::State,
+    brightness_amount: nih_widgets::param_slider::State,
+}
+
+#[derive(Default)]
+struct VoiceModeSliderStates {
+    mode: nih_widgets::param_slider::State,
+    priority: nih_widgets::param_slider::State,
+    polyphony: nih_widgets::param_slider::State,
+    steal_mode: nih_widgets::param_slider::State,
+    glide_mode: nih_widgets::param_slider::State,
+    glide_time: nih_widgets::param_slider::State,
+    glide_legato: nih_widgets::param_slider::State,
+}
+
+#[derive(Default)]
+struct AnalogSliderStates {
+    drift: nih_widgets::param_slider::State,
+    drift_rate: nih_widgets::param_slider::State,
+    cutoff_spread: nih_widgets::param_slider::State,
+    envelope_spread: nih_widgets::param_slider::State,
+}
+
+#[derive(Default)]
+struct ScalingSliderStates {
+    velocity_curve: nih_widgets::param_slider::State,
+    fixed_velocity: nih_widgets::param_slider::State,
+    velocity_points: [nih_widgets::param_slider::State; NUM_VELOCITY_POINTS],
+    velocity_to_amp: nih_widgets::param_slider::State,
+    velocity_to_envelope: nih_widgets::param_slider::State,
+    key_split: nih_widgets::param_slider::State,
+    key_to_amp: nih_widgets::param_slider::State,
+    key_to_envelope_low: nih_widgets::param_slider::State,
+    key_to_envelope_high: nih_widgets::param_slider::State,
+}
+
+#[derive(Default)]
+struct ArpSliderStates {
+    enabled: nih_widgets::param_slider::State,
+    mode: nih_widgets::param_slider::State,
+    octaves: nih_widgets::param_slider::State,
+    rate: nih_widgets::param_slider::State,
+    sync: nih_widgets::param_slider::State,
+    sync_rate: nih_widgets::param_slider::State,
+    gate: nih_widgets::param_slider::State,
+    swing: nih_widgets::param_slider::State,
+    latch: nih_widgets::param_slider::State,
+    midi_output: nih_widgets::param_slider::State,
+}
+
+/// The states for the step sequencer's parameters and for its grid's widgets.
+struct SequencerSliderStates {
+    enabled: nih_widgets::param_slider::State,
+    rate: nih_widgets::param_slider::State,
+    transpose: nih_widgets::param_slider::State,
+    length: slider::State,
+    steps: [StepSliderStates; MAX_STEPS],
+}
+
+impl Default for SequencerSliderStates {
+    fn default() -> Self {
+        Self {
+            enabled: Default::default(),
+            rate: Default::default(),
+            transpose: Default::default(),
+            length: Default::default(),
+            steps: std::array::from_fn(|_| Default::default()),
+        }
+    }
+}
+
+/// The preset bank, and the browser's filter and widget states.
+struct PresetBrowser {
+    bank: PresetBank,
+    filter: PresetFilter,
+    /// The most recently loaded or saved preset.
+    current: Option<usize>,
+    /// The name, tags and category the current state is saved with. The tags are separated by
+    /// commas.
+    save_name: String,
+    save_tags: String,
+    save_category: PresetCategory,
+    /// The result of the last save.
+    status: String,
+
+    previous: button::State,
+    next: button::State,
+    category_filter: button::State,
+    tag_filter: text_input::State,
+    save_name_input: text_input::State,
+    save_tags_input: text_input::State,
+    save_category_button: button::State,
+    save: button::State,
+    /// One button per preset in the bank.
+    presets: Vec<button::State>,
+}
+
+impl PresetBrowser {
+    fn new(bank: PresetBank) -> Self {
+        Self {
+            presets: vec![Default::default(); bank.len()],
+            bank,
+            filter: PresetFilter::default(),
+            current: None,
+            save_name: String::new(),
+            save_tags: String::new(),
+            save_category: PresetCategory::default(),
+            status: String::new(),
+            previous: Default::default(),
+            next: Default::default(),
+            category_filter: Default::default(),
+            tag_filter: Default::default(),
+            save_name_input: Default::default(),
+            save_tags_input: Default::default(),
+            save_category_button: Default::default(),
+            save: Default::default(),
+        }
+    }
+}
+
+/// The slider states for the effects. Every effect also has bypass, mix and slot sliders.
+#[derive(Default)]
+struct EffectsSliderStates {
+    distortion: DistortionSliderStates,
+    chorus: ChorusSliderStates,
+    delay: DelaySliderStates,
+    reverb: ReverbSliderStates,
+}
+
+#[derive(Default)]
+struct EffectSliderStates {
+    bypass: nih_widgets::param_slider::State,
+    mix: nih_widgets::param_slider::State,
+    slot: nih_widgets::param_slider::State,
+}
+
+#[derive(Default)]
+struct DistortionSliderStates {
+    effect: EffectSliderStates,
+    drive: nih_widgets::param_slider::State,
+    curve: nih_widgets::param_slider::State,
+    tone: nih_widgets::param_slider::State,
+    output_gain: nih_widgets::param_slider::State,
+}
+
+#[derive(Default)]
+struct ChorusSliderStates {
+    effect: EffectSliderStates,
+    rate: nih_widgets::param_slider::State,
+    depth: nih_widgets::param_slider::State,
+    voices: nih_widgets::param_slider::State,
+}
+
+#[derive(Default)]
+struct DelaySliderStates {
+    effect: EffectSliderStates,
+    time: nih_widgets::param_slider::State,
+    sync: nih_widgets::param_slider::State,
+    sync_rate: nih_widgets::param_slider::State,
+    feedback: nih_widgets::param_slider::State,
+    ping_pong: nih_widgets::param_slider::State,
+    damping: nih_widgets::param_slider::State,
+}
+
+#[derive(Default)]
+struct ReverbSliderStates {
+    effect: EffectSliderStates,
+    decay: nih_widgets::param_slider::State,
+    damping: nih_widgets::param_slider::State,
+    predelay: nih_widgets::param_slider::State,
+    width: nih_widgets::param_slider::State,
+}
+
+#[derive(Default)]
+struct WavetableStates {
+    position: nih_widgets::param_slider::State,
+    path: text_input::State,
+    import: button::State,
+    reset: button::State,
+}
+
+#[derive(Default)]
+struct TuningStates {
+    reference_frequency: nih_widgets::param_slider::State,
+    scl_path: text_input::State,
+    kbm_path: text_input::State,
+    load: button::State,
+    reset: button::State,
+}
+
+#[derive(Default)]
+struct StepSliderStates {
+    note: slider::State,
+    velocity: slider::State,
+    gate: slider::State,
+    cutoff: slider::State,
+    resonance: slider::State,
 }
 
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 enum Message {
     /// Update a parameter's value.
     ParamUpdate(nih_widgets::ParamMessage),
+    /// Change the number of steps in the step sequencer's sequence.
+    SetSequenceLength(usize),
+    /// Replace one of the step sequencer's steps.
+    SetStep(usize, Step),
+    SetSclPath(String),
+    SetKbmPath(String),
+    /// Load the Scala files at the entered paths. An empty path uses the default scale or
+    /// keyboard mapping.
+    LoadTuning,
+    /// Go back to 12-TET.
+    ResetTuning,
+    SetWavetablePath(String),
+    /// Import the WAV file at the entered path as the wavetable.
+    ImportWavetable,
+    /// Go back to the built-in wavetable.
+    ResetWavetable,
+    PreviousPreset,
+    NextPreset,
+    LoadPreset(usize),
+    /// Switch the browser's category filter to the next category, or back to all categories.
+    CyclePresetCategoryFilter,
+    SetPresetTagFilter(String),
+    SetPresetName(String),
+    SetPresetTags(String),
+    /// Switch the category the preset is saved in to the next category.
+    CyclePresetCategory,
+    /// Save the current state as a user preset.
+    SavePreset,
 }
 
 impl IcedEditor for SubSynthEditor {
     type Executor = executor::Default;
     type Message = Message;
-    type InitializationFlags = Arc<SubSynthParams>;
+    type InitializationFlags = (Arc<SubSynthParams>, AsyncExecutor<SubSynth>);
 
     fn new(
-        params: Self::InitializationFlags,
+        (params, async_executor): Self::InitializationFlags,
         context: Arc<dyn GuiContext>,
     ) -> (Self, Command<Self::Message>) {
         let editor = SubSynthEditor {
             params,
+            async_executor,
             context,
             gain_slider_state: Default::default(),
+            pan_slider_state: Default::default(),
             waveform_slider_state: Default::default(),
+            pulse_width_slider_state: Default::default(),
+            pwm_source_slider_state: Default::default(),
+            pwm_depth_slider_state: Default::default(),
+            pwm_rate_slider_state: Default::default(),
+            pwm_env_attack_ms_slider_state: Default::default(),
+            pwm_env_decay_ms_slider_state: Default::default(),
             amp_attack_ms_slider_state: Default::default(),
             amp_release_ms_slider_state: Default::default(),
             amp_decay_ms_slider_state: Default::default(),
             amp_sustain_level_slider_state: Default::default(),
             filter_cut_attack_ms_slider_state: Default::default(),
             filter_cut_decay_ms_slider_state: Default::default(),
-            filter_cut_sustain_ms_slider_state: Default::default(),
+            filter_cut_sustain_level_slider_state: Default::default(),
             filter_cut_release_ms_slider_state: Default::default(),
             filter_res_attack_ms_slider_state: Default::default(),
             filter_res_decay_ms_slider_state: Default::default(),
-            filter_res_sustain_ms_slider_state: Default::default(),
+            filter_res_sustain_level_slider_state: Default::default(),
             filter_res_release_ms_slider_state: Default::default(),
+            amp_attack_curve_slider_state: Default::default(),
+            amp_decay_curve_slider_state: Default::default(),
+            amp_release_curve_slider_state: Default::default(),
+            amp_tension_slider_state: Default::default(),
+            filter_cut_attack_curve_slider_state: Default::default(),
+            filter_cut_decay_curve_slider_state: Default::default(),
+            filter_cut_release_curve_slider_state: Default::default(),
+            filter_cut_tension_slider_state: Default::default(),
+            filter_res_attack_curve_slider_state: Default::default(),
+            filter_res_decay_curve_slider_state: Default::default(),
+            filter_res_release_curve_slider_state: Default::default(),
+            filter_res_tension_slider_state: Default::default(),
             filter_type_slider_state: Default::default(),
+            filter_slope_slider_state: Default::default(),
+            filter_env_amount_slider_state: Default::default(),
+            filter_keytrack_slider_state: Default::default(),
+            filter_velocity_slider_state: Default::default(),
+            filter_drive_slider_state: Default::default(),
+            filter_drive_curve_slider_state: Default::default(),
             filter_cut_slider_state: Default::default(),
             filter_res_slider_state: Default::default(),
+            osc2_waveform_slider_state: Default::default(),
+            osc2_coarse_slider_state: Default::default(),
+            osc2_fine_slider_state: Default::default(),
+            osc2_level_slider_state: Default::default(),
+            sub_waveform_slider_state: Default::default(),
+            sub_octave_slider_state: Default::default(),
+            sub_level_slider_state: Default::default(),
+            osc1_level_slider_state: Default::default(),
+            noise_level_slider_state: Default::default(),
+            noise_color_slider_state: Default::default(),
+            osc2_sync_slider_state: Default::default(),
+            fm_amount_slider_state: Default::default(),
+            ring_level_slider_state: Default::default(),
+            unison_voices_slider_state: Default::default(),
+            unison_detune_slider_state: Default::default(),
+            unison_spread_slider_state: Default::default(),
+            unison_blend_slider_state: Default::default(),
+            lfo_slider_states: Default::default(),
+            global_lfo_slider_states: Default::default(),
+            mod_slot_slider_states: Default::default(),
+            mpe_slider_states: Default::default(),
+            voice_mode_slider_states: Default::default(),
+            analog_slider_states: Default::default(),
+            scaling_slider_states: Default::default(),
+            arp_slider_states: Default::default(),
+            effects_slider_states: Default::default(),
+            sequencer_slider_states: Default::default(),
+            tuning_states: Default::default(),
+            scl_path: String::new(),
+            kbm_path: String::new(),
+            tuning_status: String::new(),
+            wavetable_states: Default::default(),
+            wavetable_path: String::new(),
+            wavetable_error: None,
+            preset_browser: PresetBrowser::new(PresetBank::new(
+                presets::default_state(&SubSynthParams::default()),
+                presets::user_presets_dir(),
+            )),
+
+            scrollable_state: Default::default(),
         };
         
     
@@ -101,23 +514,99 @@ impl IcedEditor for SubSynthEditor {
     ) -> Command<Self::Message> {
         match message {
             Message::ParamUpdate(message) => self.handle_param_message(message),
+            Message::SetSequenceLength(len) => self.params.sequence.write().unwrap().set_len(len),
+            Message::SetStep(step_idx, step) => {
+                self.params.sequence.write().unwrap().set_step(step_idx, step)
+            }
+            Message::SetSclPath(path) => self.scl_path = path,
+            Message::SetKbmPath(path) => self.kbm_path = path,
+            Message::LoadTuning => self.load_tuning(),
+            Message::SetWavetablePath(path) => self.wavetable_path = path,
+            Message::ImportWavetable => self.import_wavetable(),
+            Message::ResetWavetable => {
+                *self.params.wavetable.write().unwrap() = WavetableSource::default();
+                self.wavetable_error = None;
+                self.async_executor.execute_background(Task::BuildWavetable);
+            }
+            Message::ResetTuning => {
+                *self.params.tuning.write().unwrap() = Tuning::default();
+                self.tuning_status = Tuning::default().scale().description.clone();
+
+                let setter = ParamSetter::new(self.context.as_ref());
+                let reference_frequency = &self.params.reference_frequency;
+                setter.begin_set_parameter(reference_frequency);
+                setter.set_parameter(reference_frequency, reference_frequency.default_plain_value());
+                setter.end_set_parameter(reference_frequency);
+            }
+            Message::PreviousPreset => {
+                let browser = &self.preset_browser;
+                if let Some(preset_idx) = browser.bank.previous(browser.current, &browser.filter) {
+                    self.load_preset(preset_idx);
+                }
+            }
+            Message::NextPreset => {
+                let browser = &self.preset_browser;
+                if let Some(preset_idx) = browser.bank.next(browser.current, &browser.filter) {
+                    self.load_preset(preset_idx);
+                }
+            }
+            Message::LoadPreset(preset_idx) => self.load_preset(preset_idx),
+            Message::CyclePresetCategoryFilter => {
+                let filter = &mut self.preset_browser.filter;
+                filter.category = match filter.category {
+                    Some(category) => enum_iterator::next(&category),
+                    None => enum_iterator::first(),
+                };
+            }
+            Message::SetPresetTagFilter(tag) => self.preset_browser.filter.tag = tag,
+            Message::SetPresetName(name) => self.preset_browser.save_name = name,
+            Message::SetPresetTags(tags) => self.preset_browser.save_tags = tags,
+            Message::CyclePresetCategory => {
+                let browser = &mut self.preset_browser;
+                browser.save_category = enum_iterator::next_cycle(&browser.save_category).unwrap();
+            }
+            Message::SavePreset => self.save_preset(),
         }
 
         Command::none()
     }
 
     fn view(&mut self) -> Element<'_, Self::Message> {
-        // Create four columns
+        // Create five columns
         let column1 = Column::new()
             .align_items(Alignment::Center)
             .push(Text::new("Gain"))
             .push(nih_widgets::ParamSlider::new(&mut self.gain_slider_state, &self.params.gain)
                 .map(Message::ParamUpdate))
+            .push(Text::new("Pan"))
+            .push(nih_widgets::ParamSlider::new(&mut self.pan_slider_state, &self.params.pan)
+                .map(Message::ParamUpdate))
             .push(Text::new("Waveform"))
             .push(nih_widgets::ParamSlider::new(&mut self.waveform_slider_state, &self.params.waveform)
                 .map(Message::ParamUpdate))
+            .push(Text::new("Pulse Width"))
+            .push(nih_widgets::ParamSlider::new(&mut self.pulse_width_slider_state, &self.params.pulse_width)
+                .map(Message::ParamUpdate))
+            .push(Text::new("PWM Source"))
+            .push(nih_widgets::ParamSlider::new(&mut self.pwm_source_slider_state, &self.params.pwm_source)
+                .map(Message::ParamUpdate))
+            .push(Text::new("PWM Depth"))
+            .push(nih_widgets::ParamSlider::new(&mut self.pwm_depth_slider_state, &self.params.pwm_depth)
+                .map(Message::ParamUpdate))
+            .push(Text::new("PWM Rate"))
+            .push(nih_widgets::ParamSlider::new(&mut self.pwm_rate_slider_state, &self.params.pwm_rate)
+                .map(Message::ParamUpdate))
+            .push(Text::new("PWM Env Attack"))
+            .push(nih_widgets::ParamSlider::new(&mut self.pwm_env_attack_ms_slider_state, &self.params.pwm_env_attack_ms)
+                .map(Message::ParamUpdate))
+            .push(Text::new("PWM Env Decay"))
+            .push(nih_widgets::ParamSlider::new(&mut self.pwm_env_decay_ms_slider_state, &self.params.pwm_env_decay_ms)
+                .map(Message::ParamUpdate))
             .push(Text::new("Filter Type"))
             .push(nih_widgets::ParamSlider::new(&mut self.filter_type_slider_state, &self.params.filter_type)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Filter Slope"))
+            .push(nih_widgets::ParamSlider::new(&mut self.filter_slope_slider_state, &self.params.filter_slope)
                 .map(Message::ParamUpdate));
     
         let column2 = Column::new()
@@ -133,6 +622,18 @@ impl IcedEditor for SubSynthEditor {
                 .map(Message::ParamUpdate))
             .push(Text::new("Release"))
             .push(nih_widgets::ParamSlider::new(&mut self.amp_release_ms_slider_state, &self.params.amp_release_ms)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Attack Curve"))
+            .push(nih_widgets::ParamSlider::new(&mut self.amp_attack_curve_slider_state, &self.params.amp_attack_curve)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Decay Curve"))
+            .push(nih_widgets::ParamSlider::new(&mut self.amp_decay_curve_slider_state, &self.params.amp_decay_curve)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Release Curve"))
+            .push(nih_widgets::ParamSlider::new(&mut self.amp_release_curve_slider_state, &self.params.amp_release_curve)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Curve Tension"))
+            .push(nih_widgets::ParamSlider::new(&mut self.amp_tension_slider_state, &self.params.amp_tension)
                 .map(Message::ParamUpdate));
     
         let column3 = Column::new()
@@ -140,6 +641,15 @@ impl IcedEditor for SubSynthEditor {
             .push(Text::new("Filter Cut"))
             .push(nih_widgets::ParamSlider::new(&mut self.filter_cut_slider_state, &self.params.filter_cut)
                 .map(Message::ParamUpdate))
+            .push(Text::new("Filter Env Amount"))
+            .push(nih_widgets::ParamSlider::new(&mut self.filter_env_amount_slider_state, &self.params.filter_env_amount)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Filter Keytracking"))
+            .push(nih_widgets::ParamSlider::new(&mut self.filter_keytrack_slider_state, &self.params.filter_keytrack)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Filter Velocity"))
+            .push(nih_widgets::ParamSlider::new(&mut self.filter_velocity_slider_state, &self.params.filter_velocity)
+                .map(Message::ParamUpdate))
             .push(Text::new("Filter Cut Attack"))
             .push(nih_widgets::ParamSlider::new(&mut self.filter_cut_attack_ms_slider_state, &self.params.filter_cut_attack_ms)
                 .map(Message::ParamUpdate))
@@ -147,10 +657,22 @@ impl IcedEditor for SubSynthEditor {
             .push(nih_widgets::ParamSlider::new(&mut self.filter_cut_decay_ms_slider_state, &self.params.filter_cut_decay_ms)
                 .map(Message::ParamUpdate))
             .push(Text::new("Filter Cut Sustain"))
-            .push(nih_widgets::ParamSlider::new(&mut self.filter_cut_sustain_ms_slider_state, &self.params.filter_cut_sustain_ms)
+            .push(nih_widgets::ParamSlider::new(&mut self.filter_cut_sustain_level_slider_state, &self.params.filter_cut_sustain_level)
                 .map(Message::ParamUpdate))
             .push(Text::new("Filter Cut Release"))
             .push(nih_widgets::ParamSlider::new(&mut self.filter_cut_release_ms_slider_state, &self.params.filter_cut_release_ms)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Filter Cut Attack Curve"))
+            .push(nih_widgets::ParamSlider::new(&mut self.filter_cut_attack_curve_slider_state, &self.params.filter_cut_attack_curve)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Filter Cut Decay Curve"))
+            .push(nih_widgets::ParamSlider::new(&mut self.filter_cut_decay_curve_slider_state, &self.params.filter_cut_decay_curve)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Filter Cut Release Curve"))
+            .push(nih_widgets::ParamSlider::new(&mut self.filter_cut_release_curve_slider_state, &self.params.filter_cut_release_curve)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Filter Cut Curve Tension"))
+            .push(nih_widgets::ParamSlider::new(&mut self.filter_cut_tension_slider_state, &self.params.filter_cut_tension)
                 .map(Message::ParamUpdate));
     
         let column4 = Column::new()
@@ -158,6 +680,12 @@ impl IcedEditor for SubSynthEditor {
             .push(Text::new("Filter Res"))
             .push(nih_widgets::ParamSlider::new(&mut self.filter_res_slider_state, &self.params.filter_res)
                 .map(Message::ParamUpdate))
+            .push(Text::new("Filter Drive"))
+            .push(nih_widgets::ParamSlider::new(&mut self.filter_drive_slider_state, &self.params.filter_drive)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Filter Drive Curve"))
+            .push(nih_widgets::ParamSlider::new(&mut self.filter_drive_curve_slider_state, &self.params.filter_drive_curve)
+                .map(Message::ParamUpdate))
             .push(Text::new("Filter Resonance Attack"))
             .push(nih_widgets::ParamSlider::new(&mut self.filter_res_attack_ms_slider_state, &self.params.filter_res_attack_ms)
                 .map(Message::ParamUpdate))
@@ -165,19 +693,153 @@ impl IcedEditor for SubSynthEditor {
             .push(nih_widgets::ParamSlider::new(&mut self.filter_res_decay_ms_slider_state, &self.params.filter_res_decay_ms)
                 .map(Message::ParamUpdate))
             .push(Text::new("Filter Resonance Sustain"))
-            .push(nih_widgets::ParamSlider::new(&mut self.filter_res_sustain_ms_slider_state, &self.params.filter_res_sustain_ms)
+            .push(nih_widgets::ParamSlider::new(&mut self.filter_res_sustain_level_slider_state, &self.params.filter_res_sustain_level)
                 .map(Message::ParamUpdate))
             .push(Text::new("Filter Resonance Release"))
             .push(nih_widgets::ParamSlider::new(&mut self.filter_res_release_ms_slider_state, &self.params.filter_res_release_ms)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Filter Resonance Attack Curve"))
+            .push(nih_widgets::ParamSlider::new(&mut self.filter_res_attack_curve_slider_state, &self.params.filter_res_attack_curve)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Filter Resonance Decay Curve"))
+            .push(nih_widgets::ParamSlider::new(&mut self.filter_res_decay_curve_slider_state, &self.params.filter_res_decay_curve)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Filter Resonance Release Curve"))
+            .push(nih_widgets::ParamSlider::new(&mut self.filter_res_release_curve_slider_state, &self.params.filter_res_release_curve)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Filter Resonance Curve Tension"))
+            .push(nih_widgets::ParamSlider::new(&mut self.filter_res_tension_slider_state, &self.params.filter_res_tension)
                 .map(Message::ParamUpdate));
     
+        let column5 = Column::new()
+            .align_items(Alignment::Center)
+            .push(Text::new("Osc 2 Waveform"))
+            .push(nih_widgets::ParamSlider::new(&mut self.osc2_waveform_slider_state, &self.params.osc2_waveform)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Osc 2 Coarse"))
+            .push(nih_widgets::ParamSlider::new(&mut self.osc2_coarse_slider_state, &self.params.osc2_coarse)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Osc 2 Fine"))
+            .push(nih_widgets::ParamSlider::new(&mut self.osc2_fine_slider_state, &self.params.osc2_fine)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Osc 2 Sync"))
+            .push(nih_widgets::ParamSlider::new(&mut self.osc2_sync_slider_state, &self.params.osc2_sync)
+                .map(Message::ParamUpdate))
+            .push(Text::new("FM Amount"))
+            .push(nih_widgets::ParamSlider::new(&mut self.fm_amount_slider_state, &self.params.fm_amount)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Unison Voices"))
+            .push(nih_widgets::ParamSlider::new(&mut self.unison_voices_slider_state, &self.params.unison_voices)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Unison Detune"))
+            .push(nih_widgets::ParamSlider::new(&mut self.unison_detune_slider_state, &self.params.unison_detune)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Unison Spread"))
+            .push(nih_widgets::ParamSlider::new(&mut self.unison_spread_slider_state, &self.params.unison_spread)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Unison Blend"))
+            .push(nih_widgets::ParamSlider::new(&mut self.unison_blend_slider_state, &self.params.unison_blend)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Sub Waveform"))
+            .push(nih_widgets::ParamSlider::new(&mut self.sub_waveform_slider_state, &self.params.sub_waveform)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Sub Octave"))
+            .push(nih_widgets::ParamSlider::new(&mut self.sub_octave_slider_state, &self.params.sub_octave)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Osc 1 Level"))
+            .push(nih_widgets::ParamSlider::new(&mut self.osc1_level_slider_state, &self.params.osc1_level)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Osc 2 Level"))
+            .push(nih_widgets::ParamSlider::new(&mut self.osc2_level_slider_state, &self.params.osc2_level)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Sub Level"))
+            .push(nih_widgets::ParamSlider::new(&mut self.sub_level_slider_state, &self.params.sub_level)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Noise Level"))
+            .push(nih_widgets::ParamSlider::new(&mut self.noise_level_slider_state, &self.params.noise_level)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Noise Color"))
+            .push(nih_widgets::ParamSlider::new(&mut self.noise_color_slider_state, &self.params.noise_color)
+                .map(Message::ParamUpdate))
+            .push(Text::new("Ring Mod Level"))
+            .push(nih_widgets::ParamSlider::new(&mut self.ring_level_slider_state, &self.params.ring_level)
+                .map(Message::ParamUpdate));
     
-        // Combine the columns horizontally
-        Row::new()
+        let [lfo1_slider_states, lfo2_slider_states] = &mut self.lfo_slider_states;
+        let lfo_row = Row::new()
+            .push(lfo_column("LFO 1", lfo1_slider_states, &self.params.lfos[0]))
+            .push(lfo_column("LFO 2", lfo2_slider_states, &self.params.lfos[1]))
+            .push(lfo_column(
+                "Global LFO",
+                &mut self.global_lfo_slider_states,
+                &self.params.global_lfo,
+            ))
+            .push(mpe_column(&mut self.mpe_slider_states, &self.params.mpe))
+            .push(voice_mode_column(&mut self.voice_mode_slider_states, &self.params.voice_mode))
+            .push(analog_column(&mut self.analog_slider_states, &self.params.analog))
+            .push(scaling_column(&mut self.scaling_slider_states, &self.params.scaling))
+            .push(arp_column(&mut self.arp_slider_states, &self.params.arp))
+            .push(tuning_column(
+                &mut self.tuning_states,
+                &self.params,
+                &self.scl_path,
+                &self.kbm_path,
+                &self.tuning_status,
+            ));
+
+        // The mod matrix's slots are laid out in two rows of four
+        let mut mod_matrix = Column::new()
+            .align_items(Alignment::Center)
+            .push(Text::new("Mod Matrix").size(24));
+        for (row_idx, (slider_states, params)) in self
+            .mod_slot_slider_states
+            .chunks_mut(NUM_MOD_SLOTS / 2)
+            .zip(self.params.mod_slots.chunks(NUM_MOD_SLOTS / 2))
+            .enumerate()
+        {
+            let row = slider_states.iter_mut().zip(params).enumerate().fold(
+                Row::new(),
+                |row, (slot_idx, (slider_states, params))| {
+                    let slot_number = row_idx * (NUM_MOD_SLOTS / 2) + slot_idx + 1;
+                    row.push(mod_slot_column(slot_number, slider_states, params))
+                },
+            );
+            mod_matrix = mod_matrix.push(row);
+        }
+
+        // Combine the columns horizontally, with the LFOs and the mod matrix below the rest of the
+        // synth
+        let synth_row = Row::new()
             .push(column1)
             .push(column2)
             .push(column3)
             .push(column4)
+            .push(column5)
+            .push(wavetable_column(
+                &mut self.wavetable_states,
+                &self.params,
+                &self.wavetable_path,
+                self.wavetable_error.as_deref(),
+            ));
+
+        let steps = self.params.sequence.read().unwrap().steps().to_vec();
+        let sequencer = sequencer_grid(
+            &mut self.sequencer_slider_states,
+            &self.params.sequencer,
+            &steps,
+            [
+                self.params.filter_cut.unmodulated_normalized_value(),
+                self.params.filter_res.unmodulated_normalized_value(),
+            ],
+        );
+
+        Scrollable::new(&mut self.scrollable_state)
+            .push(preset_browser(&mut self.preset_browser))
+            .push(synth_row)
+            .push(lfo_row)
+            .push(effects_row(&mut self.effects_slider_states, &self.params))
+            .push(mod_matrix)
+            .push(sequencer)
             .into()
     }
     
@@ -192,3 +854,777 @@ impl IcedEditor for SubSynthEditor {
         }
     }
 }
+
+impl SubSynthEditor {
+    /// Load a preset from the bank, and fill in the save fields so it can be saved again under
+    /// the same name.
+    fn load_preset(&mut self, preset_idx: usize) {
+        let browser = &mut self.preset_browser;
+        self.context.set_state(browser.bank.state(preset_idx));
+
+        let preset = browser.bank.preset(preset_idx);
+        browser.current = Some(preset_idx);
+        browser.save_name = preset.name.clone();
+        browser.save_tags = preset.tags.join(", ");
+        browser.save_category = preset.category;
+        browser.status = String::new();
+    }
+
+    fn save_preset(&mut self) {
+        let browser = &mut self.preset_browser;
+        let name = browser.save_name.trim();
+        if name.is_empty() {
+            browser.status = String::from("Enter a name for the preset");
+            return;
+        }
+
+        let preset = Preset {
+            name: name.to_owned(),
+            category: browser.save_category,
+            tags: browser
+                .save_tags
+                .split(',')
+                .map(str::trim)
+                .filter(|tag| !tag.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            state: self.context.get_state(),
+        };
+        match browser.bank.save(preset) {
+            Ok(preset_idx) => {
+                browser.presets.resize_with(browser.bank.len(), Default::default);
+                browser.current = Some(preset_idx);
+                browser.status = format!("Saved '{name}'");
+            }
+            Err(err) => browser.status = format!("Could not save the preset: {err}"),
+        }
+    }
+
+    /// Import the WAV file at the entered path. The wavetable itself is built on a background
+    /// thread and picked up by the audio thread once it's ready.
+    fn import_wavetable(&mut self) {
+        let path = std::path::Path::new(self.wavetable_path.trim());
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                self.wavetable_error = Some(format!("Could not read '{}': {err}", path.display()));
+                return;
+            }
+        };
+        let name = path
+            .file_stem()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        match WavetableSource::from_wav(name, &bytes) {
+            Ok(source) => {
+                *self.params.wavetable.write().unwrap() = source;
+                self.wavetable_error = None;
+                self.async_executor.execute_background(Task::BuildWavetable);
+            }
+            Err(err) => {
+                self.wavetable_error = Some(format!("Could not import the wavetable: {err}"))
+            }
+        }
+    }
+
+    /// Load the Scala files at the entered paths. A keyboard mapping also sets the reference
+    /// frequency parameter to the mapping's reference frequency.
+    fn load_tuning(&mut self) {
+        let read_file = |path: &str| -> Result<Option<String>, String> {
+            if path.trim().is_empty() {
+                return Ok(None);
+            }
+
+            std::fs::read_to_string(path.trim())
+                .map(Some)
+                .map_err(|err| format!("Could not read '{}': {err}", path.trim()))
+        };
+        let files = match (read_file(&self.scl_path), read_file(&self.kbm_path)) {
+            (Ok(scl), Ok(kbm)) => TuningFiles { scl, kbm },
+            (Err(err), _) | (_, Err(err)) => {
+                self.tuning_status = err;
+                return;
+            }
+        };
+
+        match Tuning::new(files) {
+            Ok(tuning) => {
+                if tuning.files().kbm.is_some() {
+                    let setter = ParamSetter::new(self.context.as_ref());
+                    let reference_frequency = &self.params.reference_frequency;
+                    setter.begin_set_parameter(reference_frequency);
+                    setter.set_parameter(reference_frequency, tuning.mapping().reference_frequency);
+                    setter.end_set_parameter(reference_frequency);
+                }
+
+                self.tuning_status = tuning.scale().description.clone();
+                *self.params.tuning.write().unwrap() = tuning;
+            }
+            Err(err) => self.tuning_status = format!("Could not load the tuning: {err}"),
+        }
+    }
+}
+
+/// A column containing all of an LFO's parameters.
+fn lfo_column<'a>(
+    title: &str,
+    slider_states: &'a mut LfoSliderStates,
+    params: &'a LfoParams,
+) -> Column<'a, Message> {
+    Column::new()
+        .align_items(Alignment::Center)
+        .push(Text::new(title).size(24))
+        .push(Text::new("Shape"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.shape, &params.shape)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Rate"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.rate, &params.rate)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Sync"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.sync, &params.sync)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Sync Rate"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.sync_rate, &params.sync_rate)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Retrigger"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.retrigger, &params.retrigger)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Fade In"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.fade_in, &params.fade_in_ms)
+            .map(Message::ParamUpdate))
+        .push(Text::new("To Pitch"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.pitch_depth, &params.pitch_depth)
+            .map(Message::ParamUpdate))
+        .push(Text::new("To Cutoff"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.cutoff_depth, &params.cutoff_depth)
+            .map(Message::ParamUpdate))
+        .push(Text::new("To Resonance"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.resonance_depth, &params.resonance_depth)
+            .map(Message::ParamUpdate))
+        .push(Text::new("To Pulse Width"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.pulse_width_depth, &params.pulse_width_depth)
+            .map(Message::ParamUpdate))
+        .push(Text::new("To Pan"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.pan_depth, &params.pan_depth)
+            .map(Message::ParamUpdate))
+        .push(Text::new("To Amp"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.amp_depth, &params.amp_depth)
+            .map(Message::ParamUpdate))
+}
+
+/// A column containing a single mod matrix slot's parameters.
+fn mod_slot_column<'a>(
+    slot_number: usize,
+    slider_states: &'a mut ModSlotSliderStates,
+    params: &'a ModSlotParams,
+) -> Column<'a, Message> {
+    Column::new()
+        .align_items(Alignment::Center)
+        .push(Text::new(format!("Slot {slot_number}")))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.source, &params.source)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Via"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.via, &params.via)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Destination"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.destination, &params.destination)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Amount"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.amount, &params.amount)
+            .map(Message::ParamUpdate))
+}
+
+/// A column containing the pitch bend and MPE parameters.
+fn mpe_column<'a>(
+    slider_states: &'a mut MpeSliderStates,
+    params: &'a MpeParams,
+) -> Column<'a, Message> {
+    Column::new()
+        .align_items(Alignment::Center)
+        .push(Text::new("MPE").size(24))
+        .push(Text::new("Mode"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.mode, &params.mode)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Bend Range"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.bend_range, &params.bend_range)
+            .map(Message::ParamUpdate))
+        .push(Text::new("MPE Bend Range"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.member_bend_range, &params.member_bend_range)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Pitch Glide"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.glide, &params.glide_ms)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Pressure To"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.pressure_destination, &params.pressure_destination)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Pressure Amount"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.pressure_amount, &params.pressure_amount)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Brightness To"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.brightness_destination, &params.brightness_destination)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Brightness Amount"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.brightness_amount, &params.brightness_amount)
+            .map(Message::ParamUpdate))
+}
+
+/// A column containing the voice mode and glide parameters.
+fn voice_mode_column<'a>(
+    slider_states: &'a mut VoiceModeSliderStates,
+    params: &'a VoiceModeParams,
+) -> Column<'a, Message> {
+    Column::new()
+        .align_items(Alignment::Center)
+        .push(Text::new("Voicing").size(24))
+        .push(Text::new("Voice Mode"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.mode, &params.mode)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Note Priority"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.priority, &params.priority)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Polyphony"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.polyphony, &params.polyphony)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Voice Stealing"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.steal_mode, &params.steal_mode)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Glide Mode"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.glide_mode, &params.glide_mode)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Glide Time"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.glide_time, &params.glide_time_ms)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Legato Glide"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.glide_legato, &params.glide_legato)
+            .map(Message::ParamUpdate))
+}
+
+/// A column containing the analog drift and voice card variance parameters.
+fn analog_column<'a>(
+    slider_states: &'a mut AnalogSliderStates,
+    params: &'a AnalogParams,
+) -> Column<'a, Message> {
+    Column::new()
+        .align_items(Alignment::Center)
+        .push(Text::new("Analog").size(24))
+        .push(Text::new("Drift"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.drift, &params.drift)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Drift Rate"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.drift_rate, &params.drift_rate)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Cutoff Spread"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.cutoff_spread, &params.cutoff_spread)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Envelope Spread"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.envelope_spread, &params.envelope_spread)
+            .map(Message::ParamUpdate))
+}
+
+/// A column containing the velocity curve and the velocity and key scaling parameters.
+fn scaling_column<'a>(
+    slider_states: &'a mut ScalingSliderStates,
+    params: &'a ScalingParams,
+) -> Column<'a, Message> {
+    let mut column = Column::new()
+        .align_items(Alignment::Center)
+        .push(Text::new("Scaling").size(24))
+        .push(Text::new("Velocity Curve"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.velocity_curve, &params.velocity_curve)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Fixed Velocity"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.fixed_velocity, &params.fixed_velocity)
+            .map(Message::ParamUpdate));
+    for (point_idx, (slider_state, point)) in slider_states
+        .velocity_points
+        .iter_mut()
+        .zip(&params.velocity_points)
+        .enumerate()
+    {
+        column = column
+            .push(Text::new(format!("Point {}", point_idx + 1)))
+            .push(nih_widgets::ParamSlider::new(slider_state, &point.level)
+                .map(Message::ParamUpdate));
+    }
+
+    column
+        .push(Text::new("Velocity to Amp"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.velocity_to_amp, &params.velocity_to_amp)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Velocity to Env Time"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.velocity_to_envelope, &params.velocity_to_envelope)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Key Split"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.key_split, &params.key_split)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Key to Amp"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.key_to_amp, &params.key_to_amp)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Key to Env Time Low"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.key_to_envelope_low, &params.key_to_envelope_low)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Key to Env Time High"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.key_to_envelope_high, &params.key_to_envelope_high)
+            .map(Message::ParamUpdate))
+}
+
+fn arp_column<'a>(
+    slider_states: &'a mut ArpSliderStates,
+    params: &'a ArpParams,
+) -> Column<'a, Message> {
+    Column::new()
+        .align_items(Alignment::Center)
+        .push(Text::new("Arpeggiator").size(24))
+        .push(Text::new("Enabled"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.enabled, &params.enabled)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Mode"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.mode, &params.mode)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Octaves"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.octaves, &params.octaves)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Rate"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.rate, &params.rate)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Sync"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.sync, &params.sync)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Sync Rate"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.sync_rate, &params.sync_rate)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Gate"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.gate, &params.gate)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Swing"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.swing, &params.swing)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Latch"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.latch, &params.latch)
+            .map(Message::ParamUpdate))
+        .push(Text::new("MIDI Output"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.midi_output, &params.midi_output)
+            .map(Message::ParamUpdate))
+}
+
+/// The effects, with a column per effect in their default order.
+fn effects_row<'a>(
+    slider_states: &'a mut EffectsSliderStates,
+    params: &'a SubSynthParams,
+) -> Row<'a, Message> {
+    Row::new()
+        .push(distortion_column(&mut slider_states.distortion, &params.distortion))
+        .push(chorus_column(&mut slider_states.chorus, &params.chorus))
+        .push(delay_column(&mut slider_states.delay, &params.delay))
+        .push(reverb_column(&mut slider_states.reverb, &params.reverb))
+}
+
+/// The start of an effect's column, with the controls every effect shares.
+fn effect_column<'a>(
+    name: &'a str,
+    slider_states: &'a mut EffectSliderStates,
+    bypass: &'a BoolParam,
+    mix: &'a FloatParam,
+    slot: &'a IntParam,
+) -> Column<'a, Message> {
+    Column::new()
+        .align_items(Alignment::Center)
+        .push(Text::new(name).size(24))
+        .push(Text::new("Bypass"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.bypass, bypass)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Mix"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.mix, mix)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Slot"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.slot, slot)
+            .map(Message::ParamUpdate))
+}
+
+fn distortion_column<'a>(
+    slider_states: &'a mut DistortionSliderStates,
+    params: &'a DistortionParams,
+) -> Column<'a, Message> {
+    effect_column("Distortion", &mut slider_states.effect, &params.bypass, &params.mix, &params.slot)
+        .push(Text::new("Drive"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.drive, &params.drive)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Curve"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.curve, &params.curve)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Tone"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.tone, &params.tone_hz)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Output"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.output_gain, &params.output_gain)
+            .map(Message::ParamUpdate))
+}
+
+fn chorus_column<'a>(
+    slider_states: &'a mut ChorusSliderStates,
+    params: &'a ChorusParams,
+) -> Column<'a, Message> {
+    effect_column("Chorus", &mut slider_states.effect, &params.bypass, &params.mix, &params.slot)
+        .push(Text::new("Rate"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.rate, &params.rate)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Depth"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.depth, &params.depth_ms)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Voices"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.voices, &params.voices)
+            .map(Message::ParamUpdate))
+}
+
+fn delay_column<'a>(
+    slider_states: &'a mut DelaySliderStates,
+    params: &'a DelayParams,
+) -> Column<'a, Message> {
+    effect_column("Delay", &mut slider_states.effect, &params.bypass, &params.mix, &params.slot)
+        .push(Text::new("Time"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.time, &params.time_ms)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Sync"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.sync, &params.sync)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Sync Rate"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.sync_rate, &params.sync_rate)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Feedback"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.feedback, &params.feedback)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Ping-Pong"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.ping_pong, &params.ping_pong)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Damping"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.damping, &params.damping_hz)
+            .map(Message::ParamUpdate))
+}
+
+fn reverb_column<'a>(
+    slider_states: &'a mut ReverbSliderStates,
+    params: &'a ReverbParams,
+) -> Column<'a, Message> {
+    effect_column("Reverb", &mut slider_states.effect, &params.bypass, &params.mix, &params.slot)
+        .push(Text::new("Decay"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.decay, &params.decay)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Damping"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.damping, &params.damping)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Predelay"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.predelay, &params.predelay_ms)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Width"))
+        .push(nih_widgets::ParamSlider::new(&mut slider_states.width, &params.width)
+            .map(Message::ParamUpdate))
+}
+
+/// The step sequencer's parameters and its grid. Every column in the grid is a step, and the
+/// steps are laid out in rows of 16. `default_locks` are the normalized cutoff and resonance
+/// values a new parameter lock starts at.
+fn sequencer_grid<'a>(
+    slider_states: &'a mut SequencerSliderStates,
+    params: &'a SequencerParams,
+    steps: &[Step],
+    default_locks: [f32; 2],
+) -> Column<'a, Message> {
+    let controls = Row::new()
+        .align_items(Alignment::Center)
+        .push(
+            Column::new()
+                .align_items(Alignment::Center)
+                .push(Text::new("Enabled"))
+                .push(nih_widgets::ParamSlider::new(&mut slider_states.enabled, &params.enabled)
+                    .map(Message::ParamUpdate)),
+        )
+        .push(
+            Column::new()
+                .align_items(Alignment::Center)
+                .push(Text::new("Rate"))
+                .push(nih_widgets::ParamSlider::new(&mut slider_states.rate, &params.rate)
+                    .map(Message::ParamUpdate)),
+        )
+        .push(
+            Column::new()
+                .align_items(Alignment::Center)
+                .push(Text::new("Transpose"))
+                .push(nih_widgets::ParamSlider::new(&mut slider_states.transpose, &params.transpose)
+                    .map(Message::ParamUpdate)),
+        )
+        .push(
+            Column::new()
+                .align_items(Alignment::Center)
+                .push(Text::new(format!("Steps: {}", steps.len())))
+                .push(
+                    Slider::new(
+                        &mut slider_states.length,
+                        MIN_STEPS as f32..=MAX_STEPS as f32,
+                        steps.len() as f32,
+                        |len| Message::SetSequenceLength(len as usize),
+                    )
+                    .step(1.0)
+                    .width(Length::Units(200)),
+                ),
+        );
+
+    let mut grid = Column::new()
+        .align_items(Alignment::Center)
+        .push(Text::new("Sequencer").size(24))
+        .push(controls);
+    let mut step_states = slider_states.steps.iter_mut().zip(steps).enumerate().peekable();
+    while step_states.peek().is_some() {
+        let row = step_states.by_ref().take(MIN_STEPS).fold(
+            Row::new().push(step_labels_column()),
+            |row, (step_idx, (step_states, step))| {
+                row.push(step_column(step_idx, *step, step_states, default_locks))
+            },
+        );
+        grid = grid.push(row);
+    }
+
+    grid
+}
+
+/// The labels for the rows in the sequencer grid.
+fn step_labels_column<'a>() -> Column<'a, Message> {
+    ["Step", "On", "Note", "", "Velocity", "Gate", "Tie", "Cutoff", "", "Resonance", ""]
+        .into_iter()
+        .fold(Column::new().width(Length::Units(70)), |column, label| {
+            column.push(Text::new(label).size(14).height(Length::Units(20)))
+        })
+}
+
+/// A single step's column in the sequencer grid.
+fn step_column<'a>(
+    step_idx: usize,
+    step: Step,
+    step_states: &'a mut StepSliderStates,
+    [default_cutoff, default_resonance]: [f32; 2],
+) -> Column<'a, Message> {
+    let set_step = move |step: Step| Message::SetStep(step_idx, step);
+    let note_name = format!(
+        "{}{}",
+        util::NOTES[step.note as usize % 12],
+        step.note as i32 / 12 - 1
+    );
+
+    Column::new()
+        .align_items(Alignment::Center)
+        .width(Length::Units(48))
+        .push(Text::new(format!("{}", step_idx + 1)).size(14).height(Length::Units(20)))
+        .push(Checkbox::new(step.enabled, "", move |enabled| {
+            set_step(Step { enabled, ..step })
+        }))
+        .push(Text::new(note_name).size(14).height(Length::Units(20)))
+        .push(
+            Slider::new(&mut step_states.note, 0.0..=127.0, step.note as f32, move |note| {
+                set_step(Step {
+                    note: note as u8,
+                    ..step
+                })
+            })
+            .step(1.0),
+        )
+        .push(Slider::new(&mut step_states.velocity, 0.0..=1.0, step.velocity, move |velocity| {
+            set_step(Step { velocity, ..step })
+        }))
+        .push(Slider::new(&mut step_states.gate, 0.05..=MAX_GATE_STEPS, step.gate, move |gate| {
+            set_step(Step { gate, ..step })
+        }))
+        .push(Checkbox::new(step.tie, "", move |tie| set_step(Step { tie, ..step })))
+        .push(Checkbox::new(step.cutoff_lock.is_some(), "", move |locked| {
+            set_step(Step {
+                cutoff_lock: locked.then_some(default_cutoff),
+                ..step
+            })
+        }))
+        .push(Slider::new(
+            &mut step_states.cutoff,
+            0.0..=1.0,
+            step.cutoff_lock.unwrap_or(default_cutoff),
+            move |cutoff| {
+                set_step(Step {
+                    cutoff_lock: Some(cutoff),
+                    ..step
+                })
+            },
+        ))
+        .push(Checkbox::new(step.resonance_lock.is_some(), "", move |locked| {
+            set_step(Step {
+                resonance_lock: locked.then_some(default_resonance),
+                ..step
+            })
+        }))
+        .push(Slider::new(
+            &mut step_states.resonance,
+            0.0..=1.0,
+            step.resonance_lock.unwrap_or(default_resonance),
+            move |resonance| {
+                set_step(Step {
+                    resonance_lock: Some(resonance),
+                    ..step
+                })
+            },
+        ))
+}
+
+/// A column for loading Scala scales and keyboard mappings, and for the reference frequency.
+/// The wavetable position and the controls for importing a wavetable. `error` is shown instead of
+/// the loaded table's name if the last import failed.
+fn wavetable_column<'a>(
+    states: &'a mut WavetableStates,
+    params: &'a SubSynthParams,
+    path: &str,
+    error: Option<&str>,
+) -> Column<'a, Message> {
+    let status = match error {
+        Some(error) => error.to_owned(),
+        None => {
+            let source = params.wavetable.read().unwrap();
+            if source.is_builtin() {
+                String::from("Built-in")
+            } else {
+                format!("{} ({} frames)", source.name, source.num_frames())
+            }
+        }
+    };
+
+    Column::new()
+        .align_items(Alignment::Center)
+        .push(Text::new("Wavetable").size(24))
+        .push(Text::new("Position"))
+        .push(nih_widgets::ParamSlider::new(&mut states.position, &params.wavetable_position)
+            .map(Message::ParamUpdate))
+        .push(Text::new("WAV File"))
+        .push(TextInput::new(&mut states.path, "Built-in", path, Message::SetWavetablePath)
+            .padding(4)
+            .width(Length::Units(180)))
+        .push(
+            Row::new()
+                .push(Button::new(&mut states.import, Text::new("Import")).on_press(Message::ImportWavetable))
+                .push(Button::new(&mut states.reset, Text::new("Reset")).on_press(Message::ResetWavetable)),
+        )
+        .push(Text::new(status).size(14))
+}
+
+fn tuning_column<'a>(
+    states: &'a mut TuningStates,
+    params: &'a SubSynthParams,
+    scl_path: &str,
+    kbm_path: &str,
+    status: &str,
+) -> Column<'a, Message> {
+    Column::new()
+        .align_items(Alignment::Center)
+        .push(Text::new("Tuning").size(24))
+        .push(Text::new("Reference Frequency"))
+        .push(nih_widgets::ParamSlider::new(&mut states.reference_frequency, &params.reference_frequency)
+            .map(Message::ParamUpdate))
+        .push(Text::new("Scale (.scl)"))
+        .push(TextInput::new(&mut states.scl_path, "12-TET", scl_path, Message::SetSclPath)
+            .padding(4)
+            .width(Length::Units(180)))
+        .push(Text::new("Keyboard Mapping (.kbm)"))
+        .push(TextInput::new(&mut states.kbm_path, "Default", kbm_path, Message::SetKbmPath)
+            .padding(4)
+            .width(Length::Units(180)))
+        .push(
+            Row::new()
+                .push(Button::new(&mut states.load, Text::new("Load")).on_press(Message::LoadTuning))
+                .push(Button::new(&mut states.reset, Text::new("Reset")).on_press(Message::ResetTuning)),
+        )
+        .push(Text::new(status.to_owned()).size(14))
+}
+
+/// The preset browser, with next/previous navigation, filters, the list of presets, and the
+/// controls for saving the current state as a user preset.
+fn preset_browser(browser: &mut PresetBrowser) -> Column<'_, Message> {
+    let PresetBrowser {
+        bank,
+        filter,
+        current,
+        save_name,
+        save_tags,
+        save_category,
+        status,
+        previous,
+        next,
+        category_filter,
+        tag_filter,
+        save_name_input,
+        save_tags_input,
+        save_category_button,
+        save,
+        presets,
+    } = browser;
+
+    let current_name = match current {
+        Some(preset_idx) => bank.preset(*preset_idx).name.clone(),
+        None => String::from("No preset loaded"),
+    };
+    let category_filter_label = match filter.category {
+        Some(category) => format!("Category: {category}"),
+        None => String::from("Category: All"),
+    };
+    let navigation = Row::new()
+        .align_items(Alignment::Center)
+        .spacing(10)
+        .push(Button::new(previous, Text::new("<")).on_press(Message::PreviousPreset))
+        .push(Text::new(current_name).width(Length::Units(200)))
+        .push(Button::new(next, Text::new(">")).on_press(Message::NextPreset))
+        .push(
+            Button::new(category_filter, Text::new(category_filter_label))
+                .on_press(Message::CyclePresetCategoryFilter),
+        )
+        .push(
+            TextInput::new(tag_filter, "Filter by tag", &filter.tag, Message::SetPresetTagFilter)
+                .padding(4)
+                .width(Length::Units(150)),
+        );
+
+    let preset_list = presets
+        .iter_mut()
+        .enumerate()
+        .filter(|(preset_idx, _)| filter.matches(bank.preset(*preset_idx)))
+        .fold(Column::new(), |column, (preset_idx, state)| {
+            let preset = bank.preset(preset_idx);
+            let marker = if *current == Some(preset_idx) { "> " } else { "" };
+            let source = if bank.is_factory_preset(preset_idx) { "" } else { " (User)" };
+            let label = format!("{marker}{} [{}]{source}", preset.name, preset.category);
+            column.push(
+                Button::new(state, Text::new(label).size(14))
+                    .on_press(Message::LoadPreset(preset_idx))
+                    .width(Length::Units(300)),
+            )
+        });
+
+    let save_row = Row::new()
+        .align_items(Alignment::Center)
+        .spacing(10)
+        .push(
+            TextInput::new(save_name_input, "Preset name", save_name, Message::SetPresetName)
+                .padding(4)
+                .width(Length::Units(150)),
+        )
+        .push(
+            TextInput::new(save_tags_input, "Tags, comma separated", save_tags, Message::SetPresetTags)
+                .padding(4)
+                .width(Length::Units(180)),
+        )
+        .push(
+            Button::new(save_category_button, Text::new(save_category.to_string()))
+                .on_press(Message::CyclePresetCategory),
+        )
+        .push(Button::new(save, Text::new("Save")).on_press(Message::SavePreset))
+        .push(Text::new(status.clone()).size(14));
+
+    Column::new()
+        .align_items(Alignment::Center)
+        .spacing(5)
+        .push(Text::new("Presets").size(24))
+        .push(navigation)
+        .push(preset_list)
+        .push(save_row)
+}
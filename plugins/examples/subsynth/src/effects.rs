@@ -0,0 +1,306 @@
+use enum_iterator::Sequence;
+use nih_plug::prelude::*;
+
+use crate::chorus::{Chorus, ChorusSettings};
+use crate::delay::{DelaySettings, PingPongDelay};
+use crate::distortion::{Distortion, DistortionSettings};
+use crate::reverb::{Reverb, ReverbSettings};
+
+pub const NUM_EFFECTS: usize = 4;
+
+/// The effects in their default order. An effect's slot parameter moves it around in the chain.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Sequence)]
+pub enum EffectKind {
+    Distortion,
+    Chorus,
+    Delay,
+    Reverb,
+}
+
+/// The order the effects are applied in. Effects are sorted by their slot positions, and effects
+/// sharing a slot keep their default order.
+pub fn effect_order(slots: [i32; NUM_EFFECTS]) -> [EffectKind; NUM_EFFECTS] {
+    let mut order = [
+        EffectKind::Distortion,
+        EffectKind::Chorus,
+        EffectKind::Delay,
+        EffectKind::Reverb,
+    ];
+    order.sort_by_key(|kind| slots[*kind as usize]);
+
+    order
+}
+
+/// The parameter that bypasses an effect. The effects start out bypassed.
+pub fn bypass_param(name: &str) -> BoolParam {
+    BoolParam::new(format!("{name} Bypass"), true)
+}
+
+/// The dry/wet balance of an effect.
+pub fn mix_param(name: &str, default: f32) -> FloatParam {
+    FloatParam::new(
+        format!("{name} Mix"),
+        default,
+        FloatRange::Linear { min: 0.0, max: 1.0 },
+    )
+    .with_smoother(SmoothingStyle::Linear(20.0))
+    .with_unit(" %")
+    .with_value_to_string(formatters::v2s_f32_percentage(0))
+    .with_string_to_value(formatters::s2v_f32_percentage())
+}
+
+/// An effect's position in the effects chain, where the effect in slot 1 is applied first.
+pub fn slot_param(name: &str, kind: EffectKind) -> IntParam {
+    IntParam::new(
+        format!("{name} Slot"),
+        kind as i32 + 1,
+        IntRange::Linear {
+            min: 1,
+            max: NUM_EFFECTS as i32,
+        },
+    )
+}
+
+/// The effects' settings for the current buffer. Bypassed effects don't have any settings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EffectsSettings {
+    pub order: [EffectKind; NUM_EFFECTS],
+    pub distortion: Option<DistortionSettings>,
+    pub chorus: Option<ChorusSettings>,
+    pub delay: Option<DelaySettings>,
+    pub reverb: Option<ReverbSettings>,
+}
+
+impl EffectsSettings {
+    /// How long the effects keep ringing out after the voices have gone silent, in samples.
+    pub fn tail_samples(&self, sample_rate: f32) -> u32 {
+        let tail_seconds = self.chorus.map_or(0.0, |chorus| chorus.tail_seconds())
+            + self
+                .delay
+                .map_or(0.0, |delay| delay.tail_seconds(sample_rate))
+            + self
+                .reverb
+                .map_or(0.0, |reverb| reverb.tail_seconds(sample_rate));
+
+        (tail_seconds * sample_rate).ceil() as u32
+    }
+}
+
+/// A simple delay line with fractional, linearly interpolated reads.
+#[derive(Debug, Clone)]
+pub struct DelayLine {
+    buffer: Vec<f32>,
+    write_pos: usize,
+}
+
+impl DelayLine {
+    /// Create a delay line that can delay by up to `max_delay` samples.
+    pub fn new(max_delay: usize) -> Self {
+        Self {
+            buffer: vec![0.0; max_delay + 2],
+            write_pos: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.fill(0.0);
+        self.write_pos = 0;
+    }
+
+    /// The sample written `delay` samples ago, where a delay of one sample returns the last
+    /// written sample. The delay is clamped to the delay line's length.
+    pub fn read(&self, delay: f32) -> f32 {
+        let len = self.buffer.len();
+        let delay = delay.clamp(1.0, (len - 2) as f32);
+        let delay_samples = delay as usize;
+        let t = delay - delay_samples as f32;
+        let newer = self.buffer[(self.write_pos + len - delay_samples) % len];
+        let older = self.buffer[(self.write_pos + len - delay_samples - 1) % len];
+
+        newer + (older - newer) * t
+    }
+
+    pub fn write(&mut self, sample: f32) {
+        self.buffer[self.write_pos] = sample;
+        self.write_pos = (self.write_pos + 1) % self.buffer.len();
+    }
+}
+
+/// The post-voice effects chain. The effects' delay lines are allocated for a specific sample
+/// rate.
+#[derive(Debug, Clone)]
+pub struct Effects {
+    distortion: Distortion,
+    chorus: Chorus,
+    delay: PingPongDelay,
+    reverb: Reverb,
+    /// Which effects were active during the previous block. An effect is cleared when it's
+    /// enabled again so it doesn't resume with stale audio.
+    active: [bool; NUM_EFFECTS],
+}
+
+impl Effects {
+    pub fn new(sample_rate: f32) -> Self {
+        Self {
+            distortion: Distortion::default(),
+            chorus: Chorus::new(sample_rate),
+            delay: PingPongDelay::new(sample_rate),
+            reverb: Reverb::new(sample_rate),
+            active: [false; NUM_EFFECTS],
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.distortion.reset();
+        self.chorus.reset();
+        self.delay.reset();
+        self.reverb.reset();
+    }
+
+    /// Run a block of the voices' output through the effects in place. `mixes` contains every
+    /// effect's smoothed mix for each sample in the block, indexed by [`EffectKind`].
+    pub fn process(
+        &mut self,
+        left: &mut [f32],
+        right: &mut [f32],
+        settings: &EffectsSettings,
+        mixes: &[[f32; crate::MAX_BLOCK_SIZE]; NUM_EFFECTS],
+    ) {
+        let active = [
+            settings.distortion.is_some(),
+            settings.chorus.is_some(),
+            settings.delay.is_some(),
+            settings.reverb.is_some(),
+        ];
+        for (kind, (is_active, was_active)) in
+            enum_iterator::all::<EffectKind>().zip(active.iter().zip(&mut self.active))
+        {
+            if *is_active && !*was_active {
+                match kind {
+                    EffectKind::Distortion => self.distortion.reset(),
+                    EffectKind::Chorus => self.chorus.reset(),
+                    EffectKind::Delay => self.delay.reset(),
+                    EffectKind::Reverb => self.reverb.reset(),
+                }
+            }
+            *was_active = *is_active;
+        }
+
+        for (sample_idx, (left, right)) in left.iter_mut().zip(right.iter_mut()).enumerate() {
+            for kind in settings.order {
+                let input = (*left, *right);
+                let wet = match kind {
+                    EffectKind::Distortion => settings
+                        .distortion
+                        .map(|settings| self.distortion.process(input, &settings)),
+                    EffectKind::Chorus => settings
+                        .chorus
+                        .map(|settings| self.chorus.process(input, &settings)),
+                    EffectKind::Delay => settings
+                        .delay
+                        .map(|settings| self.delay.process(input, &settings)),
+                    EffectKind::Reverb => settings
+                        .reverb
+                        .map(|settings| self.reverb.process(input, &settings)),
+                };
+
+                if let Some((wet_left, wet_right)) = wet {
+                    let mix = mixes[kind as usize][sample_idx];
+                    *left += (wet_left - *left) * mix;
+                    *right += (wet_right - *right) * mix;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bypassed() -> EffectsSettings {
+        EffectsSettings {
+            order: effect_order([1, 2, 3, 4]),
+            distortion: None,
+            chorus: None,
+            delay: None,
+            reverb: None,
+        }
+    }
+
+    #[test]
+    fn test_effect_order() {
+        assert_eq!(
+            effect_order([1, 2, 3, 4]),
+            [
+                EffectKind::Distortion,
+                EffectKind::Chorus,
+                EffectKind::Delay,
+                EffectKind::Reverb
+            ]
+        );
+        assert_eq!(
+            effect_order([4, 3, 2, 1]),
+            [
+                EffectKind::Reverb,
+                EffectKind::Delay,
+                EffectKind::Chorus,
+                EffectKind::Distortion
+            ]
+        );
+        // Effects in the same slot keep their default order
+        assert_eq!(
+            effect_order([2, 1, 1, 2]),
+            [
+                EffectKind::Chorus,
+                EffectKind::Delay,
+                EffectKind::Distortion,
+                EffectKind::Reverb
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bypassed_effects_are_transparent() {
+        let mut effects = Effects::new(44100.0);
+        let mut left: Vec<f32> = (0..crate::MAX_BLOCK_SIZE)
+            .map(|i| (i as f32 * 0.1).sin())
+            .collect();
+        let mut right: Vec<f32> = left.iter().map(|sample| -sample).collect();
+        let (expected_left, expected_right) = (left.clone(), right.clone());
+
+        effects.process(
+            &mut left,
+            &mut right,
+            &bypassed(),
+            &[[1.0; crate::MAX_BLOCK_SIZE]; NUM_EFFECTS],
+        );
+        assert_eq!(left, expected_left);
+        assert_eq!(right, expected_right);
+    }
+
+    #[test]
+    fn test_tail() {
+        assert_eq!(bypassed().tail_samples(44100.0), 0);
+
+        let settings = EffectsSettings {
+            reverb: Some(ReverbSettings::new(2.0, 0.5, 0.0, 1.0, 1000.0)),
+            ..bypassed()
+        };
+        assert_eq!(settings.tail_samples(1000.0), 2000);
+    }
+
+    #[test]
+    fn test_delay_line() {
+        let mut delay_line = DelayLine::new(4);
+        for sample in [1.0, 2.0, 3.0] {
+            delay_line.write(sample);
+        }
+
+        assert_eq!(delay_line.read(1.0), 3.0);
+        assert_eq!(delay_line.read(2.0), 2.0);
+        assert_eq!(delay_line.read(1.5), 2.5);
+        // Delays are clamped to the delay line's length
+        assert_eq!(delay_line.read(10.0), delay_line.read(4.0));
+    }
+}
@@ -1,19 +1,51 @@
+use enum_iterator::Sequence;
+use nih_plug::params::enums::Enum;
+
+/// How steep the exponential curve is. At this value the segment covers about 92% of its range in
+/// the first half of the stage, which sounds close to an analog RC envelope.
+const EXPONENTIAL_STEEPNESS: f32 = 5.0;
+/// The steepness of an adjustable curve at a tension of -1 or 1.
+const MAX_CURVE_STEEPNESS: f32 = 10.0;
+
 pub trait Envelope {
-    fn get_value(&mut self, dt: f32) -> f32;
+    fn next_value(&mut self) -> f32;
     fn trigger(&mut self);
     fn release(&mut self);
 }
 
-pub struct ADSREnvelope {
-    attack: f32,
-    decay: f32,
-    sustain: f32,
-    release: f32,
-    state: ADSREnvelopeState,
-    time: f32,
+/// The shape of a single envelope stage.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Enum, Sequence)]
+pub enum EnvelopeCurve {
+    Linear,
+    Exponential,
+    /// A curve whose steepness is set by the envelope's tension. Positive tension values behave
+    /// like the exponential curve, negative values start slowly and speed up towards the end.
+    Curved,
+}
+
+impl EnvelopeCurve {
+    /// Map a stage's linear progress in `[0, 1]` to the shaped progress, also in `[0, 1]`.
+    pub fn shape(self, progress: f32, tension: f32) -> f32 {
+        match self {
+            EnvelopeCurve::Linear => progress,
+            EnvelopeCurve::Exponential => exponential_shape(progress, EXPONENTIAL_STEEPNESS),
+            EnvelopeCurve::Curved => exponential_shape(progress, tension * MAX_CURVE_STEEPNESS),
+        }
+    }
+}
+
+/// An exponential segment normalized so it starts at 0 and ends exactly at 1. Negative steepness
+/// values mirror the curve.
+fn exponential_shape(progress: f32, steepness: f32) -> f32 {
+    if steepness.abs() < 1.0e-3 {
+        progress
+    } else {
+        (1.0 - (-steepness * progress).exp()) / (1.0 - (-steepness).exp())
+    }
 }
 
-enum ADSREnvelopeState {
+#[derive(Debug, Clone, Copy, PartialEq, Enum)]
+pub enum ADSREnvelopeState {
     Idle,
     Attack,
     Decay,
@@ -21,80 +53,317 @@ enum ADSREnvelopeState {
     Release,
 }
 
+/// The settings for an [`ADSREnvelope`]. Times are in milliseconds and the sustain level is in
+/// `[0, 1]`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EnvelopeSettings {
+    pub attack_ms: f32,
+    pub decay_ms: f32,
+    pub sustain: f32,
+    pub release_ms: f32,
+    pub attack_curve: EnvelopeCurve,
+    pub decay_curve: EnvelopeCurve,
+    pub release_curve: EnvelopeCurve,
+    /// The tension used by [`EnvelopeCurve::Curved`] stages, in `[-1, 1]`.
+    pub tension: f32,
+}
+
+impl Default for EnvelopeSettings {
+    fn default() -> Self {
+        Self {
+            attack_ms: 0.0,
+            decay_ms: 0.0,
+            sustain: 1.0,
+            release_ms: 0.0,
+            attack_curve: EnvelopeCurve::Linear,
+            decay_curve: EnvelopeCurve::Linear,
+            release_curve: EnvelopeCurve::Linear,
+            tension: 0.0,
+        }
+    }
+}
+
+impl EnvelopeSettings {
+    /// Multiply the attack, decay and release times by `scale`.
+    pub fn scale_times(self, scale: f32) -> Self {
+        Self {
+            attack_ms: self.attack_ms * scale,
+            decay_ms: self.decay_ms * scale,
+            release_ms: self.release_ms * scale,
+            ..self
+        }
+    }
+}
+
+/// An ADSR envelope that advances by one sample every time [`Envelope::next_value()`] is called.
+/// Retriggering and releasing start from the envelope's current level, so there are no jumps when
+/// a note is released during its attack or retriggered during its release.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ADSREnvelope {
+    settings: EnvelopeSettings,
+    sample_rate: f32,
+    state: ADSREnvelopeState,
+    /// The number of samples since the start of the current stage.
+    stage_samples: u32,
+    /// The envelope's level at the start of the current stage.
+    start_level: f32,
+    /// The most recently produced value.
+    value: f32,
+}
+
 impl ADSREnvelope {
-    pub fn new(attack: f32, decay: f32, sustain: f32, release: f32) -> Self {
+    pub fn new(settings: EnvelopeSettings, sample_rate: f32) -> Self {
         ADSREnvelope {
-            attack,
-            decay,
-            sustain,
-            release,
+            settings,
+            sample_rate,
             state: ADSREnvelopeState::Idle,
-            time: 0.0,
+            stage_samples: 0,
+            start_level: 0.0,
+            value: 0.0,
         }
     }
 
-    pub fn set_attack(&mut self, attack: f32) {
-        self.attack = attack;
+    /// Update the envelope's settings. This can be done while the envelope is running, in which
+    /// case the current stage will continue with the new times and curves.
+    pub fn set_settings(&mut self, settings: EnvelopeSettings) {
+        self.settings = settings;
     }
 
-    pub fn set_decay(&mut self, decay: f32) {
-        self.decay = decay;
+    pub fn get_state(&self) -> ADSREnvelopeState {
+        self.state
     }
 
-    pub fn set_sustain(&mut self, sustain: f32) {
-        self.sustain = sustain;
+    /// The value produced by the last call to [`Envelope::next_value()`].
+    pub fn previous_value(&self) -> f32 {
+        self.value
     }
 
-    pub fn set_release(&mut self, release: f32) {
-        self.release = release;
+    fn enter_stage(&mut self, state: ADSREnvelopeState) {
+        self.state = state;
+        self.stage_samples = 0;
+        self.start_level = self.value;
+    }
+
+    /// Advance a ramp from the stage's start level to `target` over `length_ms`. Moves on to
+    /// `next_state` once the stage has finished.
+    fn advance_stage(
+        &mut self,
+        length_ms: f32,
+        target: f32,
+        curve: EnvelopeCurve,
+        next_state: ADSREnvelopeState,
+    ) {
+        self.stage_samples += 1;
+
+        let length_samples = length_ms / 1000.0 * self.sample_rate;
+        let elapsed_samples = self.stage_samples as f32;
+        if elapsed_samples >= length_samples {
+            self.value = target;
+            self.enter_stage(next_state);
+        } else {
+            let progress = curve.shape(elapsed_samples / length_samples, self.settings.tension);
+            self.value = self.start_level + (target - self.start_level) * progress;
+        }
     }
 }
 
 impl Envelope for ADSREnvelope {
-    fn get_value(&mut self, dt: f32) -> f32 {
-        self.time += dt;
+    fn next_value(&mut self) -> f32 {
         match self.state {
-            ADSREnvelopeState::Idle => 0.0,
-            ADSREnvelopeState::Attack => {
-                let attack_value = self.time / self.attack;
-                if self.time >= self.attack {
-                    self.state = ADSREnvelopeState::Decay;
-                    self.time = 0.0;
-                    1.0
-                } else {
-                    attack_value
-                }
+            ADSREnvelopeState::Idle => self.value = 0.0,
+            ADSREnvelopeState::Attack => self.advance_stage(
+                self.settings.attack_ms,
+                1.0,
+                self.settings.attack_curve,
+                ADSREnvelopeState::Decay,
+            ),
+            ADSREnvelopeState::Decay => self.advance_stage(
+                self.settings.decay_ms,
+                self.settings.sustain,
+                self.settings.decay_curve,
+                ADSREnvelopeState::Sustain,
+            ),
+            ADSREnvelopeState::Sustain => self.value = self.settings.sustain,
+            ADSREnvelopeState::Release => self.advance_stage(
+                self.settings.release_ms,
+                0.0,
+                self.settings.release_curve,
+                ADSREnvelopeState::Idle,
+            ),
+        }
+
+        self.value
+    }
+
+    fn trigger(&mut self) {
+        self.enter_stage(ADSREnvelopeState::Attack);
+    }
+
+    fn release(&mut self) {
+        if self.state != ADSREnvelopeState::Idle {
+            self.enter_stage(ADSREnvelopeState::Release);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATES: [f32; 3] = [44100.0, 48000.0, 96000.0];
+
+    fn settings(attack_ms: f32, decay_ms: f32, sustain: f32, release_ms: f32) -> EnvelopeSettings {
+        EnvelopeSettings {
+            attack_ms,
+            decay_ms,
+            sustain,
+            release_ms,
+            ..EnvelopeSettings::default()
+        }
+    }
+
+    fn ms_to_samples(ms: f32, sample_rate: f32) -> usize {
+        (ms / 1000.0 * sample_rate).ceil() as usize
+    }
+
+    #[test]
+    fn test_attack_timing() {
+        for sample_rate in SAMPLE_RATES {
+            let mut envelope = ADSREnvelope::new(settings(10.0, 100.0, 0.5, 100.0), sample_rate);
+            envelope.trigger();
+
+            let attack_samples = ms_to_samples(10.0, sample_rate);
+            for _ in 0..attack_samples - 1 {
+                envelope.next_value();
             }
-            ADSREnvelopeState::Decay => {
-                let decay_value = 1.0 - (1.0 - self.sustain) * (self.time / self.decay);
-                if self.time >= self.decay {
-                    self.state = ADSREnvelopeState::Sustain;
-                    self.time = 0.0;
-                    self.sustain
-                } else {
-                    decay_value
-                }
+            assert_eq!(envelope.get_state(), ADSREnvelopeState::Attack);
+            assert!(envelope.previous_value() < 1.0);
+
+            assert_eq!(envelope.next_value(), 1.0);
+            assert_eq!(envelope.get_state(), ADSREnvelopeState::Decay);
+        }
+    }
+
+    #[test]
+    fn test_linear_attack_midpoint() {
+        for sample_rate in SAMPLE_RATES {
+            let mut envelope = ADSREnvelope::new(settings(20.0, 0.0, 1.0, 0.0), sample_rate);
+            envelope.trigger();
+
+            for _ in 0..ms_to_samples(10.0, sample_rate) {
+                envelope.next_value();
             }
-            ADSREnvelopeState::Sustain => self.sustain,
-            ADSREnvelopeState::Release => {
-                let release_value = self.sustain * (1.0 - (self.time / self.release));
-                if self.time >= self.release {
-                    self.state = ADSREnvelopeState::Idle;
-                    self.time = 0.0;
-                    0.0
-                } else {
-                    release_value
-                }
+            approx::assert_relative_eq!(envelope.previous_value(), 0.5, epsilon = 1.0e-3);
+        }
+    }
+
+    #[test]
+    fn test_decay_and_release_timing() {
+        for sample_rate in SAMPLE_RATES {
+            let mut envelope = ADSREnvelope::new(settings(5.0, 50.0, 0.25, 200.0), sample_rate);
+            envelope.trigger();
+
+            let attack_decay_samples =
+                ms_to_samples(5.0, sample_rate) + ms_to_samples(50.0, sample_rate);
+            for _ in 0..attack_decay_samples {
+                envelope.next_value();
+            }
+            assert_eq!(envelope.get_state(), ADSREnvelopeState::Sustain);
+            assert_eq!(envelope.next_value(), 0.25);
+
+            envelope.release();
+            let release_samples = ms_to_samples(200.0, sample_rate);
+            for _ in 0..release_samples - 1 {
+                envelope.next_value();
             }
+            assert_eq!(envelope.get_state(), ADSREnvelopeState::Release);
+            assert!(envelope.previous_value() > 0.0);
+
+            assert_eq!(envelope.next_value(), 0.0);
+            assert_eq!(envelope.get_state(), ADSREnvelopeState::Idle);
         }
     }
 
-    fn trigger(&mut self) {
-        self.state = ADSREnvelopeState::Attack;
-        self.time = 0.0;
+    #[test]
+    fn test_release_from_current_level() {
+        let mut envelope = ADSREnvelope::new(settings(100.0, 100.0, 0.9, 100.0), 48000.0);
+        envelope.trigger();
+        for _ in 0..ms_to_samples(25.0, 48000.0) {
+            envelope.next_value();
+        }
+        let level = envelope.previous_value();
+        approx::assert_relative_eq!(level, 0.25, epsilon = 1.0e-3);
+
+        // Releasing during the attack should ramp down from the current level and not jump to the
+        // sustain level first
+        envelope.release();
+        let next = envelope.next_value();
+        assert!(next < level && next > level * 0.99);
     }
 
-    fn release(&mut self) {
-        self.state = ADSREnvelopeState::Release;
+    #[test]
+    fn test_retrigger_from_current_level() {
+        let mut envelope = ADSREnvelope::new(settings(10.0, 10.0, 0.5, 100.0), 44100.0);
+        envelope.trigger();
+        for _ in 0..ms_to_samples(100.0, 44100.0) {
+            envelope.next_value();
+        }
+        envelope.release();
+        for _ in 0..ms_to_samples(50.0, 44100.0) {
+            envelope.next_value();
+        }
+        let level = envelope.previous_value();
+        approx::assert_relative_eq!(level, 0.25, epsilon = 1.0e-3);
+
+        envelope.trigger();
+        let next = envelope.next_value();
+        assert_eq!(envelope.get_state(), ADSREnvelopeState::Attack);
+        assert!(next > level && next < level + 0.01);
+    }
+
+    #[test]
+    fn test_curve_shapes() {
+        for curve in [
+            EnvelopeCurve::Linear,
+            EnvelopeCurve::Exponential,
+            EnvelopeCurve::Curved,
+        ] {
+            for tension in [-1.0, -0.5, 0.0, 0.5, 1.0] {
+                approx::assert_relative_eq!(curve.shape(0.0, tension), 0.0, epsilon = 1.0e-6);
+                approx::assert_relative_eq!(curve.shape(1.0, tension), 1.0, epsilon = 1.0e-6);
+
+                let mut previous = 0.0;
+                for i in 1..=100 {
+                    let value = curve.shape(i as f32 / 100.0, tension);
+                    assert!(value >= previous);
+                    previous = value;
+                }
+            }
+        }
+
+        assert!(EnvelopeCurve::Exponential.shape(0.5, 0.0) > 0.5);
+        assert!(EnvelopeCurve::Curved.shape(0.5, 0.5) > 0.5);
+        assert!(EnvelopeCurve::Curved.shape(0.5, -0.5) < 0.5);
+    }
+
+    #[test]
+    fn test_curved_timing_is_unaffected_by_shape() {
+        for sample_rate in SAMPLE_RATES {
+            let mut envelope = ADSREnvelope::new(
+                EnvelopeSettings {
+                    attack_curve: EnvelopeCurve::Exponential,
+                    ..settings(30.0, 0.0, 1.0, 0.0)
+                },
+                sample_rate,
+            );
+            envelope.trigger();
+
+            let attack_samples = ms_to_samples(30.0, sample_rate);
+            for _ in 0..attack_samples - 1 {
+                envelope.next_value();
+            }
+            assert_eq!(envelope.get_state(), ADSREnvelopeState::Attack);
+            assert_eq!(envelope.next_value(), 1.0);
+        }
     }
 }
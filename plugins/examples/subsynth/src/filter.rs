@@ -1,423 +1,648 @@
-use nih_plug::params::enums::{Enum, EnumParam};
+use nih_plug::params::enums::Enum;
 use enum_iterator::Sequence;
 
-pub trait Envelope {
-    fn get_value(&mut self, dt: f32) -> f32;
-    fn trigger(&mut self);
-    fn release(&mut self);
+/// The lowest damping the state variable filter can have. At maximum resonance it has a Q of
+/// `1 / MIN_SVF_DAMPING`.
+const MIN_SVF_DAMPING: f32 = 0.02;
+/// The damping of the second state variable filter stage at the 24 dB/octave slope. Only the first
+/// stage is resonant, otherwise the two stages' resonant peaks would multiply. This is a Q of
+/// `1 / sqrt(2)`, which keeps the second stage flat up to the cutoff.
+const BUTTERWORTH_DAMPING: f32 = std::f32::consts::SQRT_2;
+/// The ladder filter's feedback gain at maximum resonance. The linear filter starts to
+/// self-oscillate at a feedback gain of 4, the saturator keeps the oscillation bounded after that.
+const MAX_LADDER_FEEDBACK: f32 = 4.2;
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Enum, Sequence)]
+pub enum FilterType {
+    Lowpass,
+    Bandpass,
+    Highpass,
+    Notch,
+    Peak,
+    Allpass,
+    /// A four-pole transistor ladder lowpass filter.
+    Ladder,
 }
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub struct ADSREnvelope {
-    attack: f32,
-    decay: f32,
-    sustain: f32,
-    release: f32,
-    state: ADSREnvelopeState,
-    time: f32,
+
+/// The filter's slope. The state variable filter modes follow the resonant stage with a Butterworth
+/// stage for the 24 dB/octave slope, the ladder filter takes its output from the second or the
+/// fourth pole.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Enum, Sequence)]
+pub enum FilterSlope {
+    #[name = "12 dB/oct"]
+    Slope12,
+    #[name = "24 dB/oct"]
+    Slope24,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Enum)]
-pub enum ADSREnvelopeState {
-    Idle,
-    Attack,
-    Decay,
-    Sustain,
-    Release,
+pub trait Filter: Send {
+    fn process(&mut self, input: f32) -> f32;
+    fn set_sample_rate(&mut self, sample_rate: f32);
+    fn set_cutoff(&mut self, cutoff: f32);
+    /// Set the filter's resonance, in `[0, 1]`.
+    fn set_resonance(&mut self, resonance: f32);
 }
 
+/// Clamps a modulated cutoff frequency to a range the filters can handle at the current sample
+/// rate.
+fn clamp_cutoff(cutoff: f32, sample_rate: f32) -> f32 {
+    cutoff.clamp(20.0, sample_rate * 0.49)
+}
 
-impl ADSREnvelope {
-    
-    pub fn new(attack: f32, decay: f32, sustain: f32, release: f32) -> Self {
-        ADSREnvelope {
-            attack,
-            decay,
-            sustain,
-            release,
-            state: ADSREnvelopeState::Attack,
-            time: 0.0,
-        }
-    }
-    pub fn get_time(&mut self) -> f32{
-        self.time
-    }
-    pub fn set_attack(&mut self, attack: f32) {
-        self.attack = attack;
-    }
+/// The prewarped integrator gain used by the TPT filter structures. With this gain the filters'
+/// responses match the analog prototypes exactly at the cutoff frequency.
+fn integrator_gain(cutoff: f32, sample_rate: f32) -> f32 {
+    (std::f32::consts::PI * clamp_cutoff(cutoff, sample_rate) / sample_rate).tan()
+}
 
-    pub fn set_decay(&mut self, decay: f32) {
-        self.decay = decay;
-    }
+/// A filter owned by a single voice. This wraps the concrete filter implementations in an enum so
+/// voices can store and swap their filter without allocating on the audio thread.
+#[derive(Debug, Clone)]
+pub enum VoiceFilter {
+    StateVariable(StateVariableFilter),
+    Ladder(LadderFilter),
+}
 
-    pub fn set_sustain(&mut self, sustain: f32) {
-        self.sustain = sustain;
+impl VoiceFilter {
+    pub fn new(
+        filter_type: FilterType,
+        slope: FilterSlope,
+        cutoff: f32,
+        resonance: f32,
+        sample_rate: f32,
+    ) -> Self {
+        match SvfMode::from_filter_type(filter_type) {
+            Some(mode) => VoiceFilter::StateVariable(StateVariableFilter::new(
+                mode,
+                slope,
+                cutoff,
+                resonance,
+                sample_rate,
+            )),
+            None => VoiceFilter::Ladder(LadderFilter::new(slope, cutoff, resonance, sample_rate)),
+        }
     }
 
-    pub fn set_release(&mut self, release: f32) {
-        self.release = release;
+    /// Change the filter's type and slope. Switching between the state variable filter's modes
+    /// keeps the filter's state so this doesn't click. Switching to or from the ladder filter
+    /// replaces the filter, which does not allocate since the filters are stored inline.
+    pub fn set_mode(&mut self, filter_type: FilterType, slope: FilterSlope) {
+        match (self, SvfMode::from_filter_type(filter_type)) {
+            (VoiceFilter::StateVariable(filter), Some(mode)) => {
+                filter.mode = mode;
+                filter.slope = slope;
+            }
+            (VoiceFilter::Ladder(filter), None) => filter.slope = slope,
+            (this @ VoiceFilter::StateVariable(_), None) => {
+                let (cutoff, resonance, sample_rate) = this.parameters();
+                *this = VoiceFilter::Ladder(LadderFilter::new(slope, cutoff, resonance, sample_rate));
+            }
+            (this @ VoiceFilter::Ladder(_), Some(mode)) => {
+                let (cutoff, resonance, sample_rate) = this.parameters();
+                *this = VoiceFilter::StateVariable(StateVariableFilter::new(
+                    mode,
+                    slope,
+                    cutoff,
+                    resonance,
+                    sample_rate,
+                ));
+            }
+        }
     }
 
-    pub fn get_state(&self) -> ADSREnvelopeState {
-        self.state
+    /// The cutoff, resonance and sample rate, used to carry the settings over when swapping
+    /// filters.
+    fn parameters(&self) -> (f32, f32, f32) {
+        match self {
+            VoiceFilter::StateVariable(filter) => {
+                (filter.cutoff, filter.resonance, filter.sample_rate)
+            }
+            VoiceFilter::Ladder(filter) => (filter.cutoff, filter.resonance, filter.sample_rate),
+        }
     }
 
-    pub fn previous_value(&self) -> f32 {
-        match self.state {
-            ADSREnvelopeState::Idle => 0.0,
-            ADSREnvelopeState::Attack => self.time / self.attack,
-            ADSREnvelopeState::Decay => 1.0 - (1.0 - self.sustain) * (self.time / self.decay),
-            ADSREnvelopeState::Sustain => self.sustain,
-            ADSREnvelopeState::Release => self.sustain * (1.0 - (self.time / self.release)),
+    fn as_filter_mut(&mut self) -> &mut dyn Filter {
+        match self {
+            VoiceFilter::StateVariable(filter) => filter,
+            VoiceFilter::Ladder(filter) => filter,
         }
     }
 }
 
-impl Envelope for ADSREnvelope {
-    fn get_value(&mut self, dt: f32) -> f32 {
-        self.time += dt;
-        // Check if the envelope has completed and move to the next stage
-        if self.state != ADSREnvelopeState::Idle && self.time >= self.release {
-            self.state = ADSREnvelopeState::Idle;
-            self.time = 0.0;
-        }
-        let value = match self.state {
-            ADSREnvelopeState::Idle => 0.0,
-            ADSREnvelopeState::Attack => {
-                let attack_value = self.time / self.attack;
-                if self.time >= self.attack {
-                    self.state = ADSREnvelopeState::Decay;
-                    self.time = 0.0;
-                    1.0
-                } else {
-                    attack_value
-                }
-            }
-            ADSREnvelopeState::Decay => {
-                let decay_value = 1.0 - (1.0 - self.sustain) * (self.time / self.decay);
-                if self.time >= self.decay {
-                    self.state = ADSREnvelopeState::Sustain;
-                    self.time = 0.0;
-                    self.sustain
-                } else {
-                    decay_value
-                }
-            }
-            ADSREnvelopeState::Sustain => self.sustain,
-            ADSREnvelopeState::Release => {
-                let release_value = self.sustain * (1.0 - (self.time / self.release));
-                if self.time >= self.release {
-                    self.state = ADSREnvelopeState::Idle;
-                    self.time = 0.0;
-                    0.0
-                } else {
-                    release_value
-                }
-            }
-        };
-
-        // Check if the envelope has completed and move to the next stage
-        if self.state != ADSREnvelopeState::Idle && self.time >= self.release {
-            self.state = ADSREnvelopeState::Idle;
-            self.time = 0.0;
-        }
+impl Filter for VoiceFilter {
+    fn process(&mut self, input: f32) -> f32 {
+        self.as_filter_mut().process(input)
+    }
 
-        value
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.as_filter_mut().set_sample_rate(sample_rate);
     }
 
-    fn trigger(&mut self) {
-        self.state = ADSREnvelopeState::Attack;
-        self.time = 0.0;
+    fn set_cutoff(&mut self, cutoff: f32) {
+        self.as_filter_mut().set_cutoff(cutoff);
     }
 
-    fn release(&mut self) {
-        self.state = ADSREnvelopeState::Release;
+    fn set_resonance(&mut self, resonance: f32) {
+        self.as_filter_mut().set_resonance(resonance);
     }
 }
 
-
-
-
-#[derive(PartialEq, Eq, Clone, Copy, Debug, Enum, Sequence)]
-pub enum FilterType {
+/// The output taken from the state variable filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SvfMode {
     Lowpass,
     Bandpass,
     Highpass,
     Notch,
-    Statevariable,
+    Peak,
+    Allpass,
 }
 
-
-pub trait Filter: Send {
-    fn process(&mut self, input: f32) -> f32;
-    fn set_sample_rate(&mut self, sample_rate: f32);
+impl SvfMode {
+    fn from_filter_type(filter_type: FilterType) -> Option<Self> {
+        match filter_type {
+            FilterType::Lowpass => Some(SvfMode::Lowpass),
+            FilterType::Bandpass => Some(SvfMode::Bandpass),
+            FilterType::Highpass => Some(SvfMode::Highpass),
+            FilterType::Notch => Some(SvfMode::Notch),
+            FilterType::Peak => Some(SvfMode::Peak),
+            FilterType::Allpass => Some(SvfMode::Allpass),
+            FilterType::Ladder => None,
+        }
+    }
 }
 
-pub struct FilterFactory;
+/// A single trapezoidal integrator state variable filter stage. This is the analog prototype
+/// `1 / (s^2 + k s + 1)` discretized with the bilinear transform.
+#[derive(Debug, Clone, Copy, Default)]
+struct SvfStage {
+    ic1eq: f32,
+    ic2eq: f32,
+}
 
-impl FilterFactory {
-    pub fn create_filter(
-        filter_type: FilterType,
-        cutoff: f32,
-        cutoff_envelope: ADSREnvelope,
-        resonance: f32,
-        resonance_envelope: ADSREnvelope,
-        sample_rate: f32,
-    ) -> Box<dyn Filter> {
-        match filter_type {
-            FilterType::Lowpass => Box::new(LowpassFilter::new(cutoff, cutoff_envelope, resonance, resonance_envelope, sample_rate)),
-            FilterType::Bandpass => Box::new(BandpassFilter::new(cutoff, cutoff_envelope, resonance, resonance_envelope, sample_rate)),
-            FilterType::Highpass => Box::new(HighpassFilter::new(cutoff, cutoff_envelope, resonance, resonance_envelope, sample_rate)),
-            FilterType::Notch => Box::new(NotchFilter::new(cutoff, cutoff_envelope, resonance, resonance_envelope, sample_rate)),
-            FilterType::Statevariable => Box::new(StatevariableFilter::new(cutoff, cutoff_envelope, resonance, resonance_envelope, sample_rate)),
+impl SvfStage {
+    /// Process a sample with integrator gain `g` and damping `k`, returning the stage's output for
+    /// `mode`.
+    fn process(&mut self, input: f32, g: f32, k: f32, mode: SvfMode) -> f32 {
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+
+        let v3 = input - self.ic2eq;
+        let v1 = a1 * self.ic1eq + a2 * v3;
+        let v2 = self.ic2eq + a2 * self.ic1eq + a3 * v3;
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+
+        let lowpass = v2;
+        let bandpass = v1;
+        let highpass = input - k * v1 - v2;
+        match mode {
+            SvfMode::Lowpass => lowpass,
+            SvfMode::Bandpass => bandpass,
+            SvfMode::Highpass => highpass,
+            SvfMode::Notch => lowpass + highpass,
+            SvfMode::Peak => lowpass - highpass,
+            SvfMode::Allpass => input - 2.0 * k * bandpass,
         }
     }
 }
 
-pub struct HighpassFilter {
+/// A zero-delay feedback multimode state variable filter.
+#[derive(Debug, Clone)]
+pub struct StateVariableFilter {
+    mode: SvfMode,
+    slope: FilterSlope,
     cutoff: f32,
     resonance: f32,
-    cutoff_envelope: ADSREnvelope,
-    resonance_envelope: ADSREnvelope,
     sample_rate: f32,
-    prev_input: f32,
-    prev_output: f32,
+    g: f32,
+    k: f32,
+    stages: [SvfStage; 2],
 }
 
-impl  HighpassFilter {
-    pub fn new(cutoff: f32, cutoff_envelope: ADSREnvelope, resonance: f32, resonance_envelope: ADSREnvelope, sample_rate: f32) -> Self {
-        HighpassFilter {
+impl StateVariableFilter {
+    fn new(
+        mode: SvfMode,
+        slope: FilterSlope,
+        cutoff: f32,
+        resonance: f32,
+        sample_rate: f32,
+    ) -> Self {
+        let mut filter = StateVariableFilter {
+            mode,
+            slope,
             cutoff,
             resonance,
-            cutoff_envelope,
-            resonance_envelope,
             sample_rate,
-            prev_input: 0.0,
-            prev_output: 0.0,
-        }
+            g: 0.0,
+            k: 0.0,
+            stages: [SvfStage::default(); 2],
+        };
+        filter.set_cutoff(cutoff);
+        filter.set_resonance(resonance);
+
+        filter
     }
 }
-impl Filter for HighpassFilter {
+
+impl Filter for StateVariableFilter {
     fn process(&mut self, input: f32) -> f32 {
-        let cutoff = self.cutoff * self.cutoff_envelope.get_value(self.cutoff_envelope.time);
-        let resonance = self.resonance * self.resonance_envelope.get_value(self.resonance_envelope.time);
-        let c = 1.0 / (2.0 * std::f32::consts::PI * cutoff / self.sample_rate);
-        let r = 1.0 - resonance;
-        let output = c * (input - self.prev_input + r * self.prev_output);
-        self.prev_input = input;
-        self.prev_output = output;
-        output
+        let output = self.stages[0].process(input, self.g, self.k, self.mode);
+        match self.slope {
+            FilterSlope::Slope12 => output,
+            FilterSlope::Slope24 => {
+                self.stages[1].process(output, self.g, BUTTERWORTH_DAMPING, self.mode)
+            }
+        }
     }
 
     fn set_sample_rate(&mut self, sample_rate: f32) {
         self.sample_rate = sample_rate;
+        self.g = integrator_gain(self.cutoff, self.sample_rate);
+    }
+
+    fn set_cutoff(&mut self, cutoff: f32) {
+        self.cutoff = cutoff;
+        self.g = integrator_gain(self.cutoff, self.sample_rate);
+    }
+
+    fn set_resonance(&mut self, resonance: f32) {
+        self.resonance = resonance.clamp(0.0, 1.0);
+        // No resonance corresponds to a Q of 0.5, the maximum resonance is just short of
+        // self-oscillation
+        self.k = MIN_SVF_DAMPING + (2.0 - MIN_SVF_DAMPING) * (1.0 - self.resonance);
     }
 }
-pub struct BandpassFilter {
+
+/// A zero-delay feedback four-pole ladder filter. The feedback loop is solved for the linear filter
+/// and the input to the ladder is then saturated, which keeps the filter stable when it
+/// self-oscillates at high resonance settings.
+#[derive(Debug, Clone)]
+pub struct LadderFilter {
+    slope: FilterSlope,
     cutoff: f32,
     resonance: f32,
-    cutoff_envelope: ADSREnvelope,
-    resonance_envelope: ADSREnvelope,
     sample_rate: f32,
-    prev_input: f32,
-    prev_output: f32,
+    g: f32,
+    k: f32,
+    /// The states of the four one-pole lowpass stages.
+    stages: [f32; 4],
 }
 
-impl BandpassFilter {
-    pub fn new(cutoff: f32, cutoff_envelope: ADSREnvelope, resonance: f32, resonance_envelope: ADSREnvelope, sample_rate: f32) -> Self {
-        BandpassFilter {
+impl LadderFilter {
+    fn new(slope: FilterSlope, cutoff: f32, resonance: f32, sample_rate: f32) -> Self {
+        let mut filter = LadderFilter {
+            slope,
             cutoff,
             resonance,
-            cutoff_envelope,
-            resonance_envelope,
             sample_rate,
-            prev_input: 0.0,
-            prev_output: 0.0,
-        }
+            g: 0.0,
+            k: 0.0,
+            stages: [0.0; 4],
+        };
+        filter.set_cutoff(cutoff);
+        filter.set_resonance(resonance);
+
+        filter
     }
 }
-impl Filter for BandpassFilter {
+
+impl Filter for LadderFilter {
     fn process(&mut self, input: f32) -> f32 {
-        let cutoff = self.cutoff * self.cutoff_envelope.get_value(self.cutoff_envelope.time);
-        let resonance = self.resonance * self.resonance_envelope.get_value(self.resonance_envelope.time);
-        let c = 1.0 / (2.0 * std::f32::consts::PI * cutoff / self.sample_rate);
-        let r = 1.0 - resonance;
-        let output = c * (input - self.prev_output) + r * self.prev_output;
-        self.prev_input = input;
-        self.prev_output = output;
-        output
+        // Each stage's output is `big_g * stage_input + state / (1 + g)`, so the ladder's output
+        // is a linear function of its input that can be solved for directly
+        let big_g = self.g / (1.0 + self.g);
+        let state_contribution = self.stages.iter().fold(0.0, |contribution, state| {
+            contribution * big_g + state / (1.0 + self.g)
+        });
+        let ladder_gain = big_g * big_g * big_g * big_g;
+        let ladder_input =
+            ((input - self.k * state_contribution) / (1.0 + self.k * ladder_gain)).tanh();
+
+        let mut stage_input = ladder_input;
+        let mut outputs = [0.0; 4];
+        for (state, output) in self.stages.iter_mut().zip(outputs.iter_mut()) {
+            let v = (stage_input - *state) * big_g;
+            *output = v + *state;
+            *state = *output + v;
+            stage_input = *output;
+        }
+
+        match self.slope {
+            FilterSlope::Slope12 => outputs[1],
+            FilterSlope::Slope24 => outputs[3],
+        }
     }
 
     fn set_sample_rate(&mut self, sample_rate: f32) {
         self.sample_rate = sample_rate;
+        self.g = integrator_gain(self.cutoff, self.sample_rate);
     }
-}
-pub struct LowpassFilter {
-    cutoff: f32,
-    resonance: f32,
-    cutoff_envelope: ADSREnvelope,
-    resonance_envelope: ADSREnvelope,
-    sample_rate: f32,
-    prev_output: f32,
-}
 
-impl LowpassFilter {
-    pub fn new(cutoff: f32, cutoff_envelope: ADSREnvelope, resonance: f32, resonance_envelope: ADSREnvelope, sample_rate: f32) -> Self {
-        LowpassFilter {
-            cutoff,
-            resonance,
-            cutoff_envelope,
-            resonance_envelope,
-            sample_rate,
-            prev_output: 0.0,
-        }
+    fn set_cutoff(&mut self, cutoff: f32) {
+        self.cutoff = cutoff;
+        self.g = integrator_gain(self.cutoff, self.sample_rate);
+    }
+
+    fn set_resonance(&mut self, resonance: f32) {
+        self.resonance = resonance.clamp(0.0, 1.0);
+        self.k = self.resonance * MAX_LADDER_FEEDBACK;
     }
 }
 
-impl Filter for LowpassFilter {
-    fn process(&mut self, input: f32) -> f32 {
-        let cutoff = self.cutoff * self.cutoff_envelope.get_value(self.cutoff_envelope.time);
-        let resonance = self.resonance * self.resonance_envelope.get_value(self.resonance_envelope.time);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48000.0;
+    const IMPULSE_RESPONSE_LENGTH: usize = 1 << 15;
+    const TEST_FREQUENCIES: [f32; 8] = [50.0, 200.0, 500.0, 900.0, 1000.0, 1100.0, 3000.0, 12000.0];
+
+    /// A complex number, just enough to evaluate the analog prototypes.
+    #[derive(Debug, Clone, Copy)]
+    struct Complex(f64, f64);
+
+    impl Complex {
+        fn add(self, other: Complex) -> Complex {
+            Complex(self.0 + other.0, self.1 + other.1)
+        }
+
+        fn mul(self, other: Complex) -> Complex {
+            Complex(
+                self.0 * other.0 - self.1 * other.1,
+                self.0 * other.1 + self.1 * other.0,
+            )
+        }
+
+        fn div(self, other: Complex) -> Complex {
+            let denominator = other.0 * other.0 + other.1 * other.1;
+            Complex(
+                (self.0 * other.0 + self.1 * other.1) / denominator,
+                (self.1 * other.0 - self.0 * other.1) / denominator,
+            )
+        }
 
-        let c = 1.0 / (2.0 * std::f32::consts::PI * cutoff / self.sample_rate);
-        let r = resonance;
-        let output = c * input + r * self.prev_output;
+        fn scale(self, factor: f64) -> Complex {
+            Complex(self.0 * factor, self.1 * factor)
+        }
 
-        self.prev_output = output;
-        output
+        fn magnitude(self) -> f64 {
+            (self.0 * self.0 + self.1 * self.1).sqrt()
+        }
     }
 
-    fn set_sample_rate(&mut self, sample_rate: f32) {
-        self.sample_rate = sample_rate;
+    /// The bilinear transform maps the digital frequency `frequency` to this point on the analog
+    /// prototype's normalized frequency axis.
+    fn prototype_s(frequency: f32, cutoff: f32) -> Complex {
+        let warped = (std::f64::consts::PI * frequency as f64 / SAMPLE_RATE as f64).tan();
+        let g = (std::f64::consts::PI * cutoff as f64 / SAMPLE_RATE as f64).tan();
+        Complex(0.0, warped / g)
     }
-}
 
+    /// The analog state variable filter prototype's transfer function for `mode`.
+    fn svf_response(mode: SvfMode, s: Complex, k: f64) -> Complex {
+        let one = Complex(1.0, 0.0);
+        let s2 = s.mul(s);
+        let denominator = s2.add(s.scale(k)).add(one);
+        let numerator = match mode {
+            SvfMode::Lowpass => one,
+            SvfMode::Bandpass => s,
+            SvfMode::Highpass => s2,
+            SvfMode::Notch => s2.add(one),
+            SvfMode::Peak => one.add(s2.scale(-1.0)),
+            SvfMode::Allpass => s2.add(s.scale(-k)).add(one),
+        };
 
-pub struct NotchFilter {
-    cutoff: f32,
-    resonance: f32,
-    cutoff_envelope: ADSREnvelope,
-    resonance_envelope: ADSREnvelope,
-    sample_rate: f32,
-    prev_input: f32,
-    prev_output: f32,
-}
+        numerator.div(denominator)
+    }
 
-impl NotchFilter {
-    pub fn new(cutoff: f32, cutoff_envelope: ADSREnvelope, resonance: f32, resonance_envelope: ADSREnvelope, sample_rate: f32) -> Self {
-        NotchFilter {
-            cutoff,
-            resonance,
-            cutoff_envelope,
-            resonance_envelope,
-            sample_rate,
-            prev_input: 0.0,
-            prev_output: 0.0,
+    /// The analog ladder filter prototype's transfer function, taking the output from pole
+    /// `output_pole`.
+    fn ladder_response(s: Complex, k: f64, output_pole: i32) -> Complex {
+        let one = Complex(1.0, 0.0);
+        let pole = one.div(one.add(s));
+        let pole4 = pole.mul(pole).mul(pole).mul(pole);
+        let mut output = one;
+        for _ in 0..output_pole {
+            output = output.mul(pole);
         }
+
+        output.div(one.add(pole4.scale(k)))
     }
-}
 
-impl Filter for NotchFilter {
-    fn process(&mut self, input: f32) -> f32 {
-        let cutoff = self.cutoff * self.cutoff_envelope.get_value(self.cutoff_envelope.time);
-        let resonance = self.resonance * self.resonance_envelope.get_value(self.resonance_envelope.time);
-        let c = 1.0 / (2.0 * std::f32::consts::PI * cutoff / self.sample_rate);
-        let r = resonance;
-        let output = (input - self.prev_output) + r * (self.prev_input - self.prev_output);
-        self.prev_input = input;
-        self.prev_output = output;
-        output
+    /// Measure the filter's magnitude response at `frequencies` from its impulse response. The
+    /// impulse is scaled down so the ladder's saturator stays in its linear region.
+    fn measured_magnitude(filter: &mut impl Filter, frequencies: &[f32]) -> Vec<f64> {
+        const IMPULSE_GAIN: f32 = 1.0e-4;
+
+        let impulse_response: Vec<f64> = (0..IMPULSE_RESPONSE_LENGTH)
+            .map(|i| {
+                let input = if i == 0 { IMPULSE_GAIN } else { 0.0 };
+                filter.process(input) as f64 / IMPULSE_GAIN as f64
+            })
+            .collect();
+
+        frequencies
+            .iter()
+            .map(|&frequency| {
+                let omega = 2.0 * std::f64::consts::PI * frequency as f64 / SAMPLE_RATE as f64;
+                let (re, im) = impulse_response.iter().enumerate().fold(
+                    (0.0, 0.0),
+                    |(re, im), (n, sample)| {
+                        let angle = omega * n as f64;
+                        (re + sample * angle.cos(), im - sample * angle.sin())
+                    },
+                );
+                (re * re + im * im).sqrt()
+            })
+            .collect()
     }
 
-    fn set_sample_rate(&mut self, sample_rate: f32) {
-        self.sample_rate = sample_rate;
+    fn assert_magnitudes_match(measured: &[f64], expected: &[f64], context: &str) {
+        for ((measured, expected), frequency) in measured.iter().zip(expected).zip(TEST_FREQUENCIES)
+        {
+            let error_db = 20.0 * (measured / expected).log10();
+            // Deep notches have a huge relative error for a tiny absolute error
+            if *expected > 1.0e-3 {
+                assert!(
+                    error_db.abs() < 0.05,
+                    "{context} at {frequency} Hz: measured {measured}, expected {expected}"
+                );
+            } else {
+                assert!(*measured < 2.0e-3, "{context} at {frequency} Hz: {measured}");
+            }
+        }
     }
-}
 
-pub struct StatevariableFilter {
-    cutoff: f32,
-    resonance: f32,
-    cutoff_envelope: ADSREnvelope,
-    resonance_envelope: ADSREnvelope,
-    sample_rate: f32,
-    prev_input: f32,
-    lowpass_output: f32,
-    highpass_output: f32,
-    bandpass_output: f32,
-}
+    #[test]
+    fn test_svf_matches_analog_prototype() {
+        const CUTOFF: f32 = 1000.0;
+
+        for mode in [
+            SvfMode::Lowpass,
+            SvfMode::Bandpass,
+            SvfMode::Highpass,
+            SvfMode::Notch,
+            SvfMode::Peak,
+            SvfMode::Allpass,
+        ] {
+            for resonance in [0.0, 0.5, 0.9] {
+                let mut filter = StateVariableFilter::new(
+                    mode,
+                    FilterSlope::Slope12,
+                    CUTOFF,
+                    resonance,
+                    SAMPLE_RATE,
+                );
+                let k = filter.k as f64;
+                let measured = measured_magnitude(&mut filter, &TEST_FREQUENCIES);
+                let expected: Vec<f64> = TEST_FREQUENCIES
+                    .iter()
+                    .map(|&f| svf_response(mode, prototype_s(f, CUTOFF), k).magnitude())
+                    .collect();
+
+                assert_magnitudes_match(&measured, &expected, &format!("{mode:?} {resonance}"));
+            }
+        }
+    }
 
-impl StatevariableFilter {
-    pub fn new(
-        cutoff: f32,
-        cutoff_envelope: ADSREnvelope,
-        resonance: f32,
-        resonance_envelope: ADSREnvelope,
-        sample_rate: f32,
-    ) -> Self {
-        StatevariableFilter {
-            cutoff,
-            resonance,
-            cutoff_envelope,
-            resonance_envelope,
-            sample_rate,
-            prev_input: 0.0,
-            lowpass_output: 0.0,
-            highpass_output: 0.0,
-            bandpass_output: 0.0,
+    #[test]
+    fn test_svf_24db_slope_adds_a_butterworth_stage() {
+        const CUTOFF: f32 = 500.0;
+
+        for mode in [SvfMode::Lowpass, SvfMode::Highpass, SvfMode::Bandpass] {
+            let mut filter =
+                StateVariableFilter::new(mode, FilterSlope::Slope24, CUTOFF, 0.3, SAMPLE_RATE);
+            let k = filter.k as f64;
+            let measured = measured_magnitude(&mut filter, &TEST_FREQUENCIES);
+            let expected: Vec<f64> = TEST_FREQUENCIES
+                .iter()
+                .map(|&f| {
+                    let s = prototype_s(f, CUTOFF);
+                    svf_response(mode, s, k).magnitude()
+                        * svf_response(mode, s, BUTTERWORTH_DAMPING as f64).magnitude()
+                })
+                .collect();
+
+            assert_magnitudes_match(&measured, &expected, &format!("{mode:?} 24 dB"));
         }
     }
-}
 
-impl Filter for StatevariableFilter {
-    fn process(&mut self, input: f32) -> f32 {
-        let cutoff = self.cutoff * self.cutoff_envelope.get_value(self.cutoff_envelope.time);
-        let resonance = self.resonance * self.resonance_envelope.get_value(self.resonance_envelope.time);
+    #[test]
+    fn test_svf_cutoff_and_q() {
+        // At the cutoff frequency the lowpass output's gain is exactly the filter's Q. At the 24
+        // dB slope the Butterworth stage only adds its own gain of `1 / sqrt(2)`, so the resonant
+        // peaks don't multiply.
+        for resonance in [0.0, 0.25, 0.75, 1.0] {
+            for (slope, stage_gain) in [
+                (FilterSlope::Slope12, 1.0),
+                (FilterSlope::Slope24, std::f64::consts::FRAC_1_SQRT_2),
+            ] {
+                let mut filter = StateVariableFilter::new(
+                    SvfMode::Lowpass,
+                    slope,
+                    2000.0,
+                    resonance,
+                    SAMPLE_RATE,
+                );
+                let q = 1.0 / filter.k as f64;
+                let measured = measured_magnitude(&mut filter, &[2000.0])[0];
+                approx::assert_relative_eq!(measured, q * stage_gain, max_relative = 1.0e-3);
+            }
+        }
 
-        let f = cutoff / self.sample_rate;
-        let k = 2.0 * (1.0 - resonance);
-        let q = 1.0 / (2.0 * resonance);
+        // An octave and a half above the cutoff the 24 dB slope is far steeper
+        let mut filter =
+            StateVariableFilter::new(SvfMode::Lowpass, FilterSlope::Slope12, 1000.0, 0.0, SAMPLE_RATE);
+        let gain_12 = measured_magnitude(&mut filter, &[8000.0])[0];
+        let mut filter =
+            StateVariableFilter::new(SvfMode::Lowpass, FilterSlope::Slope24, 1000.0, 0.0, SAMPLE_RATE);
+        let gain_24 = measured_magnitude(&mut filter, &[8000.0])[0];
+        assert!(gain_24 < gain_12 * 0.1, "{gain_12} {gain_24}");
+    }
 
-        let input_minus_hp = input - self.highpass_output;
-        let lp_output = self.lowpass_output + f * self.bandpass_output;
-        let hp_output = input_minus_hp - lp_output * q - self.bandpass_output;
-        let bp_output = f * hp_output + self.bandpass_output;
+    #[test]
+    fn test_ladder_matches_analog_prototype() {
+        const CUTOFF: f32 = 1000.0;
+
+        for (slope, output_pole) in [(FilterSlope::Slope12, 2), (FilterSlope::Slope24, 4)] {
+            for resonance in [0.0, 0.4, 0.8] {
+                let mut filter = LadderFilter::new(slope, CUTOFF, resonance, SAMPLE_RATE);
+                let k = filter.k as f64;
+                let measured = measured_magnitude(&mut filter, &TEST_FREQUENCIES);
+                let expected: Vec<f64> = TEST_FREQUENCIES
+                    .iter()
+                    .map(|&f| ladder_response(prototype_s(f, CUTOFF), k, output_pole).magnitude())
+                    .collect();
+
+                assert_magnitudes_match(&measured, &expected, &format!("{slope:?} {resonance}"));
+            }
+        }
+    }
 
-        self.prev_input = input;
-        self.lowpass_output = lp_output;
-        self.highpass_output = hp_output;
-        self.bandpass_output = bp_output;
+    #[test]
+    fn test_ladder_self_oscillates() {
+        const CUTOFF: f32 = 440.0;
+
+        let mut filter = LadderFilter::new(FilterSlope::Slope24, CUTOFF, 1.0, SAMPLE_RATE);
+        filter.process(0.01);
+
+        // After a second without any input the filter should still be ringing at its cutoff
+        // frequency, with a bounded amplitude
+        let mut peak: f32 = 0.0;
+        let mut zero_crossings = 0;
+        let mut previous = filter.process(0.0);
+        for i in 0..(2.0 * SAMPLE_RATE) as usize {
+            let sample = filter.process(0.0);
+            if i >= SAMPLE_RATE as usize {
+                peak = peak.max(sample.abs());
+                if previous < 0.0 && sample >= 0.0 {
+                    zero_crossings += 1;
+                }
+            }
+            previous = sample;
+        }
 
-        bp_output
+        assert!(peak > 0.05 && peak < 2.0, "{peak}");
+        assert!((zero_crossings as f32 - CUTOFF).abs() < CUTOFF * 0.05, "{zero_crossings}");
     }
 
-    fn set_sample_rate(&mut self, sample_rate: f32) {
-        self.sample_rate = sample_rate;
+    #[test]
+    fn test_filters_stay_stable_under_modulation() {
+        for filter_type in [FilterType::Lowpass, FilterType::Notch, FilterType::Ladder] {
+            let mut filter =
+                VoiceFilter::new(filter_type, FilterSlope::Slope24, 1000.0, 1.0, SAMPLE_RATE);
+            for i in 0..SAMPLE_RATE as usize {
+                // Sweep the cutoff over the entire range every 100 ms while feeding in a square wave
+                let sweep = (i % 4800) as f32 / 4800.0;
+                filter.set_cutoff(20.0 * 1000.0f32.powf(sweep));
+                let input = if (i / 50) % 2 == 0 { 1.0 } else { -1.0 };
+                let output = filter.process(input);
+                // Only one stage is resonant, so with a Q of 50 the gain stays well below 100
+                assert!(output.is_finite() && output.abs() < 100.0, "{filter_type:?}: {output}");
+            }
+        }
     }
-}
-
 
-pub fn generate_filter(
-    filter_type: FilterType,
-    cutoff: f32,
-    resonance: f32,
-    cutoff_attack: f32,
-    cutoff_decay: f32,
-    cutoff_sustain: f32,
-    cutoff_release: f32,
-    resonance_attack: f32,
-    resonance_decay: f32,
-    resonance_sustain: f32,
-    resonance_release: f32,
-    generated_sample: f32,
-    sample_rate: f32,
-) -> Box<dyn Filter> {
-    let cutoff_envelope = ADSREnvelope::new(cutoff_attack, cutoff_decay, cutoff_sustain, cutoff_release);
-    let resonance_envelope = ADSREnvelope::new(resonance_attack, resonance_decay, resonance_sustain, resonance_release);
-
-    match filter_type {
-        FilterType::Lowpass => Box::new(LowpassFilter::new(cutoff, cutoff_envelope, resonance, resonance_envelope, sample_rate)),
-        FilterType::Bandpass => Box::new(BandpassFilter::new(cutoff, cutoff_envelope, resonance, resonance_envelope, sample_rate)),
-        FilterType::Highpass => Box::new(HighpassFilter::new(cutoff, cutoff_envelope, resonance, resonance_envelope, sample_rate)),
-        FilterType::Notch => Box::new(NotchFilter::new(cutoff, cutoff_envelope, resonance, resonance_envelope, sample_rate)),
-        FilterType::Statevariable => Box::new(StatevariableFilter::new(cutoff, cutoff_envelope, resonance, resonance_envelope, sample_rate)),
-    }
-}
\ No newline at end of file
+    #[test]
+    fn test_switching_svf_modes_keeps_state() {
+        let mut filter =
+            VoiceFilter::new(FilterType::Lowpass, FilterSlope::Slope12, 1000.0, 0.5, SAMPLE_RATE);
+        for _ in 0..100 {
+            filter.process(1.0);
+        }
+        filter.set_mode(FilterType::Bandpass, FilterSlope::Slope24);
+        assert!(matches!(filter, VoiceFilter::StateVariable(_)));
+        assert_ne!(filter.process(1.0), 0.0);
+
+        filter.set_mode(FilterType::Ladder, FilterSlope::Slope24);
+        match &filter {
+            VoiceFilter::Ladder(ladder) => {
+                assert_eq!(ladder.cutoff, 1000.0);
+                assert_eq!(ladder.resonance, 0.5);
+            }
+            VoiceFilter::StateVariable(_) => panic!("The filter should have been swapped"),
+        }
+    }
+}
@@ -0,0 +1,454 @@
+use enum_iterator::Sequence;
+use nih_plug::prelude::*;
+use rand::Rng;
+use rand_pcg::Pcg32;
+
+/// The number of LFOs every voice has. There's also a single global LFO shared by all voices.
+pub const NUM_VOICE_LFOS: usize = 2;
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Enum, Sequence)]
+pub enum LfoShape {
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+    #[name = "S&H"]
+    SampleAndHold,
+}
+
+/// A tempo synced rate, expressed as a note length. Bars are assumed to be in 4/4.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Enum, Sequence)]
+pub enum SyncRate {
+    #[name = "4 Bars"]
+    FourBars,
+    #[name = "2 Bars"]
+    TwoBars,
+    #[name = "1 Bar"]
+    Bar,
+    #[name = "1/2"]
+    Half,
+    #[name = "1/4"]
+    Quarter,
+    #[name = "1/8"]
+    Eighth,
+    #[name = "1/16"]
+    Sixteenth,
+    #[name = "1/32"]
+    ThirtySecond,
+    #[name = "1/4 T"]
+    QuarterTriplet,
+    #[name = "1/8 T"]
+    EighthTriplet,
+    #[name = "1/16 T"]
+    SixteenthTriplet,
+    #[name = "1/4 D"]
+    QuarterDotted,
+    #[name = "1/8 D"]
+    EighthDotted,
+}
+
+impl SyncRate {
+    /// The note length in quarter note beats.
+    pub fn beats(self) -> f64 {
+        match self {
+            SyncRate::FourBars => 16.0,
+            SyncRate::TwoBars => 8.0,
+            SyncRate::Bar => 4.0,
+            SyncRate::Half => 2.0,
+            SyncRate::Quarter => 1.0,
+            SyncRate::Eighth => 0.5,
+            SyncRate::Sixteenth => 0.25,
+            SyncRate::ThirtySecond => 0.125,
+            SyncRate::QuarterTriplet => 2.0 / 3.0,
+            SyncRate::EighthTriplet => 1.0 / 3.0,
+            SyncRate::SixteenthTriplet => 1.0 / 6.0,
+            SyncRate::QuarterDotted => 1.5,
+            SyncRate::EighthDotted => 0.75,
+        }
+    }
+
+    /// The rate in Hertz at `tempo` beats per minute.
+    pub fn cycles_per_second(self, tempo: f64) -> f32 {
+        (tempo / 60.0 / self.beats()) as f32
+    }
+}
+
+/// The parameters for a single LFO. These are used for both the per-voice LFOs and the global LFO.
+#[derive(Params)]
+pub struct LfoParams {
+    #[id = "lfo_shape"]
+    pub shape: EnumParam<LfoShape>,
+    /// The LFO's rate in Hertz, used when the LFO is not synced to the host's tempo or when the
+    /// host doesn't provide a tempo.
+    #[id = "lfo_rate"]
+    pub rate: FloatParam,
+    #[id = "lfo_sync"]
+    pub sync: BoolParam,
+    #[id = "lfo_sync_rate"]
+    pub sync_rate: EnumParam<SyncRate>,
+    /// Restart the LFO's cycle on note-on. When this is disabled the LFO keeps running freely, and
+    /// a tempo synced LFO follows the host's playback position.
+    #[id = "lfo_retrigger"]
+    pub retrigger: BoolParam,
+    #[id = "lfo_fade"]
+    pub fade_in_ms: FloatParam,
+
+    /// The vibrato depth in semitones.
+    #[id = "lfo_pitch"]
+    pub pitch_depth: FloatParam,
+    /// How far the LFO moves the filter's cutoff, in octaves.
+    #[id = "lfo_cutoff"]
+    pub cutoff_depth: FloatParam,
+    #[id = "lfo_res"]
+    pub resonance_depth: FloatParam,
+    #[id = "lfo_pw"]
+    pub pulse_width_depth: FloatParam,
+    #[id = "lfo_pan"]
+    pub pan_depth: FloatParam,
+    /// The tremolo depth. At 100% the LFO's lowest point silences the voice.
+    #[id = "lfo_amp"]
+    pub amp_depth: FloatParam,
+}
+
+impl LfoParams {
+    /// Create the parameters for an LFO. `name` is prefixed to all parameter names.
+    pub fn new(name: &str) -> Self {
+        Self {
+            shape: EnumParam::new(format!("{name} Shape"), LfoShape::Sine),
+            rate: FloatParam::new(
+                format!("{name} Rate"),
+                2.0,
+                FloatRange::Skewed {
+                    min: 0.01,
+                    max: 50.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_unit(" Hz")
+            .with_value_to_string(formatters::v2s_f32_rounded(2)),
+            sync: BoolParam::new(format!("{name} Sync"), false),
+            sync_rate: EnumParam::new(format!("{name} Sync Rate"), SyncRate::Quarter),
+            retrigger: BoolParam::new(format!("{name} Retrigger"), true),
+            fade_in_ms: FloatParam::new(
+                format!("{name} Fade In"),
+                0.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 5000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_step_size(0.1)
+            .with_unit(" ms"),
+            pitch_depth: bipolar_depth_param(format!("{name} to Pitch"), 12.0, " st"),
+            cutoff_depth: bipolar_depth_param(format!("{name} to Cutoff"), 8.0, " oct"),
+            resonance_depth: bipolar_percentage_param(format!("{name} to Resonance")),
+            pulse_width_depth: bipolar_percentage_param(format!("{name} to Pulse Width")),
+            pan_depth: bipolar_percentage_param(format!("{name} to Pan")),
+            amp_depth: FloatParam::new(
+                format!("{name} to Amp"),
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+        }
+    }
+
+    /// The LFO's settings for the current block. `tempo` is the host's tempo in beats per minute,
+    /// if it provides one.
+    pub fn settings(&self, sample_rate: f32, tempo: Option<f64>) -> LfoSettings {
+        let mut settings = LfoSettings {
+            shape: self.shape.value(),
+            phase_delta: self.cycles_per_second(tempo) / sample_rate,
+            fade_in_delta: 1.0,
+            retrigger: self.retrigger.value(),
+            synced_beats: match (self.sync.value(), tempo) {
+                (true, Some(_)) => Some(self.sync_rate.value().beats()),
+                _ => None,
+            },
+            depths: LfoDestinations {
+                pitch: self.pitch_depth.value(),
+                cutoff: self.cutoff_depth.value(),
+                resonance: self.resonance_depth.value(),
+                pulse_width: self.pulse_width_depth.value(),
+                pan: self.pan_depth.value(),
+                amp: self.amp_depth.value(),
+            },
+        };
+        settings.set_fade_in(self.fade_in_ms.value(), sample_rate);
+
+        settings
+    }
+
+    /// The LFO's rate, falling back to the rate in Hertz if the LFO is synced but the host doesn't
+    /// provide a tempo.
+    fn cycles_per_second(&self, tempo: Option<f64>) -> f32 {
+        match (self.sync.value(), tempo) {
+            (true, Some(tempo)) => self.sync_rate.value().cycles_per_second(tempo),
+            _ => self.rate.value(),
+        }
+    }
+}
+
+fn bipolar_depth_param(name: String, range: f32, unit: &'static str) -> FloatParam {
+    FloatParam::new(
+        name,
+        0.0,
+        FloatRange::Linear {
+            min: -range,
+            max: range,
+        },
+    )
+    .with_step_size(0.01)
+    .with_unit(unit)
+}
+
+fn bipolar_percentage_param(name: String) -> FloatParam {
+    FloatParam::new(name, 0.0, FloatRange::Linear { min: -1.0, max: 1.0 })
+        .with_unit(" %")
+        .with_value_to_string(formatters::v2s_f32_percentage(0))
+        .with_string_to_value(formatters::s2v_f32_percentage())
+}
+
+/// An LFO's parameters, computed once per block.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LfoSettings {
+    pub shape: LfoShape,
+    pub phase_delta: f32,
+    /// How much the fade-in advances every sample.
+    pub fade_in_delta: f32,
+    pub retrigger: bool,
+    /// The length of the LFO's cycle in beats if it is synced to the host's tempo.
+    pub synced_beats: Option<f64>,
+    pub depths: LfoDestinations,
+}
+
+impl LfoSettings {
+    /// Change the LFO's rate in Hertz. This does nothing if the LFO is synced to the host's tempo.
+    pub fn set_rate(&mut self, rate: f32, sample_rate: f32) {
+        if self.synced_beats.is_none() {
+            self.phase_delta = rate / sample_rate;
+        }
+    }
+
+    pub fn set_fade_in(&mut self, fade_in_ms: f32, sample_rate: f32) {
+        let fade_in_samples = fade_in_ms / 1000.0 * sample_rate;
+        self.fade_in_delta = if fade_in_samples > 1.0 {
+            1.0 / fade_in_samples
+        } else {
+            1.0
+        };
+    }
+
+    /// The phase a free running tempo synced LFO should have at the host's playback position
+    /// `pos_beats`. Returns `None` if the LFO isn't synced.
+    pub fn transport_phase(&self, pos_beats: f64) -> Option<f32> {
+        self.synced_beats
+            .map(|beats| (pos_beats / beats).rem_euclid(1.0) as f32)
+    }
+}
+
+/// The modulation an LFO applies to each of its destinations. This is used both for an LFO's
+/// depths and for the sum of all LFOs' modulation for a single sample.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LfoDestinations {
+    /// Pitch modulation in semitones.
+    pub pitch: f32,
+    /// Cutoff modulation in octaves.
+    pub cutoff: f32,
+    pub resonance: f32,
+    pub pulse_width: f32,
+    pub pan: f32,
+    /// For the depths this is the tremolo depth. For the summed modulation this is the amount the
+    /// amplitude should be reduced by, see [`LfoDestinations::amp_gain()`].
+    pub amp: f32,
+}
+
+impl LfoDestinations {
+    /// Add an LFO's `value` in `[-1, 1]` scaled by its `depths` to the modulation.
+    pub fn accumulate(&mut self, value: f32, depths: &LfoDestinations) {
+        self.pitch += value * depths.pitch;
+        self.cutoff += value * depths.cutoff;
+        self.resonance += value * depths.resonance;
+        self.pulse_width += value * depths.pulse_width;
+        self.pan += value * depths.pan;
+        // Tremolo only ever turns the voice down, with the LFO's peak leaving the gain untouched
+        self.amp += (1.0 - value) * 0.5 * depths.amp;
+    }
+
+    /// The gain resulting from the accumulated tremolo.
+    pub fn amp_gain(&self) -> f32 {
+        (1.0 - self.amp).max(0.0)
+    }
+}
+
+/// A single LFO's state.
+#[derive(Debug, Clone)]
+pub struct Lfo {
+    phase: f32,
+    /// The current sample-and-hold value. A new value is picked every time the phase wraps around.
+    held_value: f32,
+    /// The fade-in's progress in `[0, 1]`.
+    fade: f32,
+    /// The most recently produced value.
+    value: f32,
+    prng: Pcg32,
+}
+
+impl Lfo {
+    /// Create an LFO. `seed` seeds the sample-and-hold shape's random number generator.
+    pub fn new(seed: u64) -> Self {
+        let mut prng = Pcg32::new(seed, 1442695040888963407);
+        let held_value = prng.gen_range(-1.0..1.0);
+
+        Lfo {
+            phase: 0.0,
+            held_value,
+            fade: 1.0,
+            value: 0.0,
+            prng,
+        }
+    }
+
+    /// The value produced by the last call to [`Lfo::next_value()`].
+    pub fn previous_value(&self) -> f32 {
+        self.value
+    }
+
+    /// Jump to `phase` without affecting the fade-in.
+    pub fn set_phase(&mut self, phase: f32) {
+        self.phase = phase;
+    }
+
+    /// Start the LFO at `phase` and restart the fade-in.
+    pub fn trigger(&mut self, phase: f32) {
+        self.phase = phase;
+        self.fade = 0.0;
+        self.held_value = self.prng.gen_range(-1.0..1.0);
+    }
+
+    /// Compute the LFO's current value in `[-1, 1]` and advance it by one sample.
+    pub fn next_value(&mut self, settings: &LfoSettings) -> f32 {
+        let value = match settings.shape {
+            LfoShape::Sine => (self.phase * std::f32::consts::TAU).sin(),
+            // The triangle starts at zero like the sine wave
+            LfoShape::Triangle => 1.0 - 4.0 * ((self.phase + 0.25).fract() - 0.5).abs(),
+            LfoShape::Saw => 2.0 * self.phase - 1.0,
+            LfoShape::Square => {
+                if self.phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            LfoShape::SampleAndHold => self.held_value,
+        } * self.fade;
+
+        self.phase += settings.phase_delta;
+        if self.phase >= 1.0 {
+            self.phase -= self.phase.floor();
+            self.held_value = self.prng.gen_range(-1.0..1.0);
+        }
+        self.fade = (self.fade + settings.fade_in_delta).min(1.0);
+        self.value = value;
+
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(shape: LfoShape, phase_delta: f32, fade_in_delta: f32) -> LfoSettings {
+        LfoSettings {
+            shape,
+            phase_delta,
+            fade_in_delta,
+            retrigger: true,
+            synced_beats: None,
+            depths: LfoDestinations::default(),
+        }
+    }
+
+    #[test]
+    fn test_shapes() {
+        let quarter_points = |shape| {
+            let settings = settings(shape, 0.25, 1.0);
+            let mut lfo = Lfo::new(0);
+            [(); 4].map(|_| lfo.next_value(&settings))
+        };
+
+        let sine = quarter_points(LfoShape::Sine);
+        approx::assert_relative_eq!(sine[0], 0.0);
+        approx::assert_relative_eq!(sine[1], 1.0);
+        approx::assert_relative_eq!(sine[3], -1.0);
+        assert_eq!(quarter_points(LfoShape::Triangle), [0.0, 1.0, 0.0, -1.0]);
+        assert_eq!(quarter_points(LfoShape::Saw), [-1.0, -0.5, 0.0, 0.5]);
+        assert_eq!(quarter_points(LfoShape::Square), [1.0, 1.0, -1.0, -1.0]);
+    }
+
+    #[test]
+    fn test_sample_and_hold_changes_once_per_cycle() {
+        let settings = settings(LfoShape::SampleAndHold, 0.1, 1.0);
+        let mut lfo = Lfo::new(1234);
+        let values: Vec<f32> = (0..30).map(|_| lfo.next_value(&settings)).collect();
+        for cycle in values.chunks(10) {
+            assert!(cycle.iter().all(|value| *value == cycle[0]));
+            assert!((-1.0..=1.0).contains(&cycle[0]));
+        }
+        assert_ne!(values[0], values[10]);
+        assert_ne!(values[10], values[20]);
+    }
+
+    #[test]
+    fn test_fade_in() {
+        let settings = settings(LfoShape::Square, 0.001, 0.01);
+        let mut lfo = Lfo::new(0);
+        lfo.trigger(0.0);
+
+        assert_eq!(lfo.next_value(&settings), 0.0);
+        for _ in 0..49 {
+            lfo.next_value(&settings);
+        }
+        approx::assert_relative_eq!(lfo.next_value(&settings), 0.5, epsilon = 1.0e-4);
+        for _ in 0..100 {
+            lfo.next_value(&settings);
+        }
+        assert_eq!(lfo.next_value(&settings), 1.0);
+    }
+
+    #[test]
+    fn test_tempo_sync() {
+        // Eighth notes at 120 BPM are four cycles per second
+        approx::assert_relative_eq!(SyncRate::Eighth.cycles_per_second(120.0), 4.0);
+        approx::assert_relative_eq!(SyncRate::EighthTriplet.cycles_per_second(120.0), 6.0);
+        approx::assert_relative_eq!(SyncRate::Bar.cycles_per_second(120.0), 0.5);
+
+        let mut settings = settings(LfoShape::Sine, 0.0, 1.0);
+        settings.synced_beats = Some(SyncRate::Eighth.beats());
+        approx::assert_relative_eq!(settings.transport_phase(10.25).unwrap(), 0.5);
+        approx::assert_relative_eq!(settings.transport_phase(-0.125).unwrap(), 0.75);
+
+        // LFOs that aren't synced ignore the host's tempo
+        let params = LfoParams::new("LFO");
+        let settings = params.settings(48000.0, Some(120.0));
+        approx::assert_relative_eq!(settings.phase_delta, params.rate.value() / 48000.0);
+        assert_eq!(settings.transport_phase(10.25), None);
+    }
+
+    #[test]
+    fn test_tremolo_only_turns_down() {
+        let depths = LfoDestinations {
+            amp: 1.0,
+            ..LfoDestinations::default()
+        };
+        for (value, expected_gain) in [(1.0, 1.0), (0.0, 0.5), (-1.0, 0.0)] {
+            let mut modulation = LfoDestinations::default();
+            modulation.accumulate(value, &depths);
+            approx::assert_relative_eq!(modulation.amp_gain(), expected_gain);
+        }
+    }
+}
@@ -1,73 +1,355 @@
 mod waveform;
 mod editor;
+mod envelope;
 mod filter;
+mod drive;
+mod unison;
+mod lfo;
+mod mod_matrix;
+mod mpe;
+mod voice_mode;
+mod pedals;
+mod arp;
+mod sequencer;
+mod tuning;
+mod presets;
+mod effects;
+mod distortion;
+mod chorus;
+mod delay;
+mod reverb;
+mod wavetable;
+mod noise;
+mod analog;
+mod scaling;
 
 use nih_plug::prelude::*;
 use rand::Rng;
 use rand_pcg::Pcg32;
-use std::sync::Arc;
-use waveform::Waveform;
-use waveform::generate_waveform;
-use filter::{NotchFilter, BandpassFilter, HighpassFilter, LowpassFilter, StatevariableFilter};
-use filter::{Filter, FilterType, FilterFactory, Envelope, ADSREnvelope, ADSREnvelopeState};
-
-use filter::generate_filter;
+use std::sync::{Arc, RwLock};
+use waveform::{PwmSource, SubOctave, Waveform};
+use waveform::{advance_phase, generate_waveform};
+use envelope::{ADSREnvelope, ADSREnvelopeState, Envelope, EnvelopeCurve, EnvelopeSettings};
+use filter::{Filter, FilterSlope, FilterType, VoiceFilter};
+use drive::DriveCurve;
+use unison::{UnisonLayout, MAX_UNISON_VOICES};
+use lfo::{Lfo, LfoDestinations, LfoParams, LfoSettings, NUM_VOICE_LFOS};
+use mod_matrix::{modulate, ModDestination, ModOffsets, ModSlot, ModSlotParams, ModSourceValues};
+use mod_matrix::{VoiceModulation, LFO_MOD_DESTINATIONS, MOD_PITCH_RANGE, NUM_MOD_SLOTS};
+use mpe::{
+    MpeMode, MpeParams, PitchBend, VoiceExpression, BRIGHTNESS_CC, MAX_EXPRESSION_VIBRATO,
+};
+use voice_mode::{Glide, HeldNote, HeldNotes, StealCandidate, VoiceMode, VoiceModeParams};
+use voice_mode::{MAX_POLYPHONY, STEAL_FADE_MS};
+use pedals::{NoteSet, Pedals};
+use arp::{ArpEvent, ArpParams, ArpSettings, Arpeggiator};
+use sequencer::{Sequence, Sequencer, SequencerEvent, SequencerParams, SequencerSettings};
+use sequencer::SEQUENCER_CHANNEL;
+use tuning::{Tuning, TuningTable, DEFAULT_REFERENCE_FREQUENCY};
+use effects::{effect_order, EffectKind, Effects, EffectsSettings, NUM_EFFECTS};
+use distortion::DistortionParams;
+use chorus::ChorusParams;
+use delay::DelayParams;
+use reverb::ReverbParams;
+use wavetable::{Wavetable, WavetableExchange, WavetableSource};
+use noise::{Noise, NoiseColor};
+use analog::{AnalogParams, VoiceCard};
+use scaling::ScalingParams;
 
 use nih_plug_iced::IcedState;
 use nih_plug::params::enums::EnumParam;
 
-const NUM_VOICES: u32 = 16;
+/// The number of voice slots. A note's unison stack lives inside of a single voice. Next to the
+/// voices allowed by the polyphony parameter there is room for stolen voices that are still fading
+/// out.
+const NUM_VOICE_SLOTS: usize = MAX_POLYPHONY as usize + 8;
 const MAX_BLOCK_SIZE: usize = 64;
 const GAIN_POLY_MOD_ID: u32 = 0;
+const PULSE_WIDTH_POLY_MOD_ID: u32 = 1;
+const FM_AMOUNT_POLY_MOD_ID: u32 = 2;
+const FILTER_ENV_AMOUNT_POLY_MOD_ID: u32 = 3;
+const FILTER_KEYTRACK_POLY_MOD_ID: u32 = 4;
+const FILTER_VELOCITY_POLY_MOD_ID: u32 = 5;
+const FILTER_DRIVE_POLY_MOD_ID: u32 = 6;
+const FILTER_CUTOFF_POLY_MOD_ID: u32 = 7;
+const FILTER_RESONANCE_POLY_MOD_ID: u32 = 8;
+const UNISON_DETUNE_POLY_MOD_ID: u32 = 9;
+const OSC2_FINE_POLY_MOD_ID: u32 = 10;
+const PAN_POLY_MOD_ID: u32 = 11;
+const AMP_ATTACK_POLY_MOD_ID: u32 = 12;
+const AMP_DECAY_POLY_MOD_ID: u32 = 13;
+const AMP_RELEASE_POLY_MOD_ID: u32 = 14;
+const FILTER_CUT_ATTACK_POLY_MOD_ID: u32 = 15;
+const FILTER_CUT_DECAY_POLY_MOD_ID: u32 = 16;
+const FILTER_CUT_RELEASE_POLY_MOD_ID: u32 = 17;
+const FILTER_RES_ATTACK_POLY_MOD_ID: u32 = 18;
+const FILTER_RES_DECAY_POLY_MOD_ID: u32 = 19;
+const FILTER_RES_RELEASE_POLY_MOD_ID: u32 = 20;
+const OSC1_LEVEL_POLY_MOD_ID: u32 = 21;
+const OSC2_LEVEL_POLY_MOD_ID: u32 = 22;
+const SUB_LEVEL_POLY_MOD_ID: u32 = 23;
+const NOISE_LEVEL_POLY_MOD_ID: u32 = 24;
+const RING_LEVEL_POLY_MOD_ID: u32 = 25;
+/// The maximum amount the PWM source can move the pulse width away from the `pulse_width`
+/// parameter's value at 100% depth.
+const MAX_PWM_EXCURSION: f32 = 0.45;
+/// The linear FM index at 100% FM amount. Anything above 1 drives the first oscillator's frequency
+/// through zero.
+const MAX_FM_INDEX: f32 = 4.0;
+/// How many octaves the cutoff drops for a note with zero velocity at 100% velocity sensitivity.
+const MAX_VELOCITY_CUTOFF_OCTAVES: f32 = 4.0;
+/// The note the filter's keyboard tracking and the key modulation source are centered around, C4.
+const KEYTRACK_CENTER_NOTE: f32 = 60.0;
+
+/// The work SubSynth does on a background thread.
+enum Task {
+    /// Build the mipmapped wavetable from the frames stored in the parameters.
+    BuildWavetable,
+}
 
 struct SubSynth {
     params: Arc<SubSynthParams>,
     prng: Pcg32,
-    voices: [Option<Voice>; NUM_VOICES as usize],
+    voices: [Option<Voice>; NUM_VOICE_SLOTS],
+    /// Every voice slot's fixed analog variations. These are drawn from `prng` when the plugin is
+    /// reset, so a slot sounds the same every time.
+    voice_cards: [VoiceCard; NUM_VOICE_SLOTS],
     next_internal_voice_id: u64,
-    
+    /// The LFO shared by all voices.
+    global_lfo: Lfo,
+    /// The phases the per-voice LFOs would have if they were never retriggered. Voice LFOs that
+    /// don't retrigger follow these so all voices' LFOs stay in phase.
+    free_running_lfo_phases: [f32; NUM_VOICE_LFOS],
+    /// The mod wheel's position in `[0, 1]`.
+    mod_wheel: f32,
+    pitch_bend: PitchBend,
+    /// The last channel pressure received on each MIDI channel. New notes start with their
+    /// channel's pressure.
+    channel_pressure: [f32; 16],
+    /// The last CC 74 value received on each MIDI channel, used as the brightness expression.
+    channel_brightness: [f32; 16],
+    /// The notes that are currently held down, used for note priority in the monophonic voice
+    /// modes and to decide whether a note was played legato.
+    held_notes: HeldNotes,
+    /// The sustain, sostenuto and soft pedals, and the notes they're holding.
+    pedals: Pedals,
+    arp: Arpeggiator,
+    /// The arpeggiator's notes that were sent to the MIDI output and haven't been released there
+    /// yet. These always get a note off, even if the output has been disabled in the meantime.
+    arp_midi_notes: NoteSet,
+    sequencer: Sequencer,
+    /// The loaded tuning's pitches, copied from the parameters at the start of every buffer.
+    tuning: TuningTable,
+    /// The effects chain the voices' output runs through.
+    effects: Effects,
+    /// The wavetable played by [`Waveform::Wavetable`]. This is built on a background thread and
+    /// picked up from `wavetables` at the start of every buffer.
+    wavetable: Box<Wavetable>,
+    wavetables: Arc<WavetableExchange>,
+    /// The most recently played note. Glides start from this note.
+    last_note: Option<u8>,
+    /// The voice capacity last reported to the host. This follows the polyphony and voice mode
+    /// parameters.
+    voice_capacity: u32,
+    /// The current sample rate, used to set up the voices' filters and envelopes when they are
+    /// created.
+    sample_rate: f32,
 }
 
 #[derive(Params)]
 struct SubSynthParams {
     #[persist = "editor-state"]
     editor_state: Arc<IcedState>,
+    /// The step sequencer's steps. These are edited in the editor's sequencer grid.
+    #[persist = "sequence"]
+    sequence: RwLock<Sequence>,
+    /// The Scala scale and keyboard mapping notes are tuned to. These are loaded in the editor.
+    #[persist = "tuning"]
+    tuning: RwLock<Tuning>,
+    /// The frequency of the tuning's reference note. Loading a keyboard mapping sets this to the
+    /// mapping's reference frequency.
+    #[id = "ref_freq"]
+    reference_frequency: FloatParam,
+    /// The frames for the wavetable oscillator. These are imported in the editor.
+    #[persist = "wavetable"]
+    wavetable: RwLock<WavetableSource>,
     #[id = "gain"]
     gain: FloatParam,
+    /// The voice's position in the stereo field. This can be polyphonically modulated.
+    #[id = "pan"]
+    pan: FloatParam,
     #[id = "amp_atk"]
     amp_attack_ms: FloatParam,
     #[id = "amp_rel"]
     amp_release_ms: FloatParam,
     #[id = "waveform"]
     waveform: EnumParam<Waveform>,
+    /// Morphs between the wavetable's frames.
+    #[id = "wt_pos"]
+    wavetable_position: FloatParam,
+    /// The square wave's duty cycle. The pulse wave uses half of this. This can be polyphonically
+    /// modulated.
+    #[id = "pulse_width"]
+    pulse_width: FloatParam,
+    #[id = "pwm_src"]
+    pwm_source: EnumParam<PwmSource>,
+    #[id = "pwm_depth"]
+    pwm_depth: FloatParam,
+    #[id = "pwm_rate"]
+    pwm_rate: FloatParam,
+    #[id = "pwm_env_atk"]
+    pwm_env_attack_ms: FloatParam,
+    #[id = "pwm_env_dec"]
+    pwm_env_decay_ms: FloatParam,
+
+    // The second oscillator, the sub-oscillator and the mixer in front of the filter
+    #[id = "osc2_waveform"]
+    osc2_waveform: EnumParam<Waveform>,
+    /// The second oscillator's tuning relative to the first one, in semitones.
+    #[id = "osc2_coarse"]
+    osc2_coarse: IntParam,
+    /// The second oscillator's fine tuning in cents.
+    #[id = "osc2_fine"]
+    osc2_fine: FloatParam,
+    #[id = "sub_waveform"]
+    sub_waveform: EnumParam<Waveform>,
+    #[id = "sub_octave"]
+    sub_octave: EnumParam<SubOctave>,
+    #[id = "osc1_level"]
+    osc1_level: FloatParam,
+    #[id = "osc2_level"]
+    osc2_level: FloatParam,
+    #[id = "sub_level"]
+    sub_level: FloatParam,
+    #[id = "noise_level"]
+    noise_level: FloatParam,
+    /// The color of both the noise source and the oscillators' noise waveform.
+    #[id = "noise_color"]
+    noise_color: EnumParam<NoiseColor>,
+    /// Restart the second oscillator's cycle every time the first oscillator's cycle restarts.
+    #[id = "osc2_sync"]
+    osc2_sync: BoolParam,
+    /// The level of the first and second oscillators multiplied together.
+    #[id = "ring_level"]
+    ring_level: FloatParam,
+    /// How much the second oscillator linearly modulates the first oscillator's frequency. This can
+    /// be polyphonically modulated.
+    #[id = "fm_amount"]
+    fm_amount: FloatParam,
+
+    /// The number of stacked first and second oscillators per note.
+    #[id = "unison_voices"]
+    unison_voices: IntParam,
+    /// The detuning of the outermost unison oscillators, in cents.
+    #[id = "unison_detune"]
+    unison_detune: FloatParam,
+    #[id = "unison_spread"]
+    unison_spread: FloatParam,
+    /// The level of the outer unison oscillators relative to the center ones.
+    #[id = "unison_blend"]
+    unison_blend: FloatParam,
 
     // New parameters for ADSR envelope
     #[id = "amp_dec"]
     amp_decay_ms: FloatParam,
     #[id = "amp_sus"]
     amp_sustain_level: FloatParam,
+    #[id = "amp_atk_crv"]
+    amp_attack_curve: EnumParam<EnvelopeCurve>,
+    #[id = "amp_dec_crv"]
+    amp_decay_curve: EnumParam<EnvelopeCurve>,
+    #[id = "amp_rel_crv"]
+    amp_release_curve: EnumParam<EnvelopeCurve>,
+    #[id = "amp_tension"]
+    amp_tension: FloatParam,
     #[id = "filter_cut_atk"]
     filter_cut_attack_ms: FloatParam,
     #[id = "filter_cut_dec"]
     filter_cut_decay_ms: FloatParam,
     #[id = "filter_cut_sus"]
-    filter_cut_sustain_ms: FloatParam,
+    filter_cut_sustain_level: FloatParam,
     #[id = "filter_cut_rel"]
     filter_cut_release_ms: FloatParam,
+    #[id = "filter_cut_atk_crv"]
+    filter_cut_attack_curve: EnumParam<EnvelopeCurve>,
+    #[id = "filter_cut_dec_crv"]
+    filter_cut_decay_curve: EnumParam<EnvelopeCurve>,
+    #[id = "filter_cut_rel_crv"]
+    filter_cut_release_curve: EnumParam<EnvelopeCurve>,
+    #[id = "filter_cut_tension"]
+    filter_cut_tension: FloatParam,
     #[id = "filter_res_atk"]
     filter_res_attack_ms: FloatParam,
     #[id = "filter_res_dec"]
     filter_res_decay_ms: FloatParam,
     #[id = "filter_res_sus"]
-    filter_res_sustain_ms: FloatParam,
+    filter_res_sustain_level: FloatParam,
     #[id = "filter_res_rel"]
     filter_res_release_ms: FloatParam,
+    #[id = "filter_res_atk_crv"]
+    filter_res_attack_curve: EnumParam<EnvelopeCurve>,
+    #[id = "filter_res_dec_crv"]
+    filter_res_decay_curve: EnumParam<EnvelopeCurve>,
+    #[id = "filter_res_rel_crv"]
+    filter_res_release_curve: EnumParam<EnvelopeCurve>,
+    #[id = "filter_res_tension"]
+    filter_res_tension: FloatParam,
     #[id = "filter_type"]
     filter_type: EnumParam<FilterType>,
+    #[id = "filter_slope"]
+    filter_slope: EnumParam<FilterSlope>,
     #[id = "filter_cut"]
     filter_cut: FloatParam,
     #[id = "filter_res"]
     filter_res: FloatParam,
+    /// How far the cutoff envelope moves the cutoff frequency, in octaves. This can be
+    /// polyphonically modulated.
+    #[id = "filter_env_amt"]
+    filter_env_amount: FloatParam,
+    /// How much the cutoff follows the note's pitch. At 100% the cutoff moves up an octave with
+    /// every octave above C4. This can be polyphonically modulated.
+    #[id = "filter_keytrack"]
+    filter_keytrack: FloatParam,
+    /// How much lower velocities close the filter. This can be polyphonically modulated.
+    #[id = "filter_vel"]
+    filter_velocity: FloatParam,
+    /// The gain going into the drive stage in front of the filter. This can be polyphonically
+    /// modulated.
+    #[id = "filter_drive"]
+    filter_drive: FloatParam,
+    #[id = "filter_drive_crv"]
+    filter_drive_curve: EnumParam<DriveCurve>,
+
+    /// Every voice has its own copy of these LFOs.
+    #[nested(array, group = "LFOs")]
+    lfos: [LfoParams; NUM_VOICE_LFOS],
+    #[nested(id_prefix = "global_lfo", group = "Global LFO")]
+    global_lfo: LfoParams,
+
+    #[nested(array, group = "Mod Matrix")]
+    mod_slots: [ModSlotParams; NUM_MOD_SLOTS],
+
+    #[nested(group = "MPE")]
+    mpe: MpeParams,
+    #[nested(group = "Voicing")]
+    voice_mode: VoiceModeParams,
+    #[nested(group = "Arpeggiator")]
+    arp: ArpParams,
+    #[nested(group = "Sequencer")]
+    sequencer: SequencerParams,
+    #[nested(group = "Analog")]
+    analog: AnalogParams,
+    #[nested(group = "Scaling")]
+    scaling: ScalingParams,
+
+    #[nested(group = "Distortion")]
+    distortion: DistortionParams,
+    #[nested(group = "Chorus")]
+    chorus: ChorusParams,
+    #[nested(group = "Delay")]
+    delay: DelayParams,
+    #[nested(group = "Reverb")]
+    reverb: ReverbParams,
 }
 
 #[derive(Debug, Clone)]
@@ -75,29 +357,196 @@ struct Voice {
     voice_id: i32,
     channel: u8,
     note: u8,
+    /// Whether the step sequencer is playing this voice. Sequencer voices are never matched by
+    /// their channel, so they can't be mixed up with keys or channel messages on that channel.
+    sequenced: bool,
     internal_voice_id: u64,
-    velocity_sqrt: f32,
-    phase: f32,
-    phase_delta: f32,
+    velocity: f32,
+    /// The velocity after the velocity curve has been applied.
+    velocity_response: f32,
+    /// The note's pressure in `[0, 1]`, from either polyphonic or channel pressure.
+    pressure: f32,
+    /// A random value in `[-1, 1]` picked at note-on, used as a modulation source.
+    random: f32,
+    /// The phases of the first oscillator's unison stack. All of these are initialized with random
+    /// phases at note-on, even the ones that are not currently used.
+    phases: [f32; MAX_UNISON_VOICES],
+    /// The second oscillator's phases. Its phase deltas are derived from the note's frequency and
+    /// the second oscillator's tuning.
+    osc2_phases: [f32; MAX_UNISON_VOICES],
+    sub_phase: f32,
     releasing: bool,
     amp_envelope: ADSREnvelope,
     voice_gain: Option<(f32, Smoother<f32>)>,
-    filter_cut_envelope: Smoother<f32>,
-    filter_res_envelope: Smoother<f32>,
-    filter: Option<FilterType>,
-
+    /// If this voice has polyphonic pulse width modulation applied, then this contains the
+    /// normalized offset and a smoother.
+    voice_pulse_width: Option<(f32, Smoother<f32>)>,
+    /// If this voice has polyphonic FM amount modulation applied, then this contains the
+    /// normalized offset and a smoother.
+    voice_fm_amount: Option<(f32, Smoother<f32>)>,
+    voice_filter_env_amount: Option<(f32, Smoother<f32>)>,
+    voice_filter_keytrack: Option<(f32, Smoother<f32>)>,
+    voice_filter_velocity: Option<(f32, Smoother<f32>)>,
+    voice_filter_drive: Option<(f32, Smoother<f32>)>,
+    voice_filter_cutoff: Option<(f32, Smoother<f32>)>,
+    voice_filter_resonance: Option<(f32, Smoother<f32>)>,
+    voice_unison_detune: Option<(f32, Smoother<f32>)>,
+    voice_osc2_fine: Option<(f32, Smoother<f32>)>,
+    voice_pan: Option<(f32, Smoother<f32>)>,
+    voice_amp_attack: Option<(f32, Smoother<f32>)>,
+    voice_amp_decay: Option<(f32, Smoother<f32>)>,
+    voice_amp_release: Option<(f32, Smoother<f32>)>,
+    voice_filter_cut_attack: Option<(f32, Smoother<f32>)>,
+    voice_filter_cut_decay: Option<(f32, Smoother<f32>)>,
+    voice_filter_cut_release: Option<(f32, Smoother<f32>)>,
+    voice_filter_res_attack: Option<(f32, Smoother<f32>)>,
+    voice_filter_res_decay: Option<(f32, Smoother<f32>)>,
+    voice_filter_res_release: Option<(f32, Smoother<f32>)>,
+    voice_osc1_level: Option<(f32, Smoother<f32>)>,
+    voice_osc2_level: Option<(f32, Smoother<f32>)>,
+    voice_sub_level: Option<(f32, Smoother<f32>)>,
+    voice_noise_level: Option<(f32, Smoother<f32>)>,
+    voice_ring_level: Option<(f32, Smoother<f32>)>,
+    /// The phase of the voice's PWM LFO, restarted at every note-on.
+    pwm_lfo_phase: f32,
+    pwm_envelope: ADSREnvelope,
+    /// The cutoff and resonance envelopes live as long as the voice so they keep advancing between
+    /// blocks instead of being recreated for every sample.
+    filter_cut_envelope: ADSREnvelope,
+    filter_res_envelope: ADSREnvelope,
+    /// The voice's own filters, one per output channel so the unison stack can be spread across
+    /// the stereo field. These keep their history for the entire duration of the note.
+    filters: [VoiceFilter; 2],
+    lfos: [Lfo; NUM_VOICE_LFOS],
+    /// The noise source's state. Every voice is seeded separately so renders are repeatable.
+    noise: Noise,
+    modulation: VoiceModulation,
+    expression: VoiceExpression,
+    glide: Glide,
+    /// If this voice has been stolen, then this contains the remaining gain of its fade-out. Stolen
+    /// voices have already been terminated as far as the host is concerned, so they no longer
+    /// respond to note events.
+    steal_fade: Option<f32>,
 }
 
+impl Voice {
+    /// The voice's polyphonic modulation state for a poly modulation ID. Returns `None` if the ID
+    /// is unknown.
+    fn poly_modulation_mut(
+        &mut self,
+        poly_modulation_id: u32,
+    ) -> Option<&mut Option<(f32, Smoother<f32>)>> {
+        match poly_modulation_id {
+            GAIN_POLY_MOD_ID => Some(&mut self.voice_gain),
+            PULSE_WIDTH_POLY_MOD_ID => Some(&mut self.voice_pulse_width),
+            FM_AMOUNT_POLY_MOD_ID => Some(&mut self.voice_fm_amount),
+            FILTER_ENV_AMOUNT_POLY_MOD_ID => Some(&mut self.voice_filter_env_amount),
+            FILTER_KEYTRACK_POLY_MOD_ID => Some(&mut self.voice_filter_keytrack),
+            FILTER_VELOCITY_POLY_MOD_ID => Some(&mut self.voice_filter_velocity),
+            FILTER_DRIVE_POLY_MOD_ID => Some(&mut self.voice_filter_drive),
+            FILTER_CUTOFF_POLY_MOD_ID => Some(&mut self.voice_filter_cutoff),
+            FILTER_RESONANCE_POLY_MOD_ID => Some(&mut self.voice_filter_resonance),
+            UNISON_DETUNE_POLY_MOD_ID => Some(&mut self.voice_unison_detune),
+            OSC2_FINE_POLY_MOD_ID => Some(&mut self.voice_osc2_fine),
+            PAN_POLY_MOD_ID => Some(&mut self.voice_pan),
+            AMP_ATTACK_POLY_MOD_ID => Some(&mut self.voice_amp_attack),
+            AMP_DECAY_POLY_MOD_ID => Some(&mut self.voice_amp_decay),
+            AMP_RELEASE_POLY_MOD_ID => Some(&mut self.voice_amp_release),
+            FILTER_CUT_ATTACK_POLY_MOD_ID => Some(&mut self.voice_filter_cut_attack),
+            FILTER_CUT_DECAY_POLY_MOD_ID => Some(&mut self.voice_filter_cut_decay),
+            FILTER_CUT_RELEASE_POLY_MOD_ID => Some(&mut self.voice_filter_cut_release),
+            FILTER_RES_ATTACK_POLY_MOD_ID => Some(&mut self.voice_filter_res_attack),
+            FILTER_RES_DECAY_POLY_MOD_ID => Some(&mut self.voice_filter_res_decay),
+            FILTER_RES_RELEASE_POLY_MOD_ID => Some(&mut self.voice_filter_res_release),
+            OSC1_LEVEL_POLY_MOD_ID => Some(&mut self.voice_osc1_level),
+            OSC2_LEVEL_POLY_MOD_ID => Some(&mut self.voice_osc2_level),
+            SUB_LEVEL_POLY_MOD_ID => Some(&mut self.voice_sub_level),
+            NOISE_LEVEL_POLY_MOD_ID => Some(&mut self.voice_noise_level),
+            RING_LEVEL_POLY_MOD_ID => Some(&mut self.voice_ring_level),
+            _ => None,
+        }
+    }
+
+    fn is_stolen(&self) -> bool {
+        self.steal_fade.is_some()
+    }
+
+    /// The gain for the next sample of a stolen voice's fade-out, or 1.0 if the voice has not been
+    /// stolen.
+    fn next_steal_fade_gain(&mut self, fade_delta: f32) -> f32 {
+        match &mut self.steal_fade {
+            Some(gain) => {
+                *gain = (*gain - fade_delta).max(0.0);
+                *gain
+            }
+            None => 1.0,
+        }
+    }
+
+    /// Start the voice's envelopes from their current levels with a new velocity and that
+    /// velocity's response.
+    fn trigger(&mut self, velocity: f32, velocity_response: f32) {
+        self.velocity = velocity;
+        self.velocity_response = velocity_response;
+        self.releasing = false;
+        self.amp_envelope.trigger();
+        self.filter_cut_envelope.trigger();
+        self.filter_res_envelope.trigger();
+        self.pwm_envelope.trigger();
+    }
+
+    /// The voice's polyphonic modulation state for a parameter that is only read once per block.
+    /// Returns `None` for destinations whose parameters are either read per sample or can't be
+    /// polyphonically modulated.
+    fn block_poly_modulation(&self, destination: ModDestination) -> &Option<(f32, Smoother<f32>)> {
+        match destination {
+            ModDestination::Osc2Fine => &self.voice_osc2_fine,
+            ModDestination::UnisonDetune => &self.voice_unison_detune,
+            ModDestination::AmpAttack => &self.voice_amp_attack,
+            ModDestination::AmpDecay => &self.voice_amp_decay,
+            ModDestination::AmpRelease => &self.voice_amp_release,
+            ModDestination::FilterCutAttack => &self.voice_filter_cut_attack,
+            ModDestination::FilterCutDecay => &self.voice_filter_cut_decay,
+            ModDestination::FilterCutRelease => &self.voice_filter_cut_release,
+            ModDestination::FilterResAttack => &self.voice_filter_res_attack,
+            ModDestination::FilterResDecay => &self.voice_filter_res_decay,
+            ModDestination::FilterResRelease => &self.voice_filter_res_release,
+            _ => &None,
+        }
+    }
+}
 
 impl Default for SubSynth {
     fn default() -> Self {
+        let mut prng = Pcg32::new(420, 1337);
+        let voice_cards = std::array::from_fn(|_| VoiceCard::new(&mut prng));
+
         Self {
             
             params: Arc::new(SubSynthParams::default()),
 
-            prng: Pcg32::new(420, 1337),
-            voices: [0; NUM_VOICES as usize].map(|_| None),
+            prng,
+            voices: [0; NUM_VOICE_SLOTS].map(|_| None),
+            voice_cards,
             next_internal_voice_id: 0,
+            global_lfo: Lfo::new(0),
+            free_running_lfo_phases: [0.0; NUM_VOICE_LFOS],
+            mod_wheel: 0.0,
+            pitch_bend: PitchBend::default(),
+            channel_pressure: [0.0; 16],
+            channel_brightness: [0.0; 16],
+            held_notes: HeldNotes::default(),
+            pedals: Pedals::default(),
+            arp: Arpeggiator::default(),
+            arp_midi_notes: NoteSet::default(),
+            sequencer: Sequencer::default(),
+            tuning: TuningTable::default(),
+            effects: Effects::new(44100.0),
+            wavetable: Box::default(),
+            wavetables: Arc::new(WavetableExchange::default()),
+            last_note: None,
+            voice_capacity: 0,
+            sample_rate: 44100.0,
         }
     }
 }
@@ -106,6 +555,20 @@ impl Default for SubSynthParams {
     fn default() -> Self {
         Self {
             editor_state: editor::default_state(),
+            sequence: RwLock::new(Sequence::default()),
+            tuning: RwLock::new(Tuning::default()),
+            reference_frequency: FloatParam::new(
+                "Reference Frequency",
+                DEFAULT_REFERENCE_FREQUENCY,
+                FloatRange::Skewed {
+                    min: 100.0,
+                    max: 1000.0,
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_unit(" Hz")
+            .with_value_to_string(formatters::v2s_f32_rounded(2)),
+            wavetable: RwLock::new(WavetableSource::default()),
             gain: FloatParam::new(
                 "Gain",
                 util::db_to_gain(-12.0),
@@ -119,52 +582,137 @@ impl Default for SubSynthParams {
             .with_unit(" dB")
             .with_value_to_string(formatters::v2s_f32_gain_to_db(2))
             .with_string_to_value(formatters::s2v_f32_gain_to_db()),
-            amp_attack_ms: FloatParam::new(
-                "Attack",
+            pan: FloatParam::new("Pan", 0.0, FloatRange::Linear { min: -1.0, max: 1.0 })
+                .with_poly_modulation_id(PAN_POLY_MOD_ID)
+                .with_smoother(SmoothingStyle::Linear(10.0))
+                .with_value_to_string(formatters::v2s_f32_panning())
+                .with_string_to_value(formatters::s2v_f32_panning()),
+            amp_attack_ms: envelope_time_param("Attack", 5.0)
+                .with_poly_modulation_id(AMP_ATTACK_POLY_MOD_ID),
+            amp_release_ms: envelope_time_param("Release", 200.0)
+                .with_poly_modulation_id(AMP_RELEASE_POLY_MOD_ID),
+            waveform: EnumParam::new("Waveform", Waveform::Sine),
+            wavetable_position: FloatParam::new(
+                "Wavetable Position",
                 0.0,
-                FloatRange::Skewed {
-                    min: 0.0,
-                    max: 1.0,
-                    factor: FloatRange::skew_factor(-1.0),
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            pulse_width: FloatParam::new(
+                "Pulse Width",
+                0.5,
+                FloatRange::Linear {
+                    min: 0.05,
+                    max: 0.95,
                 },
             )
-            .with_step_size(0.1)
-            .with_unit(" ms"),
-            amp_release_ms: FloatParam::new(
-                "Release",
-                0.25,
+            .with_poly_modulation_id(PULSE_WIDTH_POLY_MOD_ID)
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            pwm_source: EnumParam::new("PWM Source", PwmSource::Off),
+            pwm_depth: FloatParam::new("PWM Depth", 0.5, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_unit(" %")
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_string_to_value(formatters::s2v_f32_percentage()),
+            pwm_rate: FloatParam::new(
+                "PWM Rate",
+                1.0,
                 FloatRange::Skewed {
-                    min: 0.0,
-                    max: 1.0,
-                    factor: FloatRange::skew_factor(-1.0),
+                    min: 0.01,
+                    max: 20.0,
+                    factor: FloatRange::skew_factor(-2.0),
                 },
             )
-            .with_step_size(0.1)
-            .with_unit(" ms"),
-            waveform: EnumParam::new("Waveform", Waveform::Sine),
-            amp_decay_ms: FloatParam::new(
-                "Decay",
-                2000.0,
-                FloatRange::Skewed {
-                    min: 0.0,
-                    max: 2000.0,
-                    factor: FloatRange::skew_factor(-1.0),
+            .with_unit(" Hz")
+            .with_value_to_string(formatters::v2s_f32_rounded(2)),
+            pwm_env_attack_ms: envelope_time_param("PWM Env Attack", 500.0),
+            pwm_env_decay_ms: envelope_time_param("PWM Env Decay", 1000.0),
+            osc2_waveform: EnumParam::new("Osc 2 Waveform", Waveform::Sawtooth),
+            osc2_coarse: IntParam::new("Osc 2 Coarse", 0, IntRange::Linear { min: -24, max: 24 })
+                .with_unit(" st"),
+            osc2_fine: FloatParam::new(
+                "Osc 2 Fine",
+                0.0,
+                FloatRange::Linear {
+                    min: -100.0,
+                    max: 100.0,
                 },
             )
+            .with_poly_modulation_id(OSC2_FINE_POLY_MOD_ID)
+            .with_smoother(SmoothingStyle::Linear(10.0))
             .with_step_size(0.1)
-            .with_unit(" ms"),
-            amp_sustain_level: FloatParam::new(
-                "Sustain",
-                1000.0,
+            .with_unit(" ct"),
+            sub_waveform: EnumParam::new("Sub Waveform", Waveform::Square),
+            sub_octave: EnumParam::new("Sub Octave", SubOctave::OneDown),
+            osc1_level: mixer_level_param("Osc 1 Level", 1.0)
+                .with_poly_modulation_id(OSC1_LEVEL_POLY_MOD_ID),
+            osc2_level: mixer_level_param("Osc 2 Level", 0.0)
+                .with_poly_modulation_id(OSC2_LEVEL_POLY_MOD_ID),
+            sub_level: mixer_level_param("Sub Level", 0.0)
+                .with_poly_modulation_id(SUB_LEVEL_POLY_MOD_ID),
+            noise_level: mixer_level_param("Noise Level", 0.0)
+                .with_poly_modulation_id(NOISE_LEVEL_POLY_MOD_ID),
+            noise_color: EnumParam::new("Noise Color", NoiseColor::White),
+            osc2_sync: BoolParam::new("Osc 2 Sync", false),
+            ring_level: mixer_level_param("Ring Mod Level", 0.0)
+                .with_poly_modulation_id(RING_LEVEL_POLY_MOD_ID),
+            fm_amount: FloatParam::new("FM Amount", 0.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_poly_modulation_id(FM_AMOUNT_POLY_MOD_ID)
+                .with_smoother(SmoothingStyle::Linear(10.0))
+                .with_unit(" %")
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_string_to_value(formatters::s2v_f32_percentage()),
+            unison_voices: IntParam::new(
+                "Unison Voices",
+                1,
+                IntRange::Linear {
+                    min: 1,
+                    max: MAX_UNISON_VOICES as i32,
+                },
+            ),
+            unison_detune: FloatParam::new(
+                "Unison Detune",
+                15.0,
                 FloatRange::Skewed {
-                    min: -1000.0,
-                    max: 1000.0,
+                    min: 0.0,
+                    max: 100.0,
                     factor: FloatRange::skew_factor(-1.0),
                 },
             )
+            .with_poly_modulation_id(UNISON_DETUNE_POLY_MOD_ID)
+            .with_smoother(SmoothingStyle::Linear(10.0))
             .with_step_size(0.1)
-            .with_unit(" ms"),
+            .with_unit(" ct"),
+            unison_spread: FloatParam::new(
+                "Unison Spread",
+                0.5,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            unison_blend: FloatParam::new(
+                "Unison Blend",
+                0.5,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            amp_decay_ms: envelope_time_param("Decay", 300.0)
+                .with_poly_modulation_id(AMP_DECAY_POLY_MOD_ID),
+            amp_sustain_level: envelope_sustain_param("Sustain", 0.8),
+            amp_attack_curve: EnumParam::new("Attack Curve", EnvelopeCurve::Linear),
+            amp_decay_curve: EnumParam::new("Decay Curve", EnvelopeCurve::Exponential),
+            amp_release_curve: EnumParam::new("Release Curve", EnvelopeCurve::Exponential),
+            amp_tension: envelope_tension_param("Curve Tension"),
             filter_type: EnumParam::new("Filter Type", FilterType::Lowpass),
+            filter_slope: EnumParam::new("Filter Slope", FilterSlope::Slope12),
             filter_cut: FloatParam::new(
                 "Filter Cutoff",
                 10000.0,
@@ -173,109 +721,308 @@ impl Default for SubSynthParams {
                     max: 20000.0,
                 },
             )
+            .with_poly_modulation_id(FILTER_CUTOFF_POLY_MOD_ID)
+            .with_smoother(SmoothingStyle::Logarithmic(20.0))
             .with_unit(" Hz"),
             filter_res: FloatParam::new(
                 "Filter Resonance",
                 0.0,
-                FloatRange::Linear {
-                    min: -1000.0,
-                    max: 1000.0,
-                },
-            )
-            .with_unit(" Q"),
-            filter_cut_attack_ms: FloatParam::new(
-                "Filter Cut Attack",
-                200.0,
-                FloatRange::Skewed {
-                    min: 0.0,
-                    max: 2000.0,
-                    factor: FloatRange::skew_factor(-1.0),
-                },
+                FloatRange::Linear { min: 0.0, max: 1.0 },
             )
-            .with_step_size(0.1)
-            .with_unit(" ms"),
-            filter_cut_decay_ms: FloatParam::new(
-                "Filter Cut Decay",
-                2000.0,
-                FloatRange::Skewed {
-                    min: 0.0,
-                    max: 2000.0,
-                    factor: FloatRange::skew_factor(-1.0),
-                },
-            )
-            .with_step_size(0.1)
-            .with_unit(" ms"),
-            filter_cut_sustain_ms: FloatParam::new(
-                "Filter Cut Sustain",
-                1000.0,
-                FloatRange::Skewed {
-                    min: 0.0,
-                    max: 5000.0,
-                    factor: FloatRange::skew_factor(-1.0),
-                },
-            )
-            .with_step_size(0.1)
-            .with_unit(" ms"),
-            filter_cut_release_ms: FloatParam::new(
-                "Filter Cut Release",
-                1000.0,
-                FloatRange::Skewed {
-                    min: 0.0,
-                    max: 2000.0,
-                    factor: FloatRange::skew_factor(-1.0),
-                },
-            )
-            .with_step_size(0.1)
-            .with_unit(" ms"),
-            filter_res_attack_ms: FloatParam::new(
-                "Filter Resonance Attack",
-                2000.0,
-                FloatRange::Skewed {
-                    min: 0.0,
-                    max: 2000.0,
-                    factor: FloatRange::skew_factor(-1.0),
+            .with_poly_modulation_id(FILTER_RESONANCE_POLY_MOD_ID)
+            .with_smoother(SmoothingStyle::Linear(20.0))
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            filter_env_amount: FloatParam::new(
+                "Filter Env Amount",
+                0.0,
+                FloatRange::Linear {
+                    min: -8.0,
+                    max: 8.0,
                 },
             )
-            .with_step_size(0.1)
-            .with_unit(" ms"),
-            filter_res_decay_ms: FloatParam::new(
-                "Filter Resonance Decay",
-                2000.0,
-                FloatRange::Skewed {
-                    min: 0.0,
-                    max: 2000.0,
-                    factor: FloatRange::skew_factor(-1.0),
-                },
+            .with_poly_modulation_id(FILTER_ENV_AMOUNT_POLY_MOD_ID)
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_step_size(0.01)
+            .with_unit(" oct"),
+            filter_keytrack: FloatParam::new(
+                "Filter Keytracking",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
             )
-            .with_step_size(0.1)
-            .with_unit(" ms"),
-            filter_res_sustain_ms: FloatParam::new(
-                "Filter Resonance Sustain",
-                1000.0,
-                FloatRange::Skewed {
-                    min: 0.0,
-                    max: 5000.0,
-                    factor: FloatRange::skew_factor(-1.0),
-                },
+            .with_poly_modulation_id(FILTER_KEYTRACK_POLY_MOD_ID)
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            filter_velocity: FloatParam::new(
+                "Filter Velocity",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
             )
-            .with_step_size(0.1)
-            .with_unit(" ms"),
-            filter_res_release_ms: FloatParam::new(
-                "Filter Resonance Decay",
-                200.0,
+            .with_poly_modulation_id(FILTER_VELOCITY_POLY_MOD_ID)
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            filter_drive: FloatParam::new(
+                "Filter Drive",
+                0.0,
                 FloatRange::Skewed {
                     min: 0.0,
-                    max: 2000.0,
+                    max: 36.0,
                     factor: FloatRange::skew_factor(-1.0),
                 },
             )
+            .with_poly_modulation_id(FILTER_DRIVE_POLY_MOD_ID)
+            .with_smoother(SmoothingStyle::Linear(10.0))
             .with_step_size(0.1)
-            .with_unit(" ms"),
+            .with_unit(" dB"),
+            filter_drive_curve: EnumParam::new("Filter Drive Curve", DriveCurve::Off),
+            filter_cut_attack_ms: envelope_time_param("Filter Cut Attack", 200.0)
+                .with_poly_modulation_id(FILTER_CUT_ATTACK_POLY_MOD_ID),
+            filter_cut_decay_ms: envelope_time_param("Filter Cut Decay", 2000.0)
+                .with_poly_modulation_id(FILTER_CUT_DECAY_POLY_MOD_ID),
+            filter_cut_sustain_level: envelope_sustain_param("Filter Cut Sustain", 1.0),
+            filter_cut_release_ms: envelope_time_param("Filter Cut Release", 1000.0)
+                .with_poly_modulation_id(FILTER_CUT_RELEASE_POLY_MOD_ID),
+            filter_cut_attack_curve: EnumParam::new("Filter Cut Attack Curve", EnvelopeCurve::Linear),
+            filter_cut_decay_curve: EnumParam::new("Filter Cut Decay Curve", EnvelopeCurve::Linear),
+            filter_cut_release_curve: EnumParam::new("Filter Cut Release Curve", EnvelopeCurve::Linear),
+            filter_cut_tension: envelope_tension_param("Filter Cut Curve Tension"),
+            filter_res_attack_ms: envelope_time_param("Filter Resonance Attack", 2000.0)
+                .with_poly_modulation_id(FILTER_RES_ATTACK_POLY_MOD_ID),
+            filter_res_decay_ms: envelope_time_param("Filter Resonance Decay", 2000.0)
+                .with_poly_modulation_id(FILTER_RES_DECAY_POLY_MOD_ID),
+            filter_res_sustain_level: envelope_sustain_param("Filter Resonance Sustain", 1.0),
+            filter_res_release_ms: envelope_time_param("Filter Resonance Release", 200.0)
+                .with_poly_modulation_id(FILTER_RES_RELEASE_POLY_MOD_ID),
+            filter_res_attack_curve: EnumParam::new("Filter Resonance Attack Curve", EnvelopeCurve::Linear),
+            filter_res_decay_curve: EnumParam::new("Filter Resonance Decay Curve", EnvelopeCurve::Linear),
+            filter_res_release_curve: EnumParam::new("Filter Resonance Release Curve", EnvelopeCurve::Linear),
+            filter_res_tension: envelope_tension_param("Filter Resonance Curve Tension"),
+            lfos: [1, 2].map(|lfo_number| LfoParams::new(&format!("LFO {lfo_number}"))),
+            global_lfo: LfoParams::new("Global LFO"),
+            mod_slots: std::array::from_fn(|slot_idx| ModSlotParams::new(slot_idx + 1)),
+            mpe: MpeParams::default(),
+            voice_mode: VoiceModeParams::default(),
+            arp: ArpParams::default(),
+            sequencer: SequencerParams::default(),
+            analog: AnalogParams::default(),
+            scaling: ScalingParams::default(),
+            distortion: DistortionParams::default(),
+            chorus: ChorusParams::default(),
+            delay: DelayParams::default(),
+            reverb: ReverbParams::default(),
+        }
+    }
+}
+
+
+
+impl SubSynthParams {
+    /// The parameter belonging to a polyphonic modulation ID, if there is one.
+    fn poly_modulated_param(&self, poly_modulation_id: u32) -> Option<&FloatParam> {
+        match poly_modulation_id {
+            GAIN_POLY_MOD_ID => Some(&self.gain),
+            PULSE_WIDTH_POLY_MOD_ID => Some(&self.pulse_width),
+            FM_AMOUNT_POLY_MOD_ID => Some(&self.fm_amount),
+            FILTER_ENV_AMOUNT_POLY_MOD_ID => Some(&self.filter_env_amount),
+            FILTER_KEYTRACK_POLY_MOD_ID => Some(&self.filter_keytrack),
+            FILTER_VELOCITY_POLY_MOD_ID => Some(&self.filter_velocity),
+            FILTER_DRIVE_POLY_MOD_ID => Some(&self.filter_drive),
+            FILTER_CUTOFF_POLY_MOD_ID => Some(&self.filter_cut),
+            FILTER_RESONANCE_POLY_MOD_ID => Some(&self.filter_res),
+            UNISON_DETUNE_POLY_MOD_ID => Some(&self.unison_detune),
+            OSC2_FINE_POLY_MOD_ID => Some(&self.osc2_fine),
+            PAN_POLY_MOD_ID => Some(&self.pan),
+            AMP_ATTACK_POLY_MOD_ID => Some(&self.amp_attack_ms),
+            AMP_DECAY_POLY_MOD_ID => Some(&self.amp_decay_ms),
+            AMP_RELEASE_POLY_MOD_ID => Some(&self.amp_release_ms),
+            FILTER_CUT_ATTACK_POLY_MOD_ID => Some(&self.filter_cut_attack_ms),
+            FILTER_CUT_DECAY_POLY_MOD_ID => Some(&self.filter_cut_decay_ms),
+            FILTER_CUT_RELEASE_POLY_MOD_ID => Some(&self.filter_cut_release_ms),
+            FILTER_RES_ATTACK_POLY_MOD_ID => Some(&self.filter_res_attack_ms),
+            FILTER_RES_DECAY_POLY_MOD_ID => Some(&self.filter_res_decay_ms),
+            FILTER_RES_RELEASE_POLY_MOD_ID => Some(&self.filter_res_release_ms),
+            OSC1_LEVEL_POLY_MOD_ID => Some(&self.osc1_level),
+            OSC2_LEVEL_POLY_MOD_ID => Some(&self.osc2_level),
+            SUB_LEVEL_POLY_MOD_ID => Some(&self.sub_level),
+            NOISE_LEVEL_POLY_MOD_ID => Some(&self.noise_level),
+            RING_LEVEL_POLY_MOD_ID => Some(&self.ring_level),
+            _ => None,
+        }
+    }
+
+    /// The amplitude envelope's settings. `value` reads the parameters, so voices can apply their
+    /// own modulation.
+    fn amp_envelope_settings(
+        &self,
+        mut value: impl FnMut(&FloatParam, ModDestination) -> f32,
+    ) -> EnvelopeSettings {
+        EnvelopeSettings {
+            attack_ms: value(&self.amp_attack_ms, ModDestination::AmpAttack),
+            decay_ms: value(&self.amp_decay_ms, ModDestination::AmpDecay),
+            sustain: value(&self.amp_sustain_level, ModDestination::AmpSustain),
+            release_ms: value(&self.amp_release_ms, ModDestination::AmpRelease),
+            attack_curve: self.amp_attack_curve.value(),
+            decay_curve: self.amp_decay_curve.value(),
+            release_curve: self.amp_release_curve.value(),
+            tension: value(&self.amp_tension, ModDestination::AmpTension),
+        }
+    }
+
+    fn filter_cut_envelope_settings(
+        &self,
+        mut value: impl FnMut(&FloatParam, ModDestination) -> f32,
+    ) -> EnvelopeSettings {
+        EnvelopeSettings {
+            attack_ms: value(&self.filter_cut_attack_ms, ModDestination::FilterCutAttack),
+            decay_ms: value(&self.filter_cut_decay_ms, ModDestination::FilterCutDecay),
+            sustain: value(&self.filter_cut_sustain_level, ModDestination::FilterCutSustain),
+            release_ms: value(&self.filter_cut_release_ms, ModDestination::FilterCutRelease),
+            attack_curve: self.filter_cut_attack_curve.value(),
+            decay_curve: self.filter_cut_decay_curve.value(),
+            release_curve: self.filter_cut_release_curve.value(),
+            tension: value(&self.filter_cut_tension, ModDestination::FilterCutTension),
+        }
+    }
+
+    /// The PWM envelope is a simple attack-decay envelope that falls back to zero.
+    fn pwm_envelope_settings(
+        &self,
+        mut value: impl FnMut(&FloatParam, ModDestination) -> f32,
+    ) -> EnvelopeSettings {
+        let decay_ms = value(&self.pwm_env_decay_ms, ModDestination::PwmEnvDecay);
+        EnvelopeSettings {
+            attack_ms: value(&self.pwm_env_attack_ms, ModDestination::PwmEnvAttack),
+            decay_ms,
+            sustain: 0.0,
+            release_ms: decay_ms,
+            ..EnvelopeSettings::default()
+        }
+    }
+
+    fn filter_res_envelope_settings(
+        &self,
+        mut value: impl FnMut(&FloatParam, ModDestination) -> f32,
+    ) -> EnvelopeSettings {
+        EnvelopeSettings {
+            attack_ms: value(&self.filter_res_attack_ms, ModDestination::FilterResAttack),
+            decay_ms: value(&self.filter_res_decay_ms, ModDestination::FilterResDecay),
+            sustain: value(&self.filter_res_sustain_level, ModDestination::FilterResSustain),
+            release_ms: value(&self.filter_res_release_ms, ModDestination::FilterResRelease),
+            attack_curve: self.filter_res_attack_curve.value(),
+            decay_curve: self.filter_res_decay_curve.value(),
+            release_curve: self.filter_res_release_curve.value(),
+            tension: value(&self.filter_res_tension, ModDestination::FilterResTension),
+        }
+    }
+}
+
+/// A parameter's value without any of a voice's polyphonic modulation or mod matrix offsets
+/// applied. This is used for the envelope settings before a voice has been started.
+fn unmodulated_value(param: &FloatParam, _destination: ModDestination) -> f32 {
+    param.value()
+}
+
+/// Override a polyphonically modulatable parameter for a single voice, as used by the step
+/// sequencer's parameter locks. This sets the voice's polyphonic modulation so the parameter has
+/// `normalized_value` for the rest of the note.
+fn lock_voice_param(
+    params: &SubSynthParams,
+    voice: &mut Voice,
+    poly_modulation_id: u32,
+    normalized_value: f32,
+) {
+    if let (Some(param), Some(voice_modulation)) = (
+        params.poly_modulated_param(poly_modulation_id),
+        voice.poly_modulation_mut(poly_modulation_id),
+    ) {
+        let normalized_offset = normalized_value - param.unmodulated_normalized_value();
+        let (offset, smoother) = voice_modulation
+            .get_or_insert_with(|| (normalized_offset, param.smoothed.clone()));
+        *offset = normalized_offset;
+        smoother.reset(param.preview_plain(normalized_value));
+    }
+}
+
+/// Generate a sample for one of the oscillators. The wavetable is read at `wavetable_position`,
+/// the noise waveform is `noise_color` noise from the voice's noise generator like the mixer's
+/// noise source, and the other waveforms are computed directly.
+#[allow(clippy::too_many_arguments)]
+fn oscillator_sample(
+    waveform: Waveform,
+    noise: &mut Noise,
+    noise_color: NoiseColor,
+    wavetable: &Wavetable,
+    wavetable_position: f32,
+    phase: f32,
+    phase_delta: f32,
+    pulse_width: f32,
+) -> f32 {
+    match waveform {
+        Waveform::Noise => noise.next(noise_color),
+        Waveform::Wavetable => wavetable.sample(phase, phase_delta, wavetable_position),
+        waveform => generate_waveform(waveform, phase, phase_delta, pulse_width),
+    }
+}
+
+/// The values for a polyphonically modulatable parameter for the current block. If the voice has
+/// polyphonic modulation for the parameter, then the voice's smoother is used to fill
+/// `voice_values`. Otherwise the parameter's global `values` are used.
+fn voice_block_values<'a>(
+    voice_modulation: &Option<(f32, Smoother<f32>)>,
+    values: &'a [f32; MAX_BLOCK_SIZE],
+    voice_values: &'a mut [f32; MAX_BLOCK_SIZE],
+    block_len: usize,
+) -> &'a [f32; MAX_BLOCK_SIZE] {
+    match voice_modulation {
+        Some((_, smoother)) => {
+            smoother.next_block(voice_values, block_len);
+            voice_values
         }
+        None => values,
     }
 }
 
+/// An envelope stage's duration in milliseconds.
+fn envelope_time_param(name: &str, default_ms: f32) -> FloatParam {
+    FloatParam::new(
+        name,
+        default_ms,
+        FloatRange::Skewed {
+            min: 0.0,
+            max: 5000.0,
+            factor: FloatRange::skew_factor(-2.0),
+        },
+    )
+    .with_step_size(0.1)
+    .with_unit(" ms")
+}
+
+/// An envelope's sustain level, in `[0, 1]`.
+fn envelope_sustain_param(name: &str, default: f32) -> FloatParam {
+    FloatParam::new(name, default, FloatRange::Linear { min: 0.0, max: 1.0 })
+        .with_unit(" %")
+        .with_value_to_string(formatters::v2s_f32_percentage(0))
+        .with_string_to_value(formatters::s2v_f32_percentage())
+}
+
+/// The tension for an envelope's [`EnvelopeCurve::Curved`] stages.
+fn envelope_tension_param(name: &str) -> FloatParam {
+    FloatParam::new(name, 0.0, FloatRange::Linear { min: -1.0, max: 1.0 }).with_step_size(0.01)
+}
 
+/// A source's level in the mixer, in `[0, 1]`.
+fn mixer_level_param(name: &str, default: f32) -> FloatParam {
+    FloatParam::new(name, default, FloatRange::Linear { min: 0.0, max: 1.0 })
+        .with_smoother(SmoothingStyle::Linear(10.0))
+        .with_unit(" %")
+        .with_value_to_string(formatters::v2s_f32_percentage(0))
+        .with_string_to_value(formatters::s2v_f32_percentage())
+}
 
 impl Plugin for SubSynth {
     const NAME: &'static str = "SubSynthBeta";
@@ -291,19 +1038,32 @@ impl Plugin for SubSynth {
         ..AudioIOLayout::const_default()
     }];
 
-    const MIDI_INPUT: MidiConfig = MidiConfig::Basic;
+    const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
+    const MIDI_OUTPUT: MidiConfig = MidiConfig::Basic;
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
 
     type SysExMessage = ();
-    type BackgroundTask = ();
+    type BackgroundTask = Task;
+
+    fn task_executor(&mut self) -> TaskExecutor<Self> {
+        let params = self.params.clone();
+        let wavetables = self.wavetables.clone();
+        Box::new(move |task| match task {
+            Task::BuildWavetable => {
+                let wavetable = Wavetable::new(&params.wavetable.read().unwrap());
+                wavetables.publish(wavetable);
+            }
+        })
+    }
 
     fn params(&self) -> Arc<dyn Params> {
         self.params.clone()
     }
-    fn editor(&mut self, _async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
+    fn editor(&mut self, async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
         editor::create(
             self.params.clone(),
             self.params.editor_state.clone(),
+            async_executor,
         )
     }
 
@@ -311,19 +1071,37 @@ impl Plugin for SubSynth {
         &mut self,
         _audio_io_layout: &AudioIOLayout,
         buffer_config: &BufferConfig,
-        _context: &mut impl InitContext<Self>,
+        context: &mut impl InitContext<Self>,
     ) -> bool {
-        // After `PEAK_METER_DECAY_MS` milliseconds of pure silence, the peak meter's value should
-        // have dropped by 12 dB
+        self.sample_rate = buffer_config.sample_rate;
+        // This also runs after a new state has been loaded, which may contain a different
+        // wavetable
+        context.execute(Task::BuildWavetable);
+        self.wavetables.try_swap(&mut self.wavetable);
+        // The effects' delay lines depend on the sample rate, so they're allocated here
+        self.effects = Effects::new(buffer_config.sample_rate);
 
         true
     }
 
     fn reset(&mut self) {
         self.prng = Pcg32::new(420, 1337);
+        self.voice_cards = std::array::from_fn(|_| VoiceCard::new(&mut self.prng));
 
         self.voices.fill(None);
         self.next_internal_voice_id = 0;
+        self.global_lfo = Lfo::new(0);
+        self.free_running_lfo_phases = [0.0; NUM_VOICE_LFOS];
+        self.mod_wheel = 0.0;
+        self.pitch_bend = PitchBend::default();
+        self.channel_pressure = [0.0; 16];
+        self.channel_brightness = [0.0; 16];
+        self.held_notes.clear();
+        self.pedals = Pedals::default();
+        self.arp = Arpeggiator::default();
+        self.sequencer = Sequencer::default();
+        self.effects.reset();
+        self.last_note = None;
     }
 
     fn process(
@@ -331,10 +1109,74 @@ impl Plugin for SubSynth {
         buffer: &mut Buffer,
         _aux: &mut AuxiliaryBuffers,
         context: &mut impl ProcessContext<Self>,
+    ) -> ProcessStatus {
+        let transport = context.transport();
+        let sample_rate = transport.sample_rate;
+        let tempo = transport.tempo;
+        // Free running tempo synced LFOs follow the host's playback position while it's playing
+        let pos_beats = if transport.playing {
+            transport.pos_beats()
+        } else {
+            None
+        };
+
+        self.process_buffer(buffer, context, sample_rate, tempo, pos_beats)
+    }
+}    
+impl SubSynth {
+    /// Process a buffer with the transport information `process()` read from the host. This only
+    /// touches the process context for its events, so the event handling can be tested without a
+    /// host.
+    fn process_buffer(
+        &mut self,
+        buffer: &mut Buffer,
+        context: &mut impl ProcessContext<Self>,
+        sample_rate: f32,
+        tempo: Option<f64>,
+        pos_beats: Option<f64>,
     ) -> ProcessStatus {
         let num_samples = buffer.samples();
-        let sample_rate = context.transport().sample_rate;
         let output = buffer.as_slice();
+
+        let voice_capacity = if self.params.voice_mode.mode.value().is_monophonic() {
+            1
+        } else {
+            self.params.voice_mode.polyphony.value() as u32
+        };
+        if voice_capacity != self.voice_capacity {
+            context.set_current_voice_capacity(voice_capacity);
+            self.voice_capacity = voice_capacity;
+        }
+
+        let arp_enabled = self.params.arp.enabled.value();
+        let arp_settings = self.params.arp.settings(sample_rate, tempo, pos_beats);
+        if !arp_enabled {
+            self.arp.stop();
+        }
+        self.arp.update(&arp_settings);
+
+        // The editor may be changing the sequence, in which case the previous copy is used
+        if let Ok(sequence) = self.params.sequence.try_read() {
+            self.sequencer.load(&sequence);
+        }
+        let sequencer_settings = self.params.sequencer.settings(sample_rate, tempo, pos_beats);
+        self.sequencer.update(&sequencer_settings);
+        if let Ok(tuning) = self.params.tuning.try_read() {
+            self.tuning = tuning.table();
+        }
+        self.wavetables.try_swap(&mut self.wavetable);
+        let effects_settings = EffectsSettings {
+            order: effect_order([
+                self.params.distortion.slot.value(),
+                self.params.chorus.slot.value(),
+                self.params.delay.slot.value(),
+                self.params.reverb.slot.value(),
+            ]),
+            distortion: self.params.distortion.settings(sample_rate),
+            chorus: self.params.chorus.settings(sample_rate),
+            delay: self.params.delay.settings(sample_rate, tempo),
+            reverb: self.params.reverb.settings(sample_rate),
+        };
     
         let mut next_event = context.next_event();
         let mut block_start: usize = 0;
@@ -354,31 +1196,31 @@ impl Plugin for SubSynth {
                                 note,
                                 velocity,
                             } => {
-                                let initial_phase: f32 = self.prng.gen();
-                                let attack_time = self.params.amp_attack_ms.value(); // Convert attack time to seconds
-                                let release_time = self.params.amp_release_ms.value(); // Convert release time to seconds
-                                let decay_time = self.params.amp_decay_ms.value(); // Convert decay time to seconds
-                                let sustain_level = self.params.amp_sustain_level.value(); // Sustain level (between 0.0 and 1.0)
-
-                                let amp_envelope = ADSREnvelope::new(attack_time, decay_time, sustain_level, release_time);
-                                //amp_envelope.trigger();
-
-                                let voice = self.start_voice(context, timing, voice_id, channel, note);
-                                voice.velocity_sqrt = velocity.sqrt();
-                                voice.phase = initial_phase;
-                                voice.phase_delta = util::midi_note_to_freq(note) / sample_rate;
-
-                                voice.amp_envelope = amp_envelope;
-
+                                let held_note = HeldNote {
+                                    voice_id,
+                                    channel,
+                                    note,
+                                    velocity: self.pedals.velocity(velocity),
+                                    sequenced: false,
+                                };
+                                if arp_enabled {
+                                    self.arp.key_down(held_note);
+                                } else {
+                                    self.note_on(context, timing, held_note);
+                                }
                             }
                             NoteEvent::NoteOff {
-                                timing: _,
+                                timing,
                                 voice_id,
                                 channel,
                                 note,
                                 velocity: _,
                             } => {
-                                self.start_release_for_voices(sample_rate, voice_id, channel, note)
+                                // Keys played into the arpeggiator only change its pattern
+                                let arpeggiated = self.arp.key_up(channel, note);
+                                if !arpeggiated {
+                                    self.note_off(context, timing, voice_id, channel, note);
+                                }
                             }
                             NoteEvent::Choke {
                                 timing,
@@ -397,27 +1239,30 @@ impl Plugin for SubSynth {
                                 if let Some(voice_idx) = self.get_voice_idx(voice_id) {
                                     let voice = self.voices[voice_idx].as_mut().unwrap();
     
-                                    match poly_modulation_id {
-                                        GAIN_POLY_MOD_ID => {
-                                            let target_plain_value = self
-                                                .params
-                                                .gain
-                                                .preview_modulated(normalized_offset);
-                                            let (_, smoother) = voice.voice_gain.get_or_insert_with(|| {
-                                                (
-                                                    normalized_offset,
-                                                    self.params.gain.smoothed.clone(),
-                                                )
-                                            });
-                                            if voice.internal_voice_id >= this_sample_internal_voice_id_start {
+                                    // Newly started voices should immediately start at the modulated value
+                                    let is_new_voice = voice.internal_voice_id
+                                        >= this_sample_internal_voice_id_start;
+
+                                    match (
+                                        self.params.poly_modulated_param(poly_modulation_id),
+                                        voice.poly_modulation_mut(poly_modulation_id),
+                                    ) {
+                                        (Some(param), Some(voice_modulation)) => {
+                                            let target_plain_value =
+                                                param.preview_modulated(normalized_offset);
+                                            let (_, smoother) =
+                                                voice_modulation.get_or_insert_with(|| {
+                                                    (normalized_offset, param.smoothed.clone())
+                                                });
+                                            if is_new_voice {
                                                 smoother.reset(target_plain_value);
                                             } else {
                                                 smoother.set_target(sample_rate, target_plain_value);
                                             }
                                         }
-                                        n => nih_debug_assert_failure!(
+                                        _ => nih_debug_assert_failure!(
                                             "Polyphonic modulation sent for unknown poly modulation ID {}",
-                                            n
+                                            poly_modulation_id
                                         ),
                                     }
                                 }
@@ -427,27 +1272,170 @@ impl Plugin for SubSynth {
                                 poly_modulation_id,
                                 normalized_value,
                             } => {
-                                for voice in self.voices.iter_mut().filter_map(|v| v.as_mut()) {
-                                    match poly_modulation_id {
-                                        GAIN_POLY_MOD_ID => {
-                                            let (normalized_offset, smoother) =
-                                                match voice.voice_gain.as_mut() {
-                                                    Some((o, s)) => (o, s),
-                                                    None => continue,
-                                                };
-                                            let target_plain_value =
-                                                self.params.gain.preview_plain(
+                                match self.params.poly_modulated_param(poly_modulation_id) {
+                                    Some(param) => {
+                                        for voice in
+                                            self.voices.iter_mut().filter_map(|v| v.as_mut())
+                                        {
+                                            if let Some(Some((normalized_offset, smoother))) =
+                                                voice.poly_modulation_mut(poly_modulation_id)
+                                            {
+                                                let target_plain_value = param.preview_plain(
                                                     normalized_value + *normalized_offset,
                                                 );
-                                            smoother.set_target(sample_rate, target_plain_value);
+                                                smoother.set_target(sample_rate, target_plain_value);
+                                            }
                                         }
-                                        n => nih_debug_assert_failure!(
-                                            "Automation event sent for unknown poly modulation ID {}",
-                                            n
-                                        ),
                                     }
+                                    None => nih_debug_assert_failure!(
+                                        "Automation event sent for unknown poly modulation ID {}",
+                                        poly_modulation_id
+                                    ),
+                                }
+                            }
+                            NoteEvent::PolyPressure {
+                                timing: _,
+                                voice_id,
+                                channel,
+                                note,
+                                pressure,
+                            } => {
+                                for voice in self.expression_voices(voice_id, channel, note) {
+                                    voice.pressure = pressure;
+                                }
+                            }
+                            NoteEvent::PolyVolume {
+                                timing: _,
+                                voice_id,
+                                channel,
+                                note,
+                                gain,
+                            } => {
+                                for voice in self.expression_voices(voice_id, channel, note) {
+                                    voice.expression.volume = gain;
+                                }
+                            }
+                            NoteEvent::PolyPan {
+                                timing: _,
+                                voice_id,
+                                channel,
+                                note,
+                                pan,
+                            } => {
+                                for voice in self.expression_voices(voice_id, channel, note) {
+                                    voice.expression.pan = pan;
+                                }
+                            }
+                            NoteEvent::PolyTuning {
+                                timing: _,
+                                voice_id,
+                                channel,
+                                note,
+                                tuning,
+                            } => {
+                                for voice in self.expression_voices(voice_id, channel, note) {
+                                    voice.expression.tuning = tuning;
+                                }
+                            }
+                            NoteEvent::PolyVibrato {
+                                timing: _,
+                                voice_id,
+                                channel,
+                                note,
+                                vibrato,
+                            } => {
+                                for voice in self.expression_voices(voice_id, channel, note) {
+                                    voice.expression.vibrato = vibrato;
+                                }
+                            }
+                            NoteEvent::PolyExpression {
+                                timing: _,
+                                voice_id,
+                                channel,
+                                note,
+                                expression,
+                            } => {
+                                for voice in self.expression_voices(voice_id, channel, note) {
+                                    voice.expression.expression = expression;
+                                }
+                            }
+                            NoteEvent::PolyBrightness {
+                                timing: _,
+                                voice_id,
+                                channel,
+                                note,
+                                brightness,
+                            } => {
+                                for voice in self.expression_voices(voice_id, channel, note) {
+                                    voice.expression.brightness = brightness;
+                                }
+                            }
+                            NoteEvent::MidiChannelPressure {
+                                timing: _,
+                                channel,
+                                pressure,
+                            } => {
+                                self.channel_pressure[channel as usize] = pressure;
+                                for voice in self
+                                    .voices
+                                    .iter_mut()
+                                    .flatten()
+                                    .filter(|voice| !voice.sequenced && voice.channel == channel)
+                                {
+                                    voice.pressure = pressure;
+                                }
+                            }
+                            NoteEvent::MidiPitchBend {
+                                timing: _,
+                                channel,
+                                value,
+                            } => self.pitch_bend.set(channel, value * 2.0 - 1.0),
+                            NoteEvent::MidiCC {
+                                timing: _,
+                                channel,
+                                cc: BRIGHTNESS_CC,
+                                value,
+                            } => {
+                                self.channel_brightness[channel as usize] = value;
+                                for voice in self
+                                    .voices
+                                    .iter_mut()
+                                    .flatten()
+                                    .filter(|voice| !voice.sequenced && voice.channel == channel)
+                                {
+                                    voice.expression.brightness = value;
                                 }
                             }
+                            NoteEvent::MidiCC {
+                                timing: _,
+                                channel: _,
+                                cc: control_change::MODULATION_MSB,
+                                value,
+                            } => self.mod_wheel = value,
+                            NoteEvent::MidiCC {
+                                timing,
+                                channel: _,
+                                cc: control_change::DAMPER_PEDAL,
+                                value,
+                            } => {
+                                let released_notes = self.pedals.set_sustain(value >= 0.5);
+                                self.release_notes(context, timing, released_notes);
+                            }
+                            NoteEvent::MidiCC {
+                                timing,
+                                channel: _,
+                                cc: control_change::SUSTENUTO,
+                                value,
+                            } => {
+                                let released_notes = self.pedals.set_sostenuto(value >= 0.5);
+                                self.release_notes(context, timing, released_notes);
+                            }
+                            NoteEvent::MidiCC {
+                                timing: _,
+                                channel: _,
+                                cc: control_change::SOFT_PEDAL,
+                                value,
+                            } => self.pedals.set_soft(value >= 0.5),
                             _ => (),
                         };
     
@@ -460,6 +1448,20 @@ impl Plugin for SubSynth {
                     _ => break 'events,
                 }
             }
+
+            // The arpeggiator's and the sequencer's notes also split the block so they start
+            // sample accurately
+            self.run_arpeggiator(context, block_start as u32, &arp_settings);
+            self.run_sequencer(context, block_start as u32, &sequencer_settings);
+            for samples in [
+                self.arp.samples_until_next_event(&arp_settings),
+                self.sequencer.samples_until_next_event(&sequencer_settings),
+            ]
+            .into_iter()
+            .flatten()
+            {
+                block_end = block_end.min(block_start + (samples.ceil() as usize).max(1));
+            }
     
             // Clear output buffer
             output[0][block_start..block_end].fill(0.0);
@@ -468,123 +1470,1021 @@ impl Plugin for SubSynth {
             let block_len = block_end - block_start;
             let mut gain = [0.0; MAX_BLOCK_SIZE];
             let mut voice_gain = [0.0; MAX_BLOCK_SIZE];
-            let mut voice_amp_envelope = ADSREnvelope::new(self.params.amp_attack_ms.value(), self.params.amp_decay_ms.value(),self.params.amp_sustain_level.value(), self.params.amp_release_ms.value());
+            let mut pulse_width = [0.0; MAX_BLOCK_SIZE];
+            let mut wavetable_position = [0.0; MAX_BLOCK_SIZE];
+            let mut voice_pulse_width = [0.0; MAX_BLOCK_SIZE];
+            let mut osc1_level = [0.0; MAX_BLOCK_SIZE];
+            let mut osc2_level = [0.0; MAX_BLOCK_SIZE];
+            let mut sub_level = [0.0; MAX_BLOCK_SIZE];
+            let mut noise_level = [0.0; MAX_BLOCK_SIZE];
+            let mut ring_level = [0.0; MAX_BLOCK_SIZE];
+            let mut voice_osc1_level = [0.0; MAX_BLOCK_SIZE];
+            let mut voice_osc2_level = [0.0; MAX_BLOCK_SIZE];
+            let mut voice_sub_level = [0.0; MAX_BLOCK_SIZE];
+            let mut voice_noise_level = [0.0; MAX_BLOCK_SIZE];
+            let mut voice_ring_level = [0.0; MAX_BLOCK_SIZE];
+            let mut pan = [0.0; MAX_BLOCK_SIZE];
+            let mut voice_pan = [0.0; MAX_BLOCK_SIZE];
+            let mut filter_cut = [0.0; MAX_BLOCK_SIZE];
+            let mut voice_filter_cut = [0.0; MAX_BLOCK_SIZE];
+            let mut filter_res = [0.0; MAX_BLOCK_SIZE];
+            let mut voice_filter_res = [0.0; MAX_BLOCK_SIZE];
+            let mut fm_amount = [0.0; MAX_BLOCK_SIZE];
+            let mut voice_fm_amount = [0.0; MAX_BLOCK_SIZE];
+            let mut filter_env_amount = [0.0; MAX_BLOCK_SIZE];
+            let mut voice_filter_env_amount = [0.0; MAX_BLOCK_SIZE];
+            let mut filter_keytrack = [0.0; MAX_BLOCK_SIZE];
+            let mut voice_filter_keytrack = [0.0; MAX_BLOCK_SIZE];
+            let mut filter_velocity = [0.0; MAX_BLOCK_SIZE];
+            let mut voice_filter_velocity = [0.0; MAX_BLOCK_SIZE];
+            let mut filter_drive = [0.0; MAX_BLOCK_SIZE];
+            let mut voice_filter_drive = [0.0; MAX_BLOCK_SIZE];
             self.params.gain.smoothed.next_block(&mut gain, block_len);
-    
-            // Process voices
-            for voice in self.voices.iter_mut().filter_map(|v| v.as_mut()) {
-                let gain = match &voice.voice_gain {
-                    Some((_, smoother)) => {
-                        smoother.next_block(&mut voice_gain, block_len);
-                        &voice_gain
-                    }
-                    None => &gain,
-                };
-                    if let ADSREnvelopeState::Idle = voice_amp_envelope.get_state() {
-                        if voice_amp_envelope.get_value(0.0) == 0.0 {
-                            context.send_event(NoteEvent::VoiceTerminated {
-                                timing: block_end as u32,
-                                voice_id: Some(voice.voice_id),
-                                channel: voice.channel,
-                                note: voice.note,
-                            });
-                            //self.voices[voice_idx] = None;
-                        }
+            self.params.pan.smoothed.next_block(&mut pan, block_len);
+            self.params
+                .pulse_width
+                .smoothed
+                .next_block(&mut pulse_width, block_len);
+            self.params
+                .wavetable_position
+                .smoothed
+                .next_block(&mut wavetable_position, block_len);
+            self.params
+                .osc1_level
+                .smoothed
+                .next_block(&mut osc1_level, block_len);
+            self.params
+                .osc2_level
+                .smoothed
+                .next_block(&mut osc2_level, block_len);
+            self.params
+                .sub_level
+                .smoothed
+                .next_block(&mut sub_level, block_len);
+            self.params
+                .noise_level
+                .smoothed
+                .next_block(&mut noise_level, block_len);
+            self.params
+                .ring_level
+                .smoothed
+                .next_block(&mut ring_level, block_len);
+            self.params
+                .fm_amount
+                .smoothed
+                .next_block(&mut fm_amount, block_len);
+            self.params
+                .filter_cut
+                .smoothed
+                .next_block(&mut filter_cut, block_len);
+            self.params
+                .filter_res
+                .smoothed
+                .next_block(&mut filter_res, block_len);
+            self.params
+                .filter_env_amount
+                .smoothed
+                .next_block(&mut filter_env_amount, block_len);
+            self.params
+                .filter_keytrack
+                .smoothed
+                .next_block(&mut filter_keytrack, block_len);
+            self.params
+                .filter_velocity
+                .smoothed
+                .next_block(&mut filter_velocity, block_len);
+            self.params
+                .filter_drive
+                .smoothed
+                .next_block(&mut filter_drive, block_len);
+
+            let waveform = self.params.waveform.value();
+            let osc2_waveform = self.params.osc2_waveform.value();
+            let osc2_coarse = self.params.osc2_coarse.value() as f32;
+            let osc2_sync = self.params.osc2_sync.value();
+            let sub_waveform = self.params.sub_waveform.value();
+            let sub_frequency_ratio = self.params.sub_octave.value().frequency_ratio();
+            let unison_voices = match self.params.voice_mode.mode.value() {
+                VoiceMode::UnisonMono => MAX_UNISON_VOICES,
+                _ => self.params.unison_voices.value() as usize,
+            };
+            let filter_type = self.params.filter_type.value();
+            let filter_slope = self.params.filter_slope.value();
+            let filter_drive_curve = self.params.filter_drive_curve.value();
+            let noise_color = self.params.noise_color.value();
+            let analog_settings = self.params.analog.settings(sample_rate);
+            let scaling_settings = self.params.scaling.settings();
+            let pwm_source = self.params.pwm_source.value();
+            let mod_slots: [ModSlot; NUM_MOD_SLOTS] =
+                std::array::from_fn(|slot_idx| self.params.mod_slots[slot_idx].slot());
+            let expression_slots = self.params.mpe.expression_slots();
+            let mod_wheel = self.mod_wheel;
+            let pitch_bend = self.pitch_bend;
+            let mpe_mode = self.params.mpe.mode.value();
+            let tuning = self.tuning;
+            let wavetable = &*self.wavetable;
+            let reference_frequency = self.params.reference_frequency.value();
+            let steal_fade_delta = 1.0 / (STEAL_FADE_MS / 1000.0 * sample_rate);
+            let bend_range = self.params.mpe.bend_range.value() as f32;
+            let member_bend_range = self.params.mpe.member_bend_range.value() as f32;
+
+            let voice_lfo_settings: [LfoSettings; NUM_VOICE_LFOS] = std::array::from_fn(|lfo_idx| {
+                self.params.lfos[lfo_idx].settings(sample_rate, tempo)
+            });
+            let global_lfo_settings = self.params.global_lfo.settings(sample_rate, tempo);
+            let block_pos_beats = pos_beats.zip(tempo).map(|(pos_beats, tempo)| {
+                pos_beats + block_start as f64 / sample_rate as f64 * tempo / 60.0
+            });
+            for (phase, settings) in self
+                .free_running_lfo_phases
+                .iter_mut()
+                .zip(&voice_lfo_settings)
+            {
+                if let Some(transport_phase) =
+                    block_pos_beats.and_then(|pos_beats| settings.transport_phase(pos_beats))
+                {
+                    *phase = transport_phase;
+                }
+            }
+            if !global_lfo_settings.retrigger {
+                if let Some(transport_phase) = block_pos_beats
+                    .and_then(|pos_beats| global_lfo_settings.transport_phase(pos_beats))
+                {
+                    self.global_lfo.set_phase(transport_phase);
+                }
+            }
+            let free_running_lfo_phases = self.free_running_lfo_phases;
+            let mut global_lfo_values = [0.0; MAX_BLOCK_SIZE];
+            for value in global_lfo_values.iter_mut().take(block_len) {
+                *value = self.global_lfo.next_value(&global_lfo_settings);
+            }
+
+            for card in self.voice_cards.iter_mut() {
+                card.advance(&analog_settings, block_len);
+            }
+
+            // Process voices
+            for (voice, card) in self
+                .voices
+                .iter_mut()
+                .zip(&self.voice_cards)
+                .filter_map(|(voice, card)| Some((voice.as_mut()?, card)))
+            {
+                let gain = voice_block_values(&voice.voice_gain, &gain, &mut voice_gain, block_len);
+                let pan = voice_block_values(&voice.voice_pan, &pan, &mut voice_pan, block_len);
+                let pulse_width = voice_block_values(
+                    &voice.voice_pulse_width,
+                    &pulse_width,
+                    &mut voice_pulse_width,
+                    block_len,
+                );
+                let fm_amount = voice_block_values(
+                    &voice.voice_fm_amount,
+                    &fm_amount,
+                    &mut voice_fm_amount,
+                    block_len,
+                );
+                let filter_env_amount = voice_block_values(
+                    &voice.voice_filter_env_amount,
+                    &filter_env_amount,
+                    &mut voice_filter_env_amount,
+                    block_len,
+                );
+                let filter_keytrack = voice_block_values(
+                    &voice.voice_filter_keytrack,
+                    &filter_keytrack,
+                    &mut voice_filter_keytrack,
+                    block_len,
+                );
+                let filter_velocity = voice_block_values(
+                    &voice.voice_filter_velocity,
+                    &filter_velocity,
+                    &mut voice_filter_velocity,
+                    block_len,
+                );
+                let filter_drive = voice_block_values(
+                    &voice.voice_filter_drive,
+                    &filter_drive,
+                    &mut voice_filter_drive,
+                    block_len,
+                );
+                let filter_cut = voice_block_values(
+                    &voice.voice_filter_cutoff,
+                    &filter_cut,
+                    &mut voice_filter_cut,
+                    block_len,
+                );
+                let filter_res = voice_block_values(
+                    &voice.voice_filter_resonance,
+                    &filter_res,
+                    &mut voice_filter_res,
+                    block_len,
+                );
+                let osc1_level = voice_block_values(
+                    &voice.voice_osc1_level,
+                    &osc1_level,
+                    &mut voice_osc1_level,
+                    block_len,
+                );
+                let osc2_level = voice_block_values(
+                    &voice.voice_osc2_level,
+                    &osc2_level,
+                    &mut voice_osc2_level,
+                    block_len,
+                );
+                let sub_level = voice_block_values(
+                    &voice.voice_sub_level,
+                    &sub_level,
+                    &mut voice_sub_level,
+                    block_len,
+                );
+                let noise_level = voice_block_values(
+                    &voice.voice_noise_level,
+                    &noise_level,
+                    &mut voice_noise_level,
+                    block_len,
+                );
+                let ring_level = voice_block_values(
+                    &voice.voice_ring_level,
+                    &ring_level,
+                    &mut voice_ring_level,
+                    block_len,
+                );
+
+                let voice_bend_channel = bend_channel(mpe_mode, voice.sequenced, voice.channel);
+                // The mod matrix is evaluated once per block from the sources' most recent values.
+                // Per-sample destinations interpolate between the last block's offsets and these.
+                let mod_sources = ModSourceValues {
+                    amp_envelope: voice.amp_envelope.previous_value(),
+                    filter_cut_envelope: voice.filter_cut_envelope.previous_value(),
+                    filter_res_envelope: voice.filter_res_envelope.previous_value(),
+                    pwm_envelope: voice.pwm_envelope.previous_value(),
+                    lfos: std::array::from_fn(|lfo_idx| voice.lfos[lfo_idx].previous_value()),
+                    global_lfo: global_lfo_values[0],
+                    velocity: voice.velocity,
+                    key: ((voice.note as f32 - KEYTRACK_CENTER_NOTE) / 60.0).clamp(-1.0, 1.0),
+                    mod_wheel,
+                    aftertouch: voice.pressure,
+                    brightness: voice.expression.brightness,
+                    expression: voice.expression.expression,
+                    pitch_bend: pitch_bend.value(mpe_mode, voice_bend_channel),
+                    random: voice.random,
+                };
+                voice.modulation.update(ModOffsets::evaluate(
+                    mod_slots.iter().chain(&expression_slots),
+                    &mod_sources,
+                ));
+                voice.expression.set_pitch(
+                    sample_rate,
+                    voice.expression.tuning
+                        + pitch_bend.semitones(
+                            mpe_mode,
+                            voice_bend_channel,
+                            bend_range,
+                            member_bend_range,
+                        ),
+                );
+                let voice_modulation = voice.modulation;
+                let modulation = voice_modulation.offsets();
+
+                // Parameters that are only read once per block advance their polyphonic modulation
+                // smoothers by an entire block at a time
+                let block_value = |param: &FloatParam, destination: ModDestination| {
+                    let value = match voice.block_poly_modulation(destination) {
+                        Some((_, smoother)) => smoother.next_step(block_len as u32),
+                        None => param.value(),
+                    };
+                    modulate(param, value, modulation.get(destination))
+                };
+                let amp_scaling =
+                    scaling_settings.amp_gain(voice.velocity_response, voice.note as f32);
+                let envelope_time_scale = scaling_settings
+                    .envelope_time_scale(voice.velocity_response, voice.note as f32);
+                let vary_envelope =
+                    |settings: EnvelopeSettings| card.vary_envelope(settings, &analog_settings);
+                let amp_envelope_settings = vary_envelope(
+                    self.params
+                        .amp_envelope_settings(block_value)
+                        .scale_times(envelope_time_scale),
+                );
+                let filter_cut_envelope_settings = vary_envelope(
+                    self.params
+                        .filter_cut_envelope_settings(block_value)
+                        .scale_times(envelope_time_scale),
+                );
+                let filter_res_envelope_settings = vary_envelope(
+                    self.params
+                        .filter_res_envelope_settings(block_value)
+                        .scale_times(envelope_time_scale),
+                );
+                let pwm_envelope_settings =
+                    vary_envelope(self.params.pwm_envelope_settings(block_value));
+                let pwm_excursion =
+                    block_value(&self.params.pwm_depth, ModDestination::PwmDepth)
+                        * MAX_PWM_EXCURSION;
+                let pwm_lfo_phase_delta =
+                    block_value(&self.params.pwm_rate, ModDestination::PwmRate) / sample_rate;
+                let osc2_frequency_ratio = 2.0f32.powf(
+                    (osc2_coarse
+                        + block_value(&self.params.osc2_fine, ModDestination::Osc2Fine) / 100.0)
+                        / 12.0,
+                );
+                let unison_layout = UnisonLayout::new(
+                    unison_voices,
+                    block_value(&self.params.unison_detune, ModDestination::UnisonDetune),
+                    block_value(&self.params.unison_spread, ModDestination::UnisonSpread),
+                    block_value(&self.params.unison_blend, ModDestination::UnisonBlend),
+                );
+
+                voice.amp_envelope.set_settings(amp_envelope_settings);
+                voice
+                    .filter_cut_envelope
+                    .set_settings(filter_cut_envelope_settings);
+                voice
+                    .filter_res_envelope
+                    .set_settings(filter_res_envelope_settings);
+                voice.pwm_envelope.set_settings(pwm_envelope_settings);
+                let lfo_settings: [LfoSettings; NUM_VOICE_LFOS] = std::array::from_fn(|lfo_idx| {
+                    modulation.modulate_lfo_settings(
+                        voice_lfo_settings[lfo_idx],
+                        &self.params.lfos[lfo_idx],
+                        &LFO_MOD_DESTINATIONS[lfo_idx],
+                        sample_rate,
+                    )
+                });
+
+                for filter in voice.filters.iter_mut() {
+                    filter.set_mode(filter_type, filter_slope);
+                }
+                for ((lfo, settings), free_running_phase) in voice
+                    .lfos
+                    .iter_mut()
+                    .zip(&lfo_settings)
+                    .zip(free_running_lfo_phases)
+                {
+                    if !settings.retrigger {
+                        lfo.set_phase(free_running_phase);
                     }
-                    
-                    for (value_idx, sample_idx) in (block_start..block_end).enumerate() {
-                        let envelope_time = voice_amp_envelope.get_time();
-                        let amp = voice.velocity_sqrt * gain[value_idx] * voice_amp_envelope.get_value(envelope_time);
-                        voice_amp_envelope.trigger();
-                    
-                        // Generate waveform
-                        let waveform = self.params.waveform.value();
-                        let mut generated_sample = generate_waveform(waveform, voice.phase);
-                    
-                        // Apply filter
-                        let filter_type = self.params.filter_type.value();
-                        let cutoff = self.params.filter_cut.value();
-                        let resonance = self.params.filter_res.value();
-                        let cutoff_attack = self.params.filter_cut_attack_ms.value();
-                        let cutoff_decay = self.params.filter_cut_decay_ms.value();
-                        let cutoff_sustain = self.params.filter_cut_sustain_ms.value();
-                        let cutoff_release = self.params.filter_cut_release_ms.value();
-                        let resonance_attack = self.params.filter_res_attack_ms.value();
-                        let resonance_decay = self.params.filter_res_decay_ms.value();
-                        let resonance_sustain = self.params.filter_res_sustain_ms.value();
-                        let resonance_release = self.params.filter_res_release_ms.value();
-                    
-                        let mut filtered_sample = generate_filter(
-                            filter_type,
-                            cutoff,
-                            resonance,
-                            cutoff_attack,
-                            cutoff_decay,
-                            cutoff_sustain,
-                            cutoff_release,
-                            resonance_attack,
-                            resonance_decay,
-                            resonance_sustain,
-                            resonance_release,
-                            generated_sample,
-                            sample_rate,
-                        );
-                        filtered_sample.set_sample_rate(sample_rate);
-                    
-                        // Apply envelope to each sample of the waveform
-                        for _ in 0..block_len {
-                            let processed_sample = filtered_sample.process(generated_sample);
-                    
-                            output[0][sample_idx] += processed_sample;
-                            output[1][sample_idx] += processed_sample;
-                    
-                            generated_sample = generated_sample * amp;
-                            voice.phase += voice.phase_delta;
-                            if voice.phase >= 1.0 {
-                                voice.phase -= 1.0;
-                            }
+                }
+
+                for (value_idx, sample_idx) in (block_start..block_end).enumerate() {
+                    let t = (value_idx + 1) as f32 / block_len as f32;
+                    let modulated = |value: f32, destination: ModDestination| match destination
+                        .param(&self.params)
+                    {
+                        Some(param) => {
+                            modulate(param, value, voice_modulation.interpolated(destination, t))
                         }
+                        None => value,
+                    };
+
+                    let mut lfo_modulation = LfoDestinations::default();
+                    for (lfo, settings) in voice.lfos.iter_mut().zip(&lfo_settings) {
+                        lfo_modulation.accumulate(lfo.next_value(settings), &settings.depths);
                     }
-                    
-            }
+                    lfo_modulation
+                        .accumulate(global_lfo_values[value_idx], &global_lfo_settings.depths);
+
+                    let amp = amp_scaling
+                        * voice.next_steal_fade_gain(steal_fade_delta)
+                        * voice.expression.volume
+                        * modulated(gain[value_idx], ModDestination::Gain)
+                        * voice.amp_envelope.next_value()
+                        * lfo_modulation.amp_gain();
+                    let cutoff_envelope = voice.filter_cut_envelope.next_value();
+                    let resonance_envelope = voice.filter_res_envelope.next_value();
 
-            // Process voice termination
-            let mut terminated_voices = Vec::new(); // Track the voices to terminate
-            for (voice_idx, voice) in self.voices.iter_mut().enumerate() {
-                if let Some(voice) = voice {
-                    if voice.amp_envelope.get_state() == ADSREnvelopeState::Idle {
-                        // Voice has reached the idle state
-                        terminated_voices.push(voice_idx);
+                    // The envelope, keyboard tracking, velocity and the voice card all move the
+                    // cutoff in octaves
+                    let filter_env_amount =
+                        modulated(filter_env_amount[value_idx], ModDestination::FilterEnvAmount);
+                    let filter_keytrack =
+                        modulated(filter_keytrack[value_idx], ModDestination::FilterKeytrack);
+                    let filter_velocity =
+                        modulated(filter_velocity[value_idx], ModDestination::FilterVelocity);
+                    let cutoff_octaves = filter_env_amount * cutoff_envelope
+                        + filter_keytrack * (voice.note as f32 - KEYTRACK_CENTER_NOTE) / 12.0
+                        + filter_velocity * MAX_VELOCITY_CUTOFF_OCTAVES * (voice.velocity - 1.0)
+                        + lfo_modulation.cutoff
+                        + card.cutoff_octaves(&analog_settings);
+                    let modulated_cutoff =
+                        modulated(filter_cut[value_idx], ModDestination::FilterCutoff)
+                            * cutoff_octaves.exp2();
+                    let modulated_resonance =
+                        (modulated(filter_res[value_idx], ModDestination::FilterResonance)
+                            * resonance_envelope
+                            + lfo_modulation.resonance)
+                            .clamp(0.0, 1.0);
+                    for filter in voice.filters.iter_mut() {
+                        filter.set_cutoff(modulated_cutoff);
+                        filter.set_resonance(modulated_resonance);
+                    }
+                    let drive_gain = util::db_to_gain_fast(modulated(
+                        filter_drive[value_idx],
+                        ModDestination::FilterDrive,
+                    ));
+
+                    // The PWM envelope needs to keep running even if it's not the active source so
+                    // switching sources mid-note behaves predictably
+                    let pwm_envelope = voice.pwm_envelope.next_value();
+                    let pwm_modulation = match pwm_source {
+                        PwmSource::Off => 0.0,
+                        PwmSource::Lfo => (voice.pwm_lfo_phase * std::f32::consts::TAU).sin(),
+                        PwmSource::Envelope => pwm_envelope,
+                    };
+                    voice.pwm_lfo_phase += pwm_lfo_phase_delta;
+                    if voice.pwm_lfo_phase >= 1.0 {
+                        voice.pwm_lfo_phase -= 1.0;
+                    }
+                    let modulated_pulse_width = (modulated(
+                        pulse_width[value_idx],
+                        ModDestination::PulseWidth,
+                    ) + pwm_modulation * pwm_excursion
+                        + lfo_modulation.pulse_width * MAX_PWM_EXCURSION)
+                        .clamp(0.05, 0.95);
+                    let pitch_semitones = lfo_modulation.pitch
+                        + voice_modulation.interpolated(ModDestination::Pitch, t) * MOD_PITCH_RANGE
+                        + voice.expression.next_pitch()
+                        + voice.glide.next_offset()
+                        + voice.lfos[0].previous_value()
+                            * voice.expression.vibrato
+                            * MAX_EXPRESSION_VIBRATO
+                        + card.drift_semitones(&analog_settings);
+                    // Pitch modulation moves through the tuning's keys rather than through
+                    // semitones
+                    let note_phase_delta = tuning
+                        .frequency(reference_frequency, voice.note as f32 + pitch_semitones)
+                        / sample_rate;
+
+                    let osc1_level = modulated(osc1_level[value_idx], ModDestination::Osc1Level);
+                    let osc2_level = modulated(osc2_level[value_idx], ModDestination::Osc2Level);
+                    let ring_level = modulated(ring_level[value_idx], ModDestination::RingLevel);
+                    let fm_index =
+                        modulated(fm_amount[value_idx], ModDestination::FmAmount) * MAX_FM_INDEX;
+                    let wavetable_position = modulated(
+                        wavetable_position[value_idx],
+                        ModDestination::WavetablePosition,
+                    );
+
+                    // The first and second oscillators are stacked for unison, the sub-oscillator
+                    // and the noise source are not
+                    let mut left_sample = 0.0;
+                    let mut right_sample = 0.0;
+                    for unison_idx in 0..unison_layout.num_voices {
+                        let phase_delta =
+                            note_phase_delta * unison_layout.frequency_ratios[unison_idx];
+                        let osc2_phase_delta = phase_delta * osc2_frequency_ratio;
+                        let phase = &mut voice.phases[unison_idx];
+                        let osc2_phase = &mut voice.osc2_phases[unison_idx];
+
+                        let osc2_sample = oscillator_sample(
+                            osc2_waveform,
+                            &mut voice.noise,
+                            noise_color,
+                            wavetable,
+                            wavetable_position,
+                            *osc2_phase,
+                            osc2_phase_delta,
+                            modulated_pulse_width,
+                        );
+                        // Through-zero linear FM lets the first oscillator's phase run backwards.
+                        // The phase delta is kept below the Nyquist frequency so the phase can't
+                        // skip cycles.
+                        let osc1_phase_delta = (phase_delta
+                            * (1.0 + osc2_sample * fm_index))
+                            .clamp(-0.5, 0.5);
+                        let osc1_sample = oscillator_sample(
+                            waveform,
+                            &mut voice.noise,
+                            noise_color,
+                            wavetable,
+                            wavetable_position,
+                            *phase,
+                            osc1_phase_delta.abs(),
+                            modulated_pulse_width,
+                        );
+
+                        let osc_sample = osc1_sample * osc1_level
+                            + osc2_sample * osc2_level
+                            + osc1_sample * osc2_sample * ring_level;
+                        left_sample += osc_sample * unison_layout.left_gains[unison_idx];
+                        right_sample += osc_sample * unison_layout.right_gains[unison_idx];
+
+                        let osc1_wrapped = advance_phase(phase, osc1_phase_delta);
+                        if osc2_sync && osc1_wrapped {
+                            // Restart the second oscillator at the point within this sample where
+                            // the first oscillator's cycle restarted
+                            let samples_since_wrap = if osc1_phase_delta > 0.0 {
+                                *phase / osc1_phase_delta
+                            } else {
+                                (1.0 - *phase) / -osc1_phase_delta
+                            };
+                            *osc2_phase = (samples_since_wrap * osc2_phase_delta).min(0.999_999);
+                        } else {
+                            advance_phase(osc2_phase, osc2_phase_delta);
+                        }
                     }
+
+                    // The sub-oscillator is not affected by PWM so it stays a solid foundation
+                    let sub_phase_delta = note_phase_delta * sub_frequency_ratio;
+                    let sub_sample = oscillator_sample(
+                        sub_waveform,
+                        &mut voice.noise,
+                        noise_color,
+                        wavetable,
+                        wavetable_position,
+                        voice.sub_phase,
+                        sub_phase_delta,
+                        0.5,
+                    );
+                    advance_phase(&mut voice.sub_phase, sub_phase_delta);
+                    let sub_level = modulated(sub_level[value_idx], ModDestination::SubLevel);
+                    let noise_level = modulated(noise_level[value_idx], ModDestination::NoiseLevel);
+                    let noise_sample = if noise_level > 0.0 {
+                        voice.noise.next(noise_color)
+                    } else {
+                        0.0
+                    };
+                    let center_sample = sub_sample * sub_level + noise_sample * noise_level;
+
+                    let [left_filter, right_filter] = &mut voice.filters;
+                    let left_sample =
+                        filter_drive_curve.process(left_sample + center_sample, drive_gain);
+                    let right_sample =
+                        filter_drive_curve.process(right_sample + center_sample, drive_gain);
+                    // The voice's panning uses the same balance law as the unison spread
+                    let pan = (modulated(pan[value_idx], ModDestination::Pan)
+                        + lfo_modulation.pan
+                        + voice.expression.pan)
+                        .clamp(-1.0, 1.0);
+                    output[0][sample_idx] +=
+                        left_filter.process(left_sample) * amp * (1.0 - pan).min(1.0);
+                    output[1][sample_idx] +=
+                        right_filter.process(right_sample) * amp * (1.0 + pan).min(1.0);
                 }
             }
 
-            // Terminate the voices outside the loop to avoid modifying the vector while iterating
-            for voice_idx in terminated_voices {
-                if let Some(voice) = self.voices[voice_idx].take() {
-                    context.send_event(NoteEvent::VoiceTerminated {
-                        timing: block_end as u32,
-                        voice_id: Some(voice.voice_id),
-                        channel: voice.channel,
-                        note: voice.note,
-                    });
-                }
+            let mut effect_mixes = [[0.0; MAX_BLOCK_SIZE]; NUM_EFFECTS];
+            for (kind, mixes) in enum_iterator::all::<EffectKind>().zip(&mut effect_mixes) {
+                let mix = match kind {
+                    EffectKind::Distortion => &self.params.distortion.mix,
+                    EffectKind::Chorus => &self.params.chorus.mix,
+                    EffectKind::Delay => &self.params.delay.mix,
+                    EffectKind::Reverb => &self.params.reverb.mix,
+                };
+                mix.smoothed.next_block(mixes, block_len);
             }
+            let (left, right) = output.split_at_mut(1);
+            self.effects.process(
+                &mut left[0][block_start..block_end],
+                &mut right[0][block_start..block_end],
+                &effects_settings,
+                &effect_mixes,
+            );
 
+            for (phase, settings) in self
+                .free_running_lfo_phases
+                .iter_mut()
+                .zip(&voice_lfo_settings)
+            {
+                *phase = (*phase + settings.phase_delta * block_len as f32).fract();
+            }
 
+            // Process voice termination. Stolen voices have already been reported as terminated.
+            for voice in self.voices.iter_mut() {
+                match voice {
+                    Some(v)
+                        if v.steal_fade == Some(0.0)
+                            || (v.is_stolen()
+                                && v.amp_envelope.get_state() == ADSREnvelopeState::Idle) =>
+                    {
+                        *voice = None;
+                    }
+                    Some(v) if v.amp_envelope.get_state() == ADSREnvelopeState::Idle => {
+                        context.send_event(NoteEvent::VoiceTerminated {
+                            timing: block_end as u32,
+                            voice_id: Some(v.voice_id),
+                            channel: v.channel,
+                            note: v.note,
+                        });
+                        *voice = None;
+                    }
+                    _ => (),
+                }
+            }
 
+            self.arp.advance(block_len, &arp_settings);
+            self.sequencer.advance(block_len, &sequencer_settings);
             block_start = block_end;
             block_end = (block_start + MAX_BLOCK_SIZE).min(num_samples);
         }
 
-        ProcessStatus::Normal
+        // Delays and reverbs keep ringing out after the last voice has stopped
+        match effects_settings.tail_samples(sample_rate) {
+            0 => ProcessStatus::Normal,
+            tail => ProcessStatus::Tail(tail),
+        }
     }
-}    
-impl SubSynth {
+
     fn get_voice_idx(&mut self, voice_id: i32) -> Option<usize> {
         self.voices
             .iter_mut()
-            .position(|voice| matches!(voice, Some(voice) if voice.voice_id == voice_id))
+            .position(|voice| {
+                matches!(voice, Some(voice) if voice.voice_id == voice_id && !voice.is_stolen())
+            })
+    }
+
+    /// Handle a key being pressed. A note that's struck again while a pedal still holds it first
+    /// releases the old note.
+    fn note_on(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+        sample_offset: u32,
+        held_note: HeldNote,
+    ) {
+        if self.tuning.is_mapped(held_note.note)
+            && self.pedals.key_down(held_note.channel, held_note.note)
+        {
+            self.release_note(
+                context,
+                sample_offset,
+                None,
+                false,
+                held_note.channel,
+                held_note.note,
+            );
+        }
+
+        self.play_note(context, sample_offset, held_note);
+    }
+
+    /// Start playing a note. In the monophonic voice modes this may reuse the playing voice, or the
+    /// note may only be added to the held notes if a note with a higher priority is playing. Unlike
+    /// [`note_on()`][Self::note_on()] this bypasses the pedals, which only hold keys and not the
+    /// arpeggiator's or the sequencer's notes.
+    fn play_note(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+        sample_offset: u32,
+        held_note: HeldNote,
+    ) {
+        let voice_mode = self.params.voice_mode.mode.value();
+        let priority = self.params.voice_mode.priority.value();
+        // Keys that the keyboard mapping leaves unmapped are silent
+        if !self.tuning.is_mapped(held_note.note) {
+            return;
+        }
+        // Whether another note was still held when this note was played
+        let legato = !self.held_notes.is_empty();
+        self.held_notes.push(held_note);
+
+        if voice_mode.is_monophonic() {
+            if self.held_notes.priority_note(priority) != Some(held_note) {
+                return;
+            }
+
+            if let Some(voice_idx) = self.mono_voice_idx() {
+                self.change_mono_note(context, sample_offset, voice_idx, held_note, legato);
+                return;
+            }
+        }
+
+        let previous_note = self.last_note;
+        let glide_mode = self.params.voice_mode.glide_mode.value();
+        let glide_time_ms = self.params.voice_mode.glide_time_ms.value();
+        let glide = !self.params.voice_mode.glide_legato.value() || legato;
+        let sample_rate = self.sample_rate;
+
+        let voice = self.start_note(context, sample_offset, held_note);
+        if let (Some(previous_note), true) = (previous_note, glide) {
+            voice.glide.start(
+                held_note.note as f32 - previous_note as f32,
+                glide_mode,
+                glide_time_ms,
+                sample_rate,
+            );
+        }
+    }
+
+    /// Play and release the arpeggiator's notes that are due at `sample_offset`. These are also
+    /// sent to the plugin's MIDI output if that's enabled.
+    fn run_arpeggiator(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+        sample_offset: u32,
+        settings: &ArpSettings,
+    ) {
+        // The arpeggiator forgets its notes when the plugin is reset, so their note offs are sent
+        // here instead
+        let midi_notes = self.arp_midi_notes;
+        for (channel, note) in midi_notes.iter() {
+            if !self.arp.is_playing(channel, note) {
+                self.send_arp_midi_note_off(context, sample_offset, channel, note);
+            }
+        }
+
+        while let Some(event) = self.arp.next_event(settings) {
+            match event {
+                ArpEvent::NoteOff(notes) => {
+                    for (channel, note) in notes.iter() {
+                        // The pedals don't hold the arpeggiator's notes
+                        self.held_notes.remove(channel, note);
+                        self.release_note(context, sample_offset, None, false, channel, note);
+                        self.send_arp_midi_note_off(context, sample_offset, channel, note);
+                    }
+                }
+                ArpEvent::NoteOn(notes) => {
+                    for held_note in notes.iter() {
+                        self.play_note(context, sample_offset, held_note);
+                        if settings.midi_output {
+                            self.arp_midi_notes
+                                .insert(held_note.channel, held_note.note);
+                            context.send_event(NoteEvent::NoteOn {
+                                timing: sample_offset,
+                                voice_id: None,
+                                channel: held_note.channel,
+                                note: held_note.note,
+                                velocity: held_note.velocity,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send a note off to the MIDI output if the arpeggiator sent a note on for the note there.
+    fn send_arp_midi_note_off(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+        sample_offset: u32,
+        channel: u8,
+        note: u8,
+    ) {
+        if self.arp_midi_notes.contains(channel, note) {
+            self.arp_midi_notes.remove(channel, note);
+            context.send_event(NoteEvent::NoteOff {
+                timing: sample_offset,
+                voice_id: None,
+                channel,
+                note,
+                velocity: 0.0,
+            });
+        }
+    }
+
+    /// Play and release the step sequencer's notes that are due at `sample_offset`. A step's
+    /// parameter locks are applied to the voice that plays its note.
+    fn run_sequencer(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+        sample_offset: u32,
+        settings: &SequencerSettings,
+    ) {
+        while let Some(event) = self.sequencer.next_event(settings) {
+            match event {
+                SequencerEvent::NoteOff(note) => {
+                    self.held_notes.remove_sequenced(note);
+                    self.release_note(context, sample_offset, None, true, SEQUENCER_CHANNEL, note);
+                }
+                SequencerEvent::NoteOn(step) => {
+                    let held_note = HeldNote {
+                        voice_id: Some(compute_sequencer_voice_id(step.note)),
+                        channel: SEQUENCER_CHANNEL,
+                        note: step.note,
+                        velocity: step.velocity,
+                        sequenced: true,
+                    };
+                    self.play_note(context, sample_offset, held_note);
+
+                    let locks = [
+                        (FILTER_CUTOFF_POLY_MOD_ID, step.cutoff_lock),
+                        (FILTER_RESONANCE_POLY_MOD_ID, step.resonance_lock),
+                    ];
+                    if let Some(voice_idx) = self.newest_sequenced_voice_idx(step.note) {
+                        let voice = self.voices[voice_idx].as_mut().unwrap();
+                        for (poly_modulation_id, normalized_value) in locks {
+                            if let Some(normalized_value) = normalized_value {
+                                lock_voice_param(
+                                    &self.params,
+                                    voice,
+                                    poly_modulation_id,
+                                    normalized_value,
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// The most recently started sequencer voice that's playing `note` and that's not being
+    /// released.
+    fn newest_sequenced_voice_idx(&self, note: u8) -> Option<usize> {
+        self.voices
+            .iter()
+            .enumerate()
+            .filter_map(|(voice_idx, voice)| Some((voice_idx, voice.as_ref()?)))
+            .filter(|(_, voice)| {
+                voice.sequenced
+                    && voice.note == note
+                    && !voice.releasing
+                    && !voice.is_stolen()
+            })
+            .max_by_key(|(_, voice)| voice.internal_voice_id)
+            .map(|(voice_idx, _)| voice_idx)
+    }
+
+    /// Handle a note's key being released. The note keeps playing if the sustain or sostenuto
+    /// pedal holds it.
+    fn note_off(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+        sample_offset: u32,
+        voice_id: Option<i32>,
+        channel: u8,
+        note: u8,
+    ) {
+        self.held_notes.remove(channel, note);
+        if self.pedals.key_up(channel, note) {
+            self.release_note(context, sample_offset, voice_id, false, channel, note);
+        }
+    }
+
+    /// Release the notes a pedal stopped holding.
+    fn release_notes(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+        sample_offset: u32,
+        notes: NoteSet,
+    ) {
+        for (channel, note) in notes.iter() {
+            self.release_note(context, sample_offset, None, false, channel, note);
+        }
+    }
+
+    /// Stop playing a key's note, or the step sequencer's note if `sequenced` is set. In the
+    /// monophonic voice modes the voice moves on to the next held note instead if there is one.
+    fn release_note(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+        sample_offset: u32,
+        voice_id: Option<i32>,
+        sequenced: bool,
+        channel: u8,
+        note: u8,
+    ) {
+        if self.params.voice_mode.mode.value().is_monophonic() {
+            let priority = self.params.voice_mode.priority.value();
+            let playing_voice_idx = self.mono_voice_idx().filter(|voice_idx| {
+                let voice = self.voices[*voice_idx].as_ref().unwrap();
+                !voice.releasing
+                    && voice.sequenced == sequenced
+                    && voice.channel == channel
+                    && voice.note == note
+            });
+            if let (Some(voice_idx), Some(next_note)) =
+                (playing_voice_idx, self.held_notes.priority_note(priority))
+            {
+                self.change_mono_note(context, sample_offset, voice_idx, next_note, true);
+                return;
+            }
+        }
+
+        self.start_release_for_voices(voice_id, sequenced, channel, note);
+    }
+
+    /// The voice used by the monophonic voice modes. This is the most recently started voice, even
+    /// if it's releasing, so a new note cuts off the previous note's release.
+    fn mono_voice_idx(&self) -> Option<usize> {
+        self.voices
+            .iter()
+            .enumerate()
+            .filter_map(|(voice_idx, voice)| Some((voice_idx, voice.as_ref()?)))
+            .filter(|(_, voice)| !voice.is_stolen())
+            .max_by_key(|(_, voice)| voice.internal_voice_id)
+            .map(|(voice_idx, _)| voice_idx)
+    }
+
+    /// Switch the monophonic voice over to a new note. `legato` indicates whether the previous
+    /// note was still held.
+    fn change_mono_note(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+        sample_offset: u32,
+        voice_idx: usize,
+        held_note: HeldNote,
+        legato: bool,
+    ) {
+        let retrigger = self.params.voice_mode.mode.value() != VoiceMode::Legato || !legato;
+        let glide_mode = self.params.voice_mode.glide_mode.value();
+        let glide_time_ms = self.params.voice_mode.glide_time_ms.value();
+        let glide = !self.params.voice_mode.glide_legato.value() || legato;
+        let sample_rate = self.sample_rate;
+        let velocity_response = self
+            .params
+            .scaling
+            .settings()
+            .velocity_response(held_note.velocity);
+        self.last_note = Some(held_note.note);
+
+        let voice = self.voices[voice_idx].as_mut().unwrap();
+        let voice_id = held_note
+            .voice_id
+            .unwrap_or_else(|| compute_fallback_voice_id(held_note.note, held_note.channel));
+        if voice_id != voice.voice_id {
+            // As far as the host is concerned the previous note's voice has ended
+            context.send_event(NoteEvent::VoiceTerminated {
+                timing: sample_offset,
+                voice_id: Some(voice.voice_id),
+                channel: voice.channel,
+                note: voice.note,
+            });
+        }
+
+        let interval = held_note.note as f32 - voice.note as f32;
+        if glide {
+            voice
+                .glide
+                .start(interval, glide_mode, glide_time_ms, sample_rate);
+        } else {
+            voice.glide.stop();
+        }
+
+        voice.voice_id = voice_id;
+        voice.channel = held_note.channel;
+        voice.note = held_note.note;
+        voice.sequenced = held_note.sequenced;
+        // A releasing voice always needs to be retriggered, even in legato mode
+        if retrigger || voice.releasing {
+            voice.trigger(held_note.velocity, velocity_response);
+        }
+    }
+
+    /// Start a new voice for a note.
+    fn start_note(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+        sample_offset: u32,
+        held_note: HeldNote,
+    ) -> &mut Voice {
+        let HeldNote {
+            voice_id,
+            channel,
+            note,
+            velocity,
+            sequenced,
+        } = held_note;
+
+        let mut initial_phases = [0.0; MAX_UNISON_VOICES];
+        let mut osc2_initial_phases = [0.0; MAX_UNISON_VOICES];
+        for (phase, osc2_phase) in initial_phases.iter_mut().zip(osc2_initial_phases.iter_mut()) {
+            *phase = self.prng.gen();
+            *osc2_phase = self.prng.gen();
+        }
+        let sub_frequency_ratio = self.params.sub_octave.value().frequency_ratio();
+        let random = self.prng.gen_range(-1.0..1.0);
+        // Channel pressure doesn't apply to the sequencer's notes
+        let pressure = if sequenced {
+            0.0
+        } else {
+            self.channel_pressure[channel as usize]
+        };
+        let lfo_seeds: [u64; NUM_VOICE_LFOS] = [(); NUM_VOICE_LFOS].map(|_| self.prng.gen());
+        let noise_seed: u64 = self.prng.gen();
+        let velocity_response = self.params.scaling.settings().velocity_response(velocity);
+        let lfo_initial_phases: [f32; NUM_VOICE_LFOS] = std::array::from_fn(|lfo_idx| {
+            if self.params.lfos[lfo_idx].retrigger.value() {
+                0.0
+            } else {
+                self.free_running_lfo_phases[lfo_idx]
+            }
+        });
+
+        // The global LFO restarts when a note is played while no other notes are active
+        if self.params.global_lfo.retrigger.value()
+            && !self.voices.iter().flatten().any(|voice| !voice.releasing)
+        {
+            self.global_lfo.trigger(0.0);
+        }
+
+        self.last_note = Some(note);
+        let voice = self.start_voice(context, sample_offset, voice_id, sequenced, channel, note);
+        voice.pressure = pressure;
+        voice.random = random;
+        voice.phases = initial_phases;
+        voice.osc2_phases = osc2_initial_phases;
+        // The sub-oscillator starts in step with the main oscillator
+        voice.sub_phase = initial_phases[0] * sub_frequency_ratio;
+        voice.trigger(velocity, velocity_response);
+        voice.lfos = lfo_seeds.map(Lfo::new);
+        voice.noise = Noise::new(noise_seed);
+        for (lfo, phase) in voice.lfos.iter_mut().zip(lfo_initial_phases) {
+            lfo.trigger(phase);
+        }
+
+        voice
     }
 
     fn start_voice(
@@ -592,83 +2492,213 @@ impl SubSynth {
         context: &mut impl ProcessContext<Self>,
         sample_offset: u32,
         voice_id: Option<i32>,
+        sequenced: bool,
         channel: u8,
         note: u8,
     ) -> &mut Voice {
+        // The sequencer's voices don't follow the brightness of the channel they're reported on
+        let brightness = if sequenced {
+            0.0
+        } else {
+            self.channel_brightness[channel as usize]
+        };
         let new_voice = Voice {
             voice_id: voice_id.unwrap_or_else(|| compute_fallback_voice_id(note, channel)),
             internal_voice_id: self.next_internal_voice_id,
             channel,
             note,
-            velocity_sqrt: 1.0,
-    
-            phase: 0.0,
-            phase_delta: 0.0,
+            sequenced,
+            velocity: 1.0,
+            velocity_response: 1.0,
+            pressure: 0.0,
+            random: 0.0,
+
+            phases: [0.0; MAX_UNISON_VOICES],
+            osc2_phases: [0.0; MAX_UNISON_VOICES],
+            sub_phase: 0.0,
             releasing: false,
             amp_envelope: ADSREnvelope::new(
-                self.params.amp_attack_ms.value(),
-                self.params.amp_decay_ms.value(),
-                self.params.amp_sustain_level.value(),
-                self.params.amp_release_ms.value(),
+                self.params.amp_envelope_settings(unmodulated_value),
+                self.sample_rate,
             ),
             voice_gain: None,
-            filter_cut_envelope: Smoother::new(SmoothingStyle::Linear(0.0)),
-            filter_res_envelope: Smoother::new(SmoothingStyle::Linear(0.0)),
-    
-            filter: Some(self.params.filter_type.value()),
+            voice_pulse_width: None,
+            voice_fm_amount: None,
+            voice_filter_env_amount: None,
+            voice_filter_keytrack: None,
+            voice_filter_velocity: None,
+            voice_filter_drive: None,
+            voice_filter_cutoff: None,
+            voice_filter_resonance: None,
+            voice_unison_detune: None,
+            voice_osc2_fine: None,
+            voice_pan: None,
+            voice_amp_attack: None,
+            voice_amp_decay: None,
+            voice_amp_release: None,
+            voice_filter_cut_attack: None,
+            voice_filter_cut_decay: None,
+            voice_filter_cut_release: None,
+            voice_filter_res_attack: None,
+            voice_filter_res_decay: None,
+            voice_filter_res_release: None,
+            voice_osc1_level: None,
+            voice_osc2_level: None,
+            voice_sub_level: None,
+            voice_noise_level: None,
+            voice_ring_level: None,
+            pwm_lfo_phase: 0.0,
+            pwm_envelope: ADSREnvelope::new(
+                self.params.pwm_envelope_settings(unmodulated_value),
+                self.sample_rate,
+            ),
+            filter_cut_envelope: ADSREnvelope::new(
+                self.params.filter_cut_envelope_settings(unmodulated_value),
+                self.sample_rate,
+            ),
+            filter_res_envelope: ADSREnvelope::new(
+                self.params.filter_res_envelope_settings(unmodulated_value),
+                self.sample_rate,
+            ),
+            filters: [0; 2].map(|_| {
+                VoiceFilter::new(
+                    self.params.filter_type.value(),
+                    self.params.filter_slope.value(),
+                    self.params.filter_cut.value(),
+                    self.params.filter_res.value(),
+                    self.sample_rate,
+                )
+            }),
+            lfos: [0; NUM_VOICE_LFOS].map(|_| Lfo::new(0)),
+            noise: Noise::new(0),
+            modulation: VoiceModulation::default(),
+            expression: VoiceExpression::new(
+                self.params.mpe.glide_ms.value(),
+                self.pitch_bend_semitones(sequenced, channel),
+                brightness,
+            ),
+            glide: Glide::default(),
+            steal_fade: None,
         };
-    
         self.next_internal_voice_id = self.next_internal_voice_id.wrapping_add(1);
-    
-        if let Some(free_voice_idx) = self.voices.iter().position(|voice| voice.is_none()) {
-            let voice = &mut self.voices[free_voice_idx];
-            if voice.is_none() {
-                *voice = Some(new_voice);
-                voice.as_mut().unwrap().amp_envelope.trigger();
-            }
-            voice.as_mut().unwrap()
-        } else {
-            let oldest_voice = self
-                .voices
-                .iter_mut()
-                .min_by_key(|voice| voice.as_ref().unwrap().internal_voice_id)
-                .unwrap();
-            let oldest_voice = oldest_voice.as_mut().unwrap();
-    
+
+        // Voices are stolen until there's room for the new voice. The polyphony may have been
+        // lowered while more voices were playing.
+        let polyphony = self.params.voice_mode.polyphony.value() as usize;
+        let steal_mode = self.params.voice_mode.steal_mode.value();
+        while self.voices.iter().flatten().filter(|voice| !voice.is_stolen()).count() >= polyphony {
+            let candidates = self.voices.iter().enumerate().filter_map(|(voice_idx, voice)| {
+                let voice = voice.as_ref().filter(|voice| !voice.is_stolen())?;
+                Some(StealCandidate {
+                    voice_idx,
+                    internal_voice_id: voice.internal_voice_id,
+                    channel: voice.channel,
+                    note: voice.note,
+                    releasing: voice.releasing,
+                    level: voice.amp_envelope.previous_value(),
+                })
+            });
+            let Some(stolen_voice_idx) = steal_mode.choose(candidates, channel, note) else {
+                break;
+            };
+
+            let stolen_voice = self.voices[stolen_voice_idx].as_mut().unwrap();
             context.send_event(NoteEvent::VoiceTerminated {
                 timing: sample_offset,
-                voice_id: Some(oldest_voice.voice_id),
-                channel: oldest_voice.channel,
-                note: oldest_voice.note,
+                voice_id: Some(stolen_voice.voice_id),
+                channel: stolen_voice.channel,
+                note: stolen_voice.note,
             });
-    
-            *oldest_voice = new_voice;
-            oldest_voice
+            stolen_voice.steal_fade = Some(1.0);
         }
+
+        // If every free slot is taken up by stolen voices that are still fading out, then the
+        // quietest of those is cut off
+        let voice_idx = match self.voices.iter().position(|voice| voice.is_none()) {
+            Some(free_voice_idx) => free_voice_idx,
+            None => (0..NUM_VOICE_SLOTS)
+                .min_by(|a, b| {
+                    let fade_gain = |voice_idx: &usize| {
+                        self.voices[*voice_idx]
+                            .as_ref()
+                            .and_then(|voice| voice.steal_fade)
+                            .unwrap_or(f32::INFINITY)
+                    };
+                    fade_gain(a).total_cmp(&fade_gain(b))
+                })
+                .unwrap(),
+        };
+
+        self.voices[voice_idx].insert(new_voice)
+    }
+
+    /// The voices a note expression event applies to.
+    fn expression_voices(
+        &mut self,
+        voice_id: Option<i32>,
+        channel: u8,
+        note: u8,
+    ) -> impl Iterator<Item = &mut Voice> {
+        self.voices.iter_mut().flatten().filter(move |voice| {
+            !voice.is_stolen()
+                && (voice_id == Some(voice.voice_id)
+                    || (!voice.sequenced && channel == voice.channel && note == voice.note))
+        })
     }
-    
-    
-    
 
+    /// The current pitch bend in semitones for a note on `channel`.
+    fn pitch_bend_semitones(&self, sequenced: bool, channel: u8) -> f32 {
+        let mpe_mode = self.params.mpe.mode.value();
+        self.pitch_bend.semitones(
+            mpe_mode,
+            bend_channel(mpe_mode, sequenced, channel),
+            self.params.mpe.bend_range.value() as f32,
+            self.params.mpe.member_bend_range.value() as f32,
+        )
+    }
+
+    /// Release the voice with `voice_id`, or all voices playing `note` on `channel`. Voices that
+    /// are already releasing are skipped, so a note that was played again while its previous voice
+    /// was still ringing out releases the new voice and not the old one.
     fn start_release_for_voices(
         &mut self,
-        sample_rate: f32,
         voice_id: Option<i32>,
+        sequenced: bool,
         channel: u8,
         note: u8,
     ) {
         for voice in self.voices.iter_mut() {
-            if let Some(voice) = voice {
-                if voice_id == Some(voice.voice_id) || (channel == voice.channel && note == voice.note) {
-                    voice.amp_envelope.release();
+            match voice {
+                Some(Voice {
+                    voice_id: candidate_voice_id,
+                    channel: candidate_channel,
+                    note: candidate_note,
+                    sequenced: candidate_sequenced,
+                    releasing: releasing @ false,
+                    amp_envelope,
+                    filter_cut_envelope,
+                    filter_res_envelope,
+                    pwm_envelope,
+                    steal_fade: None,
+                    ..
+                }) if voice_id == Some(*candidate_voice_id)
+                    || (sequenced == *candidate_sequenced
+                        && channel == *candidate_channel
+                        && note == *candidate_note) =>
+                {
+                    *releasing = true;
+                    amp_envelope.release();
+                    filter_cut_envelope.release();
+                    filter_res_envelope.release();
+                    pwm_envelope.release();
+                    if voice_id.is_some() {
+                        return;
+                    }
                 }
+                _ => (),
             }
         }
     }
-    
-
-    
-    
 
     fn choke_voices(
         &mut self,
@@ -684,9 +2714,13 @@ impl SubSynth {
                     voice_id: candidate_voice_id,
                     channel: candidate_channel,
                     note: candidate_note,
+                    sequenced: candidate_sequenced,
+                    steal_fade: None,
                     ..
                 }) if voice_id == Some(*candidate_voice_id)
-                    || (channel == *candidate_channel && note == *candidate_note) =>
+                    || (!*candidate_sequenced
+                        && channel == *candidate_channel
+                        && note == *candidate_note) =>
                 {
                     context.send_event(NoteEvent::VoiceTerminated {
                         timing: sample_offset,
@@ -694,7 +2728,7 @@ impl SubSynth {
                         channel,
                         note,
                     });
-                    //*voice = None;
+                    *voice = None;
 
                     if voice_id.is_some() {
                         return;
@@ -714,6 +2748,21 @@ const fn compute_fallback_voice_id(note: u8, channel: u8) -> i32 {
     note as i32 | ((channel as i32) << 16)
 }
 
+/// The voice ID for the step sequencer's `note`. This sets a bit above the fallback voice IDs'
+/// channel bits, so it never matches a key's voice ID.
+const fn compute_sequencer_voice_id(note: u8) -> i32 {
+    note as i32 | (1 << 20)
+}
+
+/// The channel whose pitch bend a voice follows. The step sequencer's voices don't belong to an MPE
+/// member channel, so they only follow the zone's master channel.
+fn bend_channel(mpe_mode: MpeMode, sequenced: bool, channel: u8) -> u8 {
+    match mpe_mode.master_channel() {
+        Some(master_channel) if sequenced => master_channel,
+        _ => channel,
+    }
+}
+
 impl ClapPlugin for SubSynth {
     const CLAP_ID: &'static str = "art.taellinglin";
     const CLAP_DESCRIPTION: Option<&'static str> =
@@ -727,7 +2776,7 @@ impl ClapPlugin for SubSynth {
     ];
 
     const CLAP_POLY_MODULATION_CONFIG: Option<PolyModulationConfig> = Some(PolyModulationConfig {
-        max_voice_capacity: NUM_VOICES,
+        max_voice_capacity: MAX_POLYPHONY,
         supports_overlapping_voices: true,
     });
 }
@@ -744,3 +2793,401 @@ impl Vst3Plugin for SubSynth {
 
 nih_export_clap!(SubSynth);
 nih_export_vst3!(SubSynth);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pedals::SOFT_PEDAL_VELOCITY;
+    use std::collections::VecDeque;
+
+    const SAMPLE_RATE: f32 = 44100.0;
+    const BUFFER_SIZE: usize = 256;
+
+    /// A process context that hands out a fixed sequence of events. The transport is passed to
+    /// [`SubSynth::process_buffer()`] directly.
+    #[derive(Default)]
+    struct TestContext {
+        events: VecDeque<PluginNoteEvent<SubSynth>>,
+        sent_events: Vec<PluginNoteEvent<SubSynth>>,
+    }
+
+    impl ProcessContext<SubSynth> for TestContext {
+        fn plugin_api(&self) -> PluginApi {
+            PluginApi::Clap
+        }
+
+        fn execute_background(&self, _task: Task) {}
+
+        fn execute_gui(&self, _task: Task) {}
+
+        fn transport(&self) -> &Transport {
+            unimplemented!("the tests pass the transport information to process_buffer()")
+        }
+
+        fn next_event(&mut self) -> Option<PluginNoteEvent<SubSynth>> {
+            self.events.pop_front()
+        }
+
+        fn send_event(&mut self, event: PluginNoteEvent<SubSynth>) {
+            self.sent_events.push(event);
+        }
+
+        fn set_latency_samples(&self, _samples: u32) {}
+
+        fn set_current_voice_capacity(&self, _capacity: u32) {}
+    }
+
+    /// Process a single buffer with `events` at its start, returning the events SubSynth sent.
+    fn process(
+        synth: &mut SubSynth,
+        events: impl IntoIterator<Item = PluginNoteEvent<SubSynth>>,
+    ) -> Vec<PluginNoteEvent<SubSynth>> {
+        let mut context = TestContext {
+            events: events.into_iter().collect(),
+            ..TestContext::default()
+        };
+        let mut left = vec![0.0; BUFFER_SIZE];
+        let mut right = vec![0.0; BUFFER_SIZE];
+        let mut buffer = Buffer::default();
+        unsafe {
+            buffer.set_slices(BUFFER_SIZE, |slices| {
+                slices.clear();
+                slices.push(&mut left);
+                slices.push(&mut right);
+            });
+        }
+
+        synth.process_buffer(&mut buffer, &mut context, SAMPLE_RATE, None, None);
+        context.sent_events
+    }
+
+    fn note_on(note: u8, velocity: f32) -> PluginNoteEvent<SubSynth> {
+        NoteEvent::NoteOn {
+            timing: 0,
+            voice_id: None,
+            channel: 0,
+            note,
+            velocity,
+        }
+    }
+
+    fn note_off(note: u8) -> PluginNoteEvent<SubSynth> {
+        NoteEvent::NoteOff {
+            timing: 0,
+            voice_id: None,
+            channel: 0,
+            note,
+            velocity: 0.0,
+        }
+    }
+
+    fn cc(cc: u8, value: f32) -> PluginNoteEvent<SubSynth> {
+        NoteEvent::MidiCC {
+            timing: 0,
+            channel: 0,
+            cc,
+            value,
+        }
+    }
+
+    /// The voice playing `note`, if there is one.
+    fn voice(synth: &SubSynth, note: u8) -> Option<&Voice> {
+        synth
+            .voices
+            .iter()
+            .flatten()
+            .find(|voice| voice.note == note && !voice.is_stolen())
+    }
+
+    /// The number of voices playing `note` that haven't been released yet.
+    fn num_held_voices(synth: &SubSynth, note: u8) -> usize {
+        synth
+            .voices
+            .iter()
+            .flatten()
+            .filter(|voice| voice.note == note && !voice.releasing && !voice.is_stolen())
+            .count()
+    }
+
+    fn is_held(synth: &SubSynth, note: u8) -> bool {
+        num_held_voices(synth, note) > 0
+    }
+
+    #[test]
+    fn test_sustain_pedal_holds_released_notes() {
+        let mut synth = SubSynth::default();
+        process(&mut synth, [note_on(60, 1.0), cc(control_change::DAMPER_PEDAL, 1.0)]);
+        process(&mut synth, [note_off(60), note_on(64, 1.0), note_off(64)]);
+        assert!(is_held(&synth, 60));
+        assert!(is_held(&synth, 64));
+
+        // Playing a sustained note again restarts it instead of stacking a second voice
+        process(&mut synth, [note_on(60, 1.0)]);
+        assert_eq!(num_held_voices(&synth, 60), 1);
+
+        // Lifting the pedal only releases the notes whose keys are up
+        process(&mut synth, [cc(control_change::DAMPER_PEDAL, 0.0)]);
+        assert!(is_held(&synth, 60));
+        assert!(!is_held(&synth, 64));
+        process(&mut synth, [note_off(60)]);
+        assert!(!is_held(&synth, 60));
+    }
+
+    #[test]
+    fn test_sostenuto_pedal_only_holds_notes_that_were_down() {
+        let mut synth = SubSynth::default();
+        process(&mut synth, [note_on(48, 1.0), cc(control_change::SUSTENUTO, 1.0)]);
+        process(&mut synth, [note_on(60, 1.0)]);
+        process(&mut synth, [note_off(48), note_off(60)]);
+        assert!(is_held(&synth, 48));
+        assert!(!is_held(&synth, 60));
+
+        process(&mut synth, [cc(control_change::SUSTENUTO, 0.0)]);
+        assert!(!is_held(&synth, 48));
+    }
+
+    #[test]
+    fn test_sustain_and_sostenuto_together() {
+        let mut synth = SubSynth::default();
+        process(
+            &mut synth,
+            [
+                note_on(48, 1.0),
+                cc(control_change::SUSTENUTO, 1.0),
+                cc(control_change::DAMPER_PEDAL, 1.0),
+                note_off(48),
+            ],
+        );
+
+        // The note is still held by the sostenuto pedal after the sustain pedal is lifted
+        process(&mut synth, [cc(control_change::DAMPER_PEDAL, 0.0)]);
+        assert!(is_held(&synth, 48));
+        process(&mut synth, [cc(control_change::SUSTENUTO, 0.0)]);
+        assert!(!is_held(&synth, 48));
+    }
+
+    #[test]
+    fn test_soft_pedal_softens_new_notes() {
+        let mut synth = SubSynth::default();
+        process(&mut synth, [note_on(60, 1.0), cc(control_change::SOFT_PEDAL, 1.0)]);
+        process(&mut synth, [note_on(64, 1.0)]);
+        assert_eq!(voice(&synth, 60).unwrap().velocity, 1.0);
+        assert_eq!(voice(&synth, 64).unwrap().velocity, SOFT_PEDAL_VELOCITY);
+
+        process(&mut synth, [cc(control_change::SOFT_PEDAL, 0.0), note_on(67, 1.0)]);
+        assert_eq!(voice(&synth, 67).unwrap().velocity, 1.0);
+    }
+
+    #[test]
+    fn test_sustain_pedal_does_not_hold_arpeggiated_notes() {
+        let mut synth = SubSynth::default();
+        process(&mut synth, [cc(control_change::DAMPER_PEDAL, 1.0)]);
+
+        let settings = ArpSettings {
+            mode: arp::ArpMode::Up,
+            octaves: 1,
+            // Every step is four samples long
+            steps_per_sample: 0.25,
+            transport_position: None,
+            gate: 0.5,
+            swing_delay: 0.0,
+            latch: arp::ArpLatch::Off,
+            midi_output: false,
+        };
+        for note in [60, 64, 67] {
+            synth.arp.key_down(HeldNote {
+                voice_id: None,
+                channel: 0,
+                note,
+                velocity: 1.0,
+                sequenced: false,
+            });
+        }
+        let mut context = TestContext::default();
+        synth.arp.update(&settings);
+        for sample_idx in 0..64 {
+            synth.run_arpeggiator(&mut context, sample_idx, &settings);
+            synth.arp.advance(1, &settings);
+
+            // With a 50% gate only a single step sounds at a time, even with the pedal down
+            let num_held_voices = synth
+                .voices
+                .iter()
+                .flatten()
+                .filter(|voice| !voice.releasing && !voice.is_stolen())
+                .count();
+            assert!(num_held_voices <= 1, "{num_held_voices} voices at {sample_idx}");
+        }
+    }
+
+    #[test]
+    fn test_arp_midi_output_note_offs() {
+        let mut synth = SubSynth::default();
+        let mut settings = ArpSettings {
+            mode: arp::ArpMode::Up,
+            octaves: 1,
+            steps_per_sample: 0.25,
+            transport_position: None,
+            gate: 1.0,
+            swing_delay: 0.0,
+            latch: arp::ArpLatch::Off,
+            midi_output: true,
+        };
+        synth.arp.key_down(HeldNote {
+            voice_id: None,
+            channel: 0,
+            note: 60,
+            velocity: 1.0,
+            sequenced: false,
+        });
+        synth.arp.update(&settings);
+        let mut context = TestContext::default();
+        let mut run = |synth: &mut SubSynth, settings: &ArpSettings, num_samples: u32| {
+            context.sent_events.clear();
+            for sample_idx in 0..num_samples {
+                synth.run_arpeggiator(&mut context, sample_idx, settings);
+                synth.arp.advance(1, settings);
+            }
+            context
+                .sent_events
+                .iter()
+                .map(|event| match event {
+                    NoteEvent::NoteOn { note, .. } => (true, *note),
+                    NoteEvent::NoteOff { note, .. } => (false, *note),
+                    event => panic!("Unexpected event {event:?}"),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(&mut synth, &settings, 1), [(true, 60)]);
+
+        // The note that's still playing is released on the MIDI output even though it's now
+        // disabled, and the next step isn't sent anymore
+        settings.midi_output = false;
+        assert_eq!(run(&mut synth, &settings, 6), [(false, 60)]);
+
+        // Only notes that were sent to the MIDI output get a note off there
+        settings.midi_output = true;
+        assert_eq!(run(&mut synth, &settings, 2), [(true, 60)]);
+        synth.arp.stop();
+        assert_eq!(run(&mut synth, &settings, 1), [(false, 60)]);
+
+        // Resetting the plugin makes the arpeggiator forget its notes
+        synth.arp.key_down(HeldNote {
+            voice_id: None,
+            channel: 0,
+            note: 64,
+            velocity: 1.0,
+            sequenced: false,
+        });
+        synth.arp.update(&settings);
+        assert_eq!(run(&mut synth, &settings, 1), [(true, 64)]);
+        synth.reset();
+        assert_eq!(run(&mut synth, &settings, 1), [(false, 64)]);
+    }
+
+    #[test]
+    fn test_pitch_bend_and_mod_wheel() {
+        let mut synth = SubSynth::default();
+        let bend = |value: f32| NoteEvent::MidiPitchBend {
+            timing: 0,
+            channel: 0,
+            value,
+        };
+        process(&mut synth, [note_on(60, 1.0), bend(1.0)]);
+        // Give the pitch bend's smoothing time to settle
+        for _ in 0..10 {
+            process(&mut synth, []);
+        }
+        let bend_range = synth.params.mpe.bend_range.value() as f32;
+        let voice_pitch = |synth: &SubSynth| voice(synth, 60).unwrap().expression.next_pitch();
+        approx::assert_relative_eq!(voice_pitch(&synth), bend_range, epsilon = 1.0e-4);
+
+        process(&mut synth, [bend(0.25)]);
+        for _ in 0..10 {
+            process(&mut synth, []);
+        }
+        approx::assert_relative_eq!(voice_pitch(&synth), -bend_range / 2.0, epsilon = 1.0e-4);
+
+        process(&mut synth, [cc(control_change::MODULATION_MSB, 0.75)]);
+        assert_eq!(synth.mod_wheel, 0.75);
+    }
+
+    #[test]
+    fn test_noise_waveform_uses_the_noise_color() {
+        let wavetable = Wavetable::new(&WavetableSource::default());
+        for color in enum_iterator::all::<NoiseColor>() {
+            let mut noise = Noise::new(1);
+            let mut expected_noise = Noise::new(1);
+            for _ in 0..64 {
+                let sample = oscillator_sample(
+                    Waveform::Noise,
+                    &mut noise,
+                    color,
+                    &wavetable,
+                    0.0,
+                    0.0,
+                    0.0,
+                    0.5,
+                );
+                assert_eq!(sample, expected_noise.next(color), "{color:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_sequencer_notes_are_kept_apart_from_keys_on_the_same_channel() {
+        let mut synth = SubSynth::default();
+        let key_on = NoteEvent::NoteOn {
+            timing: 0,
+            voice_id: None,
+            channel: SEQUENCER_CHANNEL,
+            note: 64,
+            velocity: 1.0,
+        };
+        let held_voices = |synth: &SubSynth| -> Vec<bool> {
+            synth
+                .voices
+                .iter()
+                .flatten()
+                .filter(|voice| voice.note == 64 && !voice.releasing && !voice.is_stolen())
+                .map(|voice| voice.sequenced)
+                .collect()
+        };
+        process(&mut synth, [key_on]);
+
+        // The sequencer plays the same note while the transport is running
+        let mut sequence = Sequence::default();
+        sequence.set_step(
+            0,
+            sequencer::Step {
+                enabled: true,
+                note: 64,
+                gate: 1.0,
+                ..sequencer::Step::default()
+            },
+        );
+        let settings = SequencerSettings {
+            steps_per_sample: 0.25,
+            transport_position: Some(0.0),
+            transpose: 0,
+        };
+        let mut context = TestContext::default();
+        let mut play_sequence = |synth: &mut SubSynth| {
+            synth.sequencer.load(&sequence);
+            synth.sequencer.update(&settings);
+            synth.run_sequencer(&mut context, 0, &settings);
+        };
+        play_sequence(&mut synth);
+        assert_eq!(held_voices(&synth), [false, true]);
+
+        // Stopping the transport releases the sequencer's note but not the key
+        process(&mut synth, []);
+        assert_eq!(held_voices(&synth), [false]);
+
+        // And releasing the key leaves the sequencer's note alone
+        play_sequence(&mut synth);
+        assert_eq!(held_voices(&synth), [false, true]);
+        synth.note_off(&mut TestContext::default(), 0, None, SEQUENCER_CHANNEL, 64);
+        assert_eq!(held_voices(&synth), [true]);
+    }
+}
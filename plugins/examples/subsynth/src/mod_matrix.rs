@@ -0,0 +1,552 @@
+use enum_iterator::Sequence;
+use nih_plug::prelude::*;
+
+use crate::lfo::{LfoParams, LfoSettings, NUM_VOICE_LFOS};
+use crate::SubSynthParams;
+
+/// The number of slots in the modulation matrix.
+pub const NUM_MOD_SLOTS: usize = 8;
+/// The number of variants in [`ModDestination`].
+pub const NUM_MOD_DESTINATIONS: usize = 57;
+/// How far the pitch destination moves the voice's pitch at 100% modulation, in semitones.
+pub const MOD_PITCH_RANGE: f32 = 24.0;
+
+/// Where a modulation slot's value comes from. Envelopes, velocity, the mod wheel and the note
+/// expressions are unipolar, the LFOs, key, pitch bend and the random value are bipolar.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Enum, Sequence)]
+pub enum ModSource {
+    None,
+    #[name = "Amp Env"]
+    AmpEnvelope,
+    #[name = "Cutoff Env"]
+    FilterCutEnvelope,
+    #[name = "Resonance Env"]
+    FilterResEnvelope,
+    #[name = "PWM Env"]
+    PwmEnvelope,
+    #[name = "LFO 1"]
+    Lfo1,
+    #[name = "LFO 2"]
+    Lfo2,
+    #[name = "Global LFO"]
+    GlobalLfo,
+    Velocity,
+    /// The note's distance from C4, reaching -1 and 1 five octaves below and above it.
+    Key,
+    #[name = "Mod Wheel"]
+    ModWheel,
+    /// Polyphonic aftertouch, or channel pressure for notes on the pressure's channel.
+    Aftertouch,
+    /// The note's brightness expression, or CC 74 for notes on the CC's channel.
+    Brightness,
+    /// The note's expression value, set through CLAP and VST3 note expressions.
+    Expression,
+    #[name = "Pitch Bend"]
+    PitchBend,
+    /// A random value picked for every note.
+    Random,
+}
+
+/// What a modulation slot modulates. Apart from pitch these are all continuous parameters, and
+/// the slot's amount is added to the parameter's normalized value.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Enum, Sequence)]
+pub enum ModDestination {
+    None,
+    Pitch,
+    Pan,
+    Gain,
+    #[name = "Wavetable Position"]
+    WavetablePosition,
+    #[name = "Pulse Width"]
+    PulseWidth,
+    #[name = "PWM Depth"]
+    PwmDepth,
+    #[name = "PWM Rate"]
+    PwmRate,
+    #[name = "PWM Env Attack"]
+    PwmEnvAttack,
+    #[name = "PWM Env Decay"]
+    PwmEnvDecay,
+    #[name = "Osc 2 Fine"]
+    Osc2Fine,
+    #[name = "Osc 1 Level"]
+    Osc1Level,
+    #[name = "Osc 2 Level"]
+    Osc2Level,
+    #[name = "Sub Level"]
+    SubLevel,
+    #[name = "Noise Level"]
+    NoiseLevel,
+    #[name = "Ring Mod Level"]
+    RingLevel,
+    #[name = "FM Amount"]
+    FmAmount,
+    #[name = "Unison Detune"]
+    UnisonDetune,
+    #[name = "Unison Spread"]
+    UnisonSpread,
+    #[name = "Unison Blend"]
+    UnisonBlend,
+    #[name = "Attack"]
+    AmpAttack,
+    #[name = "Decay"]
+    AmpDecay,
+    #[name = "Sustain"]
+    AmpSustain,
+    #[name = "Release"]
+    AmpRelease,
+    #[name = "Curve Tension"]
+    AmpTension,
+    #[name = "Filter Cutoff"]
+    FilterCutoff,
+    #[name = "Filter Resonance"]
+    FilterResonance,
+    #[name = "Filter Env Amount"]
+    FilterEnvAmount,
+    #[name = "Filter Keytracking"]
+    FilterKeytrack,
+    #[name = "Filter Velocity"]
+    FilterVelocity,
+    #[name = "Filter Drive"]
+    FilterDrive,
+    #[name = "Filter Cut Attack"]
+    FilterCutAttack,
+    #[name = "Filter Cut Decay"]
+    FilterCutDecay,
+    #[name = "Filter Cut Sustain"]
+    FilterCutSustain,
+    #[name = "Filter Cut Release"]
+    FilterCutRelease,
+    #[name = "Filter Cut Tension"]
+    FilterCutTension,
+    #[name = "Filter Res Attack"]
+    FilterResAttack,
+    #[name = "Filter Res Decay"]
+    FilterResDecay,
+    #[name = "Filter Res Sustain"]
+    FilterResSustain,
+    #[name = "Filter Res Release"]
+    FilterResRelease,
+    #[name = "Filter Res Tension"]
+    FilterResTension,
+    #[name = "LFO 1 Rate"]
+    Lfo1Rate,
+    #[name = "LFO 1 Fade In"]
+    Lfo1FadeIn,
+    #[name = "LFO 1 to Pitch"]
+    Lfo1Pitch,
+    #[name = "LFO 1 to Cutoff"]
+    Lfo1Cutoff,
+    #[name = "LFO 1 to Resonance"]
+    Lfo1Resonance,
+    #[name = "LFO 1 to Pulse Width"]
+    Lfo1PulseWidth,
+    #[name = "LFO 1 to Pan"]
+    Lfo1Pan,
+    #[name = "LFO 1 to Amp"]
+    Lfo1Amp,
+    #[name = "LFO 2 Rate"]
+    Lfo2Rate,
+    #[name = "LFO 2 Fade In"]
+    Lfo2FadeIn,
+    #[name = "LFO 2 to Pitch"]
+    Lfo2Pitch,
+    #[name = "LFO 2 to Cutoff"]
+    Lfo2Cutoff,
+    #[name = "LFO 2 to Resonance"]
+    Lfo2Resonance,
+    #[name = "LFO 2 to Pulse Width"]
+    Lfo2PulseWidth,
+    #[name = "LFO 2 to Pan"]
+    Lfo2Pan,
+    #[name = "LFO 2 to Amp"]
+    Lfo2Amp,
+}
+
+/// The destinations belonging to a per-voice LFO's parameters.
+pub struct LfoModDestinations {
+    pub rate: ModDestination,
+    pub fade_in: ModDestination,
+    pub pitch: ModDestination,
+    pub cutoff: ModDestination,
+    pub resonance: ModDestination,
+    pub pulse_width: ModDestination,
+    pub pan: ModDestination,
+    pub amp: ModDestination,
+}
+
+pub const LFO_MOD_DESTINATIONS: [LfoModDestinations; NUM_VOICE_LFOS] = [
+    LfoModDestinations {
+        rate: ModDestination::Lfo1Rate,
+        fade_in: ModDestination::Lfo1FadeIn,
+        pitch: ModDestination::Lfo1Pitch,
+        cutoff: ModDestination::Lfo1Cutoff,
+        resonance: ModDestination::Lfo1Resonance,
+        pulse_width: ModDestination::Lfo1PulseWidth,
+        pan: ModDestination::Lfo1Pan,
+        amp: ModDestination::Lfo1Amp,
+    },
+    LfoModDestinations {
+        rate: ModDestination::Lfo2Rate,
+        fade_in: ModDestination::Lfo2FadeIn,
+        pitch: ModDestination::Lfo2Pitch,
+        cutoff: ModDestination::Lfo2Cutoff,
+        resonance: ModDestination::Lfo2Resonance,
+        pulse_width: ModDestination::Lfo2PulseWidth,
+        pan: ModDestination::Lfo2Pan,
+        amp: ModDestination::Lfo2Amp,
+    },
+];
+
+impl ModDestination {
+    /// The parameter this destination modulates. Pitch is not a parameter, so it returns `None`.
+    pub fn param(self, params: &SubSynthParams) -> Option<&FloatParam> {
+        let lfo_param = |lfo_idx: usize, param: fn(&LfoParams) -> &FloatParam| {
+            Some(param(&params.lfos[lfo_idx]))
+        };
+
+        match self {
+            ModDestination::None | ModDestination::Pitch => None,
+            ModDestination::Pan => Some(&params.pan),
+            ModDestination::Gain => Some(&params.gain),
+            ModDestination::WavetablePosition => Some(&params.wavetable_position),
+            ModDestination::PulseWidth => Some(&params.pulse_width),
+            ModDestination::PwmDepth => Some(&params.pwm_depth),
+            ModDestination::PwmRate => Some(&params.pwm_rate),
+            ModDestination::PwmEnvAttack => Some(&params.pwm_env_attack_ms),
+            ModDestination::PwmEnvDecay => Some(&params.pwm_env_decay_ms),
+            ModDestination::Osc2Fine => Some(&params.osc2_fine),
+            ModDestination::Osc1Level => Some(&params.osc1_level),
+            ModDestination::Osc2Level => Some(&params.osc2_level),
+            ModDestination::SubLevel => Some(&params.sub_level),
+            ModDestination::NoiseLevel => Some(&params.noise_level),
+            ModDestination::RingLevel => Some(&params.ring_level),
+            ModDestination::FmAmount => Some(&params.fm_amount),
+            ModDestination::UnisonDetune => Some(&params.unison_detune),
+            ModDestination::UnisonSpread => Some(&params.unison_spread),
+            ModDestination::UnisonBlend => Some(&params.unison_blend),
+            ModDestination::AmpAttack => Some(&params.amp_attack_ms),
+            ModDestination::AmpDecay => Some(&params.amp_decay_ms),
+            ModDestination::AmpSustain => Some(&params.amp_sustain_level),
+            ModDestination::AmpRelease => Some(&params.amp_release_ms),
+            ModDestination::AmpTension => Some(&params.amp_tension),
+            ModDestination::FilterCutoff => Some(&params.filter_cut),
+            ModDestination::FilterResonance => Some(&params.filter_res),
+            ModDestination::FilterEnvAmount => Some(&params.filter_env_amount),
+            ModDestination::FilterKeytrack => Some(&params.filter_keytrack),
+            ModDestination::FilterVelocity => Some(&params.filter_velocity),
+            ModDestination::FilterDrive => Some(&params.filter_drive),
+            ModDestination::FilterCutAttack => Some(&params.filter_cut_attack_ms),
+            ModDestination::FilterCutDecay => Some(&params.filter_cut_decay_ms),
+            ModDestination::FilterCutSustain => Some(&params.filter_cut_sustain_level),
+            ModDestination::FilterCutRelease => Some(&params.filter_cut_release_ms),
+            ModDestination::FilterCutTension => Some(&params.filter_cut_tension),
+            ModDestination::FilterResAttack => Some(&params.filter_res_attack_ms),
+            ModDestination::FilterResDecay => Some(&params.filter_res_decay_ms),
+            ModDestination::FilterResSustain => Some(&params.filter_res_sustain_level),
+            ModDestination::FilterResRelease => Some(&params.filter_res_release_ms),
+            ModDestination::FilterResTension => Some(&params.filter_res_tension),
+            ModDestination::Lfo1Rate => lfo_param(0, |lfo| &lfo.rate),
+            ModDestination::Lfo1FadeIn => lfo_param(0, |lfo| &lfo.fade_in_ms),
+            ModDestination::Lfo1Pitch => lfo_param(0, |lfo| &lfo.pitch_depth),
+            ModDestination::Lfo1Cutoff => lfo_param(0, |lfo| &lfo.cutoff_depth),
+            ModDestination::Lfo1Resonance => lfo_param(0, |lfo| &lfo.resonance_depth),
+            ModDestination::Lfo1PulseWidth => lfo_param(0, |lfo| &lfo.pulse_width_depth),
+            ModDestination::Lfo1Pan => lfo_param(0, |lfo| &lfo.pan_depth),
+            ModDestination::Lfo1Amp => lfo_param(0, |lfo| &lfo.amp_depth),
+            ModDestination::Lfo2Rate => lfo_param(1, |lfo| &lfo.rate),
+            ModDestination::Lfo2FadeIn => lfo_param(1, |lfo| &lfo.fade_in_ms),
+            ModDestination::Lfo2Pitch => lfo_param(1, |lfo| &lfo.pitch_depth),
+            ModDestination::Lfo2Cutoff => lfo_param(1, |lfo| &lfo.cutoff_depth),
+            ModDestination::Lfo2Resonance => lfo_param(1, |lfo| &lfo.resonance_depth),
+            ModDestination::Lfo2PulseWidth => lfo_param(1, |lfo| &lfo.pulse_width_depth),
+            ModDestination::Lfo2Pan => lfo_param(1, |lfo| &lfo.pan_depth),
+            ModDestination::Lfo2Amp => lfo_param(1, |lfo| &lfo.amp_depth),
+        }
+    }
+}
+
+/// The parameters for a single modulation matrix slot.
+#[derive(Params)]
+pub struct ModSlotParams {
+    #[id = "mod_src"]
+    pub source: EnumParam<ModSource>,
+    /// An optional second source the modulation is scaled by, e.g. to control an LFO's depth with
+    /// the mod wheel.
+    #[id = "mod_via"]
+    pub via: EnumParam<ModSource>,
+    #[id = "mod_dst"]
+    pub destination: EnumParam<ModDestination>,
+    /// The modulation's amount in normalized parameter units. At 100% a source at its maximum
+    /// sweeps the destination's entire range.
+    #[id = "mod_amt"]
+    pub amount: FloatParam,
+}
+
+impl ModSlotParams {
+    /// Create the parameters for the slot with the one-based number `slot_number`.
+    pub fn new(slot_number: usize) -> Self {
+        Self {
+            source: EnumParam::new(format!("Mod {slot_number} Source"), ModSource::None),
+            via: EnumParam::new(format!("Mod {slot_number} Via"), ModSource::None),
+            destination: EnumParam::new(
+                format!("Mod {slot_number} Destination"),
+                ModDestination::None,
+            ),
+            amount: FloatParam::new(
+                format!("Mod {slot_number} Amount"),
+                0.0,
+                FloatRange::Linear { min: -1.0, max: 1.0 },
+            )
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+        }
+    }
+
+    pub fn slot(&self) -> ModSlot {
+        ModSlot {
+            source: self.source.value(),
+            via: self.via.value(),
+            destination: self.destination.value(),
+            amount: self.amount.value(),
+        }
+    }
+}
+
+/// A modulation slot's settings, computed once per block.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModSlot {
+    pub source: ModSource,
+    pub via: ModSource,
+    pub destination: ModDestination,
+    pub amount: f32,
+}
+
+/// The current values of all modulation sources for a single voice.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ModSourceValues {
+    pub amp_envelope: f32,
+    pub filter_cut_envelope: f32,
+    pub filter_res_envelope: f32,
+    pub pwm_envelope: f32,
+    pub lfos: [f32; NUM_VOICE_LFOS],
+    pub global_lfo: f32,
+    pub velocity: f32,
+    pub key: f32,
+    pub mod_wheel: f32,
+    pub aftertouch: f32,
+    pub brightness: f32,
+    pub expression: f32,
+    pub pitch_bend: f32,
+    pub random: f32,
+}
+
+impl ModSourceValues {
+    pub fn get(&self, source: ModSource) -> f32 {
+        match source {
+            ModSource::None => 0.0,
+            ModSource::AmpEnvelope => self.amp_envelope,
+            ModSource::FilterCutEnvelope => self.filter_cut_envelope,
+            ModSource::FilterResEnvelope => self.filter_res_envelope,
+            ModSource::PwmEnvelope => self.pwm_envelope,
+            ModSource::Lfo1 => self.lfos[0],
+            ModSource::Lfo2 => self.lfos[1],
+            ModSource::GlobalLfo => self.global_lfo,
+            ModSource::Velocity => self.velocity,
+            ModSource::Key => self.key,
+            ModSource::ModWheel => self.mod_wheel,
+            ModSource::Aftertouch => self.aftertouch,
+            ModSource::Brightness => self.brightness,
+            ModSource::Expression => self.expression,
+            ModSource::PitchBend => self.pitch_bend,
+            ModSource::Random => self.random,
+        }
+    }
+}
+
+/// The summed modulation for every destination, in normalized parameter units.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModOffsets([f32; NUM_MOD_DESTINATIONS]);
+
+impl Default for ModOffsets {
+    fn default() -> Self {
+        ModOffsets([0.0; NUM_MOD_DESTINATIONS])
+    }
+}
+
+impl ModOffsets {
+    /// Evaluate all of the matrix's `slots` for a voice's current `sources`.
+    pub fn evaluate<'a>(
+        slots: impl IntoIterator<Item = &'a ModSlot>,
+        sources: &ModSourceValues,
+    ) -> Self {
+        let mut offsets = ModOffsets::default();
+        for slot in slots {
+            if slot.source == ModSource::None || slot.destination == ModDestination::None {
+                continue;
+            }
+
+            let via = match slot.via {
+                ModSource::None => 1.0,
+                via => sources.get(via),
+            };
+            offsets.0[slot.destination as usize] += sources.get(slot.source) * via * slot.amount;
+        }
+
+        offsets
+    }
+
+    pub fn get(&self, destination: ModDestination) -> f32 {
+        self.0[destination as usize]
+    }
+
+    /// `param`'s current value with `destination`'s modulation applied to it.
+    pub fn value(&self, param: &FloatParam, destination: ModDestination) -> f32 {
+        modulate(param, param.value(), self.get(destination))
+    }
+
+    /// Apply the modulation for one of the per-voice LFOs to its `settings`.
+    pub fn modulate_lfo_settings(
+        &self,
+        mut settings: LfoSettings,
+        params: &LfoParams,
+        destinations: &LfoModDestinations,
+        sample_rate: f32,
+    ) -> LfoSettings {
+        settings.set_rate(self.value(&params.rate, destinations.rate), sample_rate);
+        settings.set_fade_in(self.value(&params.fade_in_ms, destinations.fade_in), sample_rate);
+        settings.depths.pitch = self.value(&params.pitch_depth, destinations.pitch);
+        settings.depths.cutoff = self.value(&params.cutoff_depth, destinations.cutoff);
+        settings.depths.resonance = self.value(&params.resonance_depth, destinations.resonance);
+        settings.depths.pulse_width =
+            self.value(&params.pulse_width_depth, destinations.pulse_width);
+        settings.depths.pan = self.value(&params.pan_depth, destinations.pan);
+        settings.depths.amp = self.value(&params.amp_depth, destinations.amp);
+
+        settings
+    }
+}
+
+/// A voice's modulation matrix state. The matrix is evaluated once per block, and the offsets are
+/// interpolated from the previous block's offsets to avoid zipper noise.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct VoiceModulation {
+    previous: ModOffsets,
+    current: ModOffsets,
+    /// Whether the matrix has been evaluated for this voice before. The first evaluation is not
+    /// interpolated, so a note starts at its modulated values.
+    started: bool,
+}
+
+impl VoiceModulation {
+    pub fn update(&mut self, offsets: ModOffsets) {
+        self.previous = if self.started { self.current } else { offsets };
+        self.current = offsets;
+        self.started = true;
+    }
+
+    /// The offsets for the current block, used for things that are only updated once per block.
+    pub fn offsets(&self) -> &ModOffsets {
+        &self.current
+    }
+
+    /// The offset for `destination` interpolated over the block. `t` goes from the previous
+    /// block's offset at 0 to the current block's offset at 1.
+    pub fn interpolated(&self, destination: ModDestination, t: f32) -> f32 {
+        let previous = self.previous.get(destination);
+        previous + (self.current.get(destination) - previous) * t
+    }
+}
+
+/// Add a normalized `offset` to a parameter's `plain_value`.
+pub fn modulate(param: &FloatParam, plain_value: f32, normalized_offset: f32) -> f32 {
+    if normalized_offset == 0.0 {
+        plain_value
+    } else {
+        param.preview_plain(param.preview_normalized(plain_value) + normalized_offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slot(
+        source: ModSource,
+        via: ModSource,
+        destination: ModDestination,
+        amount: f32,
+    ) -> ModSlot {
+        ModSlot {
+            source,
+            via,
+            destination,
+            amount,
+        }
+    }
+
+    #[test]
+    fn test_destination_count() {
+        assert_eq!(enum_iterator::cardinality::<ModDestination>(), NUM_MOD_DESTINATIONS);
+    }
+
+    #[test]
+    fn test_every_parameter_destination_has_a_param() {
+        let params = SubSynthParams::default();
+        for destination in enum_iterator::all::<ModDestination>() {
+            let is_param = !matches!(destination, ModDestination::None | ModDestination::Pitch);
+            assert_eq!(destination.param(&params).is_some(), is_param, "{destination:?}");
+        }
+    }
+
+    #[test]
+    fn test_slots_sum_per_destination() {
+        let sources = ModSourceValues {
+            velocity: 0.5,
+            mod_wheel: 0.25,
+            lfos: [-1.0, 0.0],
+            ..ModSourceValues::default()
+        };
+        let slots = [
+            slot(ModSource::Velocity, ModSource::None, ModDestination::FilterCutoff, 0.5),
+            slot(ModSource::Lfo1, ModSource::ModWheel, ModDestination::FilterCutoff, 1.0),
+            slot(ModSource::Velocity, ModSource::None, ModDestination::Gain, -1.0),
+            // Slots without a source or destination don't do anything
+            slot(ModSource::None, ModSource::None, ModDestination::Pitch, 1.0),
+            slot(ModSource::Velocity, ModSource::None, ModDestination::None, 1.0),
+        ];
+
+        let offsets = ModOffsets::evaluate(&slots, &sources);
+        assert_eq!(offsets.get(ModDestination::FilterCutoff), 0.25 - 0.25);
+        assert_eq!(offsets.get(ModDestination::Gain), -0.5);
+        assert_eq!(offsets.get(ModDestination::Pitch), 0.0);
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let mut offsets = ModOffsets::default();
+        offsets.0[ModDestination::Pan as usize] = 1.0;
+
+        // The first block jumps straight to its values
+        let mut modulation = VoiceModulation::default();
+        modulation.update(offsets);
+        assert_eq!(modulation.interpolated(ModDestination::Pan, 0.0), 1.0);
+
+        offsets.0[ModDestination::Pan as usize] = -1.0;
+        modulation.update(offsets);
+        assert_eq!(modulation.interpolated(ModDestination::Pan, 0.0), 1.0);
+        assert_eq!(modulation.interpolated(ModDestination::Pan, 0.5), 0.0);
+        assert_eq!(modulation.interpolated(ModDestination::Pan, 1.0), -1.0);
+        assert_eq!(modulation.offsets().get(ModDestination::Pan), -1.0);
+    }
+
+    #[test]
+    fn test_modulate_is_clamped_to_the_range() {
+        let param = FloatParam::new("Test", 5.0, FloatRange::Linear { min: 0.0, max: 10.0 });
+        assert_eq!(modulate(&param, 5.0, 0.0), 5.0);
+        approx::assert_relative_eq!(modulate(&param, 5.0, 0.25), 7.5);
+        assert_eq!(modulate(&param, 5.0, 1.0), 10.0);
+        assert_eq!(modulate(&param, 5.0, -1.0), 0.0);
+    }
+}
@@ -0,0 +1,302 @@
+use enum_iterator::Sequence;
+use nih_plug::prelude::*;
+
+use crate::mod_matrix::{ModDestination, ModSlot, ModSource};
+
+/// The MIDI CC MPE controllers use for brightness, also known as the timbre or slide dimension.
+pub const BRIGHTNESS_CC: u8 = 74;
+/// How far the note's vibrato expression moves the pitch at its maximum, in semitones. The
+/// vibrato follows the first per-voice LFO.
+pub const MAX_EXPRESSION_VIBRATO: f32 = 1.0;
+
+/// How MIDI channels are interpreted. In an MPE zone every note gets its own member channel, so
+/// pitch bend, channel pressure and CC 74 only affect that note. Pitch bend on the zone's master
+/// channel affects all notes.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Enum, Sequence)]
+pub enum MpeMode {
+    Off,
+    /// The master channel is channel 1, and channels 2 through 16 are member channels.
+    #[name = "Lower Zone"]
+    LowerZone,
+    /// The master channel is channel 16, and channels 1 through 15 are member channels.
+    #[name = "Upper Zone"]
+    UpperZone,
+}
+
+impl MpeMode {
+    /// The zone's master channel, in `0..16`.
+    pub fn master_channel(self) -> Option<u8> {
+        match self {
+            MpeMode::Off => None,
+            MpeMode::LowerZone => Some(0),
+            MpeMode::UpperZone => Some(15),
+        }
+    }
+
+    pub fn is_member_channel(self, channel: u8) -> bool {
+        match self.master_channel() {
+            Some(master_channel) => channel != master_channel,
+            None => false,
+        }
+    }
+}
+
+/// The parameters for MIDI pitch bend and for MPE and note expressions.
+#[derive(Params)]
+pub struct MpeParams {
+    #[id = "mpe_mode"]
+    pub mode: EnumParam<MpeMode>,
+    /// The pitch bend range in semitones. In an MPE zone this is used for the master channel.
+    #[id = "bend_range"]
+    pub bend_range: IntParam,
+    /// The pitch bend range for an MPE zone's member channels. MPE controllers expect this to be
+    /// 48 semitones by default.
+    #[id = "mpe_bend_range"]
+    pub member_bend_range: IntParam,
+    /// How long a note takes to glide to a new pitch from tuning expressions or per-note pitch
+    /// bend. Changes to this apply to new notes.
+    #[id = "mpe_glide"]
+    pub glide_ms: FloatParam,
+
+    #[id = "pressure_dst"]
+    pub pressure_destination: EnumParam<ModDestination>,
+    #[id = "pressure_amt"]
+    pub pressure_amount: FloatParam,
+    #[id = "brightness_dst"]
+    pub brightness_destination: EnumParam<ModDestination>,
+    #[id = "brightness_amt"]
+    pub brightness_amount: FloatParam,
+}
+
+impl Default for MpeParams {
+    fn default() -> Self {
+        Self {
+            mode: EnumParam::new("MPE Mode", MpeMode::Off),
+            bend_range: IntParam::new("Pitch Bend Range", 2, IntRange::Linear { min: 0, max: 48 })
+                .with_unit(" st"),
+            member_bend_range: IntParam::new(
+                "MPE Pitch Bend Range",
+                48,
+                IntRange::Linear { min: 0, max: 96 },
+            )
+            .with_unit(" st"),
+            glide_ms: FloatParam::new(
+                "MPE Pitch Glide",
+                5.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 500.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_unit(" ms")
+            .with_step_size(0.1),
+            pressure_destination: EnumParam::new(
+                "Pressure Destination",
+                ModDestination::FilterCutoff,
+            ),
+            pressure_amount: expression_amount_param("Pressure Amount", 0.0),
+            brightness_destination: EnumParam::new(
+                "Brightness Destination",
+                ModDestination::FilterResonance,
+            ),
+            brightness_amount: expression_amount_param("Brightness Amount", 0.0),
+        }
+    }
+}
+
+impl MpeParams {
+    /// Pressure and brightness are routed like two extra mod matrix slots.
+    pub fn expression_slots(&self) -> [ModSlot; 2] {
+        [
+            ModSlot {
+                source: ModSource::Aftertouch,
+                via: ModSource::None,
+                destination: self.pressure_destination.value(),
+                amount: self.pressure_amount.value(),
+            },
+            ModSlot {
+                source: ModSource::Brightness,
+                via: ModSource::None,
+                destination: self.brightness_destination.value(),
+                amount: self.brightness_amount.value(),
+            },
+        ]
+    }
+}
+
+/// An expression's modulation amount in normalized parameter units, like a mod matrix slot's.
+fn expression_amount_param(name: &str, default: f32) -> FloatParam {
+    FloatParam::new(name, default, FloatRange::Linear { min: -1.0, max: 1.0 })
+        .with_unit(" %")
+        .with_value_to_string(formatters::v2s_f32_percentage(0))
+        .with_string_to_value(formatters::s2v_f32_percentage())
+}
+
+/// The most recent pitch bend for every MIDI channel, in `[-1, 1]`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PitchBend {
+    channels: [f32; 16],
+    /// The last pitch bend received on any channel. This is used when MPE is disabled.
+    last: f32,
+}
+
+impl PitchBend {
+    pub fn set(&mut self, channel: u8, value: f32) {
+        self.channels[channel as usize] = value;
+        self.last = value;
+    }
+
+    /// The pitch bend that applies to a note on `channel`. In an MPE zone this combines the
+    /// master channel's and the note's own channel's pitch bend.
+    pub fn value(&self, mode: MpeMode, channel: u8) -> f32 {
+        (self.master_value(mode) + self.member_value(mode, channel)).clamp(-1.0, 1.0)
+    }
+
+    /// The pitch bend in semitones for a note on `channel`.
+    pub fn semitones(
+        &self,
+        mode: MpeMode,
+        channel: u8,
+        bend_range: f32,
+        member_bend_range: f32,
+    ) -> f32 {
+        self.master_value(mode) * bend_range
+            + self.member_value(mode, channel) * member_bend_range
+    }
+
+    fn master_value(&self, mode: MpeMode) -> f32 {
+        match mode.master_channel() {
+            Some(master_channel) => self.channels[master_channel as usize],
+            None => self.last,
+        }
+    }
+
+    fn member_value(&self, mode: MpeMode, channel: u8) -> f32 {
+        if mode.is_member_channel(channel) {
+            self.channels[channel as usize]
+        } else {
+            0.0
+        }
+    }
+}
+
+/// A voice's note expressions. These are set by CLAP and VST3 note expressions, and in MPE mode
+/// also by the note's member channel.
+#[derive(Debug, Clone)]
+pub struct VoiceExpression {
+    /// A gain ratio, where 1.0 is unity gain.
+    pub volume: f32,
+    /// Added to the voice's panning.
+    pub pan: f32,
+    /// The note's tuning offset in semitones.
+    pub tuning: f32,
+    /// The vibrato amount, in `[0, 1]`.
+    pub vibrato: f32,
+    pub expression: f32,
+    pub brightness: f32,
+    /// The note's pitch offset in semitones from tuning and pitch bend. This glides to new values
+    /// so stepped controller data doesn't result in zipper noise.
+    pitch: Smoother<f32>,
+    /// The last pitch passed to [`set_pitch()`][Self::set_pitch()], so the smoother's target only
+    /// changes when the pitch changes.
+    pitch_target: f32,
+}
+
+impl VoiceExpression {
+    pub fn new(glide_ms: f32, initial_pitch: f32, brightness: f32) -> Self {
+        let pitch = Smoother::new(SmoothingStyle::Linear(glide_ms));
+        pitch.reset(initial_pitch);
+
+        Self {
+            volume: 1.0,
+            pan: 0.0,
+            tuning: 0.0,
+            vibrato: 0.0,
+            expression: 0.0,
+            brightness,
+            pitch,
+            pitch_target: initial_pitch,
+        }
+    }
+
+    /// Glide to a new pitch offset in semitones.
+    pub fn set_pitch(&mut self, sample_rate: f32, semitones: f32) {
+        if semitones != self.pitch_target {
+            self.pitch.set_target(sample_rate, semitones);
+            self.pitch_target = semitones;
+        }
+    }
+
+    /// The pitch offset for the next sample, in semitones.
+    pub fn next_pitch(&self) -> f32 {
+        self.pitch.next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_member_channels() {
+        assert!(!MpeMode::Off.is_member_channel(1));
+        assert!(!MpeMode::LowerZone.is_member_channel(0));
+        assert!(MpeMode::LowerZone.is_member_channel(1));
+        assert!(MpeMode::LowerZone.is_member_channel(15));
+        assert!(MpeMode::UpperZone.is_member_channel(0));
+        assert!(!MpeMode::UpperZone.is_member_channel(15));
+    }
+
+    #[test]
+    fn test_pitch_bend_without_mpe_is_global() {
+        let mut pitch_bend = PitchBend::default();
+        pitch_bend.set(3, 0.5);
+        pitch_bend.set(7, -0.25);
+
+        for channel in 0..16 {
+            assert_eq!(pitch_bend.value(MpeMode::Off, channel), -0.25);
+            assert_eq!(pitch_bend.semitones(MpeMode::Off, channel, 2.0, 48.0), -0.5);
+        }
+    }
+
+    #[test]
+    fn test_pitch_bend_in_mpe_zones() {
+        let mut pitch_bend = PitchBend::default();
+        pitch_bend.set(1, 0.5);
+        pitch_bend.set(2, -0.5);
+        assert_eq!(pitch_bend.semitones(MpeMode::LowerZone, 1, 2.0, 48.0), 24.0);
+        assert_eq!(pitch_bend.semitones(MpeMode::LowerZone, 2, 2.0, 48.0), -24.0);
+        assert_eq!(pitch_bend.semitones(MpeMode::LowerZone, 3, 2.0, 48.0), 0.0);
+
+        // The master channel's bend is added to every note in the zone
+        pitch_bend.set(0, 1.0);
+        assert_eq!(pitch_bend.semitones(MpeMode::LowerZone, 0, 2.0, 48.0), 2.0);
+        assert_eq!(pitch_bend.semitones(MpeMode::LowerZone, 1, 2.0, 48.0), 26.0);
+        assert_eq!(pitch_bend.value(MpeMode::LowerZone, 1), 1.0);
+        assert_eq!(pitch_bend.value(MpeMode::LowerZone, 2), 0.5);
+
+        // Channel 1 is a member channel in the upper zone, and channel 16 is its master channel
+        assert_eq!(pitch_bend.semitones(MpeMode::UpperZone, 0, 2.0, 48.0), 48.0);
+        assert_eq!(pitch_bend.semitones(MpeMode::UpperZone, 1, 2.0, 48.0), 24.0);
+    }
+
+    #[test]
+    fn test_pitch_glides() {
+        let mut expression = VoiceExpression::new(10.0, 0.0, 0.0);
+        expression.set_pitch(1000.0, 2.0);
+
+        let mut previous_pitch = 0.0;
+        for _ in 0..10 {
+            let pitch = expression.next_pitch();
+            assert!(pitch > previous_pitch && pitch <= 2.0);
+            previous_pitch = pitch;
+        }
+        assert_eq!(previous_pitch, 2.0);
+        assert_eq!(expression.next_pitch(), 2.0);
+
+        // Setting the same target again doesn't restart the glide
+        expression.set_pitch(1000.0, 2.0);
+        assert_eq!(expression.next_pitch(), 2.0);
+    }
+}
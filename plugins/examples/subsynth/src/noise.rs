@@ -0,0 +1,194 @@
+use enum_iterator::Sequence;
+use nih_plug::params::enums::Enum;
+use rand::Rng;
+use rand_pcg::Pcg32;
+
+/// The spectral tilt of the noise source.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Enum, Sequence)]
+pub enum NoiseColor {
+    /// Equal power at every frequency.
+    White,
+    /// -3 dB per octave, equal power in every octave.
+    Pink,
+    /// -6 dB per octave.
+    Brown,
+    /// +3 dB per octave.
+    Blue,
+}
+
+/// The pink noise filter's pole and gain pairs, from Paul Kellett's refined pink noise filter. The
+/// filter is accurate to within 0.05 dB above 9 Hz at 44.1 kHz.
+const PINK_POLES: [(f32, f32); 6] = [
+    (0.99886, 0.0555179),
+    (0.99332, 0.0750759),
+    (0.96900, 0.153852),
+    (0.86650, 0.3104856),
+    (0.55000, 0.5329522),
+    (-0.7616, -0.0168980),
+];
+/// Brings the pink noise filter's output back to roughly the same level as the white noise.
+const PINK_GAIN: f32 = 0.11;
+/// The leak in the brown noise integrator. Without this the integrator would drift off.
+const BROWN_LEAK: f32 = 0.02;
+const BROWN_GAIN: f32 = 3.5;
+/// Differentiating pink noise boosts the upper frequencies, so blue noise is turned down a bit.
+const BLUE_GAIN: f32 = 0.5;
+
+/// A noise generator with its own random number generator, so every voice produces the same noise
+/// for the same seed.
+#[derive(Debug, Clone)]
+pub struct Noise {
+    prng: Pcg32,
+    pink_filters: [f32; PINK_POLES.len()],
+    /// The pink noise filter's one sample delayed white noise term.
+    pink_delayed: f32,
+    /// The previous pink noise sample, used for blue noise.
+    previous_pink: f32,
+    brown: f32,
+}
+
+impl Noise {
+    pub fn new(seed: u64) -> Self {
+        Noise {
+            prng: Pcg32::new(seed, 1442695040888963407),
+            pink_filters: [0.0; PINK_POLES.len()],
+            pink_delayed: 0.0,
+            previous_pink: 0.0,
+            brown: 0.0,
+        }
+    }
+
+    /// Uniform white noise in `[-1, 1)`.
+    pub fn white(&mut self) -> f32 {
+        self.prng.gen_range(-1.0..1.0)
+    }
+
+    /// Produce the next sample of `color` noise. The colored noises filter the white noise, so
+    /// their filters only advance while that color is being generated.
+    pub fn next(&mut self, color: NoiseColor) -> f32 {
+        match color {
+            NoiseColor::White => self.white(),
+            NoiseColor::Pink => self.pink(),
+            NoiseColor::Brown => {
+                let white = self.white();
+                self.brown = (self.brown + white * BROWN_LEAK) / (1.0 + BROWN_LEAK);
+                self.brown * BROWN_GAIN
+            }
+            NoiseColor::Blue => {
+                let pink = self.pink();
+                let blue = (pink - self.previous_pink) * BLUE_GAIN;
+                self.previous_pink = pink;
+                blue
+            }
+        }
+    }
+
+    fn pink(&mut self) -> f32 {
+        let white = self.white();
+        let mut pink = self.pink_delayed + white * 0.5362;
+        for (filter, (pole, gain)) in self.pink_filters.iter_mut().zip(PINK_POLES) {
+            *filter = *filter * pole + white * gain;
+            pink += *filter;
+        }
+        self.pink_delayed = white * 0.115926;
+
+        pink * PINK_GAIN
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wavetable::fft;
+
+    const SEGMENT_SIZE: usize = 4096;
+    const NUM_SEGMENTS: usize = 64;
+
+    /// The noise's average power spectrum over a number of Hann windowed segments.
+    fn power_spectrum(color: NoiseColor) -> Vec<f64> {
+        let mut noise = Noise::new(1234);
+        // Let the filters settle first
+        for _ in 0..SEGMENT_SIZE {
+            noise.next(color);
+        }
+
+        let mut spectrum = vec![0.0; SEGMENT_SIZE / 2];
+        for _ in 0..NUM_SEGMENTS {
+            let mut re: Vec<f64> = (0..SEGMENT_SIZE)
+                .map(|i| {
+                    let window =
+                        0.5 - 0.5 * (std::f64::consts::TAU * i as f64 / SEGMENT_SIZE as f64).cos();
+                    noise.next(color) as f64 * window
+                })
+                .collect();
+            let mut im = vec![0.0; SEGMENT_SIZE];
+            fft(&mut re, &mut im);
+
+            for (power, (re, im)) in spectrum.iter_mut().zip(re.iter().zip(&im)) {
+                *power += re * re + im * im;
+            }
+        }
+
+        spectrum
+    }
+
+    /// The spectrum's slope in decibels per octave, measured between two bands three octaves
+    /// apart.
+    fn slope_db_per_octave(color: NoiseColor) -> f64 {
+        let spectrum = power_spectrum(color);
+        // The average power in the bins within a third of an octave around `center_bin`
+        let band_power = |center_bin: usize| {
+            let bins = &spectrum[center_bin * 8 / 9..center_bin * 9 / 8];
+            bins.iter().sum::<f64>() / bins.len() as f64
+        };
+
+        10.0 * (band_power(SEGMENT_SIZE / 16) / band_power(SEGMENT_SIZE / 128)).log10() / 3.0
+    }
+
+    #[test]
+    fn test_white_noise_is_flat() {
+        let slope = slope_db_per_octave(NoiseColor::White);
+        assert!(slope.abs() < 0.5, "{slope} dB/octave");
+    }
+
+    #[test]
+    fn test_pink_noise_slope() {
+        let slope = slope_db_per_octave(NoiseColor::Pink);
+        assert!((slope + 3.0).abs() < 0.5, "{slope} dB/octave");
+    }
+
+    #[test]
+    fn test_brown_noise_slope() {
+        let slope = slope_db_per_octave(NoiseColor::Brown);
+        assert!((slope + 6.0).abs() < 1.0, "{slope} dB/octave");
+    }
+
+    #[test]
+    fn test_blue_noise_slope() {
+        let slope = slope_db_per_octave(NoiseColor::Blue);
+        assert!((slope - 3.0).abs() < 0.5, "{slope} dB/octave");
+    }
+
+    #[test]
+    fn test_noise_is_deterministic_and_bounded() {
+        for color in enum_iterator::all::<NoiseColor>() {
+            let mut first = Noise::new(42);
+            let mut second = Noise::new(42);
+            let mut other = Noise::new(43);
+
+            let samples: Vec<f32> = (0..10000).map(|_| first.next(color)).collect();
+            assert!(
+                samples.iter().all(|sample| sample.abs() <= 1.0),
+                "{color:?}"
+            );
+            assert!(
+                samples.iter().all(|sample| *sample == second.next(color)),
+                "{color:?}"
+            );
+            assert!(
+                samples.iter().any(|sample| *sample != other.next(color)),
+                "{color:?}"
+            );
+        }
+    }
+}
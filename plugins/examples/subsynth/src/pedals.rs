@@ -0,0 +1,310 @@
+/// New notes played while the soft pedal is down have their velocity scaled by this.
+pub const SOFT_PEDAL_VELOCITY: f32 = 0.6;
+
+/// A set of notes, one bit per note for every MIDI channel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NoteSet([u128; 16]);
+
+impl NoteSet {
+    pub fn contains(&self, channel: u8, note: u8) -> bool {
+        self.0[channel as usize] & (1 << note) != 0
+    }
+
+    pub fn insert(&mut self, channel: u8, note: u8) {
+        self.0[channel as usize] |= 1 << note;
+    }
+
+    pub fn remove(&mut self, channel: u8, note: u8) {
+        self.0[channel as usize] &= !(1 << note);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|notes| *notes == 0)
+    }
+
+    /// The `(channel, note)` pairs in this set, ordered by channel and then by note.
+    pub fn iter(&self) -> impl Iterator<Item = (u8, u8)> + '_ {
+        self.0.iter().enumerate().flat_map(|(channel, notes)| {
+            (0..128u8)
+                .filter(move |note| notes & (1 << note) != 0)
+                .map(move |note| (channel as u8, note))
+        })
+    }
+
+    fn intersection(&self, other: &NoteSet) -> NoteSet {
+        NoteSet(std::array::from_fn(|channel| self.0[channel] & other.0[channel]))
+    }
+
+    fn difference(&self, other: &NoteSet) -> NoteSet {
+        NoteSet(std::array::from_fn(|channel| self.0[channel] & !other.0[channel]))
+    }
+}
+
+/// The sustain, sostenuto and soft pedals' state, and the notes they're holding. Keys that are
+/// released while a pedal holds them are only released once the pedal is lifted. The pedals apply
+/// to notes on all channels, so they also work with MPE controllers that send them on the master
+/// channel.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Pedals {
+    sustain: bool,
+    sostenuto: bool,
+    soft: bool,
+    /// The keys that are currently held down.
+    keys_down: NoteSet,
+    /// The keys that were held down when the sostenuto pedal was pressed.
+    sostenuto_notes: NoteSet,
+    /// Notes whose keys have been released, but that are still held by one of the pedals.
+    sustained_notes: NoteSet,
+}
+
+impl Pedals {
+    /// Register a key being pressed. Returns `true` if the note was still being held by a pedal,
+    /// in which case that note should be released before the new note starts.
+    pub fn key_down(&mut self, channel: u8, note: u8) -> bool {
+        let was_sustained = self.sustained_notes.contains(channel, note);
+        self.keys_down.insert(channel, note);
+        self.sustained_notes.remove(channel, note);
+
+        was_sustained
+    }
+
+    /// Register a key being released. Returns `true` if the note should be released now, or
+    /// `false` if it's held by one of the pedals.
+    pub fn key_up(&mut self, channel: u8, note: u8) -> bool {
+        self.keys_down.remove(channel, note);
+        if self.sustain || self.sostenuto_notes.contains(channel, note) {
+            self.sustained_notes.insert(channel, note);
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Press or lift the sustain pedal. Returns the notes that should be released.
+    pub fn set_sustain(&mut self, pressed: bool) -> NoteSet {
+        self.sustain = pressed;
+        if pressed {
+            return NoteSet::default();
+        }
+
+        let released = self.sustained_notes.difference(&self.sostenuto_notes);
+        self.sustained_notes = self.sustained_notes.difference(&released);
+
+        released
+    }
+
+    /// Press or lift the sostenuto pedal. Pressing the pedal holds the notes whose keys are
+    /// currently down. Returns the notes that should be released.
+    pub fn set_sostenuto(&mut self, pressed: bool) -> NoteSet {
+        if pressed == self.sostenuto {
+            return NoteSet::default();
+        }
+
+        self.sostenuto = pressed;
+        if pressed {
+            self.sostenuto_notes = self.keys_down;
+            return NoteSet::default();
+        }
+
+        let released = if self.sustain {
+            NoteSet::default()
+        } else {
+            self.sustained_notes.intersection(&self.sostenuto_notes)
+        };
+        self.sustained_notes = self.sustained_notes.difference(&released);
+        self.sostenuto_notes = NoteSet::default();
+
+        released
+    }
+
+    pub fn set_soft(&mut self, pressed: bool) {
+        self.soft = pressed;
+    }
+
+    /// The velocity for a new note, taking the soft pedal into account.
+    pub fn velocity(&self, velocity: f32) -> f32 {
+        if self.soft {
+            velocity * SOFT_PEDAL_VELOCITY
+        } else {
+            velocity
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, Copy)]
+    enum Event {
+        KeyDown(u8),
+        KeyUp(u8),
+        Sustain(bool),
+        Sostenuto(bool),
+    }
+
+    /// Apply a sequence of events to the pedals, and return the notes that got released after
+    /// every event.
+    fn released_notes(pedals: &mut Pedals, events: &[Event]) -> Vec<Vec<u8>> {
+        events
+            .iter()
+            .map(|event| match *event {
+                Event::KeyDown(note) => {
+                    if pedals.key_down(0, note) {
+                        vec![note]
+                    } else {
+                        vec![]
+                    }
+                }
+                Event::KeyUp(note) => {
+                    if pedals.key_up(0, note) {
+                        vec![note]
+                    } else {
+                        vec![]
+                    }
+                }
+                Event::Sustain(pressed) => {
+                    pedals.set_sustain(pressed).iter().map(|(_, note)| note).collect()
+                }
+                Event::Sostenuto(pressed) => {
+                    pedals.set_sostenuto(pressed).iter().map(|(_, note)| note).collect()
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_without_pedals() {
+        let mut pedals = Pedals::default();
+        let released = released_notes(
+            &mut pedals,
+            &[Event::KeyDown(60), Event::KeyDown(64), Event::KeyUp(64), Event::KeyUp(60)],
+        );
+
+        assert_eq!(released, [vec![], vec![], vec![64], vec![60]]);
+    }
+
+    #[test]
+    fn test_sustain() {
+        let mut pedals = Pedals::default();
+        let released = released_notes(
+            &mut pedals,
+            &[
+                Event::KeyDown(60),
+                Event::Sustain(true),
+                Event::KeyUp(60),
+                Event::KeyDown(64),
+                Event::KeyUp(64),
+                Event::KeyDown(67),
+                Event::Sustain(false),
+                Event::KeyUp(67),
+            ],
+        );
+
+        // Notes whose keys are still held when the pedal is lifted keep playing
+        assert_eq!(
+            released,
+            [vec![], vec![], vec![], vec![], vec![], vec![], vec![60, 64], vec![67]]
+        );
+    }
+
+    #[test]
+    fn test_restriking_a_sustained_note() {
+        let mut pedals = Pedals::default();
+        let released = released_notes(
+            &mut pedals,
+            &[
+                Event::Sustain(true),
+                Event::KeyDown(60),
+                Event::KeyUp(60),
+                Event::KeyDown(60),
+                Event::Sustain(false),
+                Event::KeyUp(60),
+            ],
+        );
+
+        // The sustained note is released when the key is struck again, and the new note is no
+        // longer held by the pedal once the key is released
+        assert_eq!(released, [vec![], vec![], vec![], vec![60], vec![], vec![60]]);
+    }
+
+    #[test]
+    fn test_sostenuto_only_holds_notes_that_were_down() {
+        let mut pedals = Pedals::default();
+        let released = released_notes(
+            &mut pedals,
+            &[
+                Event::KeyDown(48),
+                Event::Sostenuto(true),
+                Event::KeyUp(48),
+                Event::KeyDown(60),
+                Event::KeyUp(60),
+                Event::Sostenuto(false),
+            ],
+        );
+
+        assert_eq!(released, [vec![], vec![], vec![], vec![], vec![60], vec![48]]);
+    }
+
+    #[test]
+    fn test_sustain_and_sostenuto() {
+        let mut pedals = Pedals::default();
+        let released = released_notes(
+            &mut pedals,
+            &[
+                Event::KeyDown(48),
+                Event::Sostenuto(true),
+                Event::Sustain(true),
+                Event::KeyUp(48),
+                Event::KeyDown(60),
+                Event::KeyUp(60),
+                // The sostenuto pedal still holds the bass note after the sustain pedal is lifted
+                Event::Sustain(false),
+                Event::Sustain(true),
+                // And the sustain pedal still holds it after the sostenuto pedal is lifted
+                Event::Sostenuto(false),
+                Event::Sustain(false),
+            ],
+        );
+
+        assert_eq!(
+            released,
+            [
+                vec![],
+                vec![],
+                vec![],
+                vec![],
+                vec![],
+                vec![],
+                vec![60],
+                vec![],
+                vec![],
+                vec![48]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_soft_pedal() {
+        let mut pedals = Pedals::default();
+        assert_eq!(pedals.velocity(0.5), 0.5);
+        pedals.set_soft(true);
+        assert_eq!(pedals.velocity(0.5), 0.5 * SOFT_PEDAL_VELOCITY);
+        pedals.set_soft(false);
+        assert_eq!(pedals.velocity(0.5), 0.5);
+    }
+
+    #[test]
+    fn test_note_set() {
+        let mut notes = NoteSet::default();
+        assert!(notes.is_empty());
+        notes.insert(3, 127);
+        notes.insert(0, 0);
+        notes.insert(3, 5);
+        assert!(notes.contains(3, 127));
+        assert!(!notes.contains(0, 127));
+        assert_eq!(notes.iter().collect::<Vec<_>>(), [(0, 0), (3, 5), (3, 127)]);
+        notes.remove(3, 127);
+        assert_eq!(notes.iter().collect::<Vec<_>>(), [(0, 0), (3, 5)]);
+    }
+}
@@ -0,0 +1,497 @@
+use enum_iterator::Sequence;
+use nih_plug::prelude::*;
+use nih_plug::wrapper::state::ParamValue;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Persisted fields that describe the editor rather than the sound, and are left alone when
+/// loading a preset.
+const EXCLUDED_FIELDS: &[&str] = &["editor-state"];
+
+/// The presets shipped with the plugin, in browsing order. These only list the parameters that
+/// differ from their defaults.
+const FACTORY_PRESETS: &[&str] = &[
+    include_str!("../assets/presets/init.json"),
+    include_str!("../assets/presets/sub_bass.json"),
+    include_str!("../assets/presets/acid_bass.json"),
+    include_str!("../assets/presets/sync_lead.json"),
+    include_str!("../assets/presets/warm_pad.json"),
+    include_str!("../assets/presets/soft_keys.json"),
+    include_str!("../assets/presets/glass_pluck.json"),
+    include_str!("../assets/presets/bubble_arp.json"),
+    include_str!("../assets/presets/noise_sweep.json"),
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, Sequence)]
+#[serde(rename_all = "lowercase")]
+pub enum PresetCategory {
+    Bass,
+    Lead,
+    Pad,
+    Keys,
+    Pluck,
+    Arp,
+    Fx,
+    #[default]
+    Other,
+}
+
+impl fmt::Display for PresetCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PresetCategory::Bass => "Bass",
+            PresetCategory::Lead => "Lead",
+            PresetCategory::Pad => "Pad",
+            PresetCategory::Keys => "Keys",
+            PresetCategory::Pluck => "Pluck",
+            PresetCategory::Arp => "Arp",
+            PresetCategory::Fx => "FX",
+            PresetCategory::Other => "Other",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// A preset as stored in a JSON file. The state may leave out parameters and fields, in which
+/// case their default values are used.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Preset {
+    pub name: String,
+    #[serde(default)]
+    pub category: PresetCategory,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub state: PluginState,
+}
+
+/// Which presets are shown in the browser and visited by next/previous navigation.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PresetFilter {
+    /// Only show presets in this category, or all categories if this is `None`.
+    pub category: Option<PresetCategory>,
+    /// Only show presets with this tag. This is case insensitive, and empty matches everything.
+    pub tag: String,
+}
+
+impl PresetFilter {
+    pub fn matches(&self, preset: &Preset) -> bool {
+        let tag = self.tag.trim();
+        (self.category.is_none() || self.category == Some(preset.category))
+            && (tag.is_empty() || preset.tags.iter().any(|t| t.eq_ignore_ascii_case(tag)))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PresetEntry {
+    preset: Preset,
+    /// The file a user preset was loaded from or saved to. This is `None` for factory presets.
+    path: Option<PathBuf>,
+}
+
+/// The factory presets followed by the user's presets.
+#[derive(Debug, Clone)]
+pub struct PresetBank {
+    entries: Vec<PresetEntry>,
+    /// The state with every parameter and field at its default value. Presets are loaded on top
+    /// of this.
+    defaults: PluginState,
+    /// Where user presets are stored, if the platform has a data directory.
+    user_dir: Option<PathBuf>,
+}
+
+impl PresetBank {
+    /// Load the factory presets and the user presets from `user_dir`. User presets that can't be
+    /// read are skipped.
+    pub fn new(defaults: PluginState, user_dir: Option<PathBuf>) -> Self {
+        let mut entries: Vec<PresetEntry> = FACTORY_PRESETS
+            .iter()
+            .map(|json| PresetEntry {
+                preset: serde_json::from_str(json).expect("Invalid factory preset"),
+                path: None,
+            })
+            .collect();
+
+        let mut user_entries: Vec<PresetEntry> = user_dir
+            .as_deref()
+            .and_then(|dir| std::fs::read_dir(dir).ok())
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                if path.extension()? != "json" {
+                    return None;
+                }
+
+                match read_preset(&path) {
+                    Ok(preset) => Some(PresetEntry {
+                        preset,
+                        path: Some(path),
+                    }),
+                    Err(err) => {
+                        nih_log!("Could not load the preset at '{}': {err}", path.display());
+                        None
+                    }
+                }
+            })
+            .collect();
+        user_entries.sort_by_key(|entry| entry.preset.name.to_lowercase());
+        entries.extend(user_entries);
+
+        Self {
+            entries,
+            defaults,
+            user_dir,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn preset(&self, preset_idx: usize) -> &Preset {
+        &self.entries[preset_idx].preset
+    }
+
+    pub fn is_factory_preset(&self, preset_idx: usize) -> bool {
+        self.entries[preset_idx].path.is_none()
+    }
+
+    /// The presets matching the filter, along with their indices.
+    pub fn filtered<'a>(
+        &'a self,
+        filter: &'a PresetFilter,
+    ) -> impl DoubleEndedIterator<Item = (usize, &'a Preset)> + 'a {
+        self.entries
+            .iter()
+            .map(|entry| &entry.preset)
+            .enumerate()
+            .filter(move |(_, preset)| filter.matches(preset))
+    }
+
+    /// The next preset matching the filter, wrapping around to the first one.
+    pub fn next(&self, current: Option<usize>, filter: &PresetFilter) -> Option<usize> {
+        let mut matching = self.filtered(filter).map(|(preset_idx, _)| preset_idx);
+        let first = matching.next()?;
+        match current {
+            Some(current) if current >= first => matching
+                .find(|preset_idx| *preset_idx > current)
+                .or(Some(first)),
+            _ => Some(first),
+        }
+    }
+
+    /// The previous preset matching the filter, wrapping around to the last one.
+    pub fn previous(&self, current: Option<usize>, filter: &PresetFilter) -> Option<usize> {
+        let mut matching = self
+            .filtered(filter)
+            .map(|(preset_idx, _)| preset_idx)
+            .rev();
+        let last = matching.next()?;
+        match current {
+            Some(current) if current <= last => matching
+                .find(|preset_idx| *preset_idx < current)
+                .or(Some(last)),
+            _ => Some(last),
+        }
+    }
+
+    /// The complete state for a preset, with the values the preset leaves out set to their
+    /// defaults. This can be passed to [`GuiContext::set_state()`].
+    pub fn state(&self, preset_idx: usize) -> PluginState {
+        let preset_state = &self.preset(preset_idx).state;
+        let mut state = self.defaults.clone();
+        state.params.extend(
+            preset_state
+                .params
+                .iter()
+                .map(|(id, value)| (id.clone(), value.clone())),
+        );
+        state.fields.extend(
+            preset_state
+                .fields
+                .iter()
+                .filter(|(key, _)| !EXCLUDED_FIELDS.contains(&key.as_str()))
+                .map(|(key, value)| (key.clone(), value.clone())),
+        );
+        if !preset_state.version.is_empty() {
+            state.version = preset_state.version.clone();
+        }
+
+        state
+    }
+
+    /// Save a user preset, replacing any user preset with the same name. Returns the preset's
+    /// index in the bank.
+    pub fn save(&mut self, mut preset: Preset) -> std::io::Result<usize> {
+        let user_dir = self.user_dir.as_deref().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "No user preset directory")
+        })?;
+        for key in EXCLUDED_FIELDS {
+            preset.state.fields.remove(*key);
+        }
+
+        std::fs::create_dir_all(user_dir)?;
+        let path = user_dir.join(format!("{}.json", file_name(&preset.name)));
+        std::fs::write(&path, serde_json::to_string_pretty(&preset)?)?;
+
+        let entry = PresetEntry {
+            preset,
+            path: Some(path),
+        };
+        match self
+            .entries
+            .iter()
+            .position(|existing| existing.path == entry.path)
+        {
+            Some(preset_idx) => {
+                self.entries[preset_idx] = entry;
+                Ok(preset_idx)
+            }
+            None => {
+                self.entries.push(entry);
+                Ok(self.entries.len() - 1)
+            }
+        }
+    }
+}
+
+/// The directory user presets are stored in, `$XDG_DATA_HOME/subsynth/presets` on Linux.
+pub fn user_presets_dir() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("subsynth").join("presets"))
+}
+
+/// The state with every parameter and persisted field at its default value.
+pub fn default_state(params: &dyn Params) -> PluginState {
+    let params_map = params
+        .param_map()
+        .into_iter()
+        .map(|(id, param_ptr, _)| {
+            // SAFETY: The parameters are owned by `params`, which outlives this function
+            let value = unsafe {
+                match param_ptr {
+                    ParamPtr::FloatParam(p) => ParamValue::F32((*p).default_plain_value()),
+                    ParamPtr::IntParam(p) => ParamValue::I32((*p).default_plain_value()),
+                    ParamPtr::BoolParam(p) => ParamValue::Bool((*p).default_plain_value()),
+                    ParamPtr::EnumParam(p) => ParamValue::I32((*p).default_plain_value()),
+                }
+            };
+
+            (id, value)
+        })
+        .collect();
+    let mut fields = params.serialize_fields();
+    fields.retain(|key, _| !EXCLUDED_FIELDS.contains(&key.as_str()));
+
+    PluginState {
+        version: String::new(),
+        params: params_map,
+        fields,
+    }
+}
+
+fn read_preset(path: &Path) -> std::io::Result<Preset> {
+    Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+}
+
+/// A file name for a preset, with characters that aren't allowed in file names replaced.
+fn file_name(preset_name: &str) -> String {
+    let name: String = preset_name
+        .trim()
+        .chars()
+        .map(|c| {
+            if c.is_control() || "/\\:*?\"<>|".contains(c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+
+    match name.trim_start_matches('.') {
+        "" => String::from("Untitled"),
+        _ => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SubSynthParams;
+
+    fn factory_bank() -> PresetBank {
+        PresetBank::new(default_state(&SubSynthParams::default()), None)
+    }
+
+    fn preset(name: &str, category: PresetCategory, tags: &[&str]) -> Preset {
+        Preset {
+            name: name.to_owned(),
+            category,
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            state: PluginState {
+                version: String::new(),
+                params: [(String::from("filter_cut"), ParamValue::F32(440.0))].into(),
+                fields: [(String::from("editor-state"), String::from("{}"))].into(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_factory_presets_match_the_parameters() {
+        let params = SubSynthParams::default();
+        let param_map = params.param_map();
+        let bank = factory_bank();
+        assert_eq!(bank.len(), FACTORY_PRESETS.len());
+
+        for preset_idx in 0..bank.len() {
+            let preset = bank.preset(preset_idx);
+            assert!(bank.is_factory_preset(preset_idx));
+            for (id, value) in &preset.state.params {
+                let (_, param_ptr, _) = param_map
+                    .iter()
+                    .find(|(param_id, _, _)| param_id == id)
+                    .unwrap_or_else(|| panic!("{}: unknown parameter {id}", preset.name));
+                // SAFETY: `params` is still alive
+                let in_range = unsafe {
+                    match (param_ptr, value) {
+                        (ParamPtr::FloatParam(_), ParamValue::F32(v)) => {
+                            (param_ptr.preview_plain(param_ptr.preview_normalized(*v)) - v).abs()
+                                < 1e-3
+                        }
+                        (ParamPtr::IntParam(_) | ParamPtr::EnumParam(_), ParamValue::I32(v)) => {
+                            param_ptr.preview_plain(param_ptr.preview_normalized(*v as f32))
+                                == *v as f32
+                        }
+                        (ParamPtr::BoolParam(_), ParamValue::Bool(_)) => true,
+                        _ => false,
+                    }
+                };
+                assert!(
+                    in_range,
+                    "{}: invalid value {value:?} for {id}",
+                    preset.name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_preset_state_includes_defaults() {
+        let defaults = default_state(&SubSynthParams::default());
+        let bank = factory_bank();
+        let acid_bass = bank
+            .filtered(&PresetFilter::default())
+            .find(|(_, preset)| preset.name == "Acid Bass")
+            .unwrap()
+            .0;
+
+        let state = bank.state(acid_bass);
+        assert_eq!(
+            state.params.keys().collect::<Vec<_>>(),
+            defaults.params.keys().collect::<Vec<_>>()
+        );
+        assert!(matches!(state.params["filter_cut"], ParamValue::F32(v) if v == 300.0));
+        assert!(matches!(state.params["gain"], ParamValue::F32(v) if v == util::db_to_gain(-12.0)));
+        assert!(state.fields.contains_key("sequence"));
+        assert!(!state.fields.contains_key("editor-state"));
+    }
+
+    #[test]
+    fn test_navigation() {
+        let bank = factory_bank();
+        let all = PresetFilter::default();
+        assert_eq!(bank.next(None, &all), Some(0));
+        assert_eq!(bank.next(Some(0), &all), Some(1));
+        assert_eq!(bank.next(Some(bank.len() - 1), &all), Some(0));
+        assert_eq!(bank.previous(Some(0), &all), Some(bank.len() - 1));
+        assert_eq!(bank.previous(None, &all), Some(bank.len() - 1));
+
+        let bass = PresetFilter {
+            category: Some(PresetCategory::Bass),
+            tag: String::new(),
+        };
+        let bass_presets: Vec<usize> = bank.filtered(&bass).map(|(idx, _)| idx).collect();
+        assert_eq!(bass_presets.len(), 2);
+        assert_eq!(bank.next(Some(0), &bass), Some(bass_presets[0]));
+        assert_eq!(
+            bank.next(Some(bass_presets[0]), &bass),
+            Some(bass_presets[1])
+        );
+        assert_eq!(
+            bank.next(Some(bass_presets[1]), &bass),
+            Some(bass_presets[0])
+        );
+        assert_eq!(
+            bank.previous(Some(bass_presets[0]), &bass),
+            Some(bass_presets[1])
+        );
+        assert_eq!(
+            bank.previous(Some(bank.len() - 1), &bass),
+            Some(bass_presets[1])
+        );
+
+        let resonant_bass = PresetFilter {
+            tag: String::from(" Resonant "),
+            ..bass
+        };
+        assert_eq!(
+            bank.filtered(&resonant_bass)
+                .map(|(_, p)| p.name.as_str())
+                .collect::<Vec<_>>(),
+            ["Acid Bass"]
+        );
+
+        let nothing = PresetFilter {
+            tag: String::from("does not exist"),
+            ..Default::default()
+        };
+        assert_eq!(bank.next(Some(0), &nothing), None);
+        assert_eq!(bank.previous(Some(0), &nothing), None);
+    }
+
+    #[test]
+    fn test_user_presets() {
+        let user_dir =
+            std::env::temp_dir().join(format!("subsynth-presets-{}", std::process::id()));
+        let defaults = default_state(&SubSynthParams::default());
+        let mut bank = PresetBank::new(defaults.clone(), Some(user_dir.clone()));
+
+        let saved_idx = bank
+            .save(preset("Deep/Bass", PresetCategory::Bass, &["dark"]))
+            .unwrap();
+        assert_eq!(saved_idx, FACTORY_PRESETS.len());
+        assert!(!bank.is_factory_preset(saved_idx));
+        assert!(user_dir.join("Deep_Bass.json").exists());
+        // Saving under the same name replaces the preset
+        let resaved_idx = bank
+            .save(preset("Deep/Bass", PresetCategory::Lead, &[]))
+            .unwrap();
+        assert_eq!(resaved_idx, saved_idx);
+        assert_eq!(bank.len(), FACTORY_PRESETS.len() + 1);
+        bank.save(preset("Another", PresetCategory::Pad, &[]))
+            .unwrap();
+
+        // User presets are loaded after the factory presets, sorted by name
+        let reloaded = PresetBank::new(defaults, Some(user_dir.clone()));
+        std::fs::remove_dir_all(&user_dir).unwrap();
+        assert_eq!(reloaded.len(), FACTORY_PRESETS.len() + 2);
+        assert_eq!(reloaded.preset(FACTORY_PRESETS.len()).name, "Another");
+        let deep_bass = reloaded.preset(FACTORY_PRESETS.len() + 1);
+        assert_eq!(deep_bass.name, "Deep/Bass");
+        assert_eq!(deep_bass.category, PresetCategory::Lead);
+        assert!(!deep_bass.state.fields.contains_key("editor-state"));
+        assert!(matches!(
+            reloaded.state(FACTORY_PRESETS.len() + 1).params["filter_cut"],
+            ParamValue::F32(v) if v == 440.0
+        ));
+    }
+
+    #[test]
+    fn test_file_names() {
+        assert_eq!(file_name("Warm Pad"), "Warm Pad");
+        assert_eq!(file_name(" a/b\\c:d*?\"<>|e "), "a_b_c_d______e");
+        assert_eq!(file_name("..."), "Untitled");
+        assert_eq!(file_name(""), "Untitled");
+    }
+}
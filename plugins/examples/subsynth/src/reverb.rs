@@ -0,0 +1,325 @@
+use nih_plug::prelude::*;
+
+use crate::effects::{bypass_param, mix_param, slot_param, DelayLine, EffectKind};
+
+/// The comb filters' delays in samples at 44.1 kHz, from Freeverb.
+const COMB_DELAYS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+/// The allpass filters' delays in samples at 44.1 kHz, from Freeverb.
+const ALLPASS_DELAYS: [usize; 4] = [556, 441, 341, 225];
+/// The right channel's delays are this many samples longer at 44.1 kHz, which decorrelates the
+/// channels.
+const STEREO_SPREAD: usize = 23;
+const ALLPASS_FEEDBACK: f32 = 0.5;
+/// The input is attenuated before it goes into the comb filters, and the output is boosted again
+/// afterwards. Otherwise the eight parallel comb filters would easily clip.
+const INPUT_GAIN: f32 = 0.015;
+const OUTPUT_GAIN: f32 = 3.0;
+const MAX_PREDELAY_MS: f32 = 200.0;
+
+#[derive(Params)]
+pub struct ReverbParams {
+    #[id = "reverb_bypass"]
+    pub bypass: BoolParam,
+    #[id = "reverb_mix"]
+    pub mix: FloatParam,
+    #[id = "reverb_slot"]
+    pub slot: IntParam,
+    /// The time it takes for the reverb to decay by 60 dB, in seconds.
+    #[id = "reverb_decay"]
+    pub decay: FloatParam,
+    /// How quickly high frequencies decay compared to low frequencies.
+    #[id = "reverb_damping"]
+    pub damping: FloatParam,
+    #[id = "reverb_predelay"]
+    pub predelay_ms: FloatParam,
+    #[id = "reverb_width"]
+    pub width: FloatParam,
+}
+
+impl Default for ReverbParams {
+    fn default() -> Self {
+        Self {
+            bypass: bypass_param("Reverb"),
+            mix: mix_param("Reverb", 0.25),
+            slot: slot_param("Reverb", EffectKind::Reverb),
+            decay: FloatParam::new(
+                "Reverb Decay",
+                2.0,
+                FloatRange::Skewed {
+                    min: 0.1,
+                    max: 20.0,
+                    factor: FloatRange::skew_factor(-1.5),
+                },
+            )
+            .with_unit(" s")
+            .with_value_to_string(formatters::v2s_f32_rounded(2)),
+            damping: FloatParam::new(
+                "Reverb Damping",
+                0.5,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            predelay_ms: FloatParam::new(
+                "Reverb Predelay",
+                10.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: MAX_PREDELAY_MS,
+                },
+            )
+            .with_unit(" ms")
+            .with_step_size(0.1),
+            width: FloatParam::new(
+                "Reverb Width",
+                1.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+        }
+    }
+}
+
+impl ReverbParams {
+    /// The reverb's settings for the current buffer, or `None` if it's bypassed.
+    pub fn settings(&self, sample_rate: f32) -> Option<ReverbSettings> {
+        if self.bypass.value() {
+            return None;
+        }
+
+        Some(ReverbSettings::new(
+            self.decay.value(),
+            self.damping.value(),
+            self.predelay_ms.value() / 1000.0 * sample_rate,
+            self.width.value(),
+            sample_rate,
+        ))
+    }
+}
+
+/// The reverb's parameters, computed once per buffer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReverbSettings {
+    pub decay_seconds: f32,
+    /// The feedback gains of both channels' comb filters, which make them decay by 60 dB in
+    /// `decay_seconds`.
+    pub comb_feedback: [[f32; COMB_DELAYS.len()]; 2],
+    pub damping: f32,
+    pub predelay_samples: f32,
+    pub width: f32,
+}
+
+impl ReverbSettings {
+    pub fn new(
+        decay_seconds: f32,
+        damping: f32,
+        predelay_samples: f32,
+        width: f32,
+        sample_rate: f32,
+    ) -> Self {
+        let decay_samples = decay_seconds * sample_rate;
+        let comb_feedback = [0, 1].map(|channel| {
+            COMB_DELAYS.map(|len| {
+                let len = delay_len(len, channel, sample_rate);
+                10.0f32.powf(-3.0 * len as f32 / decay_samples)
+            })
+        });
+
+        Self {
+            decay_seconds,
+            comb_feedback,
+            damping,
+            predelay_samples,
+            width,
+        }
+    }
+
+    pub fn tail_seconds(&self, sample_rate: f32) -> f32 {
+        self.decay_seconds + self.predelay_samples / sample_rate
+    }
+}
+
+/// The length of a comb or allpass filter's delay line for `channel` at `sample_rate`, scaled from
+/// its length at 44.1 kHz.
+fn delay_len(len: usize, channel: usize, sample_rate: f32) -> usize {
+    let scale = sample_rate / 44100.0;
+    (((len + channel * STEREO_SPREAD) as f32 * scale).round() as usize).max(1)
+}
+
+/// A feedback comb filter with a lowpass filter in its feedback path.
+#[derive(Debug, Clone)]
+struct Comb {
+    buffer: Vec<f32>,
+    pos: usize,
+    filter: f32,
+}
+
+impl Comb {
+    fn new(len: usize) -> Self {
+        Self {
+            buffer: vec![0.0; len],
+            pos: 0,
+            filter: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let output = self.buffer[self.pos];
+        self.filter = output + (self.filter - output) * damping;
+        self.buffer[self.pos] = input + self.filter * feedback;
+        self.pos = (self.pos + 1) % self.buffer.len();
+
+        output
+    }
+}
+
+/// A Schroeder allpass filter, used to diffuse the comb filters' output.
+#[derive(Debug, Clone)]
+struct Allpass {
+    buffer: Vec<f32>,
+    pos: usize,
+}
+
+impl Allpass {
+    fn new(len: usize) -> Self {
+        Self {
+            buffer: vec![0.0; len],
+            pos: 0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let delayed = self.buffer[self.pos];
+        self.buffer[self.pos] = input + delayed * ALLPASS_FEEDBACK;
+        self.pos = (self.pos + 1) % self.buffer.len();
+
+        delayed - input
+    }
+}
+
+/// An algorithmic reverb based on Freeverb. Each channel runs eight parallel comb filters into
+/// four allpass filters. The comb filters' feedback is derived from the decay time, so the
+/// reported tail matches what's heard.
+#[derive(Debug, Clone)]
+pub struct Reverb {
+    predelay: DelayLine,
+    combs: [[Comb; COMB_DELAYS.len()]; 2],
+    allpasses: [[Allpass; ALLPASS_DELAYS.len()]; 2],
+}
+
+impl Reverb {
+    /// Create a reverb for `sample_rate`. The settings it's processed with need to be computed
+    /// for the same sample rate.
+    pub fn new(sample_rate: f32) -> Self {
+        let scaled = |len: usize, channel: usize| delay_len(len, channel, sample_rate);
+
+        Self {
+            predelay: DelayLine::new((MAX_PREDELAY_MS / 1000.0 * sample_rate).ceil() as usize + 1),
+            combs: [0, 1].map(|channel| COMB_DELAYS.map(|len| Comb::new(scaled(len, channel)))),
+            allpasses: [0, 1]
+                .map(|channel| ALLPASS_DELAYS.map(|len| Allpass::new(scaled(len, channel)))),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.predelay.clear();
+        for comb in self.combs.iter_mut().flatten() {
+            comb.buffer.fill(0.0);
+            comb.filter = 0.0;
+        }
+        for allpass in self.allpasses.iter_mut().flatten() {
+            allpass.buffer.fill(0.0);
+        }
+    }
+
+    /// Process a stereo sample, returning the wet signal.
+    pub fn process(&mut self, (left, right): (f32, f32), settings: &ReverbSettings) -> (f32, f32) {
+        // The input is written first, so a delay of one sample is the input itself
+        self.predelay.write((left + right) * INPUT_GAIN);
+        let input = self.predelay.read(settings.predelay_samples + 1.0);
+
+        let mut wet = [0.0; 2];
+        for (((wet, combs), allpasses), comb_feedback) in wet
+            .iter_mut()
+            .zip(&mut self.combs)
+            .zip(&mut self.allpasses)
+            .zip(&settings.comb_feedback)
+        {
+            let mut sample = combs
+                .iter_mut()
+                .zip(comb_feedback)
+                .map(|(comb, feedback)| comb.process(input, *feedback, settings.damping))
+                .sum::<f32>();
+            for allpass in allpasses {
+                sample = allpass.process(sample);
+            }
+
+            *wet = sample * OUTPUT_GAIN;
+        }
+
+        // At full width the channels are kept apart, and at zero width they're summed to mono
+        let direct = 0.5 + settings.width * 0.5;
+        let cross = 0.5 - settings.width * 0.5;
+        (
+            wet[0] * direct + wet[1] * cross,
+            wet[1] * direct + wet[0] * cross,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|sample| sample * sample).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn test_reverb_decays_by_60_db() {
+        let sample_rate = 44100.0;
+        let settings = ReverbSettings::new(1.0, 0.0, 0.0, 1.0, sample_rate);
+        let mut reverb = Reverb::new(sample_rate);
+
+        let mut left = Vec::new();
+        for sample_idx in 0..(sample_rate as usize * 2) {
+            let input = if sample_idx < 64 { 1.0 } else { 0.0 };
+            left.push(reverb.process((input, input), &settings).0);
+        }
+
+        // Compare a window right after the early reflections with a window one decay time later
+        let window = 4410;
+        let early = rms(&left[2205..2205 + window]);
+        let late = rms(&left[2205 + 44100..2205 + 44100 + window]);
+        let decay_db = util::gain_to_db(late / early);
+        assert!((-66.0..-54.0).contains(&decay_db), "{decay_db} dB");
+    }
+
+    #[test]
+    fn test_width() {
+        let mut settings = ReverbSettings::new(0.5, 0.5, 100.0, 1.0, 44100.0);
+        let mut reverb = Reverb::new(44100.0);
+        let outputs: Vec<(f32, f32)> = (0..4000)
+            .map(|sample_idx| {
+                let input = if sample_idx == 0 { 1.0 } else { 0.0 };
+                reverb.process((input, 0.0), &settings)
+            })
+            .collect();
+        // The predelay holds back the reverb, and the channels differ at full width
+        assert!(outputs[..100]
+            .iter()
+            .all(|(left, right)| *left == 0.0 && *right == 0.0));
+        assert!(outputs.iter().any(|(left, right)| left != right));
+
+        settings.width = 0.0;
+        reverb.reset();
+        for sample_idx in 0..4000 {
+            let input = if sample_idx == 0 { 1.0 } else { 0.0 };
+            let (left, right) = reverb.process((input, 0.0), &settings);
+            assert_eq!(left, right);
+        }
+    }
+}
@@ -0,0 +1,322 @@
+use enum_iterator::Sequence;
+use nih_plug::prelude::*;
+use std::sync::Arc;
+
+/// The number of breakpoints in the custom velocity curve. These are spread evenly over the
+/// velocity range, so the first point sets the response for zero velocity and the last point the
+/// response for full velocity.
+pub const NUM_VELOCITY_POINTS: usize = 5;
+
+/// How a note's velocity is turned into the velocity response used by the amplitude and the
+/// envelope times. The filter's velocity sensitivity follows the played velocity, so changing the
+/// curve doesn't change how bright notes are.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Enum, Sequence)]
+pub enum VelocityCurve {
+    Linear,
+    /// The velocity squared, so soft notes are much softer.
+    Exponential,
+    /// The square root of the velocity, so soft notes stay closer to full level. This is the
+    /// response SubSynth has always used for its amplitude.
+    Logarithmic,
+    /// Every note uses the fixed velocity parameter's value.
+    Fixed,
+    /// Interpolates between the custom breakpoints.
+    Custom,
+}
+
+impl VelocityCurve {
+    /// The response for `velocity` in `[0, 1]`. `fixed` is the fixed velocity and `points` are the
+    /// custom curve's breakpoints.
+    pub fn response(self, velocity: f32, fixed: f32, points: &[f32; NUM_VELOCITY_POINTS]) -> f32 {
+        let velocity = velocity.clamp(0.0, 1.0);
+        match self {
+            VelocityCurve::Linear => velocity,
+            VelocityCurve::Exponential => velocity * velocity,
+            VelocityCurve::Logarithmic => velocity.sqrt(),
+            VelocityCurve::Fixed => fixed,
+            VelocityCurve::Custom => {
+                let position = velocity * (NUM_VELOCITY_POINTS - 1) as f32;
+                let point_idx = (position as usize).min(NUM_VELOCITY_POINTS - 2);
+                let t = position - point_idx as f32;
+                points[point_idx] + (points[point_idx + 1] - points[point_idx]) * t
+            }
+        }
+    }
+}
+
+/// A single breakpoint of the custom velocity curve.
+#[derive(Params)]
+pub struct VelocityPointParams {
+    #[id = "vel_point"]
+    pub level: FloatParam,
+}
+
+impl VelocityPointParams {
+    /// The parameters for the breakpoint at `point_idx`. The breakpoints start out as a linear
+    /// curve.
+    pub fn new(point_idx: usize) -> Self {
+        Self {
+            level: FloatParam::new(
+                format!("Velocity Point {}", point_idx + 1),
+                point_idx as f32 / (NUM_VELOCITY_POINTS - 1) as f32,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+        }
+    }
+}
+
+#[derive(Params)]
+pub struct ScalingParams {
+    #[id = "vel_curve"]
+    pub velocity_curve: EnumParam<VelocityCurve>,
+    /// The velocity response used by [`VelocityCurve::Fixed`].
+    #[id = "vel_fixed"]
+    pub fixed_velocity: FloatParam,
+    #[nested(array, group = "Velocity Curve")]
+    pub velocity_points: [VelocityPointParams; NUM_VELOCITY_POINTS],
+    /// How much the velocity response scales the amplitude.
+    #[id = "vel_amp"]
+    pub velocity_to_amp: FloatParam,
+    /// How much softer notes lengthen the amplitude and filter envelopes' times. At 100% a note
+    /// with zero velocity response has twice the times of a full velocity note. Negative values
+    /// shorten them instead.
+    #[id = "vel_env"]
+    pub velocity_to_envelope: FloatParam,
+
+    /// The note the key scaling is centered around. The filter's keyboard tracking doesn't use
+    /// this, and always pivots around C4.
+    #[id = "key_split"]
+    pub key_split: IntParam,
+    /// The amplitude change per octave away from the split, in decibels.
+    #[id = "key_amp"]
+    pub key_to_amp: FloatParam,
+    /// How much the envelope times lengthen per octave below the split, in percent per octave. At
+    /// 100% they double every octave.
+    #[id = "key_env_low"]
+    pub key_to_envelope_low: FloatParam,
+    /// How much the envelope times shorten per octave above the split, in percent per octave. At
+    /// 100% they halve every octave.
+    #[id = "key_env_high"]
+    pub key_to_envelope_high: FloatParam,
+}
+
+impl Default for ScalingParams {
+    fn default() -> Self {
+        Self {
+            velocity_curve: EnumParam::new("Velocity Curve", VelocityCurve::Logarithmic),
+            fixed_velocity: FloatParam::new(
+                "Fixed Velocity",
+                1.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            velocity_points: std::array::from_fn(VelocityPointParams::new),
+            velocity_to_amp: FloatParam::new(
+                "Velocity to Amp",
+                1.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            velocity_to_envelope: envelope_scaling_param("Velocity to Env Time", " %"),
+
+            key_split: IntParam::new("Key Split", 60, IntRange::Linear { min: 0, max: 127 })
+                .with_value_to_string(formatters::v2s_i32_note_formatter())
+                .with_string_to_value(formatters::s2v_i32_note_formatter()),
+            key_to_amp: FloatParam::new(
+                "Key to Amp",
+                0.0,
+                FloatRange::Linear {
+                    min: -6.0,
+                    max: 6.0,
+                },
+            )
+            .with_step_size(0.1)
+            .with_unit(" dB/oct"),
+            key_to_envelope_low: envelope_scaling_param("Key to Env Time Low", " %/oct"),
+            key_to_envelope_high: envelope_scaling_param("Key to Env Time High", " %/oct"),
+        }
+    }
+}
+
+/// A bipolar envelope time scaling amount, where 100% doubles or halves the envelope times. For
+/// the key scaling that's per octave away from the split, and for the velocity scaling it's
+/// between full and zero velocity response.
+fn envelope_scaling_param(name: &str, unit: &'static str) -> FloatParam {
+    let string_to_value = formatters::s2v_f32_percentage();
+    FloatParam::new(
+        name,
+        0.0,
+        FloatRange::Linear {
+            min: -1.0,
+            max: 1.0,
+        },
+    )
+    .with_unit(unit)
+    .with_value_to_string(formatters::v2s_f32_percentage(0))
+    // The CLAP wrapper includes the unit in the string
+    .with_string_to_value(Arc::new(move |string| {
+        string_to_value(string.trim().trim_end_matches("/oct"))
+    }))
+}
+
+impl ScalingParams {
+    pub fn settings(&self) -> ScalingSettings {
+        ScalingSettings {
+            velocity_curve: self.velocity_curve.value(),
+            fixed_velocity: self.fixed_velocity.value(),
+            velocity_points: std::array::from_fn(|point_idx| {
+                self.velocity_points[point_idx].level.value()
+            }),
+            velocity_to_amp: self.velocity_to_amp.value(),
+            velocity_to_envelope: self.velocity_to_envelope.value(),
+            key_split: self.key_split.value() as f32,
+            key_to_amp: self.key_to_amp.value(),
+            key_to_envelope_low: self.key_to_envelope_low.value(),
+            key_to_envelope_high: self.key_to_envelope_high.value(),
+        }
+    }
+}
+
+/// The velocity and key scaling parameters, computed once per block.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScalingSettings {
+    pub velocity_curve: VelocityCurve,
+    pub fixed_velocity: f32,
+    pub velocity_points: [f32; NUM_VELOCITY_POINTS],
+    pub velocity_to_amp: f32,
+    pub velocity_to_envelope: f32,
+    pub key_split: f32,
+    /// In decibels per octave.
+    pub key_to_amp: f32,
+    /// In octaves of envelope time per octave of key distance, so 1.0 doubles the times every
+    /// octave below the split.
+    pub key_to_envelope_low: f32,
+    /// Like `key_to_envelope_low`, but halving the times every octave above the split.
+    pub key_to_envelope_high: f32,
+}
+
+impl ScalingSettings {
+    /// The velocity response for a note played with `velocity`.
+    pub fn velocity_response(&self, velocity: f32) -> f32 {
+        self.velocity_curve
+            .response(velocity, self.fixed_velocity, &self.velocity_points)
+    }
+
+    /// The distance between `note` and the split note in octaves. Notes below the split are
+    /// negative.
+    pub fn key_octaves(&self, note: f32) -> f32 {
+        (note - self.key_split) / 12.0
+    }
+
+    /// The amplitude gain for a note with the given velocity response.
+    pub fn amp_gain(&self, velocity_response: f32, note: f32) -> f32 {
+        (1.0 + (velocity_response - 1.0) * self.velocity_to_amp)
+            * util::db_to_gain_fast(self.key_to_amp * self.key_octaves(note))
+    }
+
+    /// The factor the amplitude and filter envelopes' times are multiplied by. Full velocity notes
+    /// at the split note use the unscaled times.
+    pub fn envelope_time_scale(&self, velocity_response: f32, note: f32) -> f32 {
+        let key_octaves = self.key_octaves(note);
+        let key_scaling = if key_octaves < 0.0 {
+            -key_octaves * self.key_to_envelope_low
+        } else {
+            -key_octaves * self.key_to_envelope_high
+        };
+
+        (self.velocity_to_envelope * (1.0 - velocity_response) + key_scaling).exp2()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> ScalingSettings {
+        ScalingParams::default().settings()
+    }
+
+    #[test]
+    fn test_velocity_curves() {
+        let points = [0.2, 0.2, 0.6, 0.9, 1.0];
+        for curve in enum_iterator::all::<VelocityCurve>() {
+            let responses: Vec<f32> = (0..=100)
+                .map(|velocity| curve.response(velocity as f32 / 100.0, 0.7, &points))
+                .collect();
+            assert!(
+                responses.windows(2).all(|pair| pair[0] <= pair[1]),
+                "{curve:?}"
+            );
+            assert!(
+                responses
+                    .iter()
+                    .all(|response| (0.0..=1.0).contains(response)),
+                "{curve:?}"
+            );
+        }
+
+        let response = |curve: VelocityCurve, velocity: f32| curve.response(velocity, 0.7, &points);
+        assert_eq!(response(VelocityCurve::Linear, 0.5), 0.5);
+        assert_eq!(response(VelocityCurve::Exponential, 0.5), 0.25);
+        assert_eq!(response(VelocityCurve::Logarithmic, 0.25), 0.5);
+        assert_eq!(response(VelocityCurve::Fixed, 0.1), 0.7);
+        assert_eq!(response(VelocityCurve::Custom, 0.0), 0.2);
+        assert_eq!(response(VelocityCurve::Custom, 0.5), 0.6);
+        approx::assert_relative_eq!(response(VelocityCurve::Custom, 0.625), 0.75);
+        assert_eq!(response(VelocityCurve::Custom, 1.0), 1.0);
+    }
+
+    #[test]
+    fn test_default_scaling_is_neutral() {
+        let settings = settings();
+        // The amplitude follows the square root of the velocity, like it always has
+        approx::assert_relative_eq!(
+            settings.amp_gain(settings.velocity_response(0.25), 36.0),
+            0.5
+        );
+        for note in [0.0, 60.0, 127.0] {
+            assert_eq!(settings.envelope_time_scale(0.3, note), 1.0);
+        }
+    }
+
+    #[test]
+    fn test_envelope_scaling_units() {
+        let params = ScalingParams::default();
+        assert_eq!(params.key_to_envelope_low.to_string(), "0 %/oct");
+        assert_eq!(params.velocity_to_envelope.to_string(), "0 %");
+        for input in ["50", "50 %", "50 %/oct"] {
+            approx::assert_relative_eq!(
+                params
+                    .key_to_envelope_low
+                    .string_to_normalized_value(input)
+                    .unwrap(),
+                0.75
+            );
+        }
+    }
+
+    #[test]
+    fn test_key_split_lengthens_low_notes() {
+        let mut settings = settings();
+        settings.key_split = 48.0;
+        settings.key_to_envelope_low = 0.5;
+        settings.key_to_envelope_high = 0.0;
+
+        approx::assert_relative_eq!(settings.envelope_time_scale(1.0, 24.0), 2.0);
+        assert_eq!(settings.envelope_time_scale(1.0, 48.0), 1.0);
+        assert_eq!(settings.envelope_time_scale(1.0, 84.0), 1.0);
+
+        settings.key_to_envelope_high = 1.0;
+        approx::assert_relative_eq!(settings.envelope_time_scale(1.0, 60.0), 0.5);
+
+        settings.velocity_to_envelope = 1.0;
+        approx::assert_relative_eq!(settings.envelope_time_scale(0.0, 48.0), 2.0);
+    }
+}
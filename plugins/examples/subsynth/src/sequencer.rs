@@ -0,0 +1,548 @@
+use nih_plug::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::lfo::SyncRate;
+
+pub const MIN_STEPS: usize = 16;
+pub const MAX_STEPS: usize = 64;
+/// The longest gate a step can have, in steps. Gates longer than a single step overlap with the
+/// next steps' notes, which then play on their own voices.
+pub const MAX_GATE_STEPS: f32 = 4.0;
+/// The MIDI channel the sequencer's notes are reported on. Keys can be played on this channel too,
+/// so the synth tells the sequencer's notes apart by their `sequenced` flag instead.
+pub const SEQUENCER_CHANNEL: u8 = 15;
+/// The maximum number of the sequencer's notes that can overlap. A step's gate spans at most
+/// [`MAX_GATE_STEPS`] steps, so this is never reached.
+const MAX_PLAYING_NOTES: usize = 8;
+/// How far the host's playback position may drift from the sequencer's own position, in steps,
+/// before the sequencer treats it as a jump in the transport.
+const TRANSPORT_JUMP_TOLERANCE: f64 = 0.01;
+
+/// A single step in the sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Step {
+    /// Disabled steps are rests.
+    pub enabled: bool,
+    pub note: u8,
+    pub velocity: f32,
+    /// The note's length in steps.
+    pub gate: f32,
+    /// Hold the note until the next step. If the next step plays the same note, then that note
+    /// continues without being retriggered.
+    pub tie: bool,
+    /// Overrides the filter cutoff for this step's note, as a normalized parameter value.
+    pub cutoff_lock: Option<f32>,
+    /// Overrides the filter resonance for this step's note, as a normalized parameter value.
+    pub resonance_lock: Option<f32>,
+}
+
+impl Default for Step {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            note: 60,
+            velocity: 0.8,
+            gate: 0.5,
+            tie: false,
+            cutoff_lock: None,
+            resonance_lock: None,
+        }
+    }
+}
+
+/// The step sequencer's sequence. This is stored together with the plugin's state and edited from
+/// the editor's sequencer grid.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Sequence {
+    steps: Vec<Step>,
+}
+
+impl Default for Sequence {
+    fn default() -> Self {
+        Self {
+            steps: vec![Step::default(); MIN_STEPS],
+        }
+    }
+}
+
+impl Sequence {
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    /// Change the number of steps. New steps are disabled.
+    pub fn set_len(&mut self, len: usize) {
+        self.steps
+            .resize(len.clamp(MIN_STEPS, MAX_STEPS), Step::default());
+    }
+
+    pub fn set_step(&mut self, step_idx: usize, step: Step) {
+        if let Some(old_step) = self.steps.get_mut(step_idx) {
+            *old_step = Step {
+                gate: step.gate.clamp(0.0, MAX_GATE_STEPS),
+                note: step.note.min(127),
+                ..step
+            };
+        }
+    }
+}
+
+/// The sequencer's parameters. The sequence itself is stored in
+/// [`SubSynthParams::sequence`][crate::SubSynthParams].
+#[derive(Params)]
+pub struct SequencerParams {
+    /// The sequencer plays while the host's transport is playing.
+    #[id = "seq_enable"]
+    pub enabled: BoolParam,
+    #[id = "seq_rate"]
+    pub rate: EnumParam<SyncRate>,
+    /// Transposes the whole sequence.
+    #[id = "seq_transpose"]
+    pub transpose: IntParam,
+}
+
+impl Default for SequencerParams {
+    fn default() -> Self {
+        Self {
+            enabled: BoolParam::new("Sequencer", false),
+            rate: EnumParam::new("Sequencer Rate", SyncRate::Sixteenth),
+            transpose: IntParam::new(
+                "Sequencer Transpose",
+                0,
+                IntRange::Linear { min: -24, max: 24 },
+            )
+            .with_unit(" st"),
+        }
+    }
+}
+
+impl SequencerParams {
+    /// The sequencer's settings for the current buffer. The sequencer only runs while the host is
+    /// playing, in which case `tempo` and `pos_beats` are the host's tempo in beats per minute and
+    /// its playback position in quarter notes.
+    pub fn settings(
+        &self,
+        sample_rate: f32,
+        tempo: Option<f64>,
+        pos_beats: Option<f64>,
+    ) -> SequencerSettings {
+        let step_beats = self.rate.value().beats();
+        let (steps_per_sample, transport_position) = match (self.enabled.value(), tempo, pos_beats)
+        {
+            (true, Some(tempo), Some(pos_beats)) => (
+                tempo / 60.0 / step_beats / sample_rate as f64,
+                Some(pos_beats / step_beats),
+            ),
+            _ => (0.0, None),
+        };
+
+        SequencerSettings {
+            steps_per_sample,
+            transport_position,
+            transpose: self.transpose.value(),
+        }
+    }
+}
+
+/// The sequencer's parameters, computed once per buffer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SequencerSettings {
+    pub steps_per_sample: f64,
+    /// The host's playback position in steps at the start of the buffer. The sequencer is stopped
+    /// when this is `None`.
+    pub transport_position: Option<f64>,
+    pub transpose: i32,
+}
+
+/// Something the sequencer wants to happen at the current position.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SequencerEvent {
+    NoteOff(u8),
+    /// Start playing a step's note. The step's note has already been transposed.
+    NoteOn(Step),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PlayingNote {
+    note: u8,
+    /// The position where the note is released.
+    end: f64,
+    /// Whether the note was tied to the next step.
+    tied: bool,
+}
+
+/// The step sequencer's playback state. Positions are measured in steps, and follow the host's
+/// transport so steps start sample accurately.
+#[derive(Debug, Clone)]
+pub struct Sequencer {
+    /// A copy of the sequence, so it can be read without locking on the audio thread.
+    steps: [Step; MAX_STEPS],
+    len: usize,
+    /// The current position, or `None` if the sequencer is stopped.
+    position: Option<f64>,
+    /// The index of the next step, counted from the start of the host's timeline.
+    next_step: i64,
+    playing: [Option<PlayingNote>; MAX_PLAYING_NOTES],
+}
+
+impl Default for Sequencer {
+    fn default() -> Self {
+        Self {
+            steps: [Step::default(); MAX_STEPS],
+            len: MIN_STEPS,
+            position: None,
+            next_step: 0,
+            playing: [None; MAX_PLAYING_NOTES],
+        }
+    }
+}
+
+impl Sequencer {
+    /// Copy the sequence so it can be played back. This doesn't allocate.
+    pub fn load(&mut self, sequence: &Sequence) {
+        let steps = sequence.steps();
+        let len = steps.len().min(MAX_STEPS);
+        self.steps[..len].copy_from_slice(&steps[..len]);
+        self.steps[len..].fill(Step::default());
+        self.len = len.max(1);
+    }
+
+    /// Apply the settings for the next buffer. This needs to be called at the start of every
+    /// buffer.
+    pub fn update(&mut self, settings: &SequencerSettings) {
+        match (settings.transport_position, self.position) {
+            (Some(position), Some(old_position))
+                if (position - old_position).abs() <= TRANSPORT_JUMP_TOLERANCE =>
+            {
+                // The host's position is authoritative, this only corrects rounding errors
+                self.position = Some(position);
+            }
+            (Some(position), _) => {
+                // The transport started or jumped, so the notes from before the jump are released
+                self.release_all(position);
+                self.position = Some(position);
+                self.next_step = position.ceil() as i64;
+            }
+            (None, _) => {
+                self.release_all(f64::NEG_INFINITY);
+                self.position = None;
+            }
+        }
+    }
+
+    /// The number of samples until the next event, if there is one.
+    pub fn samples_until_next_event(&self, settings: &SequencerSettings) -> Option<f64> {
+        let Some(position) = self.position else {
+            return self.playing.iter().flatten().next().map(|_| 0.0);
+        };
+
+        let next_event = self
+            .playing
+            .iter()
+            .flatten()
+            .map(|playing_note| playing_note.end)
+            .fold(self.next_step as f64, f64::min);
+
+        Some(((next_event - position) / settings.steps_per_sample).max(0.0))
+    }
+
+    /// The next event that's due at the current position. This should be called until it returns
+    /// `None`.
+    pub fn next_event(&mut self, settings: &SequencerSettings) -> Option<SequencerEvent> {
+        let position = self.position.unwrap_or(f64::INFINITY);
+
+        while self.position.is_some() && self.next_step as f64 <= position {
+            let step_start = self.next_step as f64;
+            let step = self.step(self.next_step, settings.transpose);
+            let previous_step = self.step(self.next_step - 1, settings.transpose);
+
+            if let Some(step) = step {
+                // A tie into the same note extends that note instead of retriggering it
+                let tied_note = previous_step
+                    .filter(|previous_step| previous_step.tie && previous_step.note == step.note)
+                    .and_then(|_| self.playing_note_mut(step.note))
+                    .filter(|playing_note| playing_note.tied);
+                if let Some(playing_note) = tied_note {
+                    playing_note.end = Self::note_end(step_start, &step);
+                    playing_note.tied = step.tie;
+                    self.next_step += 1;
+                    continue;
+                }
+
+                // Releases are handled first so a retriggered note doesn't release the new voice
+                if let Some(event) = self.next_note_off(position, Some(step.note)) {
+                    return Some(event);
+                }
+                if self.playing.iter().all(Option::is_some) {
+                    return self.release_oldest();
+                }
+
+                self.next_step += 1;
+                let slot = self.playing.iter_mut().find(|slot| slot.is_none()).unwrap();
+                *slot = Some(PlayingNote {
+                    note: step.note,
+                    end: Self::note_end(step_start, &step),
+                    tied: step.tie,
+                });
+
+                return Some(SequencerEvent::NoteOn(step));
+            }
+
+            self.next_step += 1;
+        }
+
+        self.next_note_off(position, None)
+    }
+
+    /// Move the position forward by `num_samples` samples.
+    pub fn advance(&mut self, num_samples: usize, settings: &SequencerSettings) {
+        if let Some(position) = &mut self.position {
+            *position += num_samples as f64 * settings.steps_per_sample;
+        }
+    }
+
+    /// The enabled step with index `step`, with the sequence repeating forever in both
+    /// directions. Steps that would be transposed outside of the MIDI note range are skipped.
+    fn step(&self, step: i64, transpose: i32) -> Option<Step> {
+        let step = self.steps[step.rem_euclid(self.len as i64) as usize];
+        let note = step.note as i32 + transpose;
+        if step.enabled && (0..128).contains(&note) {
+            Some(Step {
+                note: note as u8,
+                ..step
+            })
+        } else {
+            None
+        }
+    }
+
+    fn note_end(step_start: f64, step: &Step) -> f64 {
+        if step.tie {
+            step_start + step.gate.max(1.0) as f64
+        } else {
+            step_start + step.gate as f64
+        }
+    }
+
+    fn playing_note_mut(&mut self, note: u8) -> Option<&mut PlayingNote> {
+        self.playing
+            .iter_mut()
+            .flatten()
+            .find(|playing_note| playing_note.note == note)
+    }
+
+    /// Release a note that has reached its end, or the playing `retriggered_note` if that's going
+    /// to be played again.
+    fn next_note_off(
+        &mut self,
+        position: f64,
+        retriggered_note: Option<u8>,
+    ) -> Option<SequencerEvent> {
+        let slot = self.playing.iter_mut().find(|slot| {
+            matches!(slot, Some(playing_note)
+                if playing_note.end <= position || Some(playing_note.note) == retriggered_note)
+        })?;
+
+        slot.take()
+            .map(|playing_note| SequencerEvent::NoteOff(playing_note.note))
+    }
+
+    fn release_oldest(&mut self) -> Option<SequencerEvent> {
+        let slot = self
+            .playing
+            .iter_mut()
+            .filter(|slot| slot.is_some())
+            .min_by(|a, b| a.unwrap().end.total_cmp(&b.unwrap().end))?;
+
+        slot.take()
+            .map(|playing_note| SequencerEvent::NoteOff(playing_note.note))
+    }
+
+    /// Schedule all playing notes to be released at `position`.
+    fn release_all(&mut self, position: f64) {
+        for playing_note in self.playing.iter_mut().flatten() {
+            playing_note.end = playing_note.end.min(position);
+            playing_note.tied = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(transport_position: Option<f64>) -> SequencerSettings {
+        SequencerSettings {
+            // Every step is four samples long
+            steps_per_sample: 0.25,
+            transport_position,
+            transpose: 0,
+        }
+    }
+
+    fn step(note: u8, gate: f32) -> Step {
+        Step {
+            enabled: true,
+            note,
+            gate,
+            ..Step::default()
+        }
+    }
+
+    fn sequencer(steps: &[Step]) -> Sequencer {
+        let mut sequence = Sequence::default();
+        for (step_idx, step) in steps.iter().enumerate() {
+            sequence.set_step(step_idx, *step);
+        }
+
+        let mut sequencer = Sequencer::default();
+        sequencer.load(&sequence);
+        sequencer
+    }
+
+    /// Run the sequencer for `num_samples` samples, returning the sample offsets and events.
+    fn events(
+        sequencer: &mut Sequencer,
+        settings: &SequencerSettings,
+        num_samples: usize,
+    ) -> Vec<(usize, SequencerEvent)> {
+        let mut events = Vec::new();
+        sequencer.update(settings);
+        for sample_idx in 0..num_samples {
+            while let Some(event) = sequencer.next_event(settings) {
+                events.push((sample_idx, event));
+            }
+            sequencer.advance(1, settings);
+        }
+
+        events
+    }
+
+    fn note_on(note: u8, gate: f32) -> SequencerEvent {
+        SequencerEvent::NoteOn(step(note, gate))
+    }
+
+    #[test]
+    fn test_steps_and_rests() {
+        let mut sequencer = sequencer(&[step(60, 0.5), Step::default(), step(64, 1.0)]);
+        assert_eq!(
+            events(&mut sequencer, &settings(Some(0.0)), 16),
+            [
+                (0, note_on(60, 0.5)),
+                (2, SequencerEvent::NoteOff(60)),
+                (8, note_on(64, 1.0)),
+                (12, SequencerEvent::NoteOff(64)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_follows_transport() {
+        let mut sequencer = sequencer(&[step(60, 0.5), step(62, 0.5)]);
+
+        // Playback starting in the middle of a step waits for the next step
+        assert_eq!(
+            events(&mut sequencer, &settings(Some(0.5)), 4),
+            [(2, note_on(62, 0.5))]
+        );
+
+        // The sequence repeats after its length, also when the host jumps to a later position
+        assert_eq!(
+            events(&mut sequencer, &settings(Some(MIN_STEPS as f64)), 2),
+            [(0, SequencerEvent::NoteOff(62)), (0, note_on(60, 0.5))]
+        );
+
+        // Stopping the transport releases all notes
+        assert_eq!(
+            events(&mut sequencer, &settings(None), 4),
+            [(0, SequencerEvent::NoteOff(60))]
+        );
+        assert_eq!(sequencer.samples_until_next_event(&settings(None)), None);
+    }
+
+    #[test]
+    fn test_ties() {
+        let tied_step = Step {
+            tie: true,
+            ..step(60, 0.5)
+        };
+        let mut sequencer = sequencer(&[tied_step, step(60, 0.5), tied_step, step(62, 0.5)]);
+
+        // The tied note continues into the next step without a retrigger, and a tie into a
+        // different note releases the tied note when the next note starts
+        assert_eq!(
+            events(&mut sequencer, &settings(Some(0.0)), 16),
+            [
+                (0, SequencerEvent::NoteOn(tied_step)),
+                (6, SequencerEvent::NoteOff(60)),
+                (8, SequencerEvent::NoteOn(tied_step)),
+                (12, SequencerEvent::NoteOff(60)),
+                (12, note_on(62, 0.5)),
+                (14, SequencerEvent::NoteOff(62)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_overlapping_gates() {
+        let mut sequencer = sequencer(&[step(60, 2.0), step(64, 2.0), step(60, 0.5)]);
+
+        // Long gates overlap, and a note that's played again is released before it's retriggered
+        assert_eq!(
+            events(&mut sequencer, &settings(Some(0.0)), 12),
+            [
+                (0, note_on(60, 2.0)),
+                (4, note_on(64, 2.0)),
+                (8, SequencerEvent::NoteOff(60)),
+                (8, note_on(60, 0.5)),
+                (10, SequencerEvent::NoteOff(60)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transpose() {
+        let mut sequencer = sequencer(&[step(60, 0.5), step(120, 0.5)]);
+        let settings = SequencerSettings {
+            transpose: 12,
+            ..settings(Some(0.0))
+        };
+
+        // Notes that would be transposed out of range are skipped
+        assert_eq!(
+            events(&mut sequencer, &settings, 8),
+            [(0, note_on(72, 0.5)), (2, SequencerEvent::NoteOff(72))]
+        );
+    }
+
+    #[test]
+    fn test_sequence_length() {
+        let mut sequence = Sequence::default();
+        assert_eq!(sequence.steps().len(), MIN_STEPS);
+        sequence.set_len(100);
+        assert_eq!(sequence.steps().len(), MAX_STEPS);
+        sequence.set_len(20);
+        assert_eq!(sequence.steps().len(), 20);
+        sequence.set_len(0);
+        assert_eq!(sequence.steps().len(), MIN_STEPS);
+
+        // Steps outside of the sequence are ignored
+        sequence.set_step(MIN_STEPS, step(60, 0.5));
+        assert!(sequence.steps().iter().all(|step| !step.enabled));
+    }
+
+    #[test]
+    fn test_sequence_serialization() {
+        let mut sequence = Sequence::default();
+        sequence.set_step(
+            3,
+            Step {
+                cutoff_lock: Some(0.25),
+                ..step(48, 1.5)
+            },
+        );
+
+        let json = serde_json::to_string(&sequence).unwrap();
+        assert_eq!(serde_json::from_str::<Sequence>(&json).unwrap(), sequence);
+    }
+}
@@ -0,0 +1,693 @@
+use nih_plug::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The note the reference frequency applies to when no keyboard mapping has been loaded.
+pub const DEFAULT_REFERENCE_NOTE: u8 = 69;
+pub const DEFAULT_REFERENCE_FREQUENCY: f32 = 440.0;
+/// The note the scale's first degree is mapped to when no keyboard mapping has been loaded.
+const DEFAULT_MIDDLE_NOTE: u8 = 60;
+
+/// An error encountered while parsing a Scala scale or keyboard mapping file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TuningError {
+    /// The file ended before all of the expected lines were read.
+    UnexpectedEnd,
+    /// A line could not be parsed. Contains the 1-based line number and the line's contents.
+    InvalidLine(usize, String),
+    /// The scale does not contain any notes.
+    EmptyScale,
+}
+
+impl fmt::Display for TuningError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TuningError::UnexpectedEnd => write!(f, "unexpected end of file"),
+            TuningError::InvalidLine(line_number, line) => {
+                write!(f, "invalid value on line {line_number}: '{line}'")
+            }
+            TuningError::EmptyScale => write!(f, "the scale does not contain any notes"),
+        }
+    }
+}
+
+impl std::error::Error for TuningError {}
+
+/// The lines in a Scala file that are not comments, along with their 1-based line numbers.
+struct Lines<'a>(std::iter::Enumerate<std::str::Lines<'a>>);
+
+impl<'a> Lines<'a> {
+    fn new(text: &'a str) -> Self {
+        Self(text.lines().enumerate())
+    }
+
+    fn next_line(&mut self) -> Result<(usize, &'a str), TuningError> {
+        self.0
+            .by_ref()
+            .find(|(_, line)| !line.starts_with('!'))
+            .map(|(line_idx, line)| (line_idx + 1, line))
+            .ok_or(TuningError::UnexpectedEnd)
+    }
+
+    /// Parse the first whitespace separated word on the next line. Anything after that is ignored.
+    fn next_value<T: std::str::FromStr>(&mut self) -> Result<T, TuningError> {
+        self.next_value_where(|_| true)
+    }
+
+    /// Parse the next line's value like [`next_value()`][Self::next_value()], rejecting values
+    /// that don't pass the check.
+    fn next_value_where<T: std::str::FromStr>(
+        &mut self,
+        is_valid: impl Fn(&T) -> bool,
+    ) -> Result<T, TuningError> {
+        let (line_number, line) = self.next_line()?;
+        line.split_whitespace()
+            .next()
+            .and_then(|word| word.parse().ok())
+            .filter(is_valid)
+            .ok_or_else(|| TuningError::InvalidLine(line_number, line.to_owned()))
+    }
+}
+
+/// A scale parsed from a Scala `.scl` file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scale {
+    pub description: String,
+    /// Every degree's pitch in cents relative to the scale's implicit `1/1` first degree. The last
+    /// degree is the interval the scale repeats at, usually an octave.
+    pub cents: Vec<f64>,
+}
+
+impl Default for Scale {
+    /// 12-tone equal temperament.
+    fn default() -> Self {
+        Self {
+            description: String::from("12-tone equal temperament"),
+            cents: (1..=12).map(|degree| degree as f64 * 100.0).collect(),
+        }
+    }
+}
+
+impl Scale {
+    /// Parse a Scala `.scl` file. Pitches containing a period are in cents, and everything else
+    /// is either a ratio or a whole number.
+    pub fn parse(text: &str) -> Result<Self, TuningError> {
+        let mut lines = Lines::new(text);
+        let description = lines.next_line()?.1.trim().to_owned();
+        let num_notes: usize = lines.next_value()?;
+        if num_notes == 0 {
+            return Err(TuningError::EmptyScale);
+        }
+
+        let cents = (0..num_notes)
+            .map(|_| {
+                let (line_number, line) = lines.next_line()?;
+                line.split_whitespace()
+                    .next()
+                    .and_then(parse_pitch)
+                    .ok_or_else(|| TuningError::InvalidLine(line_number, line.to_owned()))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { description, cents })
+    }
+
+    /// The interval the scale repeats at, in cents.
+    pub fn period(&self) -> f64 {
+        *self.cents.last().unwrap()
+    }
+
+    /// A scale degree's pitch in cents. Degrees past the end of the scale and negative degrees
+    /// continue into the next and previous periods.
+    pub fn degree_cents(&self, degree: i32) -> f64 {
+        let len = self.cents.len() as i32;
+        let period = degree.div_euclid(len);
+        let step = degree.rem_euclid(len);
+        let step_cents = if step == 0 {
+            0.0
+        } else {
+            self.cents[step as usize - 1]
+        };
+
+        period as f64 * self.period() + step_cents
+    }
+}
+
+/// Parse a single pitch in cents or as a ratio, returning the pitch in cents.
+fn parse_pitch(word: &str) -> Option<f64> {
+    if word.contains('.') {
+        return word.parse().ok().filter(|cents: &f64| cents.is_finite());
+    }
+
+    let (numerator, denominator) = word.split_once('/').unwrap_or((word, "1"));
+    let numerator: u64 = numerator.parse().ok()?;
+    let denominator: u64 = denominator.parse().ok()?;
+    if numerator == 0 || denominator == 0 {
+        return None;
+    }
+
+    Some((numerator as f64 / denominator as f64).log2() * 1200.0)
+}
+
+/// A keyboard mapping parsed from a Scala `.kbm` file. This decides which scale degree every key
+/// plays, and which key the reference frequency applies to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyboardMapping {
+    /// The lowest and highest keys that play notes. Keys outside of this range are unmapped.
+    pub first_note: u8,
+    pub last_note: u8,
+    /// The key the mapping's first entry applies to, playing the scale's first degree for a
+    /// linear mapping.
+    pub middle_note: u8,
+    pub reference_note: u8,
+    pub reference_frequency: f32,
+    /// The scale degree that each repetition of the mapping is transposed by. `0` uses the
+    /// scale's own period.
+    pub octave_degree: u32,
+    /// The scale degree played by each key in the pattern, starting at the middle note. `None`
+    /// leaves the key unmapped. An empty mapping maps the keys to consecutive scale degrees.
+    pub degrees: Vec<Option<u32>>,
+}
+
+impl Default for KeyboardMapping {
+    fn default() -> Self {
+        Self {
+            first_note: 0,
+            last_note: 127,
+            middle_note: DEFAULT_MIDDLE_NOTE,
+            reference_note: DEFAULT_REFERENCE_NOTE,
+            reference_frequency: DEFAULT_REFERENCE_FREQUENCY,
+            octave_degree: 0,
+            degrees: Vec::new(),
+        }
+    }
+}
+
+impl KeyboardMapping {
+    /// Parse a Scala `.kbm` file. Keys at the end of the mapping may be left out, in which case
+    /// they're unmapped.
+    pub fn parse(text: &str) -> Result<Self, TuningError> {
+        let mut lines = Lines::new(text);
+        let map_size: usize = lines.next_value()?;
+        let first_note = next_note(&mut lines)?;
+        let last_note = next_note(&mut lines)?;
+        let middle_note = next_note(&mut lines)?;
+        let reference_note = next_note(&mut lines)?;
+        let reference_frequency =
+            lines.next_value_where(|frequency: &f32| frequency.is_finite() && *frequency > 0.0)?;
+        let octave_degree = lines.next_value()?;
+
+        let mut degrees = Vec::with_capacity(map_size);
+        for _ in 0..map_size {
+            let Ok((line_number, line)) = lines.next_line() else {
+                break;
+            };
+            let degree = match line.split_whitespace().next() {
+                Some("x") => None,
+                Some(word) => match word.parse() {
+                    Ok(degree) => Some(degree),
+                    Err(_) => return Err(TuningError::InvalidLine(line_number, line.to_owned())),
+                },
+                None => return Err(TuningError::InvalidLine(line_number, line.to_owned())),
+            };
+            degrees.push(degree);
+        }
+        degrees.resize(map_size, None);
+
+        Ok(Self {
+            first_note,
+            last_note,
+            middle_note,
+            reference_note,
+            reference_frequency,
+            octave_degree,
+            degrees,
+        })
+    }
+
+    /// The scale degree played by a key, if it's mapped. This ignores the range of keys that play
+    /// notes.
+    fn degree(&self, scale: &Scale, note: u8) -> Option<i32> {
+        let offset = note as i32 - self.middle_note as i32;
+        if self.degrees.is_empty() {
+            return Some(offset);
+        }
+
+        let octave_degree = match self.octave_degree {
+            0 => scale.cents.len() as i32,
+            degree => degree as i32,
+        };
+        let len = self.degrees.len() as i32;
+        self.degrees[offset.rem_euclid(len) as usize]
+            .map(|degree| offset.div_euclid(len) * octave_degree + degree as i32)
+    }
+}
+
+fn next_note(lines: &mut Lines) -> Result<u8, TuningError> {
+    lines.next_value_where(|note: &u8| *note <= 127)
+}
+
+/// The contents of the loaded Scala files. These are stored in the plugin's state, so the tuning
+/// can be restored without the original files.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TuningFiles {
+    pub scl: Option<String>,
+    pub kbm: Option<String>,
+}
+
+/// A scale and a keyboard mapping, stored in the plugin's state as the files they were parsed
+/// from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "TuningFiles", into = "TuningFiles")]
+pub struct Tuning {
+    files: TuningFiles,
+    scale: Scale,
+    mapping: KeyboardMapping,
+    table: TuningTable,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self::new(TuningFiles::default()).unwrap()
+    }
+}
+
+impl Tuning {
+    /// Parse the Scala files. 12-TET and the default keyboard mapping are used in place of missing
+    /// files.
+    pub fn new(files: TuningFiles) -> Result<Self, TuningError> {
+        let scale = files
+            .scl
+            .as_deref()
+            .map(Scale::parse)
+            .transpose()?
+            .unwrap_or_default();
+        let mapping = files
+            .kbm
+            .as_deref()
+            .map(KeyboardMapping::parse)
+            .transpose()?
+            .unwrap_or_default();
+        let table = TuningTable::new(&scale, &mapping);
+
+        Ok(Self {
+            files,
+            scale,
+            mapping,
+            table,
+        })
+    }
+
+    pub fn files(&self) -> &TuningFiles {
+        &self.files
+    }
+
+    pub fn scale(&self) -> &Scale {
+        &self.scale
+    }
+
+    pub fn mapping(&self) -> &KeyboardMapping {
+        &self.mapping
+    }
+
+    pub fn table(&self) -> TuningTable {
+        self.table
+    }
+}
+
+impl From<TuningFiles> for Tuning {
+    fn from(files: TuningFiles) -> Self {
+        Tuning::new(files).unwrap_or_else(|err| {
+            nih_log!("Could not restore the tuning, falling back to 12-TET: {err}");
+            Tuning::default()
+        })
+    }
+}
+
+impl From<Tuning> for TuningFiles {
+    fn from(tuning: Tuning) -> Self {
+        tuning.files
+    }
+}
+
+/// Every key's pitch under a tuning, precomputed so it can be copied to and used on the audio
+/// thread.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TuningTable {
+    /// Every key's pitch in octaves relative to the reference note. Unmapped keys are
+    /// interpolated between their mapped neighbours so pitch modulation can move past them.
+    octaves: [f32; 128],
+    /// One bit per key that plays a note.
+    mapped: u128,
+}
+
+impl Default for TuningTable {
+    /// 12-TET with A4 as the reference note.
+    fn default() -> Self {
+        Self {
+            octaves: std::array::from_fn(|note| {
+                (note as f32 - DEFAULT_REFERENCE_NOTE as f32) / 12.0
+            }),
+            mapped: u128::MAX,
+        }
+    }
+}
+
+impl TuningTable {
+    pub fn new(scale: &Scale, mapping: &KeyboardMapping) -> Self {
+        let cents: [Option<f64>; 128] = std::array::from_fn(|note| {
+            mapping
+                .degree(scale, note as u8)
+                .map(|degree| scale.degree_cents(degree))
+        });
+
+        let mut mapped = 0;
+        for note in mapping.first_note..=mapping.last_note {
+            if cents[note as usize].is_some() {
+                mapped |= 1 << note;
+            }
+        }
+
+        // Keys without a scale degree are placed between the surrounding keys, or continue in
+        // semitones past the last mapped key
+        let filled_cents: [f64; 128] = std::array::from_fn(|note| {
+            let previous = (0..note)
+                .rev()
+                .find_map(|idx| cents[idx].map(|cents| (idx, cents)));
+            let next = (note..128).find_map(|idx| cents[idx].map(|cents| (idx, cents)));
+            match (previous, next) {
+                (_, Some((next_note, next_cents))) if next_note == note => next_cents,
+                (Some((previous_note, previous_cents)), Some((next_note, next_cents))) => {
+                    let t = (note - previous_note) as f64 / (next_note - previous_note) as f64;
+                    previous_cents + (next_cents - previous_cents) * t
+                }
+                (Some((previous_note, previous_cents)), None) => {
+                    previous_cents + (note - previous_note) as f64 * 100.0
+                }
+                (None, Some((next_note, next_cents))) => {
+                    next_cents - (next_note - note) as f64 * 100.0
+                }
+                (None, None) => (note as f64 - mapping.middle_note as f64) * 100.0,
+            }
+        });
+
+        let reference_cents = filled_cents[mapping.reference_note as usize];
+        Self {
+            octaves: filled_cents.map(|cents| ((cents - reference_cents) / 1200.0) as f32),
+            mapped,
+        }
+    }
+
+    /// Whether a key plays a note under this tuning.
+    pub fn is_mapped(&self, note: u8) -> bool {
+        self.mapped & (1 << note) != 0
+    }
+
+    /// The frequency for a possibly fractional note, given the reference note's frequency.
+    /// Fractional notes lie between the neighbouring keys' pitches, so glides and pitch
+    /// modulation move through the tuning's steps rather than through semitones. Pitch
+    /// modulation past the lowest or highest key continues at the first or last step's size.
+    pub fn frequency(&self, reference_frequency: f32, note: f32) -> f32 {
+        let note_idx = (note.max(0.0) as usize).min(126);
+        let t = note - note_idx as f32;
+        let octaves =
+            self.octaves[note_idx] + (self.octaves[note_idx + 1] - self.octaves[note_idx]) * t;
+
+        reference_frequency * octaves.exp2()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_scale_edge_cases() {
+        let scale = Scale::parse(
+            "! meantone.scl\r\n\
+             !\r\n\
+             \r\n\
+             \x20 6\r\n\
+             !\r\n\
+             \x20100.\r\n\
+             \x20-5.5 cents below unison\r\n\
+             \x203/2 perfect fifth\r\n\
+             ! comments may appear between pitches\r\n\
+             \x20\t81/64\r\n\
+             \x20.5\r\n\
+             2\r\n\
+             1/1 lines past the note count are ignored\r\n",
+        )
+        .unwrap();
+
+        assert_eq!(scale.description, "");
+        assert_eq!(scale.cents.len(), 6);
+        assert_relative_eq!(scale.cents[0], 100.0);
+        assert_relative_eq!(scale.cents[1], -5.5);
+        assert_relative_eq!(scale.cents[2], 701.955, epsilon = 1e-3);
+        assert_relative_eq!(scale.cents[3], 407.820, epsilon = 1e-3);
+        assert_relative_eq!(scale.cents[4], 0.5);
+        assert_relative_eq!(scale.period(), 1200.0);
+    }
+
+    #[test]
+    fn test_scale_errors() {
+        assert_eq!(Scale::parse(""), Err(TuningError::UnexpectedEnd));
+        assert_eq!(
+            Scale::parse("Too short\n 3\n 1/1\n 2/1\n"),
+            Err(TuningError::UnexpectedEnd)
+        );
+        assert_eq!(Scale::parse("Empty\n 0\n"), Err(TuningError::EmptyScale));
+        assert_eq!(
+            Scale::parse("Missing count\n\n 2/1\n"),
+            Err(TuningError::InvalidLine(2, String::new()))
+        );
+        for pitch in ["-3/2", "3/0", "0/1", "3/-2", "abc", "1e3.", "3:2"] {
+            assert_eq!(
+                Scale::parse(&format!("Invalid\n 1\n{pitch}\n")),
+                Err(TuningError::InvalidLine(3, pitch.to_owned())),
+                "{pitch}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_scale_degrees() {
+        let scale = Scale::parse("Pentatonic\n5\n200.0\n400.0\n700.0\n900.0\n2/1\n").unwrap();
+        assert_relative_eq!(scale.degree_cents(0), 0.0);
+        assert_relative_eq!(scale.degree_cents(3), 700.0);
+        assert_relative_eq!(scale.degree_cents(5), 1200.0);
+        assert_relative_eq!(scale.degree_cents(7), 1600.0);
+        assert_relative_eq!(scale.degree_cents(-1), -300.0);
+        assert_relative_eq!(scale.degree_cents(-6), -1500.0);
+    }
+
+    #[test]
+    fn test_keyboard_mapping() {
+        let mapping = KeyboardMapping::parse(
+            "! A white keys only mapping\n\
+             12\n\
+             21\n\
+             108\n\
+             60\n\
+             69\n\
+             432.0\n\
+             7\n\
+             ! Mapping\n\
+             0\n\
+             x\n\
+             1\n\
+             x\n\
+             2\n\
+             3\n\
+             x\n\
+             4\n\
+             x\n\
+             5\n",
+        )
+        .unwrap();
+
+        assert_eq!(mapping.first_note, 21);
+        assert_eq!(mapping.last_note, 108);
+        assert_eq!(mapping.reference_frequency, 432.0);
+        assert_eq!(mapping.octave_degree, 7);
+        // The last two keys were left out, which leaves them unmapped
+        assert_eq!(
+            mapping.degrees,
+            [
+                Some(0),
+                None,
+                Some(1),
+                None,
+                Some(2),
+                Some(3),
+                None,
+                Some(4),
+                None,
+                Some(5),
+                None,
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keyboard_mapping_errors() {
+        assert_eq!(
+            KeyboardMapping::parse("0\n0\n127\n60\n69\n"),
+            Err(TuningError::UnexpectedEnd)
+        );
+        assert_eq!(
+            KeyboardMapping::parse("0\n0\n128\n60\n69\n440.0\n0\n"),
+            Err(TuningError::InvalidLine(3, String::from("128")))
+        );
+        assert_eq!(
+            KeyboardMapping::parse("1\n0\n127\n60\n69\n440.0\n0\ny\n"),
+            Err(TuningError::InvalidLine(8, String::from("y")))
+        );
+        assert_eq!(
+            KeyboardMapping::parse("! Comments don't count\n0\n0\n127\n60\n69\n-440.0\n0\n"),
+            Err(TuningError::InvalidLine(7, String::from("-440.0")))
+        );
+    }
+
+    #[test]
+    fn test_default_tuning_is_12_tet() {
+        let table = Tuning::default().table();
+        for note in 0..128 {
+            assert!(table.is_mapped(note));
+            assert_relative_eq!(
+                table.frequency(440.0, note as f32),
+                util::midi_note_to_freq(note),
+                max_relative = 1e-5
+            );
+        }
+    }
+
+    #[test]
+    fn test_pitch_past_the_keyboard_edges() {
+        let table = Tuning::default().table();
+        for note in [-24.0, -0.5, 127.5, 140.0] {
+            assert_relative_eq!(
+                table.frequency(440.0, note),
+                440.0 * ((note - 69.0) / 12.0).exp2(),
+                max_relative = 1e-5
+            );
+        }
+
+        // A bend past the edges keeps moving by the first and last steps
+        let scl = String::from("19-EDO\n19\n")
+            + &(1..=19)
+                .map(|step| format!("{:.5}\n", step as f64 * 1200.0 / 19.0))
+                .collect::<String>();
+        let table = Tuning::new(TuningFiles {
+            scl: Some(scl),
+            kbm: None,
+        })
+        .unwrap()
+        .table();
+        let step = (1.0f32 / 19.0).exp2();
+        assert_relative_eq!(
+            table.frequency(440.0, -2.0),
+            table.frequency(440.0, 0.0) / step / step,
+            max_relative = 1e-5
+        );
+        assert_relative_eq!(
+            table.frequency(440.0, 129.0),
+            table.frequency(440.0, 127.0) * step * step,
+            max_relative = 1e-5
+        );
+    }
+
+    #[test]
+    fn test_equal_divisions() {
+        let scl = String::from("19-EDO\n19\n")
+            + &(1..=19)
+                .map(|step| format!("{:.5}\n", step as f64 * 1200.0 / 19.0))
+                .collect::<String>();
+        let table = Tuning::new(TuningFiles {
+            scl: Some(scl),
+            kbm: None,
+        })
+        .unwrap()
+        .table();
+
+        let middle_c = table.frequency(440.0, 60.0);
+        assert_relative_eq!(table.frequency(440.0, 69.0), 440.0);
+        assert_relative_eq!(
+            table.frequency(440.0, 61.0) / middle_c,
+            (1.0f32 / 19.0).exp2(),
+            max_relative = 1e-5
+        );
+        assert_relative_eq!(
+            table.frequency(440.0, 79.0) / middle_c,
+            2.0,
+            max_relative = 1e-5
+        );
+        // Fractional notes lie halfway between the keys in log-frequency
+        assert_relative_eq!(
+            table.frequency(440.0, 60.5),
+            (table.frequency(440.0, 60.0) * table.frequency(440.0, 61.0)).sqrt(),
+            max_relative = 1e-5
+        );
+    }
+
+    #[test]
+    fn test_just_intonation_with_mapping() {
+        let table = Tuning::new(TuningFiles {
+            scl: Some(String::from(
+                "Just major\n7\n9/8\n5/4\n4/3\n3/2\n5/3\n15/8\n2/1\n",
+            )),
+            kbm: Some(String::from(
+                "12\n0\n127\n60\n60\n261.6256\n7\n0\nx\n1\nx\n2\n3\nx\n4\nx\n5\nx\n6\n",
+            )),
+        })
+        .unwrap()
+        .table();
+
+        assert_relative_eq!(table.frequency(261.6256, 60.0), 261.6256);
+        assert_relative_eq!(
+            table.frequency(261.6256, 67.0),
+            261.6256 * 1.5,
+            max_relative = 1e-5
+        );
+        assert_relative_eq!(
+            table.frequency(261.6256, 76.0),
+            261.6256 * 2.5,
+            max_relative = 1e-5
+        );
+        assert_relative_eq!(
+            table.frequency(261.6256, 48.0),
+            261.6256 / 2.0,
+            max_relative = 1e-5
+        );
+        assert!(table.is_mapped(64));
+        assert!(!table.is_mapped(61));
+        // Unmapped keys sit between their neighbours so pitch bends pass through them smoothly
+        assert_relative_eq!(
+            table.frequency(261.6256, 61.0),
+            261.6256 * (9.0f32 / 8.0).sqrt(),
+            max_relative = 1e-5
+        );
+    }
+
+    #[test]
+    fn test_tuning_state_roundtrip() {
+        let tuning = Tuning::new(TuningFiles {
+            scl: Some(String::from("Fifths\n2\n3/2\n2/1\n")),
+            kbm: None,
+        })
+        .unwrap();
+
+        let json = serde_json::to_string(&tuning).unwrap();
+        assert_eq!(serde_json::from_str::<Tuning>(&json).unwrap(), tuning);
+
+        // Invalid state falls back to 12-TET instead of failing to load the plugin's state
+        let json = r#"{"scl":"Broken\n1\nabc\n","kbm":null}"#;
+        assert_eq!(
+            serde_json::from_str::<Tuning>(json).unwrap(),
+            Tuning::default()
+        );
+    }
+}
@@ -0,0 +1,145 @@
+/// The maximum number of stacked oscillators per note.
+pub const MAX_UNISON_VOICES: usize = 16;
+
+/// The tuning and stereo placement of every oscillator in a unison stack. This is computed once
+/// per block from the unison parameters and then shared by all voices.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UnisonLayout {
+    pub num_voices: usize,
+    /// The frequency of each stacked oscillator relative to the note's frequency.
+    pub frequency_ratios: [f32; MAX_UNISON_VOICES],
+    pub left_gains: [f32; MAX_UNISON_VOICES],
+    pub right_gains: [f32; MAX_UNISON_VOICES],
+}
+
+impl UnisonLayout {
+    /// Spread `num_voices` oscillators evenly over `[-detune_cents, detune_cents]` and over the
+    /// stereo field according to `spread`, in `[0, 1]`. `blend` is the level of the outer
+    /// oscillators relative to the center ones, also in `[0, 1]`. The gains are normalized so the
+    /// stack has roughly the same loudness as a single oscillator.
+    pub fn new(num_voices: usize, detune_cents: f32, spread: f32, blend: f32) -> Self {
+        let num_voices = num_voices.clamp(1, MAX_UNISON_VOICES);
+        let mut layout = UnisonLayout {
+            num_voices,
+            frequency_ratios: [1.0; MAX_UNISON_VOICES],
+            left_gains: [0.0; MAX_UNISON_VOICES],
+            right_gains: [0.0; MAX_UNISON_VOICES],
+        };
+
+        if num_voices == 1 {
+            layout.left_gains[0] = 1.0;
+            layout.right_gains[0] = 1.0;
+            return layout;
+        }
+
+        // The oscillators closest to the center are the ones that are not affected by the blend.
+        // With an even number of voices there are two of them.
+        let center_distance = if num_voices % 2 == 0 {
+            1.0 / (num_voices - 1) as f32
+        } else {
+            0.0
+        };
+
+        let mut gains = [0.0; MAX_UNISON_VOICES];
+        let mut energy = 0.0;
+        for (i, gain) in gains.iter_mut().enumerate().take(num_voices) {
+            let position = 2.0 * i as f32 / (num_voices - 1) as f32 - 1.0;
+            layout.frequency_ratios[i] = 2.0f32.powf(position * detune_cents / 1200.0);
+
+            *gain = if position.abs() <= center_distance + 1.0e-6 {
+                1.0
+            } else {
+                blend
+            };
+            energy += *gain * *gain;
+
+            // A simple balance law keeps centered oscillators at unity gain in both channels
+            let pan = position * spread;
+            layout.left_gains[i] = (1.0 - pan).min(1.0);
+            layout.right_gains[i] = (1.0 + pan).min(1.0);
+        }
+
+        // The stacked oscillators are detuned and start at random phases, so their levels add up
+        // like uncorrelated signals
+        let normalization = 1.0 / energy.sqrt();
+        for ((left_gain, right_gain), gain) in layout
+            .left_gains
+            .iter_mut()
+            .zip(layout.right_gains.iter_mut())
+            .zip(gains)
+        {
+            *left_gain *= gain * normalization;
+            *right_gain *= gain * normalization;
+        }
+
+        layout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single_voice_is_unchanged() {
+        let layout = UnisonLayout::new(1, 50.0, 1.0, 0.0);
+        assert_eq!(layout.num_voices, 1);
+        assert_eq!(layout.frequency_ratios[0], 1.0);
+        assert_eq!(layout.left_gains[0], 1.0);
+        assert_eq!(layout.right_gains[0], 1.0);
+    }
+
+    #[test]
+    fn test_detune_is_symmetric() {
+        for num_voices in 2..=MAX_UNISON_VOICES {
+            let layout = UnisonLayout::new(num_voices, 25.0, 0.0, 1.0);
+            approx::assert_relative_eq!(
+                layout.frequency_ratios[0],
+                2.0f32.powf(-25.0 / 1200.0),
+                epsilon = 1.0e-6
+            );
+            for i in 0..num_voices {
+                let mirrored = layout.frequency_ratios[num_voices - 1 - i];
+                approx::assert_relative_eq!(
+                    layout.frequency_ratios[i] * mirrored,
+                    1.0,
+                    epsilon = 1.0e-5
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_gains_are_power_normalized() {
+        for num_voices in 1..=MAX_UNISON_VOICES {
+            for blend in [0.0, 0.3, 1.0] {
+                let layout = UnisonLayout::new(num_voices, 10.0, 0.0, blend);
+                let energy: f32 = layout.left_gains[..num_voices].iter().map(|g| g * g).sum();
+                approx::assert_relative_eq!(energy, 1.0, epsilon = 1.0e-5);
+                assert_eq!(layout.left_gains, layout.right_gains);
+            }
+        }
+    }
+
+    #[test]
+    fn test_blend_zero_leaves_only_the_center() {
+        let layout = UnisonLayout::new(5, 10.0, 0.0, 0.0);
+        assert_eq!(&layout.left_gains[..5], &[0.0, 0.0, 1.0, 0.0, 0.0]);
+
+        let layout = UnisonLayout::new(4, 10.0, 0.0, 0.0);
+        let center_gain = 1.0 / 2.0f32.sqrt();
+        assert_eq!(layout.left_gains[0], 0.0);
+        approx::assert_relative_eq!(layout.left_gains[1], center_gain);
+        approx::assert_relative_eq!(layout.left_gains[2], center_gain);
+        assert_eq!(layout.left_gains[3], 0.0);
+    }
+
+    #[test]
+    fn test_full_spread_pans_the_outer_voices_hard() {
+        let layout = UnisonLayout::new(3, 10.0, 1.0, 1.0);
+        assert_eq!(layout.right_gains[0], 0.0);
+        assert_eq!(layout.left_gains[2], 0.0);
+        assert_eq!(layout.left_gains[1], layout.right_gains[1]);
+        assert!(layout.left_gains[0] > 0.0 && layout.right_gains[2] > 0.0);
+    }
+}
@@ -0,0 +1,465 @@
+use enum_iterator::Sequence;
+use nih_plug::prelude::*;
+
+/// The maximum value for the polyphony parameter. This is also the voice capacity reported to the
+/// host.
+pub const MAX_POLYPHONY: u32 = 64;
+/// How long a stolen voice takes to fade out, to avoid clicks.
+pub const STEAL_FADE_MS: f32 = 5.0;
+/// The number of notes the held-note stack remembers in the monophonic modes. When more notes are
+/// held than this, the oldest ones are forgotten.
+pub const MAX_HELD_NOTES: usize = 32;
+
+/// How notes are assigned to voices.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Enum, Sequence)]
+pub enum VoiceMode {
+    Poly,
+    /// A single voice that retriggers its envelopes for every new note.
+    Mono,
+    /// A single voice that only retriggers its envelopes when no other notes were held.
+    Legato,
+    /// Like mono, but the voice always plays the full unison stack regardless of the unison voice
+    /// count.
+    #[name = "Unison Mono"]
+    UnisonMono,
+}
+
+impl VoiceMode {
+    pub fn is_monophonic(self) -> bool {
+        self != VoiceMode::Poly
+    }
+}
+
+/// Which of the held notes plays in the monophonic modes.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Enum, Sequence)]
+pub enum NotePriority {
+    Last,
+    Low,
+    High,
+}
+
+/// Which voice makes room for a new note once the polyphony limit has been reached.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Enum, Sequence)]
+pub enum StealMode {
+    Oldest,
+    /// The voice with the lowest amplitude envelope level.
+    Quietest,
+    /// A voice that's already playing the same note, falling back to the oldest voice.
+    #[name = "Same Note"]
+    SameNote,
+    /// The oldest voice in its release stage, falling back to the oldest voice.
+    #[name = "Release First"]
+    ReleaseFirst,
+}
+
+impl StealMode {
+    /// Pick the voice that should make room for a new note on `channel` and `note`. Returns the
+    /// chosen candidate's `voice_idx`, or `None` if there are no candidates.
+    pub fn choose(
+        self,
+        candidates: impl Iterator<Item = StealCandidate> + Clone,
+        channel: u8,
+        note: u8,
+    ) -> Option<usize> {
+        let chosen = match self {
+            StealMode::Oldest => None,
+            StealMode::Quietest => candidates.clone().min_by(|a, b| {
+                a.level
+                    .total_cmp(&b.level)
+                    .then(a.internal_voice_id.cmp(&b.internal_voice_id))
+            }),
+            StealMode::SameNote => candidates
+                .clone()
+                .filter(|candidate| candidate.channel == channel && candidate.note == note)
+                .min_by_key(|candidate| candidate.internal_voice_id),
+            StealMode::ReleaseFirst => candidates
+                .clone()
+                .filter(|candidate| candidate.releasing)
+                .min_by_key(|candidate| candidate.internal_voice_id),
+        };
+
+        chosen
+            .or_else(|| candidates.min_by_key(|candidate| candidate.internal_voice_id))
+            .map(|candidate| candidate.voice_idx)
+    }
+}
+
+/// A voice that may be stolen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StealCandidate {
+    pub voice_idx: usize,
+    /// Lower IDs belong to older voices.
+    pub internal_voice_id: u64,
+    pub channel: u8,
+    pub note: u8,
+    pub releasing: bool,
+    /// The voice's current amplitude envelope level.
+    pub level: f32,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Enum, Sequence)]
+pub enum GlideMode {
+    Off,
+    /// Every glide takes the glide time, regardless of the interval.
+    #[name = "Constant Time"]
+    ConstantTime,
+    /// The glide time is the time it takes to glide an octave, so larger intervals take longer.
+    #[name = "Constant Rate"]
+    ConstantRate,
+}
+
+#[derive(Params)]
+pub struct VoiceModeParams {
+    #[id = "voice_mode"]
+    pub mode: EnumParam<VoiceMode>,
+    #[id = "note_priority"]
+    pub priority: EnumParam<NotePriority>,
+    /// The maximum number of notes in the polyphonic voice mode.
+    #[id = "polyphony"]
+    pub polyphony: IntParam,
+    #[id = "steal_mode"]
+    pub steal_mode: EnumParam<StealMode>,
+    #[id = "glide_mode"]
+    pub glide_mode: EnumParam<GlideMode>,
+    #[id = "glide_time"]
+    pub glide_time_ms: FloatParam,
+    /// Only glide when the new note was played while another note was still held.
+    #[id = "glide_legato"]
+    pub glide_legato: BoolParam,
+}
+
+impl Default for VoiceModeParams {
+    fn default() -> Self {
+        Self {
+            mode: EnumParam::new("Voice Mode", VoiceMode::Poly),
+            priority: EnumParam::new("Note Priority", NotePriority::Last),
+            polyphony: IntParam::new(
+                "Polyphony",
+                16,
+                IntRange::Linear {
+                    min: 1,
+                    max: MAX_POLYPHONY as i32,
+                },
+            ),
+            steal_mode: EnumParam::new("Voice Stealing", StealMode::Oldest),
+            glide_mode: EnumParam::new("Glide Mode", GlideMode::Off),
+            glide_time_ms: FloatParam::new(
+                "Glide Time",
+                100.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 5000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_unit(" ms")
+            .with_step_size(0.1),
+            glide_legato: BoolParam::new("Glide Only When Legato", false),
+        }
+    }
+}
+
+/// A note that's currently held down.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeldNote {
+    pub voice_id: Option<i32>,
+    pub channel: u8,
+    pub note: u8,
+    pub velocity: f32,
+    /// Whether the step sequencer is playing this note. The sequencer's notes are told apart from
+    /// keys by this and not by their channel.
+    pub sequenced: bool,
+}
+
+/// The notes that are currently held down in the order they were played. This has a fixed
+/// capacity so it can be used on the audio thread.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeldNotes {
+    notes: [Option<HeldNote>; MAX_HELD_NOTES],
+    len: usize,
+}
+
+impl Default for HeldNotes {
+    fn default() -> Self {
+        Self {
+            notes: [None; MAX_HELD_NOTES],
+            len: 0,
+        }
+    }
+}
+
+impl HeldNotes {
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn clear(&mut self) {
+        self.notes = [None; MAX_HELD_NOTES];
+        self.len = 0;
+    }
+
+    /// Add a note to the top of the stack. If the note was already held it's moved to the top.
+    pub fn push(&mut self, held_note: HeldNote) {
+        self.remove_where(|other| {
+            other.sequenced == held_note.sequenced
+                && other.channel == held_note.channel
+                && other.note == held_note.note
+        });
+        if self.len == MAX_HELD_NOTES {
+            self.notes.rotate_left(1);
+            self.len -= 1;
+        }
+
+        self.notes[self.len] = Some(held_note);
+        self.len += 1;
+    }
+
+    /// Remove the key playing `note` on `channel`.
+    pub fn remove(&mut self, channel: u8, note: u8) {
+        self.remove_where(|held_note| {
+            !held_note.sequenced && held_note.channel == channel && held_note.note == note
+        });
+    }
+
+    /// Remove the step sequencer's `note`.
+    pub fn remove_sequenced(&mut self, note: u8) {
+        self.remove_where(|held_note| held_note.sequenced && held_note.note == note);
+    }
+
+    fn remove_where(&mut self, predicate: impl Fn(&HeldNote) -> bool) {
+        let idx = self.iter().position(|held_note| predicate(&held_note));
+        if let Some(idx) = idx {
+            self.notes[idx..self.len].rotate_left(1);
+            self.notes[self.len - 1] = None;
+            self.len -= 1;
+        }
+    }
+
+    /// The note that should be playing according to `priority`, if any notes are held.
+    pub fn priority_note(&self, priority: NotePriority) -> Option<HeldNote> {
+        match priority {
+            NotePriority::Last => self.iter().next_back(),
+            // For equal notes on different channels the most recent one wins. `min_by_key()`
+            // returns the first minimum and `max_by_key()` returns the last maximum.
+            NotePriority::Low => self.iter().rev().min_by_key(|held_note| held_note.note),
+            NotePriority::High => self.iter().max_by_key(|held_note| held_note.note),
+        }
+    }
+
+    /// The held notes, from the oldest to the most recent note.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = HeldNote> + '_ {
+        self.notes[..self.len].iter().flatten().copied()
+    }
+}
+
+/// A voice's portamento, stored as the offset in semitones from the voice's note to the pitch
+/// it's gliding from. The offset moves towards zero with every sample.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Glide {
+    offset: f32,
+    /// How far the offset moves towards zero every sample.
+    step: f32,
+}
+
+impl Glide {
+    /// Start gliding to a note `interval` semitones away from the voice's previous note. If the
+    /// voice was already gliding, then the new glide starts from the voice's current pitch.
+    pub fn start(&mut self, interval: f32, mode: GlideMode, time_ms: f32, sample_rate: f32) {
+        self.offset -= interval;
+
+        // Glides shorter than a sample jump straight to the new note
+        let glide_samples = time_ms / 1000.0 * sample_rate;
+        match mode {
+            GlideMode::ConstantTime if glide_samples >= 1.0 => {
+                self.step = self.offset.abs() / glide_samples
+            }
+            GlideMode::ConstantRate if glide_samples >= 1.0 => self.step = 12.0 / glide_samples,
+            GlideMode::Off | GlideMode::ConstantTime | GlideMode::ConstantRate => self.stop(),
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.offset = 0.0;
+        self.step = 0.0;
+    }
+
+    /// The offset in semitones for the next sample.
+    pub fn next_offset(&mut self) -> f32 {
+        if self.offset > 0.0 {
+            self.offset = (self.offset - self.step).max(0.0);
+        } else if self.offset < 0.0 {
+            self.offset = (self.offset + self.step).min(0.0);
+        }
+
+        self.offset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn held_note(note: u8) -> HeldNote {
+        HeldNote {
+            voice_id: None,
+            channel: 0,
+            note,
+            velocity: 1.0,
+            sequenced: false,
+        }
+    }
+
+    #[test]
+    fn test_note_priority() {
+        let mut held_notes = HeldNotes::default();
+        assert_eq!(held_notes.priority_note(NotePriority::Last), None);
+
+        held_notes.push(held_note(60));
+        held_notes.push(held_note(48));
+        held_notes.push(held_note(72));
+        held_notes.push(held_note(64));
+        assert_eq!(held_notes.priority_note(NotePriority::Last), Some(held_note(64)));
+        assert_eq!(held_notes.priority_note(NotePriority::Low), Some(held_note(48)));
+        assert_eq!(held_notes.priority_note(NotePriority::High), Some(held_note(72)));
+
+        // Releasing the playing note falls back to the next note in line
+        held_notes.remove(0, 64);
+        assert_eq!(held_notes.priority_note(NotePriority::Last), Some(held_note(72)));
+        held_notes.remove(0, 48);
+        assert_eq!(held_notes.priority_note(NotePriority::Low), Some(held_note(60)));
+
+        // Playing a held note again moves it to the top of the stack
+        held_notes.push(held_note(60));
+        assert_eq!(held_notes.priority_note(NotePriority::Last), Some(held_note(60)));
+        held_notes.remove(0, 60);
+        assert_eq!(held_notes.priority_note(NotePriority::Last), Some(held_note(72)));
+        held_notes.remove(0, 72);
+        assert!(held_notes.is_empty());
+    }
+
+    #[test]
+    fn test_sequenced_notes_are_kept_apart_from_keys() {
+        let mut held_notes = HeldNotes::default();
+        let sequenced_note = HeldNote {
+            sequenced: true,
+            ..held_note(60)
+        };
+        held_notes.push(held_note(60));
+        held_notes.push(sequenced_note);
+        assert_eq!(held_notes.len(), 2);
+
+        held_notes.remove(0, 60);
+        assert_eq!(held_notes.priority_note(NotePriority::Last), Some(sequenced_note));
+        held_notes.remove(0, 60);
+        assert_eq!(held_notes.len(), 1);
+        held_notes.remove_sequenced(60);
+        assert!(held_notes.is_empty());
+    }
+
+    #[test]
+    fn test_held_notes_forget_the_oldest_note_when_full() {
+        let mut held_notes = HeldNotes::default();
+        for note in 0..(MAX_HELD_NOTES as u8 + 4) {
+            held_notes.push(held_note(note));
+        }
+
+        assert_eq!(held_notes.priority_note(NotePriority::Low), Some(held_note(4)));
+        assert_eq!(
+            held_notes.priority_note(NotePriority::Last),
+            Some(held_note(MAX_HELD_NOTES as u8 + 3))
+        );
+    }
+
+    #[test]
+    fn test_steal_modes() {
+        let candidates = [
+            StealCandidate {
+                voice_idx: 0,
+                internal_voice_id: 3,
+                channel: 0,
+                note: 60,
+                releasing: false,
+                level: 0.2,
+            },
+            StealCandidate {
+                voice_idx: 1,
+                internal_voice_id: 1,
+                channel: 0,
+                note: 62,
+                releasing: false,
+                level: 0.9,
+            },
+            StealCandidate {
+                voice_idx: 2,
+                internal_voice_id: 2,
+                channel: 0,
+                note: 64,
+                releasing: true,
+                level: 0.5,
+            },
+        ];
+        let choose =
+            |mode: StealMode, note: u8| mode.choose(candidates.iter().copied(), 0, note);
+
+        assert_eq!(choose(StealMode::Oldest, 60), Some(1));
+        assert_eq!(choose(StealMode::Quietest, 60), Some(0));
+        assert_eq!(choose(StealMode::SameNote, 64), Some(2));
+        assert_eq!(choose(StealMode::SameNote, 65), Some(1));
+        assert_eq!(choose(StealMode::ReleaseFirst, 60), Some(2));
+        assert_eq!(
+            StealMode::ReleaseFirst.choose(candidates[..2].iter().copied(), 0, 60),
+            Some(1)
+        );
+        assert_eq!(StealMode::Oldest.choose(std::iter::empty(), 0, 60), None);
+    }
+
+    #[test]
+    fn test_constant_time_glide() {
+        for interval in [-12.0, 3.0, 24.0] {
+            let mut glide = Glide::default();
+            glide.start(interval, GlideMode::ConstantTime, 100.0, 1000.0);
+            for _ in 0..99 {
+                assert!(glide.next_offset() != 0.0, "{interval}");
+            }
+            assert!(glide.next_offset().abs() < 1.0e-4, "{interval}");
+        }
+    }
+
+    #[test]
+    fn test_constant_rate_glide() {
+        let mut glide = Glide::default();
+        glide.start(24.0, GlideMode::ConstantRate, 100.0, 1000.0);
+        assert_eq!(glide.next_offset(), -24.0 + 0.12);
+
+        // Two octaves take twice the glide time
+        let samples = std::iter::repeat_with(|| glide.next_offset())
+            .take_while(|offset| *offset != 0.0)
+            .count();
+        assert_eq!(samples + 2, 200);
+    }
+
+    #[test]
+    fn test_glide_continues_from_the_current_pitch() {
+        let mut glide = Glide::default();
+        glide.start(12.0, GlideMode::ConstantTime, 10.0, 1000.0);
+        for _ in 0..5 {
+            glide.next_offset();
+        }
+
+        // Halfway through the glide the voice is six semitones below the first target. Going back
+        // down by an octave leaves it six semitones above the new target.
+        glide.start(-12.0, GlideMode::ConstantTime, 10.0, 1000.0);
+        assert!((glide.next_offset() - 5.4).abs() < 1.0e-4);
+
+        glide.start(0.0, GlideMode::Off, 10.0, 1000.0);
+        assert_eq!(glide.next_offset(), 0.0);
+
+        // Glides shorter than a sample don't glide at all
+        glide.start(12.0, GlideMode::ConstantRate, 0.5, 1000.0);
+        assert_eq!(glide.next_offset(), 0.0);
+    }
+}
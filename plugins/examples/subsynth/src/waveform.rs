@@ -9,16 +9,57 @@ pub enum Waveform {
     Square,
     Pulse,
     Noise,
+    /// Plays the loaded wavetable.
+    Wavetable,
 }
 
-pub fn generate_waveform(waveform: Waveform, phase: f32) -> f32 {
+/// The modulation source for the square and pulse waveforms' pulse width.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Enum, Sequence)]
+pub enum PwmSource {
+    Off,
+    #[name = "LFO"]
+    Lfo,
+    Envelope,
+}
+
+/// How far below the main oscillator the sub-oscillator plays.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Enum, Sequence)]
+pub enum SubOctave {
+    #[name = "-1 Octave"]
+    OneDown,
+    #[name = "-2 Octaves"]
+    TwoDown,
+}
+
+impl SubOctave {
+    /// The sub-oscillator's frequency relative to the main oscillator.
+    pub fn frequency_ratio(self) -> f32 {
+        match self {
+            SubOctave::OneDown => 0.5,
+            SubOctave::TwoDown => 0.25,
+        }
+    }
+}
+
+/// Generate a single sample for `waveform` at `phase`. `phase_delta` is the phase increment per
+/// sample, which the band-limited waveforms use to smooth out their discontinuities with PolyBLEP
+/// and PolyBLAMP residuals. `pulse_width` is the square wave's duty cycle in `(0, 1)`. The pulse
+/// wave is the narrow variant and uses half of that, so at the default 50% pulse width it has a 25%
+/// duty cycle. Noise and wavetables need state that lives in the voice, so the voices render those
+/// themselves and they are silent here.
+pub fn generate_waveform(
+    waveform: Waveform,
+    phase: f32,
+    phase_delta: f32,
+    pulse_width: f32,
+) -> f32 {
     match waveform {
         Waveform::Sine => sine(phase),
-        Waveform::Triangle => (2.0 * (phase - 0.5)).abs() * 2.0 - 1.0,
-        Waveform::Sawtooth => 1.0 - phase * 2.0,
-        Waveform::Square => if phase < 0.5 { 1.0 } else { -1.0 },
-        Waveform::Pulse => if phase < 0.25 || phase >= 0.75 { 1.0 } else { -1.0 },
-        Waveform::Noise => rand::random::<f32>() * 2.0 - 1.0,
+        Waveform::Triangle => triangle(phase, phase_delta),
+        Waveform::Sawtooth => sawtooth(phase, phase_delta),
+        Waveform::Square => pulse(phase, phase_delta, pulse_width),
+        Waveform::Pulse => pulse(phase, phase_delta, pulse_width * 0.5),
+        Waveform::Noise | Waveform::Wavetable => 0.0,
     }
 }
 
@@ -31,4 +72,260 @@ fn sine(phase: f32) -> f32 {
 
     // Compute the sine value
     angle.sin()
-}
\ No newline at end of file
+}
+
+/// A falling sawtooth that jumps from -1 back up to 1 at the start of every period.
+fn sawtooth(phase: f32, phase_delta: f32) -> f32 {
+    let naive = 1.0 - 2.0 * phase;
+
+    naive + poly_blep(phase, phase_delta)
+}
+
+/// A pulse wave that is high for the first `width` part of the period.
+fn pulse(phase: f32, phase_delta: f32, width: f32) -> f32 {
+    let naive = if phase < width { 1.0 } else { -1.0 };
+
+    naive + poly_blep(phase, phase_delta) - poly_blep(wrap(phase + 1.0 - width), phase_delta)
+}
+
+/// A triangle wave that peaks at the start of the period and reaches its lowest point halfway.
+fn triangle(phase: f32, phase_delta: f32) -> f32 {
+    let naive = (2.0 * (phase - 0.5)).abs() * 2.0 - 1.0;
+
+    naive
+        + 4.0
+            * phase_delta
+            * (poly_blamp(wrap(phase + 0.5), phase_delta) - poly_blamp(phase, phase_delta))
+}
+
+/// Advance an oscillator's phase by one sample, wrapping it back into `[0, 1)`. The phase delta
+/// may be negative for through-zero FM. Returns `true` if the phase wrapped around.
+pub fn advance_phase(phase: &mut f32, phase_delta: f32) -> bool {
+    *phase += phase_delta;
+    if *phase >= 1.0 {
+        *phase -= 1.0;
+        true
+    } else if *phase < 0.0 {
+        *phase += 1.0;
+        true
+    } else {
+        false
+    }
+}
+
+fn wrap(phase: f32) -> f32 {
+    phase - phase.floor()
+}
+
+/// The two-sample polynomial band-limited step residual for an upwards step of height 2 at phase 0.
+/// This is added to a naive waveform to round off its discontinuity.
+fn poly_blep(phase: f32, phase_delta: f32) -> f32 {
+    if phase < phase_delta {
+        let t = phase / phase_delta;
+        2.0 * t - t * t - 1.0
+    } else if phase > 1.0 - phase_delta {
+        let t = (phase - 1.0) / phase_delta;
+        t * t + 2.0 * t + 1.0
+    } else {
+        0.0
+    }
+}
+
+/// The integrated version of [`poly_blep()`], used to round off the corners of waveforms whose
+/// slope changes abruptly.
+fn poly_blamp(phase: f32, phase_delta: f32) -> f32 {
+    if phase < phase_delta {
+        let t = phase / phase_delta - 1.0;
+        -t * t * t / 3.0
+    } else if phase > 1.0 - phase_delta {
+        let t = (phase - 1.0) / phase_delta + 1.0;
+        t * t * t / 3.0
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 44100.0;
+    const FFT_SIZE: usize = 1 << 15;
+    /// C4 up to C8, which is the highest note the team needs to be clean.
+    const TEST_NOTES: [u8; 5] = [60, 72, 84, 96, 108];
+
+    /// An in-place radix-2 FFT. `re` and `im` need to have the same power of two length.
+    fn fft(re: &mut [f64], im: &mut [f64]) {
+        let n = re.len();
+        let mut j = 0;
+        for i in 1..n {
+            let mut bit = n >> 1;
+            while j & bit != 0 {
+                j ^= bit;
+                bit >>= 1;
+            }
+            j |= bit;
+            if i < j {
+                re.swap(i, j);
+                im.swap(i, j);
+            }
+        }
+
+        let mut len = 2;
+        while len <= n {
+            let angle = -2.0 * std::f64::consts::PI / len as f64;
+            for start in (0..n).step_by(len) {
+                for k in 0..len / 2 {
+                    let (w_re, w_im) = ((angle * k as f64).cos(), (angle * k as f64).sin());
+                    let (a, b) = (start + k, start + k + len / 2);
+                    let t_re = re[b] * w_re - im[b] * w_im;
+                    let t_im = re[b] * w_im + im[b] * w_re;
+                    re[b] = re[a] - t_re;
+                    im[b] = im[a] - t_im;
+                    re[a] += t_re;
+                    im[a] += t_im;
+                }
+            }
+            len <<= 1;
+        }
+    }
+
+    /// Render `waveform` at `note` and return the energy of all non-harmonic components relative
+    /// to the energy of the harmonics, in decibels. The frequency is rounded to an odd number of
+    /// cycles per FFT window so the harmonics land exactly on FFT bins and the aliased components
+    /// never do.
+    fn aliasing_db(waveform: Waveform, note: u8, pulse_width: f32) -> f64 {
+        let frequency = nih_plug::util::midi_note_to_freq(note);
+        let mut cycles = (frequency * FFT_SIZE as f32 / SAMPLE_RATE).round() as usize;
+        if cycles % 2 == 0 {
+            cycles += 1;
+        }
+        let phase_delta = cycles as f32 / FFT_SIZE as f32;
+
+        let mut re: Vec<f64> = (0..FFT_SIZE)
+            .map(|i| {
+                let phase = ((i * cycles) % FFT_SIZE) as f32 / FFT_SIZE as f32;
+                generate_waveform(waveform, phase, phase_delta, pulse_width) as f64
+            })
+            .collect();
+        let mut im = vec![0.0; FFT_SIZE];
+        fft(&mut re, &mut im);
+
+        let mut harmonic_energy = 0.0;
+        let mut aliasing_energy = 0.0;
+        for bin in 1..FFT_SIZE / 2 {
+            let energy = re[bin] * re[bin] + im[bin] * im[bin];
+            if bin % cycles == 0 {
+                harmonic_energy += energy;
+            } else {
+                aliasing_energy += energy;
+            }
+        }
+
+        10.0 * (aliasing_energy / harmonic_energy).log10()
+    }
+
+    #[test]
+    fn test_sine_does_not_alias() {
+        for note in TEST_NOTES {
+            let aliasing = aliasing_db(Waveform::Sine, note, 0.5);
+            assert!(aliasing < -90.0, "note {note}: {aliasing} dB");
+        }
+    }
+
+    #[test]
+    fn test_sawtooth_aliasing() {
+        for note in TEST_NOTES {
+            let aliasing = aliasing_db(Waveform::Sawtooth, note, 0.5);
+            assert!(aliasing < -24.0, "note {note}: {aliasing} dB");
+        }
+    }
+
+    #[test]
+    fn test_square_aliasing() {
+        for note in TEST_NOTES {
+            let aliasing = aliasing_db(Waveform::Square, note, 0.5);
+            assert!(aliasing < -27.0, "note {note}: {aliasing} dB");
+        }
+    }
+
+    #[test]
+    fn test_pulse_aliasing() {
+        for note in TEST_NOTES {
+            let aliasing = aliasing_db(Waveform::Pulse, note, 0.5);
+            assert!(aliasing < -24.0, "note {note}: {aliasing} dB");
+        }
+    }
+
+    #[test]
+    fn test_narrow_pulse_width_aliasing() {
+        // Narrow pulses have much less energy in their harmonics, so the ratio is naturally worse
+        for note in TEST_NOTES {
+            let aliasing = aliasing_db(Waveform::Square, note, 0.1);
+            assert!(aliasing < -18.0, "note {note}: {aliasing} dB");
+        }
+    }
+
+    #[test]
+    fn test_pulse_width_sets_duty_cycle() {
+        for pulse_width in [0.1, 0.3, 0.5, 0.8] {
+            // At a low frequency the PolyBLEP residuals only affect a handful of samples, so the
+            // average of the waveform should be close to the naive `2 * width - 1`
+            let phase_delta = 1.0 / 4096.0;
+            let average = (0..4096)
+                .map(|i| {
+                    let phase = i as f32 * phase_delta;
+                    generate_waveform(Waveform::Square, phase, phase_delta, pulse_width)
+                })
+                .sum::<f32>()
+                / 4096.0;
+            approx::assert_relative_eq!(average, 2.0 * pulse_width - 1.0, epsilon = 1.0e-3);
+
+            let average = (0..4096)
+                .map(|i| {
+                    let phase = i as f32 * phase_delta;
+                    generate_waveform(Waveform::Pulse, phase, phase_delta, pulse_width)
+                })
+                .sum::<f32>()
+                / 4096.0;
+            approx::assert_relative_eq!(average, pulse_width - 1.0, epsilon = 1.0e-3);
+        }
+    }
+
+    #[test]
+    fn test_triangle_aliasing() {
+        for note in TEST_NOTES {
+            let aliasing = aliasing_db(Waveform::Triangle, note, 0.5);
+            assert!(aliasing < -45.0, "note {note}: {aliasing} dB");
+        }
+    }
+
+    #[test]
+    fn test_advance_phase_wraps_in_both_directions() {
+        let mut phase = 0.9;
+        assert!(!advance_phase(&mut phase, 0.05));
+        assert!(advance_phase(&mut phase, 0.1));
+        approx::assert_relative_eq!(phase, 0.05, epsilon = 1.0e-6);
+
+        assert!(advance_phase(&mut phase, -0.1));
+        approx::assert_relative_eq!(phase, 0.95, epsilon = 1.0e-6);
+        assert!(!advance_phase(&mut phase, -0.1));
+        approx::assert_relative_eq!(phase, 0.85, epsilon = 1.0e-6);
+    }
+
+    #[test]
+    fn test_band_limited_waveforms_stay_in_range() {
+        let phase_delta = nih_plug::util::midi_note_to_freq(108) / SAMPLE_RATE;
+        for waveform in [
+            Waveform::Triangle,
+            Waveform::Sawtooth,
+            Waveform::Square,
+            Waveform::Pulse,
+        ] {
+            for i in 0..1000 {
+                let sample = generate_waveform(waveform, i as f32 / 1000.0, phase_delta, 0.5);
+                assert!(sample.abs() <= 1.1, "{waveform:?}: {sample}");
+            }
+        }
+    }
+}
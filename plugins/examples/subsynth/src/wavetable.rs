@@ -0,0 +1,530 @@
+use serde::{Deserialize, Serialize};
+use std::f64::consts::TAU;
+use std::fmt;
+use std::sync::Mutex;
+
+/// The length of a single-cycle frame. This is the frame size Serum uses for its wavetables.
+pub const FRAME_SIZE: usize = 2048;
+/// Imported files with more frames than this are truncated.
+pub const MAX_FRAMES: usize = 256;
+/// The number of band-limited versions of every frame. Every mipmap has half as many harmonics as
+/// the one before it, down to a single harmonic.
+const NUM_MIPMAPS: usize = 10;
+/// The number of harmonics in the first mipmap. The frame's upper harmonics are dropped so every
+/// mipmap can be stored at four samples per cycle of its highest harmonic.
+const MAX_HARMONICS: usize = FRAME_SIZE / 4;
+/// The shortest mipmap table. Shorter tables would make the linear interpolation audible.
+const MIN_TABLE_SIZE: usize = 64;
+/// The number of frames in the built-in table, used until a wavetable has been imported.
+const BUILTIN_FRAMES: usize = 8;
+
+/// An error encountered while importing a WAV file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WavError {
+    /// The file does not start with a RIFF WAVE header.
+    NotAWav,
+    /// The file is missing a required chunk. Contains the chunk's ID.
+    MissingChunk(&'static str),
+    /// The file's sample format is not supported. Contains the format tag and the bit depth.
+    UnsupportedFormat(u16, u16),
+    /// The file contains fewer samples than a single frame. Contains the number of samples.
+    TooShort(usize),
+}
+
+impl fmt::Display for WavError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WavError::NotAWav => write!(f, "not a WAV file"),
+            WavError::MissingChunk(id) => write!(f, "the file does not contain a '{id}' chunk"),
+            WavError::UnsupportedFormat(format, bits) => {
+                write!(f, "unsupported sample format {format} at {bits} bits")
+            }
+            WavError::TooShort(len) => write!(
+                f,
+                "the file contains {len} samples, but a frame needs {FRAME_SIZE} samples"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for WavError {}
+
+/// The frames a wavetable is built from. This is what gets stored in the plugin's state, so
+/// projects keep their wavetable even if the imported file is gone.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct WavetableSource {
+    /// The name of the imported file, shown in the editor.
+    pub name: String,
+    /// The imported frames back to back. Empty for the built-in table.
+    pub samples: Vec<f32>,
+}
+
+impl WavetableSource {
+    /// Import a Serum style wavetable from a WAV file's contents. The file's first channel is
+    /// split into frames of [`FRAME_SIZE`] samples, and any samples after the last full frame are
+    /// ignored.
+    pub fn from_wav(name: String, bytes: &[u8]) -> Result<Self, WavError> {
+        let mut samples = read_wav(bytes)?;
+        if samples.len() < FRAME_SIZE {
+            return Err(WavError::TooShort(samples.len()));
+        }
+
+        let num_frames = (samples.len() / FRAME_SIZE).min(MAX_FRAMES);
+        samples.truncate(num_frames * FRAME_SIZE);
+
+        Ok(Self { name, samples })
+    }
+
+    pub fn is_builtin(&self) -> bool {
+        self.samples.len() < FRAME_SIZE
+    }
+
+    pub fn num_frames(&self) -> usize {
+        if self.is_builtin() {
+            BUILTIN_FRAMES
+        } else {
+            self.samples.len() / FRAME_SIZE
+        }
+    }
+
+    /// The frames making up this table, or the built-in frames if nothing has been imported.
+    fn frames(&self) -> Vec<Vec<f32>> {
+        if self.is_builtin() {
+            return builtin_frames();
+        }
+
+        self.samples
+            .chunks_exact(FRAME_SIZE)
+            .map(|frame| frame.to_vec())
+            .collect()
+    }
+}
+
+/// The built-in table morphs from a sine wave to a sawtooth by adding more and more harmonics.
+fn builtin_frames() -> Vec<Vec<f32>> {
+    (0..BUILTIN_FRAMES)
+        .map(|frame_idx| {
+            let num_harmonics = 1 << (frame_idx * 7 / (BUILTIN_FRAMES - 1));
+            (0..FRAME_SIZE)
+                .map(|sample_idx| {
+                    let phase = sample_idx as f64 / FRAME_SIZE as f64;
+                    (1..=num_harmonics)
+                        .map(|harmonic| (TAU * harmonic as f64 * phase).sin() / harmonic as f64)
+                        .sum::<f64>() as f32
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// A wavetable ready for playback. Every frame is stored as a set of mipmaps with progressively
+/// fewer harmonics, and playback picks the mipmap whose highest harmonic stays below the Nyquist
+/// frequency. Building a wavetable is expensive, so this should happen on a background thread.
+#[derive(Debug, Clone, Default)]
+pub struct Wavetable {
+    /// Every frame's mipmaps, starting with the mipmap containing the most harmonics.
+    frames: Vec<[Vec<f32>; NUM_MIPMAPS]>,
+}
+
+impl Wavetable {
+    pub fn new(source: &WavetableSource) -> Self {
+        let frames = source
+            .frames()
+            .iter()
+            .map(|frame| {
+                let (mut re, mut im): (Vec<f64>, Vec<f64>) =
+                    frame.iter().map(|sample| (*sample as f64, 0.0)).unzip();
+                fft(&mut re, &mut im);
+                let mut mipmaps: [Vec<f32>; NUM_MIPMAPS] = std::array::from_fn(|mipmap_idx| {
+                    band_limit(&re, &im, MAX_HARMONICS >> mipmap_idx)
+                });
+
+                // Every frame is normalized on its own so morphing between frames doesn't change
+                // the volume
+                let peak = mipmaps[0]
+                    .iter()
+                    .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+                if peak > 0.0 {
+                    for sample in mipmaps.iter_mut().flatten() {
+                        *sample /= peak;
+                    }
+                }
+
+                mipmaps
+            })
+            .collect();
+
+        Self { frames }
+    }
+
+    /// Read the table at `phase`. `position` in `[0, 1]` morphs between the first and the last
+    /// frame, and `phase_delta` selects the mipmap. An empty table is silent.
+    pub fn sample(&self, phase: f32, phase_delta: f32, position: f32) -> f32 {
+        if self.frames.is_empty() {
+            return 0.0;
+        }
+
+        let mut mipmap_idx = 0;
+        while mipmap_idx < NUM_MIPMAPS - 1
+            && (MAX_HARMONICS >> mipmap_idx) as f32 * phase_delta.abs() > 0.5
+        {
+            mipmap_idx += 1;
+        }
+
+        let frame_pos = position.clamp(0.0, 1.0) * (self.frames.len() - 1) as f32;
+        let frame_idx = (frame_pos as usize).min(self.frames.len() - 1);
+        let next_frame_idx = (frame_idx + 1).min(self.frames.len() - 1);
+        let t = frame_pos - frame_idx as f32;
+
+        let current = read_table(&self.frames[frame_idx][mipmap_idx], phase);
+        let next = read_table(&self.frames[next_frame_idx][mipmap_idx], phase);
+        current + (next - current) * t
+    }
+}
+
+/// Hands wavetables built on a background thread over to the audio thread. The audio thread swaps
+/// its current table for the new one, and the table it gave up is only dropped when the next
+/// table is published so the audio thread never has to deallocate.
+#[derive(Debug, Default)]
+pub struct WavetableExchange {
+    slot: Mutex<ExchangeSlot>,
+}
+
+#[derive(Debug, Default)]
+enum ExchangeSlot {
+    #[default]
+    Empty,
+    /// A table that has not yet been picked up by the audio thread.
+    Pending(Box<Wavetable>),
+    /// The table the audio thread replaced.
+    Retired(Box<Wavetable>),
+}
+
+impl WavetableExchange {
+    /// Make a new table available to the audio thread. This replaces any table that hasn't been
+    /// picked up yet.
+    pub fn publish(&self, wavetable: Wavetable) {
+        let wavetable = Box::new(wavetable);
+        let previous = std::mem::replace(
+            &mut *self.slot.lock().unwrap(),
+            ExchangeSlot::Pending(wavetable),
+        );
+
+        // The previous table is dropped after the lock has been released so the audio thread
+        // never has to wait for the deallocation
+        if let ExchangeSlot::Pending(previous) | ExchangeSlot::Retired(previous) = previous {
+            drop(previous);
+        }
+    }
+
+    /// Swap `current` for the most recently published table if there is one. Returns `true` if
+    /// the table was replaced. This never blocks or allocates, so it can be called from the audio
+    /// thread.
+    pub fn try_swap(&self, current: &mut Box<Wavetable>) -> bool {
+        let Ok(mut slot) = self.slot.try_lock() else {
+            return false;
+        };
+
+        match std::mem::take(&mut *slot) {
+            ExchangeSlot::Pending(mut wavetable) => {
+                std::mem::swap(current, &mut wavetable);
+                *slot = ExchangeSlot::Retired(wavetable);
+                true
+            }
+            other => {
+                *slot = other;
+                false
+            }
+        }
+    }
+}
+
+/// Read a single-cycle table at `phase` with linear interpolation.
+fn read_table(table: &[f32], phase: f32) -> f32 {
+    let pos = phase.rem_euclid(1.0) * table.len() as f32;
+    let idx = (pos as usize).min(table.len() - 1);
+    let t = pos - idx as f32;
+    let current = table[idx];
+    let next = table[(idx + 1) % table.len()];
+
+    current + (next - current) * t
+}
+
+/// Resynthesize a frame from its spectrum with only the first `num_harmonics` harmonics. The DC
+/// offset is removed as well. The resulting table has just enough samples for its harmonics.
+fn band_limit(re: &[f64], im: &[f64], num_harmonics: usize) -> Vec<f32> {
+    let len = (num_harmonics * 4).clamp(MIN_TABLE_SIZE, FRAME_SIZE);
+    let scale = len as f64 / re.len() as f64;
+
+    // This is an inverse FFT done with a forward FFT on the conjugated spectrum
+    let mut table_re = vec![0.0; len];
+    let mut table_im = vec![0.0; len];
+    for harmonic in 1..=num_harmonics.min(len / 2 - 1) {
+        table_re[harmonic] = re[harmonic] * scale;
+        table_im[harmonic] = -im[harmonic] * scale;
+        table_re[len - harmonic] = re[harmonic] * scale;
+        table_im[len - harmonic] = im[harmonic] * scale;
+    }
+    fft(&mut table_re, &mut table_im);
+
+    table_re
+        .iter()
+        .map(|sample| (sample / len as f64) as f32)
+        .collect()
+}
+
+/// An in-place radix-2 FFT. `re` and `im` need to have the same power of two length.
+pub(crate) fn fft(re: &mut [f64], im: &mut [f64]) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -TAU / len as f64;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (w_re, w_im) = ((angle * k as f64).cos(), (angle * k as f64).sin());
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+/// Decode the first channel of a PCM or floating point WAV file.
+fn read_wav(bytes: &[u8]) -> Result<Vec<f32>, WavError> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(WavError::NotAWav);
+    }
+
+    let u16_at =
+        |chunk: &[u8], offset: usize| u16::from_le_bytes([chunk[offset], chunk[offset + 1]]);
+    let mut format = None;
+    let mut data = None;
+    let mut pos = 12;
+    while pos + 8 <= bytes.len() {
+        let id = &bytes[pos..pos + 4];
+        let size = u32::from_le_bytes(bytes[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        let chunk = &bytes[pos + 8..(pos + 8 + size).min(bytes.len())];
+        match id {
+            b"fmt " if chunk.len() >= 16 => {
+                let mut format_tag = u16_at(chunk, 0);
+                // WAVE_FORMAT_EXTENSIBLE stores the actual format in its subformat GUID
+                if format_tag == 0xfffe && chunk.len() >= 26 {
+                    format_tag = u16_at(chunk, 24);
+                }
+                format = Some((format_tag, u16_at(chunk, 12) as usize, u16_at(chunk, 14)));
+            }
+            b"data" => data = Some(chunk),
+            _ => (),
+        }
+
+        // Chunks are padded to an even length
+        pos += 8 + size + (size & 1);
+    }
+
+    let (format_tag, block_align, bits) = format.ok_or(WavError::MissingChunk("fmt "))?;
+    let data = data.ok_or(WavError::MissingChunk("data"))?;
+    let decode: fn(&[u8]) -> f32 = match (format_tag, bits) {
+        (1, 8) => |bytes| (bytes[0] as f32 - 128.0) / 128.0,
+        (1, 16) => |bytes| i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.0,
+        (1, 24) => {
+            |bytes| i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) as f32 / 2_147_483_648.0
+        }
+        (1, 32) => {
+            |bytes| i32::from_le_bytes(bytes[..4].try_into().unwrap()) as f32 / 2_147_483_648.0
+        }
+        (3, 32) => |bytes| f32::from_le_bytes(bytes[..4].try_into().unwrap()),
+        (3, 64) => |bytes| f64::from_le_bytes(bytes[..8].try_into().unwrap()) as f32,
+        (format_tag, bits) => return Err(WavError::UnsupportedFormat(format_tag, bits)),
+    };
+    if block_align < (bits as usize).div_ceil(8) {
+        return Err(WavError::UnsupportedFormat(format_tag, bits));
+    }
+
+    Ok(data.chunks_exact(block_align).map(decode).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a WAV file containing `channels` interleaved channels of raw sample data.
+    fn wav_file(format_tag: u16, bits: u16, channels: u16, data: &[u8]) -> Vec<u8> {
+        let block_align = channels * bits / 8;
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(b"WAVE");
+        bytes.extend_from_slice(b"fmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&format_tag.to_le_bytes());
+        bytes.extend_from_slice(&channels.to_le_bytes());
+        bytes.extend_from_slice(&44100u32.to_le_bytes());
+        bytes.extend_from_slice(&(44100 * block_align as u32).to_le_bytes());
+        bytes.extend_from_slice(&block_align.to_le_bytes());
+        bytes.extend_from_slice(&bits.to_le_bytes());
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(data);
+
+        bytes
+    }
+
+    fn sawtooth_frame() -> Vec<f32> {
+        (0..FRAME_SIZE)
+            .map(|i| 1.0 - 2.0 * i as f32 / FRAME_SIZE as f32)
+            .collect()
+    }
+
+    #[test]
+    fn test_import_float_wav() {
+        let samples: Vec<f32> = (0..FRAME_SIZE * 2 + 100)
+            .map(|i| (i % 7) as f32 / 8.0)
+            .collect();
+        let data: Vec<u8> = samples
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect();
+        let source =
+            WavetableSource::from_wav(String::from("test"), &wav_file(3, 32, 1, &data)).unwrap();
+
+        // The incomplete third frame is dropped
+        assert_eq!(source.num_frames(), 2);
+        assert_eq!(source.samples, samples[..FRAME_SIZE * 2]);
+    }
+
+    #[test]
+    fn test_import_stereo_pcm_wav() {
+        // Only the left channel is used
+        let data: Vec<u8> = (0..FRAME_SIZE)
+            .flat_map(|_| [16384i16.to_le_bytes(), (-32768i16).to_le_bytes()])
+            .flatten()
+            .collect();
+        let source =
+            WavetableSource::from_wav(String::from("test"), &wav_file(1, 16, 2, &data)).unwrap();
+        assert_eq!(source.num_frames(), 1);
+        assert!(source.samples.iter().all(|sample| *sample == 0.5));
+
+        let data: Vec<u8> = (0..FRAME_SIZE).flat_map(|_| [0x00, 0x00, 0xc0]).collect();
+        let source =
+            WavetableSource::from_wav(String::from("test"), &wav_file(1, 24, 1, &data)).unwrap();
+        assert!(source.samples.iter().all(|sample| *sample == -0.5));
+    }
+
+    #[test]
+    fn test_import_errors() {
+        assert_eq!(
+            WavetableSource::from_wav(String::new(), b"not a wav file"),
+            Err(WavError::NotAWav)
+        );
+        assert_eq!(
+            WavetableSource::from_wav(String::new(), &wav_file(1, 16, 1, &[0; 200])),
+            Err(WavError::TooShort(100))
+        );
+        assert_eq!(
+            WavetableSource::from_wav(String::new(), &wav_file(2, 4, 1, &[0; 200])),
+            Err(WavError::UnsupportedFormat(2, 4))
+        );
+    }
+
+    #[test]
+    fn test_mipmaps_are_band_limited() {
+        let source = WavetableSource {
+            name: String::from("saw"),
+            samples: sawtooth_frame(),
+        };
+        let wavetable = Wavetable::new(&source);
+
+        for (mipmap_idx, table) in wavetable.frames[0].iter().enumerate() {
+            let num_harmonics = MAX_HARMONICS >> mipmap_idx;
+            let (mut re, mut im): (Vec<f64>, Vec<f64>) =
+                table.iter().map(|sample| (*sample as f64, 0.0)).unzip();
+            fft(&mut re, &mut im);
+
+            let magnitude = |bin: usize| (re[bin] * re[bin] + im[bin] * im[bin]).sqrt();
+            assert!(magnitude(0) < 1e-3, "mipmap {mipmap_idx} has a DC offset");
+            assert!(magnitude(num_harmonics) > 1e-3, "mipmap {mipmap_idx}");
+            for bin in num_harmonics + 1..table.len() / 2 {
+                assert!(magnitude(bin) < 1e-3, "mipmap {mipmap_idx}, bin {bin}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_mipmap_selection() {
+        let source = WavetableSource {
+            name: String::from("saw"),
+            samples: sawtooth_frame(),
+        };
+        let wavetable = Wavetable::new(&source);
+
+        // At a low pitch the first sample after the saw's reset should be close to the peak, at a
+        // high pitch a band-limited mipmap with far fewer harmonics is used
+        let phase = 8.0 / FRAME_SIZE as f32;
+        assert!(wavetable.sample(phase, 1.0 / 4096.0, 0.0) > 0.8);
+        assert!(wavetable.sample(phase, 0.1, 0.0) < 0.2);
+    }
+
+    #[test]
+    fn test_position_morphs_between_frames() {
+        let mut samples = sawtooth_frame();
+        samples.extend(sawtooth_frame().iter().map(|sample| -sample));
+        let wavetable = Wavetable::new(&WavetableSource {
+            name: String::from("morph"),
+            samples,
+        });
+        assert_eq!(wavetable.frames.len(), 2);
+
+        for phase in [0.1, 0.3, 0.6] {
+            let first = wavetable.sample(phase, 0.001, 0.0);
+            let last = wavetable.sample(phase, 0.001, 1.0);
+            approx::assert_relative_eq!(first, -last, epsilon = 1e-5);
+            approx::assert_relative_eq!(wavetable.sample(phase, 0.001, 0.5), 0.0, epsilon = 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_builtin_table() {
+        let source = WavetableSource::default();
+        assert!(source.is_builtin());
+
+        let wavetable = Wavetable::new(&source);
+        assert_eq!(wavetable.frames.len(), BUILTIN_FRAMES);
+        // The first frame is a plain sine wave
+        approx::assert_relative_eq!(wavetable.sample(0.25, 0.001, 0.0), 1.0, epsilon = 1e-3);
+        approx::assert_relative_eq!(wavetable.sample(0.75, 0.001, 0.0), -1.0, epsilon = 1e-3);
+    }
+
+    #[test]
+    fn test_exchange() {
+        let exchange = WavetableExchange::default();
+        let mut current = Box::default();
+        assert!(!exchange.try_swap(&mut current));
+
+        exchange.publish(Wavetable::new(&WavetableSource::default()));
+        assert!(exchange.try_swap(&mut current));
+        assert_eq!(current.frames.len(), BUILTIN_FRAMES);
+        // The table is only picked up once
+        assert!(!exchange.try_swap(&mut current));
+        assert_eq!(current.frames.len(), BUILTIN_FRAMES);
+    }
+}